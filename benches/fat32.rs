@@ -0,0 +1,75 @@
+//! Throughput of the hot paths: sequential reads, listing a large directory,
+//! resolving deep paths and allocating clusters. Run with `cargo bench`.
+
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use fat32::fat32::file::Fat32OpenOptions;
+use fat32::fat32::format::{format, FormatOptions};
+use fat32::fat32::volume::Fat32Volume;
+
+const MB: usize = 1024 * 1024;
+
+fn fresh_image(size: usize) -> Vec<u8> {
+    let mut data = vec![0u8; size];
+    format(&mut data, &FormatOptions::default()).unwrap();
+    data
+}
+
+fn sequential_read(c: &mut Criterion) {
+    let mut data = fresh_image(34 * MB);
+    let mut volume = Fat32Volume::new(&mut data).unwrap();
+    let content: Vec<u8> = (0..4 * MB).map(|i| i as u8).collect();
+    volume.create_file("big.bin", &content, false).unwrap();
+
+    let mut group = c.benchmark_group("sequential_read");
+    group.throughput(Throughput::Bytes(content.len() as u64));
+    group.bench_function("read_file 4MB", |b| b.iter(|| volume.read_file(black_box("big.bin")).unwrap()));
+    group.finish();
+}
+
+fn list_directory(c: &mut Criterion) {
+    let mut data = fresh_image(34 * MB);
+    let mut volume = Fat32Volume::new(&mut data).unwrap();
+    let dir = volume.create_directory("many").unwrap();
+    for i in 0..10_000 {
+        volume.create_file_in(dir, &format!("F{}.TXT", i), b"", false).unwrap();
+    }
+
+    c.bench_function("read_dir 10k entries", |b| b.iter(|| volume.read_dir(black_box(dir)).unwrap().len()));
+}
+
+fn path_resolution(c: &mut Criterion) {
+    let mut data = fresh_image(34 * MB);
+    let mut volume = Fat32Volume::new(&mut data).unwrap();
+    let mut path = String::new();
+    for _ in 0..16 {
+        path.push_str("/level");
+        volume.create_directory(&path).unwrap();
+    }
+
+    let mut group = c.benchmark_group("resolve_path");
+    for depth in [1, 4, 16] {
+        let path = "/level".repeat(depth);
+        group.bench_with_input(BenchmarkId::from_parameter(depth), &path, |b, path| {
+            b.iter(|| volume.resolve_path(black_box(path)).unwrap())
+        });
+    }
+    group.finish();
+}
+
+fn cluster_allocation(c: &mut Criterion) {
+    let mut data = fresh_image(34 * MB);
+    let mut volume = Fat32Volume::new(&mut data).unwrap();
+
+    let mut group = c.benchmark_group("allocation");
+    group.throughput(Throughput::Bytes(MB as u64));
+    group.bench_function("grow 1MB and free", |b| b.iter(|| {
+        let mut file = Fat32OpenOptions::new().write(true).create(true).open(&mut volume, "alloc.bin").unwrap();
+        file.set_len(MB as u64).unwrap();
+        drop(file);
+        volume.remove_file("alloc.bin").unwrap();
+    }));
+    group.finish();
+}
+
+criterion_group!(benches, sequential_read, list_directory, path_resolution, cluster_allocation);
+criterion_main!(benches);
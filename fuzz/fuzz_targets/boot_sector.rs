@@ -0,0 +1,23 @@
+//! Mounts arbitrary bytes as an image: boot sector parsing, its sanity checks and
+//! the FAT scan done when mounting, then a walk of the tree reading every file.
+
+#![no_main]
+
+use fat32::fat32::bpb::BiosParameterBlock;
+use fat32::fat32::volume::Fat32Volume;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(bpb) = BiosParameterBlock::parse(data) {
+        let _ = bpb.warnings(data.len() as u64);
+    }
+
+    let mut image = data.to_vec();
+    let Ok(volume) = Fat32Volume::new(&mut image) else { return };
+    let _ = volume.get_info();
+    let _ = volume.list_current();
+    let Ok(walk) = volume.walk("/") else { return };
+    for (_, path, metadata) in walk.flatten() {
+        if !metadata.is_dir() { let _ = volume.read_file(&path); }
+    }
+});
@@ -0,0 +1,36 @@
+//! Arbitrary bytes as the root directory of a valid volume: short and long entries,
+//! deleted ones, cluster numbers and sizes pointing anywhere, read back by listing and
+//! walking the tree and reading every file found.
+
+#![no_main]
+
+use fat32::fat32::format::{format, FormatOptions};
+use fat32::fat32::volume::Fat32Volume;
+use libfuzzer_sys::fuzz_target;
+
+/// 34 MiB with 512-byte clusters, about the smallest FAT32 volume: formatting only writes
+/// its first 600 KiB, so it can still be done on every run.
+const IMAGE_SIZE: usize = 34 * 1024 * 1024;
+
+fuzz_target!(|data: &[u8]| {
+    let mut image = vec![0u8; IMAGE_SIZE];
+    format(&mut image, &FormatOptions { sectors_per_cluster: Some(1), ..Default::default() }).unwrap();
+    let root = root_offset(&Fat32Volume::new(&mut image).unwrap());
+    // The root directory is one cluster; longer input spills into the clusters after it.
+    let len = data.len().min(IMAGE_SIZE - root);
+    image[root..root + len].copy_from_slice(&data[..len]);
+
+    let volume = Fat32Volume::new(&mut image).unwrap();
+    let _ = volume.read_dir(2);
+    let Ok(walk) = volume.walk("/") else { return };
+    for (_, path, metadata) in walk.flatten() {
+        if !metadata.is_dir() { let _ = volume.read_file(&path); }
+    }
+});
+
+/// Byte offset of the root directory, the first cluster of the data region.
+fn root_offset(volume: &Fat32Volume) -> usize {
+    let boot = &volume.boot_sector;
+    let sectors = boot.reserved_sectors as usize + boot.number_of_fats as usize * boot.sectors_per_fat_32 as usize;
+    sectors * boot.bytes_per_sector as usize
+}
@@ -0,0 +1,34 @@
+//! Arbitrary paths, with wildcards, `.` and `..`, resolved against a volume whose root
+//! directory holds arbitrary bytes.
+
+#![no_main]
+
+use fat32::fat32::format::{format, FormatOptions};
+use fat32::fat32::volume::Fat32Volume;
+use libfuzzer_sys::fuzz_target;
+
+const IMAGE_SIZE: usize = 34 * 1024 * 1024;
+
+fuzz_target!(|input: (String, Vec<u8>)| {
+    let (path, root_dir) = input;
+    let mut image = vec![0u8; IMAGE_SIZE];
+    format(&mut image, &FormatOptions { sectors_per_cluster: Some(1), ..Default::default() }).unwrap();
+    let root = root_offset(&Fat32Volume::new(&mut image).unwrap());
+    let len = root_dir.len().min(IMAGE_SIZE - root);
+    image[root..root + len].copy_from_slice(&root_dir[..len]);
+
+    let mut volume = Fat32Volume::new(&mut image).unwrap();
+    let _ = volume.resolve_path(&path);
+    let _ = volume.glob(&path);
+    let _ = volume.complete_path(&path);
+    if volume.change_directory(&path).is_ok() {
+        let _ = volume.directory_path(volume.current_cluster);
+    }
+});
+
+/// Byte offset of the root directory, the first cluster of the data region.
+fn root_offset(volume: &Fat32Volume) -> usize {
+    let boot = &volume.boot_sector;
+    let sectors = boot.reserved_sectors as usize + boot.number_of_fats as usize * boot.sectors_per_fat_32 as usize;
+    sectors * boot.bytes_per_sector as usize
+}
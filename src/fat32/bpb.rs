@@ -0,0 +1,168 @@
+//! Every field of the boot sector, for display and sanity checks. The volume itself only
+//! keeps the few it needs, in `BootSector`.
+
+use core::fmt;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BiosParameterBlock {
+    pub jump: [u8; 3],
+    pub oem_name: [u8; 8],
+    pub bytes_per_sector: u16,
+    pub sectors_per_cluster: u8,
+    pub reserved_sectors: u16,
+    pub number_of_fats: u8,
+    pub root_entries: u16,
+    pub total_sectors_16: u16,
+    pub media_descriptor: u8,
+    pub sectors_per_fat_16: u16,
+    pub sectors_per_track: u16,
+    pub heads: u16,
+    pub hidden_sectors: u32,
+    pub total_sectors_32: u32,
+    pub sectors_per_fat_32: u32,
+    pub ext_flags: u16,
+    pub fs_version: u16,
+    pub root_dir_cluster: u32,
+    pub fs_info_sector: u16,
+    pub backup_boot_sector: u16,
+    pub drive_number: u8,
+    /// 0x29 when the serial, label and type fields below are present.
+    pub boot_signature: u8,
+    pub volume_serial: u32,
+    pub volume_label: [u8; 11],
+    pub fs_type: [u8; 8],
+    /// The last two bytes of the sector, 0x55 0xAA on a valid one.
+    pub signature: u16,
+}
+
+impl BiosParameterBlock {
+    /// Reads the first sector of the volume, whatever its values: `warnings` tells what is
+    /// wrong with them. Fails only when `data` is shorter than a sector.
+    pub fn parse(data: &[u8]) -> Result<Self, &'static str> {
+        let data: &[u8; 512] = data.first_chunk().ok_or("Secteur de démarrage tronqué")?;
+        let read_u16 = |offset: usize| u16::from_le_bytes([data[offset], data[offset + 1]]);
+        let read_u32 = |offset: usize| u32::from_le_bytes([data[offset], data[offset + 1], data[offset + 2], data[offset + 3]]);
+
+        Ok(BiosParameterBlock {
+            jump: array(data, 0),
+            oem_name: array(data, 3),
+            bytes_per_sector: read_u16(11),
+            sectors_per_cluster: data[13],
+            reserved_sectors: read_u16(14),
+            number_of_fats: data[16],
+            root_entries: read_u16(17),
+            total_sectors_16: read_u16(19),
+            media_descriptor: data[21],
+            sectors_per_fat_16: read_u16(22),
+            sectors_per_track: read_u16(24),
+            heads: read_u16(26),
+            hidden_sectors: read_u32(28),
+            total_sectors_32: read_u32(32),
+            sectors_per_fat_32: read_u32(36),
+            ext_flags: read_u16(40),
+            fs_version: read_u16(42),
+            root_dir_cluster: read_u32(44),
+            fs_info_sector: read_u16(48),
+            backup_boot_sector: read_u16(50),
+            drive_number: data[64],
+            boot_signature: data[66],
+            volume_serial: read_u32(67),
+            volume_label: array(data, 71),
+            fs_type: array(data, 82),
+            signature: u16::from_be_bytes([data[510], data[511]]),
+        })
+    }
+
+    /// Total sectors, from whichever of the 16 and 32-bit fields is set.
+    pub fn total_sectors(&self) -> u32 {
+        if self.total_sectors_16 != 0 { self.total_sectors_16 as u32 } else { self.total_sectors_32 }
+    }
+
+    /// Values a FAT32 volume should not have. `image_len` is the size of what holds the
+    /// volume, in bytes.
+    pub fn warnings(&self, image_len: u64) -> heapless::Vec<&'static str, 16> {
+        let mut warnings = heapless::Vec::new();
+        let mut warn = |condition: bool, message| if condition { let _ = warnings.push(message); };
+        warn(!matches!(self.bytes_per_sector, 512 | 1024 | 2048 | 4096), "bytes per sector is not 512, 1024, 2048 or 4096");
+        warn(!self.sectors_per_cluster.is_power_of_two(), "sectors per cluster is not a power of two");
+        warn(self.reserved_sectors == 0, "no reserved sectors");
+        warn(self.number_of_fats == 0, "no FAT");
+        warn(self.number_of_fats > 2, "more than two FATs");
+        warn(self.root_entries != 0, "root entry count is set, FAT32 keeps the root in clusters");
+        warn(self.sectors_per_fat_16 != 0 || self.total_sectors_16 != 0, "FAT12/16 size fields are set");
+        warn(self.sectors_per_fat_32 == 0, "FAT size is zero");
+        warn(self.media_descriptor != 0xF0 && self.media_descriptor < 0xF8, "unknown media descriptor");
+        warn(self.total_sectors() as u64 * self.bytes_per_sector as u64 > image_len, "volume is larger than the image");
+        warn(self.root_dir_cluster < 2, "root directory cluster is below 2");
+        warn(self.fs_info_sector == 0 || self.fs_info_sector >= self.reserved_sectors, "FSInfo sector is outside the reserved area");
+        warn(self.backup_boot_sector == 0 || self.backup_boot_sector >= self.reserved_sectors, "no backup boot sector");
+        warn(self.boot_signature != 0x29, "no extended boot signature, serial and label are not valid");
+        warn(self.signature != 0x55AA, "missing 0x55AA signature");
+        warnings
+    }
+}
+
+/// The `N` bytes of `sector` from `offset` on.
+fn array<const N: usize>(sector: &[u8; 512], offset: usize) -> [u8; N] {
+    core::array::from_fn(|i| sector[offset + i])
+}
+
+/// Writes `bytes` as text, non-printable bytes as `.`.
+fn write_ascii(f: &mut fmt::Formatter<'_>, bytes: &[u8]) -> fmt::Result {
+    for &b in bytes {
+        write!(f, "{}", if b.is_ascii_graphic() || b == b' ' { b as char } else { '.' })?;
+    }
+    Ok(())
+}
+
+impl fmt::Display for BiosParameterBlock {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, " - Jump: {:02x} {:02x} {:02x}\n - OEM Name: ", self.jump[0], self.jump[1], self.jump[2])?;
+        write_ascii(f, &self.oem_name)?;
+        writeln!(f)?;
+        writeln!(f, " - Bytes per Sector: {}", self.bytes_per_sector)?;
+        writeln!(f, " - Sectors per Cluster: {}", self.sectors_per_cluster)?;
+        writeln!(f, " - Reserved Sectors: {}", self.reserved_sectors)?;
+        writeln!(f, " - Number of FATs: {}", self.number_of_fats)?;
+        writeln!(f, " - Root Entries: {}", self.root_entries)?;
+        writeln!(f, " - Total Sectors (16/32): {} / {}", self.total_sectors_16, self.total_sectors_32)?;
+        writeln!(f, " - Media Descriptor: {:#04x}", self.media_descriptor)?;
+        writeln!(f, " - Sectors per FAT (16/32): {} / {}", self.sectors_per_fat_16, self.sectors_per_fat_32)?;
+        writeln!(f, " - Sectors per Track: {}", self.sectors_per_track)?;
+        writeln!(f, " - Heads: {}", self.heads)?;
+        writeln!(f, " - Hidden Sectors: {}", self.hidden_sectors)?;
+        writeln!(f, " - Ext Flags: {:#06x}", self.ext_flags)?;
+        writeln!(f, " - FS Version: {}.{}", self.fs_version >> 8, self.fs_version & 0xFF)?;
+        writeln!(f, " - Root Cluster: {}", self.root_dir_cluster)?;
+        writeln!(f, " - FSInfo Sector: {}", self.fs_info_sector)?;
+        writeln!(f, " - Backup Boot Sector: {}", self.backup_boot_sector)?;
+        writeln!(f, " - Drive Number: {:#04x}", self.drive_number)?;
+        writeln!(f, " - Boot Signature: {:#04x}", self.boot_signature)?;
+        writeln!(f, " - Volume Serial: {:04X}-{:04X}", self.volume_serial >> 16, self.volume_serial & 0xFFFF)?;
+        write!(f, " - Volume Label: ")?;
+        write_ascii(f, &self.volume_label)?;
+        write!(f, "\n - FS Type: ")?;
+        write_ascii(f, &self.fs_type)?;
+        write!(f, "\n - Signature: {:#06x}", self.signature)
+    }
+}
+
+#[cfg(all(test, feature = "alloc"))]
+mod tests {
+    use super::*;
+    use alloc::vec;
+    use crate::fat32::format::{format, FormatOptions};
+
+    #[test]
+    fn test_parse_formatted() {
+        let mut data = vec![0u8; 34 * 1024 * 1024];
+        format(&mut data, &FormatOptions { volume_id: 0x1234ABCD, ..Default::default() }).unwrap();
+        let bpb = BiosParameterBlock::parse(&data).unwrap();
+        assert_eq!(&bpb.oem_name, b"MSWIN4.1");
+        assert_eq!((bpb.fs_info_sector, bpb.backup_boot_sector), (1, 6));
+        assert_eq!(bpb.volume_serial, 0x1234ABCD);
+        assert_eq!(&bpb.fs_type, b"FAT32   ");
+        assert!(bpb.warnings(data.len() as u64).is_empty());
+        assert_eq!(bpb.warnings(1024).as_slice(), ["volume is larger than the image"]);
+    }
+}
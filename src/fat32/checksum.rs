@@ -0,0 +1,91 @@
+extern crate alloc;
+use alloc::string::String;
+use core::fmt::Write;
+
+use md5::Md5;
+use sha2::{Digest, Sha256};
+
+use super::volume::Fat32Volume;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HashAlgorithm {
+    Sha256,
+    Md5,
+}
+
+impl<'a> Fat32Volume<'a> {
+    /// Hex digest of the file at `path`, fed to the hasher one cluster at a time.
+    pub fn hash_file(&self, path: &str, algorithm: HashAlgorithm) -> Result<String, &'static str> {
+        let entry = self.file_entry(path)?;
+        self.hash_chain(entry.first_cluster, entry.size, algorithm)
+    }
+
+    /// Calls `visit` with the path (relative to `path`) and digest of every file below
+    /// the directory `path`, depth first. Returns the number of files.
+    /// Files are hashed in parallel with the `parallel` feature; `visit` still sees them in order.
+    pub fn checksum_tree(&self, path: &str, algorithm: HashAlgorithm, visit: &mut dyn FnMut(&str, &str)) -> Result<usize, &'static str> {
+        let digests = self.map_files(path, |volume, metadata| volume.hash_chain(metadata.first_cluster, metadata.size, algorithm))?;
+        let count = digests.len();
+        for (path, digest) in digests {
+            visit(&path, &digest?);
+        }
+        Ok(count)
+    }
+
+    pub(super) fn hash_chain(&self, cluster: u32, size: u32, algorithm: HashAlgorithm) -> Result<String, &'static str> {
+        match algorithm {
+            HashAlgorithm::Sha256 => self.digest_hex::<Sha256>(cluster, size),
+            HashAlgorithm::Md5 => self.digest_hex::<Md5>(cluster, size),
+        }
+    }
+
+    fn digest_hex<D: Digest>(&self, cluster: u32, size: u32) -> Result<String, &'static str> {
+        let mut hasher = D::new();
+        self.for_each_chunk(cluster, size, |chunk| hasher.update(chunk))?;
+        Ok(to_hex(&hasher.finalize()))
+    }
+}
+
+fn to_hex(digest: &[u8]) -> String {
+    let mut hex = String::new();
+    for byte in digest {
+        let _ = write!(hex, "{:02x}", byte);
+    }
+    hex
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec::Vec;
+    use alloc::format;
+    use crate::fat32::volume::tests::create_mock_volume;
+
+    #[test]
+    fn test_hash_file_across_clusters() {
+        let mut data = create_mock_volume();
+        let mut volume = Fat32Volume::new(&mut data).unwrap();
+        volume.create_file("abc.txt", b"abc", false).unwrap();
+        volume.create_file("big.bin", &[b'a'; 1000], false).unwrap();
+
+        assert_eq!(volume.hash_file("abc.txt", HashAlgorithm::Sha256).unwrap(),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad");
+        assert_eq!(volume.hash_file("abc.txt", HashAlgorithm::Md5).unwrap(),
+            "900150983cd24fb0d6963f7d28e17f72");
+        assert_eq!(volume.hash_file("big.bin", HashAlgorithm::Md5).unwrap(),
+            "cabe45dcc9ae5b66ba86600cca6b8ba8");
+    }
+
+    #[test]
+    fn test_checksum_tree() {
+        let mut data = create_mock_volume();
+        let mut volume = Fat32Volume::new(&mut data).unwrap();
+        let fw = volume.create_directory("fw").unwrap();
+        volume.create_file_in(fw, "a.bin", b"abc", false).unwrap();
+
+        let mut manifest = Vec::new();
+        let count = volume.checksum_tree("/", HashAlgorithm::Md5, &mut |p, d| manifest.push(format!("{}  {}", d, p))).unwrap();
+        assert_eq!(count, 1);
+        assert_eq!(manifest, ["900150983cd24fb0d6963f7d28e17f72  fw/a.bin"]);
+    }
+}
@@ -0,0 +1,67 @@
+/// OEM codepages used to store non-ASCII bytes in short names.
+/// Long names are UTF-16 and don't depend on it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Codepage {
+    /// US (the DOS and Windows default).
+    #[default]
+    Cp437,
+    /// Western Europe.
+    Cp850,
+}
+
+impl Codepage {
+    pub fn from_number(number: u16) -> Option<Self> {
+        match number {
+            437 => Some(Codepage::Cp437),
+            850 => Some(Codepage::Cp850),
+            _ => None,
+        }
+    }
+
+    pub fn number(self) -> u16 {
+        match self {
+            Codepage::Cp437 => 437,
+            Codepage::Cp850 => 850,
+        }
+    }
+
+    fn table(self) -> &'static [char; 128] {
+        match self {
+            Codepage::Cp437 => &CP437,
+            Codepage::Cp850 => &CP850,
+        }
+    }
+
+    pub fn decode(self, byte: u8) -> char {
+        if byte < 0x80 { byte as char } else { self.table()[byte as usize - 0x80] }
+    }
+
+    /// Byte representing `c` in this codepage, if it has one.
+    pub fn encode(self, c: char) -> Option<u8> {
+        if c.is_ascii() { return Some(c as u8); }
+        self.table().iter().position(|&t| t == c).map(|i| (i + 0x80) as u8)
+    }
+}
+
+/// Characters of bytes 0x80-0xFF.
+const CP437: [char; 128] = [
+    'Ç', 'ü', 'é', 'â', 'ä', 'à', 'å', 'ç', 'ê', 'ë', 'è', 'ï', 'î', 'ì', 'Ä', 'Å',
+    'É', 'æ', 'Æ', 'ô', 'ö', 'ò', 'û', 'ù', 'ÿ', 'Ö', 'Ü', '¢', '£', '¥', '₧', 'ƒ',
+    'á', 'í', 'ó', 'ú', 'ñ', 'Ñ', 'ª', 'º', '¿', '⌐', '¬', '½', '¼', '¡', '«', '»',
+    '░', '▒', '▓', '│', '┤', '╡', '╢', '╖', '╕', '╣', '║', '╗', '╝', '╜', '╛', '┐',
+    '└', '┴', '┬', '├', '─', '┼', '╞', '╟', '╚', '╔', '╩', '╦', '╠', '═', '╬', '╧',
+    '╨', '╤', '╥', '╙', '╘', '╒', '╓', '╫', '╪', '┘', '┌', '█', '▄', '▌', '▐', '▀',
+    'α', 'ß', 'Γ', 'π', 'Σ', 'σ', 'µ', 'τ', 'Φ', 'Θ', 'Ω', 'δ', '∞', 'φ', 'ε', '∩',
+    '≡', '±', '≥', '≤', '⌠', '⌡', '÷', '≈', '°', '∙', '·', '√', 'ⁿ', '²', '■', '\u{00A0}',
+];
+
+const CP850: [char; 128] = [
+    'Ç', 'ü', 'é', 'â', 'ä', 'à', 'å', 'ç', 'ê', 'ë', 'è', 'ï', 'î', 'ì', 'Ä', 'Å',
+    'É', 'æ', 'Æ', 'ô', 'ö', 'ò', 'û', 'ù', 'ÿ', 'Ö', 'Ü', 'ø', '£', 'Ø', '×', 'ƒ',
+    'á', 'í', 'ó', 'ú', 'ñ', 'Ñ', 'ª', 'º', '¿', '®', '¬', '½', '¼', '¡', '«', '»',
+    '░', '▒', '▓', '│', '┤', 'Á', 'Â', 'À', '©', '╣', '║', '╗', '╝', '¢', '¥', '┐',
+    '└', '┴', '┬', '├', '─', '┼', 'ã', 'Ã', '╚', '╔', '╩', '╦', '╠', '═', '╬', '¤',
+    'ð', 'Ð', 'Ê', 'Ë', 'È', 'ı', 'Í', 'Î', 'Ï', '┘', '┌', '█', '▄', '¦', 'Ì', '▀',
+    'Ó', 'ß', 'Ô', 'Ò', 'õ', 'Õ', 'µ', 'þ', 'Þ', 'Ú', 'Û', 'Ù', 'ý', 'Ý', '¯', '´',
+    '\u{00AD}', '±', '‗', '¾', '¶', '§', '÷', '¸', '°', '¨', '·', '¹', '³', '²', '■', '\u{00A0}',
+];
@@ -0,0 +1,90 @@
+use super::volume::Fat32Volume;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Comparison {
+    Identical,
+    /// Offset of the first differing byte. When one side is a prefix of the other,
+    /// this is the length of the shorter one.
+    DiffersAt(u64),
+}
+
+impl<'a> Fat32Volume<'a> {
+    /// Compares the file at `path` with another stream, one cluster at a time.
+    /// `read_other` fills the buffer it is given and returns how many bytes it wrote,
+    /// 0 meaning end of stream, like `read(2)`.
+    pub fn compare_file(&self, path: &str, read_other: &mut dyn FnMut(&mut [u8]) -> Result<usize, &'static str>) -> Result<Comparison, &'static str> {
+        let entry = self.file_entry(path)?;
+        let mut buffer = alloc::vec![0u8; self.cluster_size()];
+        let mut offset = 0u64;
+
+        // Offset of the first difference, once found.
+        let mut outcome: Result<Option<u64>, &'static str> = Ok(None);
+        self.for_each_chunk(entry.first_cluster, entry.size, |chunk| {
+            if !matches!(outcome, Ok(None)) { return; }
+            let other = &mut buffer[..chunk.len()];
+            outcome = read_full(read_other, other).map(|filled| {
+                chunk[..filled].iter().zip(other.iter()).position(|(a, b)| a != b)
+                    .or((filled < chunk.len()).then_some(filled))
+                    .map(|i| offset + i as u64)
+            });
+            offset += chunk.len() as u64;
+        })?;
+        if let Some(at) = outcome? {
+            return Ok(Comparison::DiffersAt(at));
+        }
+
+        // The image file is exhausted; the other stream must be too.
+        if read_other(&mut buffer[..1])? > 0 {
+            return Ok(Comparison::DiffersAt(offset));
+        }
+        Ok(Comparison::Identical)
+    }
+}
+
+/// Calls `read` until `buf` is full or the stream ends, returning the bytes read.
+fn read_full(read: &mut dyn FnMut(&mut [u8]) -> Result<usize, &'static str>, buf: &mut [u8]) -> Result<usize, &'static str> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        let n = read(&mut buf[filled..])?;
+        if n == 0 { break; }
+        filled += n;
+    }
+    Ok(filled)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec::Vec;
+    use crate::fat32::volume::tests::create_mock_volume;
+
+    fn reader(data: &[u8]) -> impl FnMut(&mut [u8]) -> Result<usize, &'static str> + '_ {
+        let mut pos = 0;
+        // Hands out at most 100 bytes per call, like a short read.
+        move |buf: &mut [u8]| {
+            let n = buf.len().min(data.len() - pos).min(100);
+            buf[..n].copy_from_slice(&data[pos..pos + n]);
+            pos += n;
+            Ok(n)
+        }
+    }
+
+    #[test]
+    fn test_compare_file() {
+        let mut data = create_mock_volume();
+        let mut volume = Fat32Volume::new(&mut data).unwrap();
+        let content: Vec<u8> = (0..2000).map(|i| i as u8).collect();
+        volume.create_file("fw.bin", &content, false).unwrap();
+
+        assert_eq!(volume.compare_file("fw.bin", &mut reader(&content)), Ok(Comparison::Identical));
+
+        let mut changed = content.clone();
+        changed[1500] ^= 0xFF;
+        assert_eq!(volume.compare_file("fw.bin", &mut reader(&changed)), Ok(Comparison::DiffersAt(1500)));
+        assert_eq!(volume.compare_file("fw.bin", &mut reader(&content[..700])), Ok(Comparison::DiffersAt(700)));
+
+        let mut longer = content.clone();
+        longer.push(0);
+        assert_eq!(volume.compare_file("fw.bin", &mut reader(&longer)), Ok(Comparison::DiffersAt(2000)));
+    }
+}
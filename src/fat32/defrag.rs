@@ -0,0 +1,178 @@
+extern crate alloc;
+use alloc::vec::Vec;
+
+use super::dir::DirEntry;
+use super::fat::{is_contiguous, FAT_EOC, FAT_FREE};
+use super::progress::{ProgressSink, ProgressTracker};
+use super::volume::Fat32Volume;
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct DefragReport {
+    pub files_moved: usize,
+    pub clusters_moved: usize,
+    /// Fragmented files left as they were because no free run was large enough.
+    pub files_skipped: usize,
+    /// Fragmented directories, the root included. They are never moved, see `defrag`.
+    pub dirs_fragmented: usize,
+}
+
+impl<'a> Fat32Volume<'a> {
+    /// Rewrites every fragmented file into a contiguous run of clusters.
+    /// With `compact`, files are also moved toward the start of the data region
+    /// whenever a free run exists before their current position.
+    ///
+    /// Directories are left in place: moving them would also require rewriting
+    /// the `..` entry of each of their sub-directories. Those that are fragmented are
+    /// counted in the report, so they are not taken for done.
+    /// Progress is reported in bytes of files examined.
+    pub fn defrag(&mut self, compact: bool, progress: &mut dyn ProgressSink) -> Result<DefragReport, &'static str> {
+        let mut files = Vec::new();
+        let mut dirs = Vec::new();
+        let root = self.boot_sector.root_dir_cluster;
+        self.collect_files(root, &mut dirs, &mut files)?;
+
+        if compact {
+            files.sort_by_key(|f| f.first_cluster);
+        }
+
+        let total = files.iter().map(|f| f.size as u64).sum();
+        let mut tracker = ProgressTracker::new(progress, total);
+        let mut report = DefragReport::default();
+        for dir in dirs {
+            if !is_contiguous(&self.cluster_chain(dir)?) { report.dirs_fragmented += 1; }
+        }
+        for file in files {
+            tracker.file(&file.name, file.size as u64);
+            if file.first_cluster < 2 { continue; }
+            let chain = self.cluster_chain(file.first_cluster)?;
+            let fragmented = !is_contiguous(&chain);
+            if !fragmented && !compact { continue; }
+
+            let target = match self.find_free_run(chain.len() as u32) {
+                Some(t) => t,
+                None => {
+                    if fragmented { report.files_skipped += 1; }
+                    continue;
+                }
+            };
+            if !fragmented && target > chain[0] { continue; }
+
+            self.move_chain(&chain, target)?;
+            self.set_entry_cluster(file.offset, target)?;
+            report.files_moved += 1;
+            report.clusters_moved += chain.len();
+        }
+        tracker.finish();
+        Ok(report)
+    }
+
+    /// Gathers every file below the directory at `cluster`. `visited` guards against
+    /// corrupted directories pointing back to one of their parents.
+    fn collect_files(&self, cluster: u32, visited: &mut Vec<u32>, files: &mut Vec<DirEntry>) -> Result<(), &'static str> {
+        if visited.contains(&cluster) { return Ok(()); }
+        visited.push(cluster);
+
+        for entry in self.read_dir(cluster)? {
+            if entry.is_dot() { continue; }
+            if entry.is_dir() {
+                if entry.first_cluster >= 2 {
+                    self.collect_files(entry.first_cluster, visited, files)?;
+                }
+            } else {
+                files.push(entry);
+            }
+        }
+        Ok(())
+    }
+
+    /// Copies the clusters of `chain` to the free run starting at `target`,
+    /// links the new run in the FAT and releases the old clusters.
+    fn move_chain(&mut self, chain: &[u32], target: u32) -> Result<(), &'static str> {
+        let cluster_size = self.cluster_size();
+        let len = chain.len() as u32;
+
+        for (i, &old) in chain.iter().enumerate() {
+            let new = target + i as u32;
+            let src = self.offset_from_cluster(old)?;
+            let dst = self.offset_from_cluster(new)?;
+            self.storage.copy_within(src..src + cluster_size, dst)?;
+            let next = if i as u32 + 1 == len { FAT_EOC } else { new + 1 };
+            self.write_fat_entry(new, next)?;
+        }
+        for &old in chain {
+            self.write_fat_entry(old, FAT_FREE)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fat32::progress::NoProgress;
+    use crate::fat32::volume::tests::{create_mock_volume, put_raw_entry};
+
+    #[test]
+    fn test_defrag_makes_file_contiguous() {
+        let mut data = create_mock_volume();
+        let mut volume = Fat32Volume::new(&mut data).unwrap();
+
+        // FRAG.BIN spans clusters 5 -> 9 -> 7, each filled with its index.
+        let chain = [5u32, 9, 7];
+        for (i, &c) in chain.iter().enumerate() {
+            let offset = volume.offset_from_cluster(c).unwrap();
+            volume.storage.fill(offset, 512, i as u8 + 1).unwrap();
+            let next = chain.get(i + 1).copied().unwrap_or(FAT_EOC);
+            volume.write_fat_entry(c, next).unwrap();
+        }
+        let root = volume.offset_from_cluster(2).unwrap();
+        put_raw_entry(&mut volume, root, b"FRAG    BIN", 0x20, 5, 1536);
+
+        let report = volume.defrag(false, &mut NoProgress).unwrap();
+        assert_eq!(report.files_moved, 1);
+        assert_eq!(report.clusters_moved, 3);
+
+        let entry = &volume.read_dir(2).unwrap()[0];
+        let new_chain = volume.cluster_chain(entry.first_cluster).unwrap();
+        assert!(is_contiguous(&new_chain));
+        assert_eq!(volume.read_fat_entry(9).unwrap(), FAT_FREE);
+
+        let content = volume.read_file("FRAG.BIN").unwrap();
+        assert_eq!(content[0], 1);
+        assert_eq!(content[512], 2);
+        assert_eq!(content[1024], 3);
+    }
+
+    #[test]
+    fn test_defrag_reports_fragmented_directories() {
+        let mut data = create_mock_volume();
+        let mut volume = Fat32Volume::new(&mut data).unwrap();
+
+        // LOGS spans clusters 5 -> 8; its entries must stay where they are.
+        volume.write_fat_entry(5, 8).unwrap();
+        volume.write_fat_entry(8, FAT_EOC).unwrap();
+        let root = volume.offset_from_cluster(2).unwrap();
+        put_raw_entry(&mut volume, root, b"LOGS       ", 0x10, 5, 0);
+
+        let report = volume.defrag(true, &mut NoProgress).unwrap();
+        assert_eq!((report.files_moved, report.dirs_fragmented), (0, 1));
+        assert_eq!(volume.cluster_chain(5).unwrap(), alloc::vec![5, 8]);
+    }
+
+    #[test]
+    fn test_defrag_compact_moves_data_to_start() {
+        let mut data = create_mock_volume();
+        let mut volume = Fat32Volume::new(&mut data).unwrap();
+
+        volume.write_fat_entry(50, 51).unwrap();
+        volume.write_fat_entry(51, FAT_EOC).unwrap();
+        let root = volume.offset_from_cluster(2).unwrap();
+        put_raw_entry(&mut volume, root, b"LATE    TXT", 0x20, 50, 600);
+
+        assert_eq!(volume.defrag(false, &mut NoProgress).unwrap().files_moved, 0);
+        let report = volume.defrag(true, &mut NoProgress).unwrap();
+        assert_eq!(report.files_moved, 1);
+        assert_eq!(volume.read_dir(2).unwrap()[0].first_cluster, 3);
+        assert_eq!(volume.cluster_chain(3).unwrap(), alloc::vec![3, 4]);
+    }
+}
@@ -0,0 +1,404 @@
+extern crate alloc;
+use alloc::vec::Vec;
+use alloc::vec;
+use alloc::string::String;
+use alloc::format;
+use core::cmp::Ordering;
+use log::warn;
+
+use super::codepage::Codepage;
+use super::fat::{FAT_EOC, FAT_FREE};
+use super::name::{decode_lfn, format_name, lfn_checksum, lfn_chars, LFN_CHARS_PER_ENTRY, LFN_LAST_ENTRY};
+use super::time::DateTime;
+use super::volume::Fat32Volume;
+
+pub const ATTR_READ_ONLY: u8 = 0x01;
+pub const ATTR_HIDDEN: u8 = 0x02;
+pub const ATTR_SYSTEM: u8 = 0x04;
+pub const ATTR_VOLUME_ID: u8 = 0x08;
+pub const ATTR_DIRECTORY: u8 = 0x10;
+pub const ATTR_ARCHIVE: u8 = 0x20;
+pub const ATTR_LONG_NAME: u8 = 0x0F;
+
+/// Most 32-byte slots a directory can have, so a looping or oversized chain read from a
+/// damaged image ends.
+pub const MAX_DIR_ENTRIES: usize = 65536;
+
+/// A parsed directory entry: the 32-byte short entry plus its long name, if any.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DirEntry {
+    /// The long name when one is stored, otherwise the short name as `NAME.EXT`.
+    pub name: String,
+    /// The raw 8.3 name as stored on disk, space padded.
+    pub short_name: [u8; 11],
+    /// The short name rendered as `NAME.EXT`.
+    pub alias: String,
+    pub attr: u8,
+    pub first_cluster: u32,
+    pub size: u32,
+    pub created: DateTime,
+    /// Hundredths of a second to add to `created`, 0 to 199, as the 2-second resolution
+    /// of the time field can't hold them (the `CrtTimeTenth` byte, despite its name).
+    pub created_hundredths: u8,
+    pub modified: DateTime,
+    /// Last access date; FAT doesn't store a time for it.
+    pub accessed: DateTime,
+    /// Absolute offset of the entry in the image, used to update it in place.
+    pub offset: usize,
+    /// Absolute offsets of the long-name entries that precede it.
+    pub lfn_offsets: Vec<usize>,
+}
+
+impl DirEntry {
+    fn parse(raw: &[u8; 32], offset: usize, codepage: Codepage) -> Self {
+        let short_name: [u8; 11] = core::array::from_fn(|i| raw[i]);
+        let read_u16 = |at: usize| u16::from_le_bytes([raw[at], raw[at + 1]]);
+        let cluster_hi = read_u16(20);
+        let cluster_lo = read_u16(26);
+
+        let alias = format_name(&short_name, codepage, raw[12]);
+        DirEntry {
+            name: alias.clone(),
+            short_name,
+            alias,
+            attr: raw[11],
+            first_cluster: ((cluster_hi as u32) << 16) | (cluster_lo as u32),
+            size: u32::from_le_bytes([raw[28], raw[29], raw[30], raw[31]]),
+            created: DateTime::from_fat(read_u16(16), read_u16(14)),
+            created_hundredths: raw[13],
+            modified: DateTime::from_fat(read_u16(24), read_u16(22)),
+            accessed: DateTime::from_fat(read_u16(18), 0),
+            offset,
+            lfn_offsets: Vec::new(),
+        }
+    }
+
+    /// True when `name` is either the long or the short name of the entry, ignoring case.
+    pub fn matches(&self, name: &str) -> bool {
+        self.name.eq_ignore_ascii_case(name) || self.alias.eq_ignore_ascii_case(name)
+    }
+
+    pub fn is_dir(&self) -> bool {
+        (self.attr & ATTR_DIRECTORY) != 0
+    }
+
+    pub fn is_volume_label(&self) -> bool {
+        (self.attr & ATTR_VOLUME_ID) != 0
+    }
+
+    /// True for the `.` and `..` entries every sub-directory starts with.
+    pub fn is_dot(&self) -> bool {
+        self.name == "." || self.name == ".."
+    }
+
+    /// One line of a short listing: `<DIR> name (0 bytes)`, `<VOL>` for a volume label.
+    pub fn list_line(&self) -> String {
+        let type_str = if self.is_volume_label() { "<VOL>" } else if self.is_dir() { "<DIR>" } else { "     " };
+        format!("{} {} ({} bytes)", type_str, self.name, self.size)
+    }
+
+    pub fn metadata(&self) -> Metadata {
+        Metadata {
+            attr: self.attr,
+            first_cluster: self.first_cluster,
+            size: self.size,
+            created: self.created,
+            created_hundredths: self.created_hundredths,
+            modified: self.modified,
+            accessed: self.accessed,
+        }
+    }
+}
+
+/// Order of the entries `list_entries` returns.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SortOrder {
+    /// As stored in the directory.
+    #[default]
+    Disk,
+    /// By name, ignoring case.
+    Name,
+    /// Largest first.
+    Size,
+    /// Most recently modified first.
+    Time,
+}
+
+/// Which entries `list_entries` and `list_recursive` return, and in which order.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ListOptions {
+    /// Include hidden entries, `.`/`..` and volume labels, like `ls -a`.
+    pub all: bool,
+    pub sort: SortOrder,
+    pub reverse: bool,
+}
+
+/// Sorts `entries` in place, keeping `.` and `..` first. The sort is stable: entries
+/// that compare equal stay in directory order, whichever the direction, except for
+/// `SortOrder::Disk` where reversing reverses everything.
+pub fn sort_entries(entries: &mut [DirEntry], order: SortOrder, reverse: bool) {
+    let compare = |a: &DirEntry, b: &DirEntry| match order {
+        SortOrder::Disk => Ordering::Equal,
+        SortOrder::Name => a.name.to_lowercase().cmp(&b.name.to_lowercase()),
+        SortOrder::Size => b.size.cmp(&a.size),
+        SortOrder::Time => b.modified.cmp(&a.modified),
+    };
+    let order_of = |a: &DirEntry, b: &DirEntry| if reverse { compare(b, a) } else { compare(a, b) };
+    entries.sort_by(|a, b| match (a.is_dot(), b.is_dot()) {
+        (false, false) => order_of(a, b),
+        (a_dot, b_dot) => b_dot.cmp(&a_dot),
+    });
+    if order == SortOrder::Disk && reverse {
+        let dots = entries.iter().take_while(|e| e.is_dot()).count();
+        entries[dots..].reverse();
+    }
+}
+
+/// The attributes, size and timestamps of an entry, detached from its location on disk.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Metadata {
+    pub attr: u8,
+    pub first_cluster: u32,
+    pub size: u32,
+    pub created: DateTime,
+    pub created_hundredths: u8,
+    pub modified: DateTime,
+    pub accessed: DateTime,
+}
+
+impl Metadata {
+    pub fn is_dir(&self) -> bool {
+        (self.attr & ATTR_DIRECTORY) != 0
+    }
+
+    pub fn is_hidden(&self) -> bool {
+        (self.attr & ATTR_HIDDEN) != 0
+    }
+
+    /// True when the file changed since its archive attribute was last cleared.
+    pub fn is_archive(&self) -> bool {
+        (self.attr & ATTR_ARCHIVE) != 0
+    }
+}
+
+/// How the 32-byte slots of a directory's cluster chain are used, from `dir_slots`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct DirSlots {
+    /// Clusters in the directory's chain.
+    pub clusters: usize,
+    /// Short entries in use, including `.`, `..` and the volume label.
+    pub used: usize,
+    /// Long-name entries in use.
+    pub long_name: usize,
+    /// Entries marked deleted (0xE5), reusable but still walked by every lookup.
+    pub deleted: usize,
+    /// Slots from the end marker on, never written since the directory was cleared.
+    pub free: usize,
+}
+
+impl DirSlots {
+    pub fn total(&self) -> usize {
+        self.used + self.long_name + self.deleted + self.free
+    }
+}
+
+/// What `compact_dir` did to a directory.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CompactReport {
+    /// Deleted entries dropped.
+    pub removed: usize,
+    /// Clusters at the end of the chain left empty and freed.
+    pub freed_clusters: usize,
+}
+
+impl<'a> Fat32Volume<'a> {
+    /// Returns the entries of the directory starting at `cluster`, following its whole
+    /// cluster chain. Deleted entries, long-name fragments and volume labels are skipped.
+    pub fn read_dir(&self, cluster: u32) -> Result<Vec<DirEntry>, &'static str> {
+        self.read_dir_entries(cluster, false)
+    }
+
+    /// Same as `read_dir`, keeping the volume label entries (only the root should hold one).
+    pub fn read_dir_with_labels(&self, cluster: u32) -> Result<Vec<DirEntry>, &'static str> {
+        self.read_dir_entries(cluster, true)
+    }
+
+    fn read_dir_entries(&self, cluster: u32, labels: bool) -> Result<Vec<DirEntry>, &'static str> {
+        let mut slots = 0;
+        let mut entries = Vec::new();
+        let mut raw_cluster = vec![0u8; self.cluster_size()];
+        // Long-name characters gathered so far, with the offsets and checksum of their entries.
+        let mut lfn: Vec<u16> = Vec::new();
+        let mut lfn_offsets: Vec<usize> = Vec::new();
+        let mut checksum = 0;
+
+        for c in self.cluster_chain(cluster)? {
+            let start = self.offset_from_cluster(c)?;
+            self.storage.read_dir(start, &mut raw_cluster)?;
+            for (i, raw) in raw_cluster.as_chunks::<32>().0.iter().enumerate() {
+                let cursor = start + i * 32;
+                if slots == MAX_DIR_ENTRIES {
+                    warn!("directory at cluster {} goes on past {} entries, the rest is ignored", cluster, MAX_DIR_ENTRIES);
+                    return Ok(entries);
+                }
+                slots += 1;
+                if raw[0] == 0 { return Ok(entries); }
+                if raw[0] == 0xE5 { lfn.clear(); continue; }
+                let attr = raw[11];
+
+                if attr == ATTR_LONG_NAME {
+                    let seq = (raw[0] & 0x1F) as usize;
+                    if raw[0] & LFN_LAST_ENTRY != 0 {
+                        lfn = vec![0xFFFF; seq * LFN_CHARS_PER_ENTRY];
+                        lfn_offsets.clear();
+                        checksum = raw[13];
+                    }
+                    if seq == 0 || seq * LFN_CHARS_PER_ENTRY > lfn.len() || raw[13] != checksum {
+                        warn!("orphan long-name entry at offset {:#x}", cursor);
+                        lfn.clear();
+                        continue;
+                    }
+                    let at = (seq - 1) * LFN_CHARS_PER_ENTRY;
+                    lfn[at..at + LFN_CHARS_PER_ENTRY].copy_from_slice(&lfn_chars(raw));
+                    lfn_offsets.push(cursor);
+                    continue;
+                }
+                if (attr & ATTR_VOLUME_ID) != 0 && !labels { lfn.clear(); continue; }
+
+                let mut entry = DirEntry::parse(raw, cursor, self.codepage);
+                if !lfn.is_empty() && checksum == lfn_checksum(&entry.short_name) {
+                    entry.name = decode_lfn(&lfn);
+                    entry.lfn_offsets = core::mem::take(&mut lfn_offsets);
+                } else if !lfn.is_empty() {
+                    warn!("long name before {} doesn't match its checksum, ignored", entry.alias);
+                }
+                lfn.clear();
+                entries.push(entry);
+            }
+        }
+        Ok(entries)
+    }
+
+    /// Counts the used, deleted and free slots of the directory starting at `cluster`,
+    /// across its whole cluster chain. Everything past the end marker counts as free,
+    /// whatever it holds, as readers stop there.
+    pub fn dir_slots(&self, cluster: u32) -> Result<DirSlots, &'static str> {
+        let chain = self.cluster_chain(cluster)?;
+        let mut slots = DirSlots { clusters: chain.len(), ..DirSlots::default() };
+        let mut raw_cluster = vec![0u8; self.cluster_size()];
+        let mut ended = false;
+        for c in chain {
+            self.storage.read_dir(self.offset_from_cluster(c)?, &mut raw_cluster)?;
+            for raw in raw_cluster.chunks_exact(32) {
+                ended |= raw[0] == 0;
+                match raw[0] {
+                    _ if ended => slots.free += 1,
+                    0xE5 => slots.deleted += 1,
+                    _ if raw[11] == ATTR_LONG_NAME => slots.long_name += 1,
+                    _ => slots.used += 1,
+                }
+            }
+        }
+        Ok(slots)
+    }
+
+    /// Rewrites the directory starting at `cluster` with its entries back to back, in the
+    /// same order, dropping the deleted ones, then frees the clusters left empty at the end
+    /// of its chain. The first cluster is always kept, so the entry pointing at the
+    /// directory and the `..` of its children stay valid.
+    pub fn compact_dir(&mut self, cluster: u32) -> Result<CompactReport, &'static str> {
+        let chain = self.cluster_chain(cluster)?;
+        let size = self.cluster_size();
+        let mut kept: Vec<u8> = Vec::new();
+        let mut removed = 0;
+        let mut raw_cluster = vec![0u8; size];
+        'read: for &c in &chain {
+            self.storage.read_dir(self.offset_from_cluster(c)?, &mut raw_cluster)?;
+            for raw in raw_cluster.chunks_exact(32) {
+                match raw[0] {
+                    0 => break 'read,
+                    0xE5 => removed += 1,
+                    _ => kept.extend_from_slice(raw),
+                }
+            }
+        }
+
+        let keep = kept.len().div_ceil(size).max(1);
+        if removed == 0 && keep == chain.len() { return Ok(CompactReport { removed, freed_clusters: 0 }); }
+        kept.resize(keep * size, 0);
+        for (&c, content) in chain.iter().zip(kept.chunks_exact(size)) {
+            self.storage.write_dir(self.offset_from_cluster(c)?, content)?;
+        }
+        if keep < chain.len() {
+            self.write_fat_entry(chain[keep - 1], FAT_EOC)?;
+            for &c in &chain[keep..] { self.write_fat_entry(c, FAT_FREE)?; }
+        }
+        Ok(CompactReport { removed, freed_clusters: chain.len() - keep })
+    }
+
+    /// Looks `name` up in the directory at `cluster` by its long or short name.
+    pub fn find_entry(&self, cluster: u32, name: &str) -> Result<Option<DirEntry>, &'static str> {
+        Ok(self.read_dir(cluster)?.into_iter().find(|e| e.matches(name)))
+    }
+
+    /// Rewrites the size field of the entry located at `offset`.
+    pub(super) fn set_entry_size(&mut self, offset: usize, size: u32) -> Result<(), &'static str> {
+        self.storage.write_dir(offset + 28, &size.to_le_bytes())
+    }
+
+    /// Records a change of the content or size of the file of `entry`: sets its archive
+    /// attribute, so backup tools pick it up, and its modification and access dates to
+    /// the time of `options.clock` when there is one.
+    pub(super) fn mark_modified(&mut self, entry: &mut DirEntry) -> Result<(), &'static str> {
+        if entry.attr & ATTR_ARCHIVE == 0 {
+            entry.attr |= ATTR_ARCHIVE;
+            self.storage.write_dir(entry.offset + 11, &[entry.attr])?;
+        }
+        self.stamp_modified(entry)
+    }
+
+    /// Sets the modification and access dates of `entry` to the time of `options.clock`,
+    /// when there is one.
+    pub(super) fn stamp_modified(&mut self, entry: &mut DirEntry) -> Result<(), &'static str> {
+        let Some(clock) = self.options.clock else { return Ok(()) };
+        self.write_modified(entry, clock().0)
+    }
+
+    /// Sets the modification date of the file at `path` to `modified`, and its access
+    /// date to the same day, as when copying a file while keeping its times.
+    pub fn set_modified(&mut self, path: &str, modified: DateTime) -> Result<(), &'static str> {
+        let mut entry = self.file_entry(path)?;
+        self.write_modified(&mut entry, modified)
+    }
+
+    fn write_modified(&mut self, entry: &mut DirEntry, now: DateTime) -> Result<(), &'static str> {
+        let (date, time) = now.to_fat();
+        self.storage.write_dir(entry.offset + 18, &date.to_le_bytes())?;
+        let mut modified = [0u8; 4];
+        modified[..2].copy_from_slice(&time.to_le_bytes());
+        modified[2..].copy_from_slice(&date.to_le_bytes());
+        self.storage.write_dir(entry.offset + 22, &modified)?;
+        entry.modified = now;
+        entry.accessed = DateTime { hour: 0, minute: 0, second: 0, ..now };
+        Ok(())
+    }
+
+    /// With `options.update_access_date` and a clock, sets the last-access date of `entry`
+    /// to today, unless it already holds it.
+    pub(super) fn stamp_accessed(&mut self, entry: &mut DirEntry) -> Result<(), &'static str> {
+        let Some(clock) = self.options.clock.filter(|_| self.options.update_access_date) else { return Ok(()) };
+        let today = DateTime { hour: 0, minute: 0, second: 0, ..clock().0 };
+        if entry.accessed == today { return Ok(()); }
+        self.storage.write_dir(entry.offset + 18, &today.to_fat().0.to_le_bytes())?;
+        entry.accessed = today;
+        Ok(())
+    }
+
+    /// Rewrites the first-cluster fields of the entry located at `offset`.
+    pub(super) fn set_entry_cluster(&mut self, offset: usize, cluster: u32) -> Result<(), &'static str> {
+        let high = ((cluster >> 16) as u16).to_le_bytes();
+        let low = (cluster as u16).to_le_bytes();
+        self.storage.write_dir(offset + 20, &high)?;
+        self.storage.write_dir(offset + 26, &low)
+    }
+}
@@ -0,0 +1,268 @@
+//! Where the volume lies in an image file that holds more than a volume: a fixed-size VHD,
+//! whose 512-byte footer follows the disk, a disk with an MBR or GPT partition table, as
+//! Hyper-V and Azure virtual disks are, or a bootable ISO whose El Torito catalog points
+//! to the image of its EFI System Partition.
+
+use core::ops::Range;
+
+use super::structs::BootSector;
+
+/// Size of the footer at the end of a VHD file.
+pub const VHD_FOOTER_LEN: usize = 512;
+
+const VHD_COOKIE: &[u8; 8] = b"conectix";
+const VHD_FIXED: u32 = 2;
+/// MBR partition entries start here in the first sector, four of 16 bytes.
+const MBR_PARTITIONS: usize = 446;
+const MBR_SECTOR: usize = 512;
+const MBR_ESP: u8 = 0xEF;
+/// The GPT header is in the second sector; the partition entries wherever it says.
+const GPT_HEADER: usize = 512;
+/// `C12A7328-F81F-11D2-BA4B-00A0C93EC93B` as stored, the first three fields little-endian.
+const GPT_ESP: [u8; 16] = [0x28, 0x73, 0x2A, 0xC1, 0x1F, 0xF8, 0xD2, 0x11, 0xBA, 0x4B, 0x00, 0xA0, 0xC9, 0x3E, 0xC9, 0x3B];
+const ISO_SECTOR: usize = 2048;
+/// ISO 9660 volume descriptors start at the 17th sector and end with a terminator.
+const ISO_DESCRIPTORS: usize = 16;
+/// El Torito platform ID of UEFI boot images.
+const EL_TORITO_EFI: u8 = 0xEF;
+
+/// A partition, or boot image, found in a disk.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Partition {
+    /// Bytes of the disk it spans.
+    pub range: Range<usize>,
+    /// Marked as an EFI System Partition, or as a UEFI boot image.
+    pub esp: bool,
+}
+
+/// Length of the disk `image` holds: all of it, or all but the footer of a fixed VHD.
+/// Fails for a dynamic or differencing VHD, whose blocks aren't stored in order, and for
+/// a VHD footer whose checksum is wrong.
+pub fn vhd_disk_len(image: &[u8]) -> Result<usize, &'static str> {
+    let Some(footer) = image.len().checked_sub(VHD_FOOTER_LEN).map(|at| &image[at..]) else { return Ok(image.len()) };
+    if !footer.starts_with(VHD_COOKIE) {
+        // Dynamic VHDs also keep a copy of the footer at the start.
+        return if image.starts_with(VHD_COOKIE) { Err("VHD dynamique non pris en charge") } else { Ok(image.len()) };
+    }
+    let read_u32 = |at: usize| u32::from_be_bytes([footer[at], footer[at + 1], footer[at + 2], footer[at + 3]]);
+    // One's complement of the sum of every byte but the checksum's own.
+    let sum = footer.iter().enumerate().filter(|(i, _)| !(64..68).contains(i)).fold(0u32, |sum, (_, &b)| sum.wrapping_add(b as u32));
+    if !sum != read_u32(64) { return Err("Pied de VHD invalide"); }
+    match read_u32(60) {
+        VHD_FIXED => Ok(image.len() - VHD_FOOTER_LEN),
+        _ => Err("VHD dynamique non pris en charge"),
+    }
+}
+
+/// The primary MBR partitions of `disk`, empty ones left out. The partitions of an
+/// extended partition aren't listed.
+pub fn mbr_partitions(disk: &[u8]) -> impl Iterator<Item = Partition> + '_ {
+    let table = disk.get(..MBR_SECTOR).filter(|sector| sector[510..] == [0x55, 0xAA]);
+    table.into_iter().flat_map(|sector| sector[MBR_PARTITIONS..510].chunks_exact(16)).filter_map(|entry| {
+        let start = u32::from_le_bytes([entry[8], entry[9], entry[10], entry[11]]) as usize * MBR_SECTOR;
+        let len = u32::from_le_bytes([entry[12], entry[13], entry[14], entry[15]]) as usize * MBR_SECTOR;
+        (entry[4] != 0 && len > 0).then_some(Partition { range: start..start + len, esp: entry[4] == MBR_ESP })
+    })
+}
+
+/// The partitions of the GPT of `disk`, with 512-byte sectors, unused entries left out.
+/// The header's checksum isn't checked: a hybrid ISO's is often stale.
+pub fn gpt_partitions(disk: &[u8]) -> impl Iterator<Item = Partition> + '_ {
+    let read_u32 = |b: &[u8], at: usize| u32::from_le_bytes([b[at], b[at + 1], b[at + 2], b[at + 3]]) as usize;
+    let read_u64 = |b: &[u8], at: usize| u64::from_le_bytes(core::array::from_fn(|i| b[at + i])) as usize;
+    let header = disk.get(GPT_HEADER..GPT_HEADER + 92).filter(|header| header.starts_with(b"EFI PART"));
+    let entries = header.and_then(|header| {
+        let (start, count, size) = (read_u64(header, 72).checked_mul(MBR_SECTOR)?, read_u32(header, 80), read_u32(header, 84));
+        if size < 128 { return None; }
+        Some(disk.get(start..)?.chunks_exact(size).take(count))
+    });
+    entries.into_iter().flatten().filter_map(move |entry| {
+        let (first, last) = (read_u64(entry, 32), read_u64(entry, 40));
+        if entry[..16] == [0; 16] || last < first { return None; }
+        let range = first.checked_mul(MBR_SECTOR)?..(last + 1).checked_mul(MBR_SECTOR)?;
+        Some(Partition { range, esp: entry[..16] == GPT_ESP })
+    })
+}
+
+/// The boot images the El Torito catalog of an ISO 9660 `disk` lists. As their sector
+/// count is often left at 1, an image holding a FAT boot sector is taken to be as large
+/// as the volume it describes.
+pub fn el_torito_images(disk: &[u8]) -> impl Iterator<Item = Partition> + '_ {
+    let sector = |n: usize| disk.get(n.checked_mul(ISO_SECTOR)?..(n + 1).checked_mul(ISO_SECTOR)?);
+    let catalog = (ISO_DESCRIPTORS..ISO_DESCRIPTORS + 32)
+        .map_while(|n| sector(n).filter(|d| &d[1..6] == b"CD001" && d[0] != 0xFF))
+        .find(|d| d[0] == 0 && d[7..].starts_with(b"EL TORITO SPECIFICATION"))
+        .and_then(|d| sector(u32::from_le_bytes([d[0x47], d[0x48], d[0x49], d[0x4A]]) as usize))
+        .filter(|catalog| catalog[0] == 1 && catalog[30..32] == [0x55, 0xAA]);
+    // The validation entry gives the platform of the initial entry, section headers that
+    // of the entries after them.
+    let platform = catalog.map_or(0, |catalog| catalog[1]);
+    catalog.into_iter().flat_map(|catalog| catalog.chunks_exact(32).skip(1))
+        .scan(platform, |platform, entry| {
+            if matches!(entry[0], 0x90 | 0x91) { *platform = entry[1]; }
+            Some((*platform, entry))
+        })
+        .filter(|(_, entry)| entry[0] == 0x88)
+        .filter_map(move |(platform, entry)| {
+            let start = u32::from_le_bytes([entry[8], entry[9], entry[10], entry[11]]) as usize * ISO_SECTOR;
+            let mut len = u16::from_le_bytes([entry[6], entry[7]]) as usize * MBR_SECTOR;
+            if let Ok(boot) = BootSector::parse(disk.get(start..)?) {
+                len = len.max(boot.total_sectors() as usize * boot.bytes_per_sector as usize);
+            }
+            Some(Partition { range: start..start + len, esp: platform == EL_TORITO_EFI })
+        })
+}
+
+/// Every partition and boot image of `disk`: MBR, then GPT, then El Torito.
+pub fn partitions(disk: &[u8]) -> impl Iterator<Item = Partition> + '_ {
+    mbr_partitions(disk).chain(gpt_partitions(disk)).chain(el_torito_images(disk))
+}
+
+/// `partition` cut to the end of `disk`, if a FAT32 boot sector starts it.
+fn fat32_in(disk: &[u8], partition: &Partition) -> Option<Range<usize>> {
+    let range = partition.range.start..partition.range.end.min(disk.len());
+    BootSector::parse(disk.get(range.clone())?).ok().map(|_| range)
+}
+
+/// Bytes of `image` the FAT32 volume spans. With `offset`, it starts there; otherwise it
+/// is the whole disk when that starts with a FAT32 boot sector, or else the first of its
+/// `partitions` that does. The disk ends before the footer of a fixed VHD. When nothing
+/// looks like a volume, the whole disk is returned for mounting it to tell why.
+pub fn locate_volume(image: &[u8], offset: Option<usize>) -> Result<Range<usize>, &'static str> {
+    let disk = &image[..vhd_disk_len(image)?];
+    if let Some(offset) = offset {
+        if offset >= disk.len() { return Err("Décalage au-delà de l'image"); }
+        return Ok(offset..disk.len());
+    }
+    if BootSector::parse(disk).is_ok() { return Ok(0..disk.len()); }
+    Ok(partitions(disk).find_map(|p| fat32_in(disk, &p)).unwrap_or(0..disk.len()))
+}
+
+/// Bytes of `image` its FAT32 EFI System Partition spans, for a UEFI bootable disk or
+/// hybrid ISO: the first partition or El Torito boot image marked as one, or failing
+/// that, on an ISO, the first boot image.
+pub fn locate_esp(image: &[u8]) -> Result<Range<usize>, &'static str> {
+    let disk = &image[..vhd_disk_len(image)?];
+    partitions(disk).filter(|p| p.esp).find_map(|p| fat32_in(disk, &p))
+        .or_else(|| el_torito_images(disk).find_map(|p| fat32_in(disk, &p)))
+        .ok_or("Partition système EFI introuvable")
+}
+
+#[cfg(all(test, feature = "alloc"))]
+mod tests {
+    extern crate alloc;
+    use alloc::vec;
+    use alloc::vec::Vec;
+    use super::*;
+    use crate::fat32::testutil::ImageBuilder;
+    use crate::fat32::volume::Fat32Volume;
+
+    const MB: usize = 1024 * 1024;
+
+    fn vhd_footer(disk_len: usize, disk_type: u32) -> [u8; VHD_FOOTER_LEN] {
+        let mut footer = [0u8; VHD_FOOTER_LEN];
+        footer[..8].copy_from_slice(VHD_COOKIE);
+        footer[16..24].fill(0xFF);
+        footer[48..56].copy_from_slice(&(disk_len as u64).to_be_bytes());
+        footer[60..64].copy_from_slice(&disk_type.to_be_bytes());
+        let sum = footer.iter().fold(0u32, |sum, &b| sum.wrapping_add(b as u32));
+        footer[64..68].copy_from_slice(&(!sum).to_be_bytes());
+        footer
+    }
+
+    #[test]
+    fn test_locate_volume() {
+        let volume = ImageBuilder::new().file("EFI/BOOT/BOOTX64.EFI", *b"MZ").build();
+        let len = volume.len();
+        assert_eq!(locate_volume(&volume, None), Ok(0..len));
+        assert_eq!(locate_volume(&volume, Some(512)), Ok(512..len));
+        assert_eq!(locate_volume(&volume, Some(len)), Err("Décalage au-delà de l'image"));
+
+        // A partitioned disk: MBR, a gap, the volume, in a fixed VHD.
+        let mut disk = vec![0u8; MB];
+        disk[MBR_PARTITIONS + 4] = 0x0C;
+        disk[MBR_PARTITIONS + 8..MBR_PARTITIONS + 12].copy_from_slice(&((MB / 512) as u32).to_le_bytes());
+        disk[MBR_PARTITIONS + 12..MBR_PARTITIONS + 16].copy_from_slice(&((len / 512) as u32).to_le_bytes());
+        disk[510..512].copy_from_slice(&[0x55, 0xAA]);
+        disk.extend_from_slice(&volume);
+        let disk_len = disk.len();
+        let mut vhd: Vec<u8> = disk.clone();
+        vhd.extend_from_slice(&vhd_footer(disk_len, VHD_FIXED));
+        assert!(mbr_partitions(&disk).eq(core::iter::once(Partition { range: MB..MB + len, esp: false })));
+        assert_eq!(vhd_disk_len(&vhd), Ok(disk_len));
+        assert_eq!(locate_volume(&disk, None), Ok(MB..MB + len));
+        let range = locate_volume(&vhd, None).unwrap();
+        assert_eq!(range, MB..MB + len);
+        let volume = Fat32Volume::new(&mut vhd[range]).unwrap();
+        assert_eq!(volume.read_file("EFI/BOOT/BOOTX64.EFI").unwrap(), b"MZ");
+        drop(volume);
+        assert_eq!(locate_volume(&vhd, Some(MB)), Ok(MB..disk_len));
+
+        let last = vhd.len() - 1;
+        vhd[last] ^= 1;
+        assert_eq!(vhd_disk_len(&vhd), Err("Pied de VHD invalide"));
+        vhd.truncate(disk_len);
+        vhd.extend_from_slice(&vhd_footer(disk_len, 3));
+        assert_eq!(vhd_disk_len(&vhd), Err("VHD dynamique non pris en charge"));
+        assert_eq!(vhd_disk_len(b"conectix"), Ok(8));
+    }
+
+    #[test]
+    fn test_locate_esp() {
+        let data = ImageBuilder::new().file("data.txt", *b"data").build();
+        let esp = ImageBuilder::new().file("EFI/BOOT/BOOTX64.EFI", *b"MZ").build();
+        let len = data.len();
+
+        // A GPT disk whose first partition is a FAT32 data volume and the second its ESP.
+        let mut disk = vec![0u8; MB];
+        disk[MBR_PARTITIONS + 4] = 0xEE;
+        disk[MBR_PARTITIONS + 8..MBR_PARTITIONS + 12].copy_from_slice(&1u32.to_le_bytes());
+        disk[MBR_PARTITIONS + 12..MBR_PARTITIONS + 16].copy_from_slice(&u32::MAX.to_le_bytes());
+        disk[510..512].copy_from_slice(&[0x55, 0xAA]);
+        disk[GPT_HEADER..GPT_HEADER + 8].copy_from_slice(b"EFI PART");
+        disk[GPT_HEADER + 72..GPT_HEADER + 80].copy_from_slice(&2u64.to_le_bytes());
+        disk[GPT_HEADER + 80..GPT_HEADER + 84].copy_from_slice(&128u32.to_le_bytes());
+        disk[GPT_HEADER + 84..GPT_HEADER + 88].copy_from_slice(&128u32.to_le_bytes());
+        for (i, kind) in [[0xA2; 16], GPT_ESP].iter().enumerate() {
+            let entry = &mut disk[1024 + i * 128..1024 + (i + 1) * 128];
+            let first = (MB + i * len) / 512;
+            entry[..16].copy_from_slice(kind);
+            entry[32..40].copy_from_slice(&(first as u64).to_le_bytes());
+            entry[40..48].copy_from_slice(&((first + len / 512 - 1) as u64).to_le_bytes());
+        }
+        disk.extend_from_slice(&data);
+        disk.extend_from_slice(&esp);
+        assert_eq!(gpt_partitions(&disk).filter(|p| p.esp).count(), 1);
+        assert_eq!(locate_volume(&disk, None), Ok(MB..MB + len));
+        assert_eq!(locate_esp(&disk), Ok(MB + len..MB + 2 * len));
+        assert_eq!(locate_esp(&data), Err("Partition système EFI introuvable"));
+
+        // An ISO whose El Torito catalog has a BIOS boot image, then a UEFI one holding the
+        // ESP and claiming a single sector.
+        let mut iso = vec![0u8; 20 * ISO_SECTOR];
+        let descriptor = ISO_DESCRIPTORS * ISO_SECTOR;
+        iso[descriptor + 1..descriptor + 6].copy_from_slice(b"CD001");
+        iso[descriptor + 7..descriptor + 30].copy_from_slice(b"EL TORITO SPECIFICATION");
+        iso[descriptor + 0x47] = 18;
+        iso[descriptor + ISO_SECTOR] = 0xFF;
+        iso[descriptor + ISO_SECTOR + 1..descriptor + ISO_SECTOR + 6].copy_from_slice(b"CD001");
+        let catalog = &mut iso[18 * ISO_SECTOR..19 * ISO_SECTOR];
+        catalog[0] = 1;
+        catalog[30..32].copy_from_slice(&[0x55, 0xAA]);
+        catalog[32] = 0x88;
+        catalog[32 + 6] = 4;
+        catalog[32 + 8] = 19;
+        catalog[64..66].copy_from_slice(&[0x91, EL_TORITO_EFI]);
+        catalog[96] = 0x88;
+        catalog[96 + 6] = 1;
+        catalog[96 + 8] = 20;
+        iso.extend_from_slice(&esp);
+        let start = 20 * ISO_SECTOR;
+        assert_eq!(el_torito_images(&iso).map(|p| p.esp).collect::<Vec<_>>(), [false, true]);
+        assert_eq!(locate_esp(&iso), Ok(start..start + len));
+        let range = locate_volume(&iso, None).unwrap();
+        assert_eq!(range, start..start + len);
+        let volume = Fat32Volume::new(&mut iso[range]).unwrap();
+        assert_eq!(volume.read_file("EFI/BOOT/BOOTX64.EFI").unwrap(), b"MZ");
+    }
+}
@@ -0,0 +1,26 @@
+//! Messages of the errors a caller may want to tell apart. Errors are plain strings; those
+//! worth matching on are named here, so the code returning them and the code testing for
+//! them use the same text.
+
+/// A file or directory is already at the path given.
+pub const ALREADY_EXISTS: &str = "Le fichier existe déjà";
+/// A cluster number outside the FAT or the data region.
+pub const INVALID_CLUSTER: &str = "Cluster invalide";
+/// Nothing at the path given, or not a file.
+pub const FILE_NOT_FOUND: &str = "Fichier introuvable";
+/// A directory on the path given is missing.
+pub const DIR_NOT_FOUND: &str = "Dossier introuvable";
+/// A file handle read from without `read` among its open options.
+pub const NOT_OPEN_FOR_READING: &str = "Fichier non ouvert en lecture";
+/// A file handle written to without `write` among its open options.
+pub const NOT_OPEN_FOR_WRITING: &str = "Fichier non ouvert en écriture";
+/// A seek before the start of a file.
+pub const INVALID_POSITION: &str = "Position invalide";
+/// A block read or write whose buffer isn't a whole number of blocks.
+pub const INVALID_BLOCK_SIZE: &str = "Taille de bloc invalide";
+/// A block past the end of the image or device.
+pub const BLOCK_OUT_OF_RANGE: &str = "Bloc hors de l'image";
+/// No free cluster left.
+pub const DISK_FULL: &str = "Disque plein";
+/// A file that would grow past the 4 GiB FAT32 allows.
+pub const FILE_TOO_LARGE: &str = "Fichier trop grand";
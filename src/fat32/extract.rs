@@ -0,0 +1,132 @@
+extern crate alloc;
+use alloc::vec::Vec;
+use alloc::format;
+use log::warn;
+
+use super::dir::DirEntry;
+use super::error::FILE_NOT_FOUND;
+use super::path::Resolved;
+use super::progress::{ProgressSink, ProgressTracker};
+use super::volume::Fat32Volume;
+
+/// Receives the content of a tree walked by `extract_tree`, depth first.
+/// Paths are relative to the extracted directory and use `/` separators.
+pub trait TreeSink {
+    fn enter_dir(&mut self, path: &str, entry: &DirEntry) -> Result<(), &'static str>;
+
+    /// Called once the whole content of the directory has been emitted,
+    /// e.g. to restore its timestamps after the files inside changed them.
+    fn leave_dir(&mut self, _path: &str, _entry: &DirEntry) -> Result<(), &'static str> {
+        Ok(())
+    }
+
+    fn file(&mut self, path: &str, entry: &DirEntry, content: &[u8]) -> Result<(), &'static str>;
+}
+
+impl<'a> Fat32Volume<'a> {
+    /// Emits every directory and file below `path` to `sink`. When `path` names a file,
+    /// only that file is emitted. Returns the number of files.
+    /// Progress is reported in bytes of file content emitted.
+    pub fn extract_tree(&self, path: &str, sink: &mut dyn TreeSink, progress: &mut dyn ProgressSink) -> Result<usize, &'static str> {
+        let mut total = 0;
+        for item in self.walk(path)? {
+            let (_, _, metadata) = item?;
+            if !metadata.is_dir() { total += metadata.size as u64; }
+        }
+        let mut tracker = ProgressTracker::new(progress, total);
+        let result = match self.resolve_path(path)? {
+            Resolved::Dir(cluster) => self.extract_dir(cluster, "", sink, &mut tracker, &mut Vec::new()),
+            Resolved::File(entry) => self.read_chain(entry.first_cluster, entry.size).and_then(|content| {
+                tracker.file(&entry.name, entry.size as u64);
+                sink.file(&entry.name, &entry, &content).map(|_| 1)
+            }),
+            Resolved::NotFound { .. } => Err(FILE_NOT_FOUND),
+        };
+        tracker.finish();
+        result
+    }
+
+    fn extract_dir(&self, cluster: u32, prefix: &str, sink: &mut dyn TreeSink, tracker: &mut ProgressTracker, visited: &mut Vec<u32>) -> Result<usize, &'static str> {
+        // A corrupted directory pointing back to one of its parents would loop forever.
+        if visited.contains(&cluster) {
+            warn!("directory loop at cluster {}, skipped", cluster);
+            return Ok(0);
+        }
+        visited.push(cluster);
+
+        let mut count = 0;
+        for entry in self.read_dir(cluster)? {
+            if entry.is_dot() { continue; }
+            let path = if prefix.is_empty() { entry.name.clone() } else { format!("{}/{}", prefix, entry.name) };
+
+            if entry.is_dir() {
+                sink.enter_dir(&path, &entry)?;
+                if entry.first_cluster >= 2 {
+                    count += self.extract_dir(entry.first_cluster, &path, sink, tracker, visited)?;
+                }
+                sink.leave_dir(&path, &entry)?;
+            } else {
+                tracker.file(&path, entry.size as u64);
+                let content = self.read_chain(entry.first_cluster, entry.size)?;
+                sink.file(&path, &entry, &content)?;
+                count += 1;
+            }
+        }
+        Ok(count)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::string::String;
+    use crate::fat32::progress::NoProgress;
+    use crate::fat32::volume::tests::create_mock_volume;
+
+    #[derive(Default)]
+    struct Record(Vec<(u64, u64, String)>);
+
+    impl ProgressSink for Record {
+        fn progress(&mut self, done: u64, total: u64, current: &str) {
+            self.0.push((done, total, current.into()));
+        }
+    }
+
+    #[derive(Default)]
+    struct Collect(Vec<(String, Vec<u8>)>);
+
+    impl TreeSink for Collect {
+        fn enter_dir(&mut self, path: &str, _entry: &DirEntry) -> Result<(), &'static str> {
+            self.0.push((format!("{}/", path), Vec::new()));
+            Ok(())
+        }
+        fn file(&mut self, path: &str, _entry: &DirEntry, content: &[u8]) -> Result<(), &'static str> {
+            self.0.push((path.into(), content.into()));
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_extract_tree() {
+        let mut data = create_mock_volume();
+        let mut volume = Fat32Volume::new(&mut data).unwrap();
+        let dcim = volume.create_directory("DCIM").unwrap();
+        let sub = volume.create_directory("DCIM/100CANON").unwrap();
+        volume.create_file_in(sub, "IMG_0001.JPG", b"jpeg", false).unwrap();
+        volume.create_file_in(dcim, "index.txt", b"idx", false).unwrap();
+        volume.create_file("other.txt", b"x", false).unwrap();
+
+        let mut sink = Collect::default();
+        let mut progress = Record::default();
+        assert_eq!(volume.extract_tree("/DCIM", &mut sink, &mut progress).unwrap(), 2);
+        assert_eq!(progress.0, [
+            (0, 7, "100CANON/IMG_0001.JPG".into()), (4, 7, "index.txt".into()), (7, 7, "".into()),
+        ]);
+        let paths: Vec<&str> = sink.0.iter().map(|(p, _)| p.as_str()).collect();
+        assert_eq!(paths, ["100CANON/", "100CANON/IMG_0001.JPG", "index.txt"]);
+        assert_eq!(sink.0[1].1, b"jpeg");
+
+        let mut single = Collect::default();
+        assert_eq!(volume.extract_tree("other.txt", &mut single, &mut NoProgress).unwrap(), 1);
+    }
+}
@@ -0,0 +1,585 @@
+#[cfg(feature = "alloc")]
+use alloc::{vec, vec::Vec};
+use core::fmt;
+#[cfg(feature = "alloc")]
+use core::ops::Range;
+#[cfg(feature = "alloc")]
+use log::{trace, warn};
+
+#[cfg(feature = "alloc")]
+use super::error::{FILE_NOT_FOUND, INVALID_CLUSTER};
+#[cfg(feature = "alloc")]
+use super::path::Resolved;
+#[cfg(feature = "alloc")]
+use super::storage::IoCounters;
+#[cfg(feature = "alloc")]
+use super::volume::Fat32Volume;
+
+/// Value written in the FAT to mark the last cluster of a chain.
+pub const FAT_EOC: u32 = 0x0FFFFFFF;
+/// Value of a FAT entry whose cluster is not used.
+pub const FAT_FREE: u32 = 0;
+/// Value marking a cluster whose sectors can't be trusted; it is never allocated.
+pub const FAT_BAD: u32 = 0x0FFFFFF7;
+/// Bits of a FAT entry that hold its value. The top 4 are reserved: they are ignored when
+/// reading and kept as they are when writing.
+pub const FAT_ENTRY_MASK: u32 = 0x0FFFFFFF;
+/// First number that can't be a cluster: from there on, 28-bit FAT values are reserved,
+/// bad-cluster or end-of-chain markers.
+pub const CLUSTER_LIMIT: u32 = 0x0FFFFFF0;
+/// Fewest data clusters a FAT32 volume can have: below that, systems go by the count and
+/// take it for FAT16 whatever its boot sector says.
+pub const MIN_CLUSTERS: u32 = 65525;
+
+/// A FAT entry, decoded. Only the low 28 bits of an entry are meaningful.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FatValue {
+    Free,
+    /// The cluster is used and the chain goes on at this cluster.
+    Next(u32),
+    EndOfChain,
+    Bad,
+    /// Value 1 and 0x0FFFFFF0 to 0x0FFFFFF6, which no cluster should hold.
+    Reserved,
+}
+
+impl FatValue {
+    pub fn decode(raw: u32) -> Self {
+        match raw & FAT_ENTRY_MASK {
+            0 => FatValue::Free,
+            1 | CLUSTER_LIMIT..FAT_BAD => FatValue::Reserved,
+            FAT_BAD => FatValue::Bad,
+            0x0FFFFFF8.. => FatValue::EndOfChain,
+            next => FatValue::Next(next),
+        }
+    }
+}
+
+impl fmt::Display for FatValue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FatValue::Free => write!(f, "FREE"),
+            FatValue::Next(next) => write!(f, "USED -> {}", next),
+            FatValue::EndOfChain => write!(f, "EOC"),
+            FatValue::Bad => write!(f, "BAD"),
+            FatValue::Reserved => write!(f, "RESERVED"),
+        }
+    }
+}
+
+/// The clusters of a file or directory as the FAT links them, returned by `Fat32Volume::chain_report`.
+#[cfg(feature = "alloc")]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChainReport {
+    pub clusters: Vec<u32>,
+    /// Clusters the recorded size calls for; `None` for directories, which have no size.
+    pub expected: Option<usize>,
+    /// True when the chain ends on an end-of-chain marker rather than a free, reserved or out of range value.
+    pub terminated: bool,
+}
+
+#[cfg(feature = "alloc")]
+impl ChainReport {
+    /// Runs of consecutive clusters as `(first, length)`; more than one means the chain is fragmented.
+    pub fn runs(&self) -> Vec<(u32, usize)> {
+        let mut runs: Vec<(u32, usize)> = Vec::new();
+        for &cluster in &self.clusters {
+            match runs.last_mut() {
+                Some((first, len)) if *first + *len as u32 == cluster => *len += 1,
+                _ => runs.push((cluster, 1)),
+            }
+        }
+        runs
+    }
+
+    /// True when the chain is as long as the size requires and properly terminated.
+    pub fn is_consistent(&self) -> bool {
+        self.terminated && self.expected.is_none_or(|expected| expected == self.clusters.len())
+    }
+}
+
+/// Room in the data region of a volume, in bytes, as `df` shows it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Space {
+    pub total: u64,
+    pub free: u64,
+}
+
+impl Space {
+    pub fn used(&self) -> u64 {
+        self.total - self.free
+    }
+}
+
+/// Where a FAT copy differs from the first one, as `fatcmp` shows it.
+#[cfg(feature = "alloc")]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FatDivergence {
+    /// Index of the copy, from 0.
+    pub copy: usize,
+    /// Clusters whose entries differ, consecutive ones merged.
+    pub ranges: Vec<Range<u32>>,
+}
+
+#[cfg(feature = "alloc")]
+impl FatDivergence {
+    /// Number of entries that differ.
+    pub fn entries(&self) -> u32 {
+        self.ranges.iter().map(|r| r.end - r.start).sum()
+    }
+}
+
+#[cfg(feature = "alloc")]
+impl<'a> Fat32Volume<'a> {
+    /// Byte offset of the first FAT copy.
+    pub(super) fn fat_start(&self) -> usize {
+        self.boot_sector.fat_start()
+    }
+
+    /// Size in bytes of one cluster.
+    pub fn cluster_size(&self) -> usize {
+        self.boot_sector.cluster_size()
+    }
+
+    /// First cluster number past the end of the usable data region.
+    /// Bounded both by the number of FAT entries and by the size of the image.
+    pub(super) fn cluster_limit(&self) -> u32 {
+        let fat_entries = self.fat_entries();
+        let data_start = self.boot_sector.data_start();
+        let cluster_size = self.cluster_size();
+        let size = self.storage.len();
+        if data_start >= size { return 2; }
+        let data_clusters = ((size - data_start) / cluster_size) as u32 + 2;
+        fat_entries.min(data_clusters).min(self.boot_sector.clusters().end)
+    }
+
+    /// Value of the FAT entry of `cluster`, without its reserved bits.
+    pub fn read_fat_entry(&self, cluster: u32) -> Result<u32, &'static str> {
+        Ok(self.read_raw_fat_entry(cluster)? & FAT_ENTRY_MASK)
+    }
+
+    fn read_raw_fat_entry(&self, cluster: u32) -> Result<u32, &'static str> {
+        if cluster >= self.fat_entries() { return Err(INVALID_CLUSTER); }
+        let mut raw = [0u8; 4];
+        self.storage.read(self.fat_start() + cluster as usize * 4, &mut raw)?;
+        Ok(u32::from_le_bytes(raw))
+    }
+
+    /// Number of entries of one FAT copy, clusters 0 and 1 included.
+    pub fn fat_entries(&self) -> u32 {
+        let bytes = self.boot_sector.sectors_per_fat_32 as u64 * self.boot_sector.bytes_per_sector as u64;
+        (bytes / 4).min(u32::MAX as u64) as u32
+    }
+
+    /// Size in bytes of one FAT copy.
+    fn fat_size(&self) -> usize {
+        self.boot_sector.sectors_per_fat_32 as usize * self.boot_sector.bytes_per_sector as usize
+    }
+
+    /// Compares every FAT copy with the first, a sector at a time, and returns those that
+    /// differ from it. Empty when all copies agree.
+    pub fn compare_fats(&self) -> Result<Vec<FatDivergence>, &'static str> {
+        let fats = self.boot_sector.number_of_fats as usize;
+        let mut divergences: Vec<FatDivergence> = (1..fats).map(|copy| FatDivergence { copy, ranges: Vec::new() }).collect();
+        let sector_size = (self.boot_sector.bytes_per_sector as usize).max(4);
+        let mut first = vec![0u8; sector_size];
+        let mut other = vec![0u8; sector_size];
+        for start in (0..self.fat_size()).step_by(sector_size) {
+            self.storage.read(self.fat_start() + start, &mut first)?;
+            for divergence in &mut divergences {
+                self.storage.read(self.fat_start() + divergence.copy * self.fat_size() + start, &mut other)?;
+                let pairs = first.as_chunks::<4>().0.iter().zip(other.as_chunks::<4>().0);
+                for (i, _) in pairs.enumerate().filter(|(_, (a, b))| a != b) {
+                    let cluster = (start / 4 + i) as u32;
+                    match divergence.ranges.last_mut() {
+                        Some(range) if range.end == cluster => range.end += 1,
+                        _ => divergence.ranges.push(cluster..cluster + 1),
+                    }
+                }
+            }
+        }
+        divergences.retain(|d| !d.ranges.is_empty());
+        Ok(divergences)
+    }
+
+    /// Overwrites every other FAT copy with copy `from` (from 0), then rebuilds the free
+    /// map from the result.
+    pub fn sync_fats(&mut self, from: usize) -> Result<(), &'static str> {
+        let fats = self.boot_sector.number_of_fats as usize;
+        if from >= fats { return Err("Copie de FAT invalide"); }
+        let mut sector = vec![0u8; (self.boot_sector.bytes_per_sector as usize).max(4)];
+        for start in (0..self.fat_size()).step_by(sector.len()) {
+            self.storage.read(self.fat_start() + from * self.fat_size() + start, &mut sector)?;
+            for copy in (0..fats).filter(|&copy| copy != from) {
+                self.storage.write(self.fat_start() + copy * self.fat_size() + start, &sector)?;
+            }
+        }
+        self.build_free_map()
+    }
+
+    /// Raw and decoded FAT entry of `cluster`.
+    pub fn fat_value(&self, cluster: u32) -> Result<(u32, FatValue), &'static str> {
+        if cluster >= self.fat_entries() { return Err("Cluster hors de la FAT"); }
+        let raw = self.read_raw_fat_entry(cluster)?;
+        Ok((raw, FatValue::decode(raw)))
+    }
+
+    /// Writes `value` for `cluster` in every FAT copy so they stay in sync, keeping the
+    /// reserved bits the entry had. Only the entries of clusters in the data region can be
+    /// written.
+    pub(super) fn write_fat_entry(&mut self, cluster: u32, value: u32) -> Result<(), &'static str> {
+        if !(2..self.cluster_limit()).contains(&cluster) { return Err(INVALID_CLUSTER); }
+        let value = value & FAT_ENTRY_MASK;
+        let raw = (self.read_raw_fat_entry(cluster)? & !FAT_ENTRY_MASK) | value;
+        trace!("FAT[{}] = {:#010x}", cluster, raw);
+        let fats = self.boot_sector.number_of_fats as usize;
+        for i in 0..fats.max(1) {
+            let offset = self.fat_start() + i * self.fat_size() + cluster as usize * 4;
+            self.storage.write(offset, &raw.to_le_bytes())?;
+        }
+        self.mark_free(cluster, raw == FAT_FREE);
+        Ok(())
+    }
+
+    /// Scans the FAT once and records which clusters are free, so that allocating
+    /// does not have to walk the table again on every call. The FAT is read a sector at a time.
+    /// A free entry with reserved bits set is left alone: whoever set them may still use it.
+    /// Allocation then starts from the FSInfo hint, if there is a usable one.
+    pub(super) fn build_free_map(&mut self) -> Result<(), &'static str> {
+        let limit = self.cluster_limit();
+        let mut map = vec![0u64; (limit as usize).div_ceil(64)];
+        let mut sector = vec![0u8; (self.boot_sector.bytes_per_sector as usize).max(4)];
+        let per_sector = sector.len() as u32 / 4;
+        let mut first = 0;
+        while first < limit {
+            self.storage.read(self.fat_start() + first as usize * 4, &mut sector)?;
+            for (i, raw) in sector.as_chunks::<4>().0.iter().enumerate() {
+                let cluster = first + i as u32;
+                if cluster >= 2 && cluster < limit && u32::from_le_bytes(*raw) == FAT_FREE {
+                    map[cluster as usize / 64] |= 1 << (cluster % 64);
+                }
+            }
+            first += per_sector;
+        }
+        self.free_map = map;
+        self.next_free = self.fsinfo_hint().unwrap_or(2);
+        Ok(())
+    }
+
+    /// The next free cluster recorded in the FSInfo sector, when the sector has its
+    /// signatures and the cluster is in the data region. 0xFFFFFFFF means unknown.
+    fn fsinfo_hint(&self) -> Option<u32> {
+        let info = self.fsinfo_sector(self.boot_sector.fs_info_sector as usize)?;
+        let mut hint = [0u8; 4];
+        self.storage.read(info + 492, &mut hint).ok()?;
+        let hint = u32::from_le_bytes(hint);
+        (2..self.cluster_limit()).contains(&hint).then_some(hint)
+    }
+
+    /// Byte offset of `sector` when it is a reserved sector holding the FSInfo signatures.
+    pub(super) fn fsinfo_sector(&self, sector: usize) -> Option<usize> {
+        if sector == 0 || sector >= self.boot_sector.reserved_sectors as usize { return None; }
+        let offset = sector * self.boot_sector.bytes_per_sector as usize;
+        let mut info = [0u8; 512];
+        self.storage.read(offset, &mut info).ok()?;
+        let read_u32 = |at: usize| u32::from_le_bytes([info[at], info[at + 1], info[at + 2], info[at + 3]]);
+        (read_u32(0) == 0x41615252 && read_u32(484) == 0x61417272).then_some(offset)
+    }
+
+    /// Writes the number of free clusters, from the free map, to the FSInfo sector and
+    /// to its backup after the backup boot sector. A next free cluster hint no longer in
+    /// the data region is set to unknown.
+    pub(super) fn update_fsinfo(&mut self) -> Result<(), &'static str> {
+        let free = (2..self.cluster_limit()).filter(|&c| self.is_free(c)).count() as u32;
+        let sector = self.boot_sector.fs_info_sector as usize;
+        let backup = self.boot_sector.backup_boot_sector as usize;
+        let copies = [Some(sector), (backup > 0).then_some(backup + sector)].map(|s| s.and_then(|s| self.fsinfo_sector(s)));
+        for info in copies.into_iter().flatten() {
+            self.storage.write(info + 488, &free.to_le_bytes())?;
+            let mut hint = [0u8; 4];
+            self.storage.read(info + 492, &mut hint)?;
+            let hint = u32::from_le_bytes(hint);
+            if hint != 0xFFFFFFFF && hint >= self.cluster_limit() {
+                self.storage.write(info + 492, &0xFFFFFFFFu32.to_le_bytes())?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Size of the data region and how much of it is free, in bytes, from the free map.
+    pub fn space(&self) -> Space {
+        let limit = self.cluster_limit();
+        let free = (2..limit).filter(|&c| self.is_free(c)).count() as u64;
+        let cluster_size = self.cluster_size() as u64;
+        Space { total: (limit - 2) as u64 * cluster_size, free: free * cluster_size }
+    }
+
+    pub(super) fn is_free(&self, cluster: u32) -> bool {
+        match self.free_map.get(cluster as usize / 64) {
+            Some(word) => (word >> (cluster % 64)) & 1 == 1,
+            None => false,
+        }
+    }
+
+    fn mark_free(&mut self, cluster: u32, free: bool) {
+        if let Some(word) = self.free_map.get_mut(cluster as usize / 64) {
+            let was_free = (*word >> (cluster % 64)) & 1 == 1;
+            if free { *word |= 1 << (cluster % 64); } else { *word &= !(1 << (cluster % 64)); }
+            let counters = &self.storage.counters;
+            match (was_free, free) {
+                (true, false) => IoCounters::bump(&counters.clusters_allocated, 1),
+                (false, true) => IoCounters::bump(&counters.clusters_freed, 1),
+                _ => {}
+            }
+        }
+    }
+
+    /// First free cluster at or after `from`, skipping fully used words of the bitmap.
+    pub(super) fn next_free_cluster(&self, from: u32) -> Option<u32> {
+        let limit = self.cluster_limit();
+        let mut cluster = from;
+        while cluster < limit {
+            let word = self.free_map[cluster as usize / 64] >> (cluster % 64);
+            if word == 0 {
+                cluster = (cluster / 64 + 1) * 64;
+                continue;
+            }
+            let found = cluster + word.trailing_zeros();
+            return if found < limit { Some(found) } else { None };
+        }
+        None
+    }
+
+    /// Follows the FAT from `start` and returns every cluster of the chain, in order.
+    /// Stops on an end-of-chain marker (any value from 0x0FFFFFF8 on), or broken on a
+    /// free, bad or reserved value or a loop. Cluster 0, the
+    /// start of empty files, has an empty chain; other starts outside the data region fail.
+    pub fn cluster_chain(&self, start: u32) -> Result<Vec<u32>, &'static str> {
+        let mut chain = Vec::new();
+        let limit = self.cluster_limit();
+        if start != 0 && !(2..limit).contains(&start) { return Err(INVALID_CLUSTER); }
+        let mut cluster = start;
+        while cluster >= 2 && cluster < limit && chain.len() < limit as usize {
+            chain.push(cluster);
+            let next = self.read_fat_entry(cluster)?;
+            match FatValue::decode(next) {
+                FatValue::EndOfChain => return Ok(chain),
+                FatValue::Next(_) => cluster = next,
+                FatValue::Free | FatValue::Bad | FatValue::Reserved => {
+                    cluster = next;
+                    break;
+                }
+            }
+        }
+        if !chain.is_empty() {
+            warn!("chain starting at cluster {} is broken after {} clusters (next: {:#x})", start, chain.len(), cluster);
+        }
+        Ok(chain)
+    }
+
+    /// Cluster chain of the file or directory at `path`, checked against its size.
+    pub fn chain_report(&self, path: &str) -> Result<ChainReport, &'static str> {
+        let (start, expected) = match self.resolve_path(path)? {
+            Resolved::Dir(cluster) => (cluster, None),
+            Resolved::File(entry) => (entry.first_cluster, Some((entry.size as usize).div_ceil(self.cluster_size()))),
+            Resolved::NotFound { .. } => return Err(FILE_NOT_FOUND),
+        };
+        let clusters = if start < 2 { Vec::new() } else { self.cluster_chain(start)? };
+        let terminated = match clusters.last() {
+            Some(&last) => FatValue::decode(self.read_fat_entry(last)?) == FatValue::EndOfChain,
+            // An empty file has no chain at all.
+            None => start == 0,
+        };
+        Ok(ChainReport { clusters, expected, terminated })
+    }
+
+    /// Marks every cluster of the chain starting at `start` as free.
+    pub(super) fn free_chain(&mut self, start: u32) -> Result<(), &'static str> {
+        for cluster in self.cluster_chain(start)? {
+            self.write_fat_entry(cluster, FAT_FREE)?;
+        }
+        Ok(())
+    }
+
+    /// Finds the first run of `len` consecutive free clusters and returns its first cluster.
+    /// With an allocation alignment set, a run starting on an aligned cluster is preferred.
+    pub(super) fn find_free_run(&self, len: u32) -> Option<u32> {
+        if self.options.allocation_alignment.is_some() {
+            if let Some(start) = self.find_run(len, true) { return Some(start); }
+        }
+        self.find_run(len, false)
+    }
+
+    fn find_run(&self, len: u32, aligned: bool) -> Option<u32> {
+        if len == 0 { return None; }
+        let mut run_start = 2;
+        let mut run_len = 0;
+        for i in 2..self.cluster_limit() {
+            if self.is_free(i) {
+                if run_len == 0 {
+                    if aligned && !self.is_aligned(i) { continue; }
+                    run_start = i;
+                }
+                run_len += 1;
+                if run_len == len { return Some(run_start); }
+            } else {
+                run_len = 0;
+            }
+        }
+        None
+    }
+}
+
+/// True when every cluster of the chain directly follows the previous one.
+pub fn is_contiguous(chain: &[u32]) -> bool {
+    chain.windows(2).all(|w| w[1] == w[0] + 1)
+}
+
+#[cfg(all(test, feature = "alloc"))]
+mod tests {
+    use super::*;
+    use crate::fat32::volume::tests::create_mock_volume;
+
+    #[test]
+    fn test_chain_report() {
+        let mut data = create_mock_volume();
+        let mut volume = Fat32Volume::new(&mut data).unwrap();
+        volume.create_file("big.bin", &[4; 1500], false).unwrap();
+        volume.create_file("c.txt", &[3; 512], false).unwrap();
+        // Move the last cluster of big.bin past c.txt.
+        let chain = volume.cluster_chain(volume.file_entry("big.bin").unwrap().first_cluster).unwrap();
+        let moved = volume.next_free_cluster(chain[2] + 2).unwrap();
+        volume.write_fat_entry(chain[1], moved).unwrap();
+        volume.write_fat_entry(moved, FAT_EOC).unwrap();
+        volume.write_fat_entry(chain[2], FAT_FREE).unwrap();
+
+        let report = volume.chain_report("big.bin").unwrap();
+        assert_eq!(report.expected, Some(3));
+        assert_eq!(report.runs().len(), 2);
+        assert!(report.is_consistent());
+
+        // A size claiming more clusters than the chain holds.
+        let entry = volume.file_entry("c.txt").unwrap();
+        volume.set_entry_size(entry.offset, 2000).unwrap();
+        let report = volume.chain_report("c.txt").unwrap();
+        assert_eq!((report.clusters.len(), report.expected), (1, Some(4)));
+        assert!(!report.is_consistent());
+        assert!(volume.chain_report("/").unwrap().is_consistent());
+    }
+
+    #[test]
+    fn test_fat_value() {
+        assert_eq!(FatValue::decode(0xF0000000), FatValue::Free);
+        assert_eq!(FatValue::decode(0x10000005), FatValue::Next(5));
+        assert_eq!(FatValue::decode(0x0FFFFFF7), FatValue::Bad);
+        assert_eq!(FatValue::decode(0xFFFFFFF8), FatValue::EndOfChain);
+        assert_eq!(FatValue::decode(1), FatValue::Reserved);
+
+        let mut data = create_mock_volume();
+        let volume = Fat32Volume::new(&mut data).unwrap();
+        assert_eq!(volume.fat_value(2), Ok((FAT_EOC, FatValue::EndOfChain)));
+        assert_eq!(volume.fat_value(3), Ok((0, FatValue::Free)));
+        assert_eq!(volume.fat_value(volume.fat_entries()), Err("Cluster hors de la FAT"));
+    }
+
+    #[test]
+    fn test_reserved_bits() {
+        let mut data = create_mock_volume();
+        // As Windows leaves them: top bits set on a link, 0xFFFFFFFF to end the chain, a
+        // free entry with its top bits set and a bad cluster.
+        let fat = 32 * 512;
+        for (cluster, value) in [(5u32, 0xF0000006u32), (6, 0xFFFFFFFF), (7, 0xF0000000), (8, FAT_BAD)] {
+            data[fat + cluster as usize * 4..][..4].copy_from_slice(&value.to_le_bytes());
+        }
+        let mut volume = Fat32Volume::new(&mut data).unwrap();
+        assert_eq!(volume.read_fat_entry(5), Ok(6));
+        assert_eq!(volume.cluster_chain(5), Ok(vec![5, 6]));
+        assert!(!volume.is_free(7) && !volume.is_free(8));
+        assert_eq!(volume.fat_value(8), Ok((FAT_BAD, FatValue::Bad)));
+
+        volume.write_fat_entry(6, 0x12345678).unwrap();
+        assert_eq!(volume.fat_value(6), Ok((0xF2345678, FatValue::Next(0x02345678))));
+        volume.write_fat_entry(6, FAT_EOC).unwrap();
+        assert_eq!(volume.fat_value(6), Ok((0xFFFFFFFF, FatValue::EndOfChain)));
+        assert_eq!(volume.fat_value(5).unwrap().0, 0xF0000006);
+    }
+
+    #[test]
+    fn test_allocation_order() {
+        let mut data = create_mock_volume();
+        data[48] = 1; // FSInfo in sector 1
+        data[512..516].copy_from_slice(&0x41615252u32.to_le_bytes());
+        data[512 + 484..512 + 488].copy_from_slice(&0x61417272u32.to_le_bytes());
+        data[512 + 492..512 + 496].copy_from_slice(&1814u32.to_le_bytes());
+        let fat = 32 * 512;
+        data[fat + 3 * 4..][..4].copy_from_slice(&0xF0000000u32.to_le_bytes());
+        data[fat + 4 * 4..][..4].copy_from_slice(&FAT_BAD.to_le_bytes());
+
+        let mut image = data.clone();
+        let mut volume = Fat32Volume::new(&mut image).unwrap();
+        assert_eq!(volume.next_free, 1814);
+        let taken: Vec<u32> = (0..6).map(|_| volume.allocate_cluster().unwrap()).collect();
+        // Up to the last cluster, then round from the start, past the root, the entry with
+        // reserved bits and the bad cluster.
+        assert_eq!(taken, [1814, 1815, 1816, 1817, 5, 6]);
+
+        // A hint outside the data region is ignored.
+        data[512 + 492..512 + 496].copy_from_slice(&1818u32.to_le_bytes());
+        assert_eq!(Fat32Volume::new(&mut data).unwrap().next_free, 2);
+    }
+
+    #[test]
+    fn test_free_run_from_first_cluster() {
+        let mut data = create_mock_volume();
+        // Cluster 2, where the root usually is, free like any other.
+        data[32 * 512 + 2 * 4..][..4].copy_from_slice(&FAT_FREE.to_le_bytes());
+        let volume = Fat32Volume::new(&mut data).unwrap();
+        assert_eq!(volume.find_free_run(1), Some(2));
+        assert_eq!(volume.find_free_run(4), Some(2));
+    }
+
+    #[test]
+    fn test_compare_and_sync_fats() {
+        let mut data = create_mock_volume();
+        let second = 132 * 512;
+        for cluster in [10usize, 11, 12, 700] {
+            data[second + cluster * 4] = 0x42;
+        }
+        let mut volume = Fat32Volume::new(&mut data).unwrap();
+        let divergences = volume.compare_fats().unwrap();
+        assert_eq!(divergences, [FatDivergence { copy: 1, ranges: vec![10..13, 700..701] }]);
+        assert_eq!(divergences[0].entries(), 4);
+
+        // The second copy wins: its clusters are no longer free.
+        volume.sync_fats(1).unwrap();
+        assert_eq!(volume.compare_fats(), Ok(vec![]));
+        assert_eq!(volume.read_fat_entry(700), Ok(0x42));
+        assert!(!volume.is_free(700));
+
+        volume.write_fat_entry(700, FAT_FREE).unwrap();
+        volume.sync_fats(0).unwrap();
+        assert!(volume.is_free(700));
+        assert_eq!(volume.sync_fats(2), Err("Copie de FAT invalide"));
+    }
+
+    #[test]
+    fn test_cluster_bounds() {
+        let mut data = create_mock_volume();
+        let mut volume = Fat32Volume::new(&mut data).unwrap();
+        // 2048 sectors, 232 of them before the data region, one per cluster.
+        assert_eq!(volume.boot_sector.clusters(), 2..1818);
+        assert_eq!(volume.cluster_limit(), 1818);
+        for cluster in [0, 1, 1818, u32::MAX] {
+            assert_eq!(volume.offset_from_cluster(cluster), Err(INVALID_CLUSTER));
+            assert_eq!(volume.write_fat_entry(cluster, FAT_EOC), Err(INVALID_CLUSTER));
+        }
+        assert_eq!(volume.offset_from_cluster(1817), Ok(1023 * 1024 + 512));
+        assert_eq!(volume.read_fat_entry(0), Ok(0x0FFFFFF8));
+        assert_eq!(volume.read_fat_entry(volume.fat_entries()), Err(INVALID_CLUSTER));
+
+        // A file starting past the end of the volume.
+        let root = volume.offset_from_cluster(2).unwrap();
+        crate::fat32::volume::tests::put_raw_entry(&mut volume, root, b"FAR     BIN", 0, 5000, 100);
+        assert_eq!(volume.read_file("FAR.BIN"), Err(INVALID_CLUSTER));
+    }
+}
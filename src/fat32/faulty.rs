@@ -0,0 +1,161 @@
+//! A test device that fails on command, to exercise the error paths of chain
+//! traversal, allocation and journal commits.
+
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use super::io::{BlockDevice, BLOCK_SIZE};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Fault {
+    /// The operation with this number fails, counting reads and writes from 0.
+    Nth(usize),
+    /// Every read touching this block fails.
+    ReadBlock(u64),
+    /// Every write touching this block fails, leaving it untouched.
+    WriteBlock(u64),
+    /// Reads touching this block only fill the buffer up to it, then fail.
+    ShortRead(u64),
+}
+
+/// An image in memory whose accesses fail as its `faults` say.
+pub struct FaultyDevice {
+    pub data: Vec<u8>,
+    pub faults: Vec<Fault>,
+    ops: AtomicUsize,
+}
+
+impl FaultyDevice {
+    pub fn new(data: Vec<u8>) -> Self {
+        FaultyDevice { data, faults: Vec::new(), ops: AtomicUsize::new(0) }
+    }
+
+    /// Number of reads and writes so far.
+    pub fn ops(&self) -> usize {
+        self.ops.load(Ordering::Relaxed)
+    }
+
+    /// The first block of `start..start + len` hit by `fault`, if any.
+    fn hit(start: u64, len: usize, fault: &Fault, matches: impl Fn(&Fault) -> Option<u64>) -> Option<u64> {
+        matches(fault).filter(|&block| (start..start + (len / BLOCK_SIZE) as u64).contains(&block))
+    }
+
+    fn failing_nth(&self) -> bool {
+        let op = self.ops.fetch_add(1, Ordering::Relaxed);
+        self.faults.contains(&Fault::Nth(op))
+    }
+}
+
+impl BlockDevice for FaultyDevice {
+    fn read_blocks(&self, start: u64, buf: &mut [u8]) -> Result<(), &'static str> {
+        if self.failing_nth() { return Err("Erreur de lecture"); }
+        for fault in &self.faults {
+            if Self::hit(start, buf.len(), fault, |f| match f { Fault::ReadBlock(b) => Some(*b), _ => None }).is_some() {
+                return Err("Erreur de lecture");
+            }
+            if let Some(block) = Self::hit(start, buf.len(), fault, |f| match f { Fault::ShortRead(b) => Some(*b), _ => None }) {
+                let len = (block - start) as usize * BLOCK_SIZE;
+                self.data.read_blocks(start, &mut buf[..len])?;
+                return Err("Lecture incomplète");
+            }
+        }
+        self.data.read_blocks(start, buf)
+    }
+
+    fn write_blocks(&mut self, start: u64, buf: &[u8]) -> Result<(), &'static str> {
+        if self.failing_nth() { return Err("Erreur d'écriture"); }
+        for fault in &self.faults {
+            if Self::hit(start, buf.len(), fault, |f| match f { Fault::WriteBlock(b) => Some(*b), _ => None }).is_some() {
+                return Err("Erreur d'écriture");
+            }
+        }
+        self.data.write_blocks(start, buf)
+    }
+
+    fn num_blocks(&self) -> u64 {
+        self.data.num_blocks()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fat32::error::FILE_NOT_FOUND;
+    use crate::fat32::journal::JournalArea;
+    use crate::fat32::volume::tests::create_mock_volume;
+    use crate::fat32::volume::Fat32Volume;
+
+    /// Block of the first FAT holding the entry of `cluster` on the mock volume.
+    fn fat_block(cluster: u32) -> u64 {
+        32 + cluster as u64 * 4 / BLOCK_SIZE as u64
+    }
+
+    fn free_clusters(volume: &Fat32Volume) -> usize {
+        (2..volume.cluster_limit()).filter(|&c| volume.is_free(c)).count()
+    }
+
+    #[test]
+    fn test_chain_traversal_errors() {
+        let mut data = create_mock_volume();
+        let content: Vec<u8> = (0..3000).map(|i| i as u8).collect();
+        Fat32Volume::new(&mut data).unwrap().create_file("a.bin", &content, false).unwrap();
+        let mut device = FaultyDevice::new(data);
+        let first = Fat32Volume::new(&mut device.data).unwrap().file_entry("a.bin").unwrap().first_cluster;
+        let data_block = Fat32Volume::new(&mut device.data).unwrap().offset_from_cluster(first + 2).unwrap() as u64 / BLOCK_SIZE as u64;
+
+        device.faults = alloc::vec![Fault::ShortRead(data_block)];
+        {
+            let volume = Fat32Volume::from_device(&mut device).unwrap();
+            assert_eq!(volume.read_file("a.bin"), Err("Lecture incomplète"));
+            let mut buf = [0u8; 100];
+            assert_eq!(volume.read_at("a.bin", 0, &mut buf), Ok(100));
+        }
+        // The FAT becomes unreadable once mounted: the first access after mounting fails.
+        device.faults.clear();
+        let before = device.ops();
+        drop(Fat32Volume::from_device(&mut device).unwrap());
+        let mount_ops = device.ops() - before;
+        device.faults = alloc::vec![Fault::Nth(device.ops() + mount_ops)];
+        let volume = Fat32Volume::from_device(&mut device).unwrap();
+        assert_eq!(volume.cluster_chain(first), Err("Erreur de lecture"));
+        assert_eq!(volume.cluster_chain(first).unwrap().len(), 6);
+        drop(volume);
+        device.faults = alloc::vec![Fault::ReadBlock(fat_block(first))];
+        assert!(Fat32Volume::from_device(&mut device).is_err());
+    }
+
+    #[test]
+    fn test_failed_allocation_leaks_nothing() {
+        let mut device = FaultyDevice::new(create_mock_volume());
+        let free = free_clusters(&Fat32Volume::from_device(&mut device).unwrap());
+        // The directory entry is written after the chain, so its failure must free the chain.
+        let root = Fat32Volume::new(&mut device.data).unwrap().offset_from_cluster(2).unwrap() as u64 / BLOCK_SIZE as u64;
+        device.faults = alloc::vec![Fault::WriteBlock(root)];
+        {
+            let mut volume = Fat32Volume::from_device(&mut device).unwrap();
+            assert_eq!(volume.create_file("a.bin", &[1u8; 3000], false), Err("Erreur d'écriture"));
+            assert_eq!(free_clusters(&volume), free);
+        }
+        device.faults.clear();
+        let volume = Fat32Volume::from_device(&mut device).unwrap();
+        assert_eq!(free_clusters(&volume), free);
+        assert_eq!(volume.read_file("a.bin"), Err(FILE_NOT_FOUND));
+    }
+
+    #[test]
+    fn test_failed_commit_leaves_image_unchanged() {
+        let mut device = FaultyDevice::new(create_mock_volume());
+        let pristine = device.data.clone();
+        let area = JournalArea::reserved(&Fat32Volume::new(&mut device.data).unwrap().boot_sector).unwrap();
+        let JournalArea::Reserved(range) = &area else { unreachable!() };
+        // The journal header, written last, never makes it.
+        device.faults = alloc::vec![Fault::WriteBlock(range.start)];
+        let mut volume = Fat32Volume::from_device(&mut device).unwrap();
+        volume.enable_journal(area).unwrap();
+        volume.create_file("a.bin", b"never applied", false).unwrap();
+        assert_eq!(volume.commit(), Err("Erreur d'écriture"));
+        drop(volume);
+        let journal_end = 32 * BLOCK_SIZE;
+        assert_eq!(device.data[journal_end..], pristine[journal_end..]);
+    }
+}
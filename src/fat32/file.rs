@@ -0,0 +1,514 @@
+extern crate alloc;
+use alloc::vec::Vec;
+
+use super::dir::DirEntry;
+use super::error::{FILE_NOT_FOUND, FILE_TOO_LARGE, INVALID_POSITION, NOT_OPEN_FOR_READING, NOT_OPEN_FOR_WRITING};
+use super::fat::{FAT_EOC, FAT_FREE};
+use super::path::Resolved;
+use super::storage::Storage;
+use super::volume::Fat32Volume;
+
+/// Options for `Fat32OpenOptions::open`, with the same meaning as `std::fs::OpenOptions`.
+#[derive(Debug, Clone, Default)]
+pub struct Fat32OpenOptions {
+    read: bool,
+    write: bool,
+    append: bool,
+    truncate: bool,
+    create: bool,
+    read_ahead: Option<u32>,
+}
+
+/// Clusters read ahead of the cursor once reads turn out sequential, for images that
+/// aren't held in memory. They are read then and there, in the same call, not in the
+/// background: what it saves is the requests to the device, one per small read.
+pub const READ_AHEAD_CLUSTERS: u32 = 8;
+
+impl Fat32OpenOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn read(&mut self, read: bool) -> &mut Self {
+        self.read = read;
+        self
+    }
+
+    pub fn write(&mut self, write: bool) -> &mut Self {
+        self.write = write;
+        self
+    }
+
+    /// Every write goes to the end of the file. Implies `write`.
+    pub fn append(&mut self, append: bool) -> &mut Self {
+        self.append = append;
+        self
+    }
+
+    /// Empties the file when it is opened. Requires `write`.
+    pub fn truncate(&mut self, truncate: bool) -> &mut Self {
+        self.truncate = truncate;
+        self
+    }
+
+    /// Creates the file if it doesn't exist. Requires `write` or `append`.
+    pub fn create(&mut self, create: bool) -> &mut Self {
+        self.create = create;
+        self
+    }
+
+    /// How many clusters to read ahead of sequential reads, 0 to turn it off. Defaults to
+    /// `READ_AHEAD_CLUSTERS`, or 0 when the image is in memory and there is nothing to gain.
+    pub fn read_ahead(&mut self, clusters: u32) -> &mut Self {
+        self.read_ahead = Some(clusters);
+        self
+    }
+
+    pub fn open<'v, 'a>(&self, volume: &'v mut Fat32Volume<'a>, path: &str) -> Result<Fat32File<'v, 'a>, &'static str> {
+        let writable = self.write || self.append;
+        if (!self.read && !writable) || (self.truncate && !self.write) || (self.create && !writable) {
+            return Err("Options d'ouverture invalides");
+        }
+
+        let mut entry = match volume.resolve_path(path)? {
+            Resolved::File(entry) => entry,
+            Resolved::NotFound { parent, name } if self.create => {
+                volume.create_file_in(parent, &name, &[], false)?;
+                volume.find_entry(parent, &name)?.ok_or(FILE_NOT_FOUND)?
+            }
+            Resolved::NotFound { .. } => return Err(FILE_NOT_FOUND),
+            Resolved::Dir(_) => return Err("C'est un dossier"),
+        };
+        let mut chain = volume.file_chain(&entry)?;
+        if self.truncate {
+            volume.set_chain_len(&mut entry, &mut chain, 0)?;
+        }
+
+        let in_memory = matches!(*volume.storage, Storage::Memory(_) | Storage::Shared(_));
+        let read_ahead = self.read_ahead.unwrap_or(if in_memory { 0 } else { READ_AHEAD_CLUSTERS });
+        Ok(Fat32File {
+            volume, entry, chain, pos: 0, read: self.read, write: writable, append: self.append,
+            read_ahead, ahead: Vec::new(), ahead_start: 0, last_end: None,
+        })
+    }
+}
+
+/// Where `Fat32File::seek` moves the cursor, like `std::io::SeekFrom`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SeekFrom {
+    Start(u64),
+    End(i64),
+    Current(i64),
+}
+
+/// An open file of the image, with a cursor. Borrows the volume until dropped.
+pub struct Fat32File<'v, 'a> {
+    volume: &'v mut Fat32Volume<'a>,
+    entry: DirEntry,
+    /// The clusters of the file, followed once when it is opened and kept up to date by
+    /// `write` and `set_len`, so reads and writes don't walk the FAT again.
+    chain: Vec<u32>,
+    pos: u64,
+    read: bool,
+    write: bool,
+    append: bool,
+    /// Clusters to read ahead, 0 when off.
+    read_ahead: u32,
+    /// Bytes of the file from `ahead_start` on, read before they were asked for.
+    ahead: Vec<u8>,
+    ahead_start: u64,
+    /// Where the previous read stopped; a read starting there is sequential.
+    last_end: Option<u64>,
+}
+
+impl<'v, 'a> Fat32File<'v, 'a> {
+    /// Reads from the cursor. From the second of a run of sequential reads smaller than
+    /// the read-ahead window, the following clusters are fetched in one go and later
+    /// reads are served from them, so small reads don't each go to the device.
+    pub fn read(&mut self, buf: &mut [u8]) -> Result<usize, &'static str> {
+        if !self.read { return Err(NOT_OPEN_FOR_READING); }
+        let window = self.read_ahead as usize * self.volume.cluster_size();
+        if self.last_end == Some(self.pos) && buf.len() < window && self.buffered().is_none() {
+            self.ahead.resize(window, 0);
+            let n = self.volume.read_chain_at(&self.entry, &self.chain, self.pos, &mut self.ahead)?;
+            self.ahead.truncate(n);
+            self.ahead_start = self.pos;
+        }
+        let n = match self.buffered() {
+            Some(ahead) => {
+                let n = ahead.len().min(buf.len());
+                buf[..n].copy_from_slice(&ahead[..n]);
+                n
+            }
+            None => self.volume.read_chain_at(&self.entry, &self.chain, self.pos, buf)?,
+        };
+        self.pos += n as u64;
+        self.last_end = Some(self.pos);
+        if n > 0 { self.volume.stamp_accessed(&mut self.entry)?; }
+        Ok(n)
+    }
+
+    /// The bytes read ahead from the cursor on, if there are any.
+    fn buffered(&self) -> Option<&[u8]> {
+        let skip = usize::try_from(self.pos.checked_sub(self.ahead_start)?).ok()?;
+        self.ahead.get(skip..).filter(|rest| !rest.is_empty())
+    }
+
+    pub fn write(&mut self, data: &[u8]) -> Result<usize, &'static str> {
+        if !self.write { return Err(NOT_OPEN_FOR_WRITING); }
+        if self.append { self.pos = self.entry.size as u64; }
+        self.ahead.clear();
+        self.volume.write_chain_at(&mut self.entry, &mut self.chain, self.pos, data)?;
+        self.pos += data.len() as u64;
+        Ok(data.len())
+    }
+
+    pub fn seek(&mut self, pos: SeekFrom) -> Result<u64, &'static str> {
+        let target = match pos {
+            SeekFrom::Start(n) => Some(n),
+            SeekFrom::End(n) => (self.entry.size as u64).checked_add_signed(n),
+            SeekFrom::Current(n) => self.pos.checked_add_signed(n),
+        };
+        self.pos = target.ok_or(INVALID_POSITION)?;
+        Ok(self.pos)
+    }
+
+    /// Shrinks or zero-extends the file to `size` bytes. The cursor is left unchanged.
+    pub fn set_len(&mut self, size: u64) -> Result<(), &'static str> {
+        if !self.write { return Err(NOT_OPEN_FOR_WRITING); }
+        self.ahead.clear();
+        self.volume.set_chain_len(&mut self.entry, &mut self.chain, size)
+    }
+
+    pub fn len(&self) -> u64 {
+        self.entry.size as u64
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entry.size == 0
+    }
+
+    pub fn position(&self) -> u64 {
+        self.pos
+    }
+}
+
+impl<'a> Fat32Volume<'a> {
+    /// Reads up to `buf.len()` bytes of the file at `path`, starting at byte `offset`.
+    /// Returns the number of bytes read, 0 at or past the end of the file.
+    pub fn read_at(&self, path: &str, offset: u64, buf: &mut [u8]) -> Result<usize, &'static str> {
+        let entry = self.file_entry(path)?;
+        self.read_entry_at(&entry, offset, buf)
+    }
+
+    /// Writes `data` into the file at `path` starting at byte `offset`, only touching the
+    /// clusters concerned. Writing past the end grows the file, zero-filling any gap.
+    pub fn write_at(&mut self, path: &str, offset: u64, data: &[u8]) -> Result<(), &'static str> {
+        let mut entry = self.file_entry(path)?;
+        self.write_entry_at(&mut entry, offset, data)
+    }
+
+    pub(super) fn read_entry_at(&self, entry: &DirEntry, offset: u64, buf: &mut [u8]) -> Result<usize, &'static str> {
+        let chain = self.file_chain(entry)?;
+        self.read_chain_at(entry, &chain, offset, buf)
+    }
+
+    pub(super) fn write_entry_at(&mut self, entry: &mut DirEntry, offset: u64, data: &[u8]) -> Result<(), &'static str> {
+        let mut chain = self.file_chain(entry)?;
+        self.write_chain_at(entry, &mut chain, offset, data)
+    }
+
+    /// The clusters of the file `entry`, none when it is empty. Followed once, it lets
+    /// [`read_chain_at`](Self::read_chain_at) read a file piece by piece without walking
+    /// the FAT again.
+    pub fn file_chain(&self, entry: &DirEntry) -> Result<Vec<u32>, &'static str> {
+        if entry.first_cluster >= 2 { self.cluster_chain(entry.first_cluster) } else { Ok(Vec::new()) }
+    }
+
+    /// Reads up to `buf.len()` bytes of the file `entry` from byte `offset`, through its
+    /// `chain` as given by [`file_chain`](Self::file_chain).
+    pub fn read_chain_at(&self, entry: &DirEntry, chain: &[u32], offset: u64, buf: &mut [u8]) -> Result<usize, &'static str> {
+        let size = entry.size as u64;
+        if offset >= size { return Ok(0); }
+        let end = size.min(offset + buf.len() as u64);
+        self.read_chain_range(chain, offset, end, buf)
+    }
+
+    /// `write_entry_at` with the chain of `entry` at hand; the clusters it appends are
+    /// added to `chain`.
+    fn write_chain_at(&mut self, entry: &mut DirEntry, chain: &mut Vec<u32>, offset: u64, data: &[u8]) -> Result<(), &'static str> {
+        let cluster_size = self.cluster_size() as u64;
+        let old_size = entry.size as u64;
+        let end = offset + data.len() as u64;
+        if end > u32::MAX as u64 { return Err(FILE_TOO_LARGE); }
+
+        let old_len = chain.len();
+        let needed = end.max(old_size).div_ceil(cluster_size) as usize;
+        while chain.len() < needed {
+            if let Err(e) = self.grow_chain(entry, chain) {
+                self.release_grown(entry, chain, old_len)?;
+                chain.truncate(old_len);
+                return Err(e);
+            }
+        }
+
+        // Bytes between the old end and `offset` may hold stale data in the last old cluster.
+        if offset > old_size {
+            let gap_end = offset.min(old_len as u64 * cluster_size);
+            self.fill_chain_range(chain, old_size, gap_end, None)?;
+        }
+        self.fill_chain_range(chain, offset, end, Some(data))?;
+
+        if end > old_size {
+            self.set_entry_size(entry.offset, end as u32)?;
+            entry.size = end as u32;
+        }
+        self.mark_modified(entry)
+    }
+
+    /// Appends a zeroed cluster to `chain`, the chain of `entry`.
+    fn grow_chain(&mut self, entry: &mut DirEntry, chain: &mut Vec<u32>) -> Result<(), &'static str> {
+        let cluster = if chain.is_empty() { self.allocate_first_cluster()? } else { self.allocate_cluster()? };
+        chain.push(cluster);
+        self.storage.fill(self.offset_from_cluster(cluster)?, self.cluster_size(), 0)?;
+        match chain.iter().rev().nth(1) {
+            Some(&last) => self.write_fat_entry(last, cluster),
+            None => {
+                self.set_entry_cluster(entry.offset, cluster)?;
+                entry.first_cluster = cluster;
+                Ok(())
+            }
+        }
+    }
+
+    /// Shrinks the file to `size` bytes, releasing the clusters it no longer needs,
+    /// or grows it with zeros. An empty file owns no cluster at all. `chain`, the chain
+    /// of `entry`, is cut or grown to match.
+    fn set_chain_len(&mut self, entry: &mut DirEntry, chain: &mut Vec<u32>, size: u64) -> Result<(), &'static str> {
+        if size >= entry.size as u64 {
+            return self.write_chain_at(entry, chain, size, &[]);
+        }
+
+        let keep = size.div_ceil(self.cluster_size() as u64) as usize;
+        for &c in chain.iter().skip(keep) { self.write_fat_entry(c, FAT_FREE)?; }
+        if keep == 0 {
+            self.set_entry_cluster(entry.offset, 0)?;
+            entry.first_cluster = 0;
+        } else if keep < chain.len() {
+            self.write_fat_entry(chain[keep - 1], FAT_EOC)?;
+        }
+        chain.truncate(keep);
+        self.set_entry_size(entry.offset, size as u32)?;
+        entry.size = size as u32;
+        self.mark_modified(entry)
+    }
+
+    /// Undoes the clusters appended by a failed `write_entry_at`.
+    fn release_grown(&mut self, entry: &mut DirEntry, chain: &[u32], old_len: usize) -> Result<(), &'static str> {
+        for &c in &chain[old_len..] { self.write_fat_entry(c, FAT_FREE)?; }
+        match old_len {
+            0 => {
+                entry.first_cluster = 0;
+                self.set_entry_cluster(entry.offset, 0)
+            }
+            n => self.write_fat_entry(chain[n - 1], FAT_EOC),
+        }
+    }
+
+    /// Reads the byte range `start..end` of the chain into `buf`. Returns the bytes covered.
+    fn read_chain_range(&self, chain: &[u32], start: u64, end: u64, buf: &mut [u8]) -> Result<usize, &'static str> {
+        let cluster_size = self.cluster_size() as u64;
+        let mut pos = start;
+        while pos < end {
+            let index = (pos / cluster_size) as usize;
+            let Some(&cluster) = chain.get(index) else { break };
+            // Clusters that follow this one on disk are read in the same request.
+            let mut run = 1;
+            while ((index + run) as u64 * cluster_size) < end && chain.get(index + run) == Some(&(cluster + run as u32)) {
+                run += 1;
+            }
+            let within = pos % cluster_size;
+            let len = (run as u64 * cluster_size - within).min(end - pos) as usize;
+            let at = self.offset_from_cluster(cluster)? + within as usize;
+            let dst = (pos - start) as usize;
+            self.storage.read(at, &mut buf[dst..dst + len])?;
+            pos += len as u64;
+        }
+        Ok((pos - start) as usize)
+    }
+
+    /// Writes `data` (or zeros when `None`) over the byte range `start..end` of the chain.
+    fn fill_chain_range(&mut self, chain: &[u32], start: u64, end: u64, data: Option<&[u8]>) -> Result<(), &'static str> {
+        let cluster_size = self.cluster_size() as u64;
+        let mut pos = start;
+        while pos < end {
+            let Some(&cluster) = chain.get((pos / cluster_size) as usize) else { break };
+            let within = pos % cluster_size;
+            let len = (cluster_size - within).min(end - pos) as usize;
+            let at = self.offset_from_cluster(cluster)? + within as usize;
+            match data {
+                Some(d) => {
+                    let from = (pos - start) as usize;
+                    self.storage.write(at, &d[from..from + len])?;
+                }
+                None => self.storage.fill(at, len, 0)?,
+            }
+            pos += len as u64;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec;
+    use core::sync::atomic::{AtomicUsize, Ordering};
+    use crate::fat32::io::BlockDevice;
+    use crate::fat32::volume::tests::create_mock_volume;
+
+    /// An image that counts the read requests made to it.
+    struct Counting<'c> { data: Vec<u8>, reads: &'c AtomicUsize }
+
+    impl BlockDevice for Counting<'_> {
+        fn read_blocks(&self, start: u64, buf: &mut [u8]) -> Result<(), &'static str> {
+            self.reads.fetch_add(1, Ordering::Relaxed);
+            self.data.read_blocks(start, buf)
+        }
+
+        fn write_blocks(&mut self, start: u64, buf: &[u8]) -> Result<(), &'static str> {
+            self.data.write_blocks(start, buf)
+        }
+
+        fn num_blocks(&self) -> u64 {
+            self.data.num_blocks()
+        }
+    }
+
+    #[test]
+    fn test_open_options() {
+        let mut data = create_mock_volume();
+        let mut volume = Fat32Volume::new(&mut data).unwrap();
+
+        assert!(Fat32OpenOptions::new().read(true).open(&mut volume, "log.txt").is_err());
+        assert!(Fat32OpenOptions::new().read(true).create(true).open(&mut volume, "log.txt").is_err());
+
+        let mut file = Fat32OpenOptions::new().write(true).create(true).open(&mut volume, "log.txt").unwrap();
+        file.write(b"hello world").unwrap();
+        file.seek(SeekFrom::Start(6)).unwrap();
+        file.write(b"fat32").unwrap();
+        assert_eq!(file.len(), 11);
+        drop(file);
+
+        let mut file = Fat32OpenOptions::new().append(true).read(true).open(&mut volume, "log.txt").unwrap();
+        file.write(b"!").unwrap();
+        file.seek(SeekFrom::Start(0)).unwrap();
+        let mut buf = [0u8; 32];
+        let n = file.read(&mut buf).unwrap();
+        assert_eq!(&buf[..n], b"hello fat32!");
+        drop(file);
+
+        let file = Fat32OpenOptions::new().write(true).truncate(true).open(&mut volume, "log.txt").unwrap();
+        assert!(file.is_empty());
+        drop(file);
+        assert_eq!(volume.file_entry("log.txt").unwrap().first_cluster, 0);
+        assert!(volume.is_free(3));
+    }
+
+    #[test]
+    fn test_read_at_across_clusters() {
+        let mut data = create_mock_volume();
+        let mut volume = Fat32Volume::new(&mut data).unwrap();
+        let content: Vec<u8> = (0..1500).map(|i| (i % 256) as u8).collect();
+        volume.create_file("blob.bin", &content, false).unwrap();
+
+        let mut buf = [0u8; 100];
+        assert_eq!(volume.read_at("blob.bin", 480, &mut buf).unwrap(), 100);
+        assert_eq!(&buf[..], &content[480..580]);
+        assert_eq!(volume.read_at("blob.bin", 1450, &mut buf).unwrap(), 50);
+        assert_eq!(volume.read_at("blob.bin", 5000, &mut buf).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_write_at_patches_and_grows() {
+        let mut data = create_mock_volume();
+        let mut volume = Fat32Volume::new(&mut data).unwrap();
+        volume.create_file("config.bin", &[1u8; 600], false).unwrap();
+
+        volume.write_at("config.bin", 510, b"PATCH").unwrap();
+        let content = volume.read_file("config.bin").unwrap();
+        assert_eq!(content.len(), 600);
+        assert_eq!(&content[508..517], b"\x01\x01PATCH\x01\x01");
+
+        volume.write_at("config.bin", 1100, b"END").unwrap();
+        let content = volume.read_file("config.bin").unwrap();
+        assert_eq!(content.len(), 1103);
+        assert!(content[600..1100].iter().all(|&b| b == 0));
+        assert_eq!(&content[1100..], b"END");
+        assert_eq!(volume.cluster_chain(3).unwrap().len(), 3);
+    }
+
+    #[test]
+    fn test_handle_keeps_its_chain() {
+        let mut data = create_mock_volume();
+        let content: Vec<u8> = (0..8192).map(|i| (i % 251) as u8).collect();
+        Fat32Volume::new(&mut data).unwrap().create_file("big.bin", &content, false).unwrap();
+        let reads = AtomicUsize::new(0);
+        let mut device = Counting { data, reads: &reads };
+        let mut volume = Fat32Volume::from_device(&mut device).unwrap();
+        let cluster_size = volume.cluster_size();
+        let mut file = Fat32OpenOptions::new().read(true).write(true).read_ahead(0).open(&mut volume, "big.bin").unwrap();
+
+        // Once the file is open, reading a cluster is one request: the FAT isn't read again.
+        let before = reads.load(Ordering::Relaxed);
+        let (mut read, mut buf) = (Vec::new(), vec![0u8; cluster_size]);
+        loop {
+            let n = file.read(&mut buf).unwrap();
+            if n == 0 { break; }
+            read.extend_from_slice(&buf[..n]);
+        }
+        assert_eq!(read, content);
+        assert_eq!(reads.load(Ordering::Relaxed) - before, content.len() / cluster_size);
+
+        // Growing and cutting the file keeps the chain in step.
+        file.write(b"tail").unwrap();
+        file.set_len(600).unwrap();
+        file.seek(SeekFrom::Start(0)).unwrap();
+        let mut buf = vec![0u8; 1000];
+        assert_eq!(file.read(&mut buf).unwrap(), 600);
+        assert_eq!(&buf[..600], &content[..600]);
+        file.seek(SeekFrom::Start(2000)).unwrap();
+        file.write(b"end").unwrap();
+        file.seek(SeekFrom::Start(1990)).unwrap();
+        assert_eq!(file.read(&mut buf).unwrap(), 13);
+        assert_eq!(&buf[..13], b"\0\0\0\0\0\0\0\0\0\0end");
+        drop(file);
+        let first = volume.file_entry("big.bin").unwrap().first_cluster;
+        assert_eq!(volume.cluster_chain(first).unwrap().len(), 2003usize.div_ceil(cluster_size));
+    }
+
+    #[test]
+    fn test_read_ahead() {
+        let mut data = create_mock_volume();
+        let content: Vec<u8> = (0..20000).map(|i| (i % 251) as u8).collect();
+        Fat32Volume::new(&mut data).unwrap().create_file("big.bin", &content, false).unwrap();
+        let reads = AtomicUsize::new(0);
+        let mut device = Counting { data, reads: &reads };
+
+        let mut counts = Vec::new();
+        for clusters in [0, READ_AHEAD_CLUSTERS] {
+            let mut volume = Fat32Volume::from_device(&mut device).unwrap();
+            let mut file = Fat32OpenOptions::new().read(true).read_ahead(clusters).open(&mut volume, "big.bin").unwrap();
+            let (mut read, mut buf) = (Vec::new(), [0u8; 100]);
+            let before = reads.load(Ordering::Relaxed);
+            loop {
+                let n = file.read(&mut buf).unwrap();
+                if n == 0 { break; }
+                read.extend_from_slice(&buf[..n]);
+            }
+            assert_eq!(read, content);
+            counts.push(reads.load(Ordering::Relaxed) - before);
+        }
+        assert!(counts[1] * 4 < counts[0], "{:?}", counts);
+    }
+}
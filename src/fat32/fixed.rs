@@ -0,0 +1,336 @@
+//! Reading without a heap. `FixedVolume` lists directories, resolves paths and reads files
+//! with only the buffers its caller hands it, and keeps names in `heapless` strings. It is
+//! the part of the crate left when the `alloc` feature is off.
+
+use super::bpb::BiosParameterBlock;
+use super::fat::FatValue;
+use super::codepage::Codepage;
+use super::error::{DIR_NOT_FOUND, FILE_NOT_FOUND};
+use super::io::BLOCK_SIZE;
+use super::name::{lfn_checksum, lfn_chars, write_lfn, write_short_name, LFN_CHARS_PER_ENTRY, LFN_LAST_ENTRY};
+use super::storage::Storage;
+use super::structs::BootSector;
+
+const ATTR_VOLUME_ID: u8 = 0x08;
+const ATTR_DIRECTORY: u8 = 0x10;
+const ATTR_LONG_NAME: u8 = 0x0F;
+/// A long name spans at most 20 entries.
+const LFN_MAX_UNITS: usize = 20 * LFN_CHARS_PER_ENTRY;
+
+/// Room for a name, in UTF-8 bytes. Long names that don't fit are replaced by the short one.
+pub const NAME_CAPACITY: usize = 255;
+pub type Name = heapless::String<NAME_CAPACITY>;
+
+/// A directory entry with its names held inline.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FixedEntry {
+    pub name: Name,
+    /// The short name rendered as `NAME.EXT`.
+    pub alias: heapless::String<12>,
+    pub attr: u8,
+    pub first_cluster: u32,
+    pub size: u32,
+}
+
+impl FixedEntry {
+    pub fn is_dir(&self) -> bool {
+        (self.attr & ATTR_DIRECTORY) != 0
+    }
+
+    pub fn is_dot(&self) -> bool {
+        self.name == "." || self.name == ".."
+    }
+
+    /// True when `name` is either the long or the short name of the entry, ignoring case.
+    pub fn matches(&self, name: &str) -> bool {
+        self.name.eq_ignore_ascii_case(name) || self.alias.eq_ignore_ascii_case(name)
+    }
+}
+
+/// A read-only view of a volume that never allocates.
+pub struct FixedVolume<'a> {
+    storage: Storage<'a>,
+    boot_sector: BootSector,
+    /// OEM codepage used for the non-ASCII bytes of short names.
+    pub codepage: Codepage,
+}
+
+impl<'a> FixedVolume<'a> {
+    pub fn new(storage: Storage<'a>) -> Result<Self, &'static str> {
+        let mut sector = [0u8; BLOCK_SIZE];
+        storage.read(0, &mut sector)?;
+        let boot_sector = BootSector::parse(&sector)?;
+        Ok(FixedVolume { storage, boot_sector, codepage: Codepage::default() })
+    }
+
+    pub fn bpb(&self) -> Result<BiosParameterBlock, &'static str> {
+        let mut sector = [0u8; BLOCK_SIZE];
+        self.storage.read(0, &mut sector)?;
+        BiosParameterBlock::parse(&sector)
+    }
+
+    pub fn root_cluster(&self) -> u32 {
+        self.boot_sector.root_dir_cluster
+    }
+
+    /// Iterates over the directory starting at `cluster`, loading it into `buf` one block
+    /// at a time.
+    pub fn read_dir<'s>(&'s self, cluster: u32, buf: &'s mut [u8; BLOCK_SIZE]) -> FixedDir<'s, 'a> {
+        FixedDir {
+            volume: self,
+            buf,
+            cluster,
+            block: 0,
+            slot: SLOTS_PER_BLOCK,
+            loaded: false,
+            done: false,
+            lfn: [0xFFFF; LFN_MAX_UNITS],
+            lfn_len: 0,
+            checksum: 0,
+            clusters_left: self.max_chain_len(),
+        }
+    }
+
+    /// Looks `name` up in the directory at `cluster` by its long or short name.
+    pub fn find(&self, cluster: u32, name: &str, buf: &mut [u8; BLOCK_SIZE]) -> Result<Option<FixedEntry>, &'static str> {
+        for entry in self.read_dir(cluster, buf) {
+            let entry = entry?;
+            if entry.matches(name) { return Ok(Some(entry)); }
+        }
+        Ok(None)
+    }
+
+    /// Resolves the absolute `path` to its entry; `/` gives an entry for the root directory.
+    pub fn open(&self, path: &str, buf: &mut [u8; BLOCK_SIZE]) -> Result<FixedEntry, &'static str> {
+        let root = self.root_cluster();
+        let mut current = FixedEntry {
+            name: Name::try_from("/").unwrap(),
+            alias: heapless::String::new(),
+            attr: ATTR_DIRECTORY,
+            first_cluster: root,
+            size: 0,
+        };
+        let mut components = path.split('/').filter(|c| !c.is_empty() && *c != ".").peekable();
+        while let Some(component) = components.next() {
+            let last = components.peek().is_none();
+            if !current.is_dir() { return Err("Ce n'est pas un dossier"); }
+            let cluster = if current.first_cluster == 0 { root } else { current.first_cluster };
+            // The root directory has no `..` entry.
+            if component == ".." && cluster == root { continue; }
+            current = match self.find(cluster, component, buf)? {
+                Some(entry) => entry,
+                None if last => return Err(FILE_NOT_FOUND),
+                None => return Err(DIR_NOT_FOUND),
+            };
+        }
+        Ok(current)
+    }
+
+    /// Reads up to `buf.len()` bytes of the file of `entry` from byte `offset` straight
+    /// into `buf`. Returns the number of bytes read, 0 at or past the end of the file.
+    pub fn read(&self, entry: &FixedEntry, offset: u64, buf: &mut [u8]) -> Result<usize, &'static str> {
+        if entry.is_dir() { return Err("C'est un dossier"); }
+        let size = entry.size as u64;
+        if offset >= size { return Ok(0); }
+        let end = size.min(offset + buf.len() as u64);
+        let cluster_size = self.boot_sector.cluster_size() as u64;
+
+        let mut cluster = entry.first_cluster;
+        for _ in 0..offset / cluster_size {
+            cluster = self.next_cluster(cluster)?.ok_or(FILE_NOT_FOUND)?;
+        }
+        let mut pos = offset;
+        while pos < end {
+            if cluster < 2 { break; }
+            let within = pos % cluster_size;
+            let len = (cluster_size - within).min(end - pos) as usize;
+            let dst = (pos - offset) as usize;
+            self.storage.read(self.boot_sector.cluster_offset(cluster)? + within as usize, &mut buf[dst..dst + len])?;
+            pos += len as u64;
+            if pos < end {
+                cluster = self.next_cluster(cluster)?.unwrap_or(0);
+            }
+        }
+        Ok((pos - offset) as usize)
+    }
+
+    /// The cluster following `cluster` in its chain, `None` at its end.
+    fn next_cluster(&self, cluster: u32) -> Result<Option<u32>, &'static str> {
+        let mut raw = [0u8; 4];
+        self.storage.read(self.boot_sector.fat_start() + cluster as usize * 4, &mut raw)?;
+        Ok(match FatValue::decode(u32::from_le_bytes(raw)) {
+            FatValue::Next(next) => Some(next),
+            _ => None,
+        })
+    }
+
+    /// Longest chain the image can hold; anything longer loops.
+    fn max_chain_len(&self) -> usize {
+        self.storage.len() / self.boot_sector.cluster_size()
+    }
+}
+
+const SLOTS_PER_BLOCK: usize = BLOCK_SIZE / 32;
+
+/// Iterator returned by `FixedVolume::read_dir`. Deleted entries, long-name fragments
+/// and volume labels are skipped, like `Fat32Volume::read_dir` does.
+pub struct FixedDir<'s, 'a> {
+    volume: &'s FixedVolume<'a>,
+    buf: &'s mut [u8; BLOCK_SIZE],
+    cluster: u32,
+    /// Block of the current cluster held in `buf`.
+    block: usize,
+    /// Next slot of `buf` to look at.
+    slot: usize,
+    /// Whether `buf` holds a block yet.
+    loaded: bool,
+    /// Set at the end-of-directory marker, the end of the chain, or after an error.
+    done: bool,
+    lfn: [u16; LFN_MAX_UNITS],
+    lfn_len: usize,
+    checksum: u8,
+    clusters_left: usize,
+}
+
+impl FixedDir<'_, '_> {
+    /// Makes the next slot available in `buf`. Returns false at the end of the chain.
+    fn advance(&mut self) -> Result<bool, &'static str> {
+        if self.slot < SLOTS_PER_BLOCK { return Ok(true); }
+        let blocks_per_cluster = self.volume.boot_sector.cluster_size() / BLOCK_SIZE;
+        if self.loaded {
+            self.block += 1;
+            if self.block == blocks_per_cluster {
+                match self.volume.next_cluster(self.cluster)? {
+                    Some(next) => self.cluster = next,
+                    None => return Ok(false),
+                }
+                self.block = 0;
+            }
+        }
+        if self.block == 0 {
+            if self.clusters_left == 0 || self.cluster < 2 { return Ok(false); }
+            self.clusters_left -= 1;
+        }
+        let offset = self.volume.boot_sector.cluster_offset(self.cluster)? + self.block * BLOCK_SIZE;
+        self.volume.storage.read(offset, self.buf)?;
+        self.loaded = true;
+        self.slot = 0;
+        Ok(true)
+    }
+
+    fn entry(&self, raw: &[u8; 32]) -> FixedEntry {
+        let short_name: [u8; 11] = core::array::from_fn(|i| raw[i]);
+        let mut alias = heapless::String::new();
+        let _ = write_short_name(&short_name, self.volume.codepage, raw[12], &mut alias);
+        let mut name = Name::new();
+        let has_lfn = self.lfn_len > 0 && self.checksum == lfn_checksum(&short_name);
+        if !has_lfn || write_lfn(&self.lfn[..self.lfn_len], &mut name).is_err() {
+            name.clear();
+            let _ = name.push_str(&alias);
+        }
+        let cluster_hi = u16::from_le_bytes([raw[20], raw[21]]) as u32;
+        let cluster_lo = u16::from_le_bytes([raw[26], raw[27]]) as u32;
+        FixedEntry {
+            name,
+            alias,
+            attr: raw[11],
+            first_cluster: (cluster_hi << 16) | cluster_lo,
+            size: u32::from_le_bytes([raw[28], raw[29], raw[30], raw[31]]),
+        }
+    }
+}
+
+impl Iterator for FixedDir<'_, '_> {
+    type Item = Result<FixedEntry, &'static str>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while !self.done {
+            match self.advance() {
+                Ok(true) => {}
+                Ok(false) => self.done = true,
+                Err(e) => {
+                    self.done = true;
+                    return Some(Err(e));
+                }
+            }
+            if self.done { break; }
+            let at = self.slot * 32;
+            self.slot += 1;
+            let mut raw = [0u8; 32];
+            raw.copy_from_slice(&self.buf[at..at + 32]);
+
+            if raw[0] == 0 {
+                self.done = true;
+                break;
+            }
+            if raw[0] == 0xE5 { self.lfn_len = 0; continue; }
+
+            if raw[11] == ATTR_LONG_NAME {
+                let seq = (raw[0] & 0x1F) as usize;
+                if raw[0] & LFN_LAST_ENTRY != 0 && seq * LFN_CHARS_PER_ENTRY <= LFN_MAX_UNITS {
+                    self.lfn_len = seq * LFN_CHARS_PER_ENTRY;
+                    self.lfn[..self.lfn_len].fill(0xFFFF);
+                    self.checksum = raw[13];
+                }
+                if seq == 0 || seq * LFN_CHARS_PER_ENTRY > self.lfn_len || raw[13] != self.checksum {
+                    self.lfn_len = 0;
+                    continue;
+                }
+                let at = (seq - 1) * LFN_CHARS_PER_ENTRY;
+                self.lfn[at..at + LFN_CHARS_PER_ENTRY].copy_from_slice(&lfn_chars(&raw));
+                continue;
+            }
+            if (raw[11] & ATTR_VOLUME_ID) != 0 { self.lfn_len = 0; continue; }
+
+            let entry = self.entry(&raw);
+            self.lfn_len = 0;
+            return Some(Ok(entry));
+        }
+        None
+    }
+}
+
+#[cfg(all(test, feature = "alloc"))]
+mod tests {
+    use super::*;
+    use alloc::vec::Vec;
+    use crate::fat32::volume::tests::create_mock_volume;
+    use crate::fat32::volume::Fat32Volume;
+
+    #[test]
+    fn test_fixed_volume_reads_without_allocating() {
+        let mut data = create_mock_volume();
+        let content: Vec<u8> = (0..2000).map(|i| (i % 249) as u8).collect();
+        {
+            let mut volume = Fat32Volume::new(&mut data).unwrap();
+            let etc = volume.create_directory("etc").unwrap();
+            volume.create_file_in(etc, "A rather long configuration name.conf", &content, false).unwrap();
+            // Enough entries to spill the directory over several blocks.
+            for i in 0..20 {
+                volume.create_file_in(etc, &alloc::format!("f{}.txt", i), b"", false).unwrap();
+            }
+        }
+
+        let volume = FixedVolume::new(Storage::Memory(&mut data)).unwrap();
+        let mut buf = [0u8; BLOCK_SIZE];
+        let names: Vec<Name> = volume.read_dir(volume.root_cluster(), &mut buf).map(|e| e.unwrap().name).collect();
+        assert_eq!(names, ["etc"]);
+
+        let entry = volume.open("/etc/../etc/A RATHER LONG configuration name.conf", &mut buf).unwrap();
+        assert_eq!(entry.name, "A rather long configuration name.conf");
+        assert_eq!(entry.size, 2000);
+        let etc = volume.open("/etc", &mut buf).unwrap();
+        assert_eq!(volume.read_dir(etc.first_cluster, &mut buf).count(), 23);
+        let alias = entry.alias.clone();
+        assert_ne!(alias, entry.name);
+        assert_eq!(volume.open(&alloc::format!("/etc/{}", alias.to_lowercase()), &mut buf), Ok(entry.clone()));
+        assert_eq!(volume.open("/etc/nope", &mut buf), Err(FILE_NOT_FOUND));
+        assert_eq!(volume.open("/nope/x", &mut buf), Err(DIR_NOT_FOUND));
+
+        let mut out = [0u8; 700];
+        assert_eq!(volume.read(&entry, 1500, &mut out), Ok(500));
+        assert_eq!(&out[..500], &content[1500..]);
+        assert_eq!(volume.read(&entry, 300, &mut out), Ok(700));
+        assert_eq!(&out[..], &content[300..1000]);
+    }
+}
@@ -0,0 +1,262 @@
+extern crate alloc;
+use alloc::vec;
+use alloc::vec::Vec;
+
+use super::error::INVALID_CLUSTER;
+use super::progress::{ProgressSink, ProgressTracker};
+use super::volume::Fat32Volume;
+
+/// File types `carve` recognizes by their first bytes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CarveKind {
+    Jpeg,
+    Png,
+    Zip,
+}
+
+impl CarveKind {
+    fn detect(head: &[u8; 8]) -> Option<Self> {
+        match head {
+            [0xFF, 0xD8, 0xFF, ..] => Some(CarveKind::Jpeg),
+            [0x89, b'P', b'N', b'G', 0x0D, 0x0A, 0x1A, 0x0A] => Some(CarveKind::Png),
+            [b'P', b'K', 3, 4, ..] => Some(CarveKind::Zip),
+            _ => None,
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            CarveKind::Jpeg => "jpg",
+            CarveKind::Png => "png",
+            CarveKind::Zip => "zip",
+        }
+    }
+
+    /// How far back from newly read bytes the end marker may start.
+    fn lookback(&self) -> usize {
+        match self {
+            CarveKind::Jpeg => 1,
+            CarveKind::Png => 11,
+            // The end of central directory record may carry a comment of up to 64 KiB.
+            CarveKind::Zip => 22 + 0xFFFF,
+        }
+    }
+
+    /// Length of the file in `data` once its end marker is there, searching from `from`.
+    fn find_end(&self, data: &[u8], from: usize) -> Option<usize> {
+        let find = |marker: &[u8]| data.get(from..)?.windows(marker.len()).position(|w| w == marker).map(|p| from + p);
+        match self {
+            CarveKind::Jpeg => find(&[0xFF, 0xD9]).map(|p| p + 2).filter(|&end| end > 4),
+            CarveKind::Png => find(b"IEND").map(|p| p + 8).filter(|&end| end <= data.len()),
+            CarveKind::Zip => {
+                let p = find(b"PK\x05\x06")?;
+                let comment = u16::from_le_bytes(*data.get(p + 20..)?.first_chunk()?) as usize;
+                Some(p + 22 + comment).filter(|&end| end <= data.len())
+            }
+        }
+    }
+}
+
+/// A file found by `carve`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Carved {
+    pub kind: CarveKind,
+    /// Cluster the file starts at.
+    pub cluster: u32,
+    /// False when no end marker was found before the size limit or a cluster in use.
+    pub complete: bool,
+    pub data: Vec<u8>,
+}
+
+/// The unused tail of the last cluster of a file, and where it is in the image.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Slack {
+    pub cluster: u32,
+    /// Byte offset of the slack in the image.
+    pub offset: usize,
+    pub bytes: Vec<u8>,
+}
+
+/// Bytes `dump_clusters` reads at a time.
+const DUMP_CHUNK: usize = 64 * 1024;
+
+impl<'a> Fat32Volume<'a> {
+    /// Bytes between the end of the file at `path` and the end of the cluster holding it.
+    /// Empty when the size is a whole number of clusters.
+    pub fn slack(&self, path: &str) -> Result<Slack, &'static str> {
+        let entry = self.file_entry(path)?;
+        let cluster_size = self.cluster_size();
+        let used = entry.size as usize % cluster_size;
+        if entry.size == 0 || entry.first_cluster < 2 || used == 0 {
+            return Ok(Slack { cluster: 0, offset: 0, bytes: Vec::new() });
+        }
+        let chain = self.cluster_chain(entry.first_cluster)?;
+        let cluster = *chain.get(entry.size as usize / cluster_size).ok_or("Chaîne trop courte")?;
+        let offset = self.offset_from_cluster(cluster)? + used;
+        let mut bytes = vec![0u8; cluster_size - used];
+        self.storage.read(offset, &mut bytes)?;
+        Ok(Slack { cluster, offset, bytes })
+    }
+
+    /// Looks for JPEG, PNG and ZIP files starting at clusters no live file uses: free
+    /// ones, and used ones no directory entry reaches. Each file is assumed contiguous and
+    /// cut at its end marker, or at `max_len` bytes. Returns the number of files found.
+    pub fn carve(&self, max_len: usize, found: &mut dyn FnMut(Carved)) -> Result<usize, &'static str> {
+        let limit = self.cluster_limit();
+        let referenced = self.referenced_clusters()?;
+        let unused = |cluster: u32| self.is_free(cluster) || !referenced[cluster as usize];
+        let cluster_size = self.cluster_size();
+        let mut head = [0u8; 8];
+        let mut count = 0;
+        let mut cluster = 2;
+        while cluster < limit {
+            if !unused(cluster) { cluster += 1; continue; }
+            self.storage.read(self.offset_from_cluster(cluster)?, &mut head)?;
+            let Some(kind) = CarveKind::detect(&head) else { cluster += 1; continue };
+
+            let start = cluster;
+            let mut data = Vec::new();
+            let mut end = None;
+            while end.is_none() && cluster < limit && unused(cluster) && data.len() < max_len {
+                let at = data.len();
+                data.resize(at + cluster_size, 0);
+                self.storage.read(self.offset_from_cluster(cluster)?, &mut data[at..])?;
+                end = kind.find_end(&data, at.saturating_sub(kind.lookback()));
+                cluster += 1;
+            }
+            data.truncate(end.unwrap_or(max_len));
+            found(Carved { kind, cluster: start, complete: end.is_some(), data });
+            count += 1;
+        }
+        Ok(count)
+    }
+
+    /// Passes the `count` clusters from `first` on to `write`, as they lie on disk whatever
+    /// the FAT says, for data whose directory entry is gone. Adjacent clusters go in pieces
+    /// of up to 64 KiB. Fails, with nothing written, when the run leaves the data region.
+    pub fn dump_clusters(&self, first: u32, count: u32, write: &mut dyn FnMut(&[u8]) -> Result<(), &'static str>) -> Result<(), &'static str> {
+        let end = first.checked_add(count).ok_or(INVALID_CLUSTER)?;
+        if first < 2 || end > self.cluster_limit() { return Err(INVALID_CLUSTER); }
+        if count == 0 { return Ok(()); }
+        let start = self.offset_from_cluster(first)?;
+        let len = count as usize * self.cluster_size();
+        let mut buf = vec![0u8; len.min(DUMP_CHUNK)];
+        for at in (0..len).step_by(buf.len()) {
+            let n = buf.len().min(len - at);
+            self.storage.read(start + at, &mut buf[..n])?;
+            write(&buf[..n])?;
+        }
+        Ok(())
+    }
+
+    /// Writes zeros over every free cluster, so that deleted data can't be recovered and
+    /// the image compresses well. Clusters already zero are only read. Returns the number
+    /// of clusters written; progress is reported in bytes of free space examined.
+    pub fn zero_free(&mut self, progress: &mut dyn ProgressSink) -> Result<usize, &'static str> {
+        let limit = self.cluster_limit();
+        let cluster_size = self.cluster_size();
+        let free: Vec<u32> = (2..limit).filter(|&c| self.is_free(c)).collect();
+        let mut tracker = ProgressTracker::new(progress, (free.len() * cluster_size) as u64);
+        let mut buf = vec![0u8; cluster_size];
+        let mut zeroed = 0;
+        let result = free.into_iter().try_for_each(|cluster| {
+            tracker.file("", cluster_size as u64);
+            let offset = self.offset_from_cluster(cluster)?;
+            self.storage.read(offset, &mut buf)?;
+            if buf.iter().any(|&b| b != 0) {
+                self.storage.fill(offset, cluster_size, 0)?;
+                zeroed += 1;
+            }
+            Ok(())
+        });
+        tracker.finish();
+        result.map(|_| zeroed)
+    }
+
+    /// One flag per cluster, set for those in the chain of a directory or file reachable from the root.
+    pub(super) fn referenced_clusters(&self) -> Result<Vec<bool>, &'static str> {
+        let mut referenced = vec![false; self.cluster_limit() as usize];
+        let mut mark = |chain: Vec<u32>| for cluster in chain { referenced[cluster as usize] = true; };
+        mark(self.cluster_chain(self.boot_sector.root_dir_cluster)?);
+        for item in self.walk("/")? {
+            let (_, _, metadata) = item?;
+            if metadata.first_cluster >= 2 { mark(self.cluster_chain(metadata.first_cluster)?); }
+        }
+        Ok(referenced)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fat32::progress::NoProgress;
+    use crate::fat32::volume::tests::create_mock_volume;
+
+    #[test]
+    fn test_slack() {
+        let mut data = create_mock_volume();
+        let mut volume = Fat32Volume::new(&mut data).unwrap();
+        volume.create_file("a.bin", &[0xAA; 700], false).unwrap();
+        volume.create_file("b.bin", &[0xBB; 512], false).unwrap();
+        let slack = volume.slack("a.bin").unwrap();
+        assert_eq!(slack.bytes.len(), 1024 - 700);
+        // Hide something after the end of the file.
+        volume.storage.write(slack.offset, b"secret").unwrap();
+        assert_eq!(&volume.slack("a.bin").unwrap().bytes[..6], b"secret");
+        assert_eq!(volume.read_file("a.bin").unwrap(), [0xAA; 700]);
+        assert!(volume.slack("b.bin").unwrap().bytes.is_empty());
+    }
+
+    #[test]
+    fn test_carve_deleted_files() {
+        let mut data = create_mock_volume();
+        let mut volume = Fat32Volume::new(&mut data).unwrap();
+        let mut jpeg = vec![0xFF, 0xD8, 0xFF, 0xE0];
+        jpeg.extend((0..1200).map(|i| (i % 200) as u8));
+        jpeg.extend([0xFF, 0xD9]);
+        let mut png = b"\x89PNG\r\n\x1a\n".to_vec();
+        png.extend([0u8; 600]);
+        png.extend(b"\0\0\0\0IEND\xaeB`\x82");
+        volume.create_file("photo.jpg", &jpeg, false).unwrap();
+        volume.create_file("image.png", &png, false).unwrap();
+        volume.create_file("keep.jpg", &jpeg, false).unwrap();
+        volume.remove_file("photo.jpg").unwrap();
+        volume.remove_file("image.png").unwrap();
+
+        let mut carved = Vec::new();
+        assert_eq!(volume.carve(1 << 20, &mut |c| carved.push(c)), Ok(2));
+        assert_eq!((carved[0].kind, carved[0].complete), (CarveKind::Jpeg, true));
+        assert_eq!(carved[0].data, jpeg);
+        assert_eq!(carved[1].data, png);
+
+        // Cut short by the size limit.
+        carved.clear();
+        volume.carve(1000, &mut |c| carved.push(c)).unwrap();
+        assert_eq!((carved[0].complete, carved[0].data.len()), (false, 1000));
+
+        assert_eq!(volume.zero_free(&mut NoProgress), Ok(5));
+        assert_eq!(volume.carve(1 << 20, &mut |_| {}), Ok(0));
+        assert_eq!(volume.read_file("keep.jpg").unwrap(), jpeg);
+        assert_eq!(volume.zero_free(&mut NoProgress), Ok(0));
+    }
+
+    #[test]
+    fn test_dump_clusters() {
+        let mut data = create_mock_volume();
+        let mut volume = Fat32Volume::new(&mut data).unwrap();
+        let content: Vec<u8> = (0..1500).map(|i| i as u8).collect();
+        volume.create_file("a.bin", &content, false).unwrap();
+        let first = volume.file_entry("a.bin").unwrap().first_cluster;
+        volume.remove_file("a.bin").unwrap();
+
+        let mut out = Vec::new();
+        volume.dump_clusters(first, 3, &mut |bytes| { out.extend_from_slice(bytes); Ok(()) }).unwrap();
+        assert_eq!(out.len(), 3 * 512);
+        assert_eq!(out[..1500], content);
+        let limit = volume.cluster_limit();
+        for (first, count) in [(1, 1), (limit - 1, 2), (2, u32::MAX)] {
+            assert_eq!(volume.dump_clusters(first, count, &mut |_| Ok(())), Err(INVALID_CLUSTER));
+        }
+        assert_eq!(volume.dump_clusters(2, 1, &mut |_| Err("plein")), Err("plein"));
+    }
+}
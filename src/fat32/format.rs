@@ -0,0 +1,143 @@
+use super::fat::{FAT_EOC, MIN_CLUSTERS};
+
+const BYTES_PER_SECTOR: usize = 512;
+const RESERVED_SECTORS: u32 = 32;
+const NUMBER_OF_FATS: u32 = 2;
+const FSINFO_SECTOR: usize = 1;
+const BACKUP_BOOT_SECTOR: usize = 6;
+
+/// Parameters of a fresh file system written by `format`.
+#[derive(Debug, Clone, Copy)]
+pub struct FormatOptions {
+    /// Serial number stored in the boot sector, usually derived from the current time.
+    pub volume_id: u32,
+    /// Volume label, space padded.
+    pub label: [u8; 11],
+    /// Forces the cluster size; by default it is picked from the image size like mkfs does.
+    pub sectors_per_cluster: Option<u8>,
+}
+
+impl Default for FormatOptions {
+    fn default() -> Self {
+        FormatOptions { volume_id: 0, label: *b"NO NAME    ", sectors_per_cluster: None }
+    }
+}
+
+/// Cluster size used by Microsoft's formatter for a FAT32 volume of `sectors` sectors.
+fn default_sectors_per_cluster(sectors: u32) -> u8 {
+    match sectors {
+        0..=532_480 => 1,
+        532_481..=16_777_216 => 8,
+        16_777_217..=33_554_432 => 16,
+        33_554_433..=67_108_864 => 32,
+        _ => 64,
+    }
+}
+
+/// Sectors per FAT and data clusters of a volume of `total_sectors` sectors, following
+/// the FAT size formula of the FAT specification (fatgen103), FAT32 variant.
+fn layout(total_sectors: u32, spc: u8) -> (u32, u32) {
+    let divisor = (256 * spc as u32 + NUMBER_OF_FATS) / 2;
+    let fat_sectors = total_sectors.saturating_sub(RESERVED_SECTORS).div_ceil(divisor);
+    let data_sectors = total_sectors.saturating_sub(RESERVED_SECTORS + NUMBER_OF_FATS * fat_sectors);
+    (fat_sectors, data_sectors / spc as u32)
+}
+
+/// Writes an empty FAT32 file system over the whole of `data`: boot sector and its backup,
+/// FSInfo sector, both FATs and an empty root directory in cluster 2. Fails when the volume
+/// would have fewer than `MIN_CLUSTERS` clusters, which is under about 33 MiB.
+pub fn format(data: &mut [u8], options: &FormatOptions) -> Result<(), &'static str> {
+    let total_sectors = u32::try_from(data.len() / BYTES_PER_SECTOR).map_err(|_| "Image trop grande")?;
+    let spc = options.sectors_per_cluster.unwrap_or_else(|| default_sectors_per_cluster(total_sectors));
+    if !spc.is_power_of_two() { return Err("Taille de cluster invalide"); }
+
+    let (fat_sectors, clusters) = layout(total_sectors, spc);
+    if clusters < MIN_CLUSTERS {
+        return Err(if spc == 1 { "Image trop petite" } else { "Clusters trop grands pour l'image" });
+    }
+
+    let system_end = (RESERVED_SECTORS + NUMBER_OF_FATS * fat_sectors) as usize * BYTES_PER_SECTOR;
+    let cluster_bytes = spc as usize * BYTES_PER_SECTOR;
+    data[..system_end + cluster_bytes].fill(0);
+
+    let boot = &mut data[..BYTES_PER_SECTOR];
+    boot[0..3].copy_from_slice(&[0xEB, 0x58, 0x90]);
+    boot[3..11].copy_from_slice(b"MSWIN4.1");
+    boot[11..13].copy_from_slice(&(BYTES_PER_SECTOR as u16).to_le_bytes());
+    boot[13] = spc;
+    boot[14..16].copy_from_slice(&(RESERVED_SECTORS as u16).to_le_bytes());
+    boot[16] = NUMBER_OF_FATS as u8;
+    boot[21] = 0xF8;
+    boot[24..26].copy_from_slice(&32u16.to_le_bytes());
+    boot[26..28].copy_from_slice(&64u16.to_le_bytes());
+    boot[32..36].copy_from_slice(&total_sectors.to_le_bytes());
+    boot[36..40].copy_from_slice(&fat_sectors.to_le_bytes());
+    boot[44..48].copy_from_slice(&2u32.to_le_bytes());
+    boot[48..50].copy_from_slice(&(FSINFO_SECTOR as u16).to_le_bytes());
+    boot[50..52].copy_from_slice(&(BACKUP_BOOT_SECTOR as u16).to_le_bytes());
+    boot[64] = 0x80;
+    boot[66] = 0x29;
+    boot[67..71].copy_from_slice(&options.volume_id.to_le_bytes());
+    boot[71..82].copy_from_slice(&options.label);
+    boot[82..90].copy_from_slice(b"FAT32   ");
+    boot[510] = 0x55;
+    boot[511] = 0xAA;
+
+    // Free count and next free cluster are left "unknown" (0xFFFFFFFF).
+    let fsinfo = &mut data[FSINFO_SECTOR * BYTES_PER_SECTOR..(FSINFO_SECTOR + 1) * BYTES_PER_SECTOR];
+    fsinfo[0..4].copy_from_slice(&0x41615252u32.to_le_bytes());
+    fsinfo[484..488].copy_from_slice(&0x61417272u32.to_le_bytes());
+    fsinfo[488..496].fill(0xFF);
+    fsinfo[508..512].copy_from_slice(&0xAA550000u32.to_le_bytes());
+
+    data.copy_within(0..2 * BYTES_PER_SECTOR, BACKUP_BOOT_SECTOR * BYTES_PER_SECTOR);
+
+    // FAT[0] holds the media descriptor, FAT[1] is reserved and cluster 2 is the root directory.
+    for i in 0..NUMBER_OF_FATS {
+        let fat = (RESERVED_SECTORS + i * fat_sectors) as usize * BYTES_PER_SECTOR;
+        for (j, value) in [0x0FFFFFF8, 0x0FFFFFFF, FAT_EOC].iter().enumerate() {
+            data[fat + j * 4..fat + j * 4 + 4].copy_from_slice(&value.to_le_bytes());
+        }
+    }
+    Ok(())
+}
+
+#[cfg(all(test, feature = "alloc"))]
+mod tests {
+    extern crate alloc;
+    use alloc::vec;
+    use super::*;
+    use crate::fat32::volume::Fat32Volume;
+
+    const MB: usize = 1024 * 1024;
+
+    #[test]
+    fn test_format_and_use() {
+        let mut data = vec![0xAAu8; 34 * MB];
+        let options = FormatOptions { volume_id: 0xCAFE, ..Default::default() };
+        format(&mut data, &options).unwrap();
+        assert_eq!(&data[510..512], &[0x55, 0xAA]);
+        assert_eq!(data[..512], data[6 * 512..7 * 512]);
+
+        let mut volume = Fat32Volume::new(&mut data).unwrap();
+        let spf = volume.boot_sector.sectors_per_fat_32;
+        assert_eq!(spf, 540);
+        assert!(volume.read_dir(2).unwrap().is_empty());
+        volume.create_directory("boot").unwrap();
+        volume.create_file("boot/config.txt", b"arm_64bit=1", false).unwrap();
+        assert_eq!(volume.read_file("/boot/config.txt").unwrap(), b"arm_64bit=1");
+
+        assert_eq!(format(&mut [0u8; 16 * 1024], &options), Err("Image trop petite"));
+    }
+
+    #[test]
+    fn test_too_few_clusters() {
+        // 20 MiB in 512-byte clusters would be about 40,000 clusters: FAT16 territory.
+        assert_eq!(format(&mut vec![0u8; 20 * MB], &FormatOptions::default()), Err("Image trop petite"));
+        let mut data = vec![0u8; 34 * MB];
+        let options = FormatOptions { sectors_per_cluster: Some(2), ..Default::default() };
+        assert_eq!(format(&mut data, &options), Err("Clusters trop grands pour l'image"));
+        format(&mut data, &FormatOptions::default()).unwrap();
+        assert!(Fat32Volume::new(&mut data).unwrap().space().total >= MIN_CLUSTERS as u64 * 512);
+    }
+}
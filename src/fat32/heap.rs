@@ -0,0 +1,91 @@
+//! A global allocator written in Rust over a static arena, for targets without malloc.
+//! The crate itself never installs an allocator: the application picks one with
+//! `#[global_allocator]`, either its own or this one.
+//!
+//! ```ignore
+//! #[global_allocator]
+//! static HEAP: fat32::fat32::heap::FixedHeap<{ 64 * 1024 }> = FixedHeap::new();
+//! ```
+
+use core::alloc::{GlobalAlloc, Layout};
+use core::cell::UnsafeCell;
+use core::mem::MaybeUninit;
+use core::ptr::{self, NonNull};
+
+use linked_list_allocator::LockedHeap;
+
+/// A first-fit heap of `N` bytes, set up on the first allocation.
+pub struct FixedHeap<const N: usize> {
+    heap: LockedHeap,
+    arena: UnsafeCell<[MaybeUninit<u8>; N]>,
+}
+
+// SAFETY: the arena is only handed to the heap, once, while holding its lock.
+unsafe impl<const N: usize> Sync for FixedHeap<N> {}
+
+impl<const N: usize> FixedHeap<N> {
+    pub const fn new() -> Self {
+        FixedHeap { heap: LockedHeap::empty(), arena: UnsafeCell::new([MaybeUninit::uninit(); N]) }
+    }
+
+    /// Bytes currently allocated.
+    pub fn used(&self) -> usize {
+        self.heap.lock().used()
+    }
+
+    /// Bytes still available, possibly fragmented.
+    pub fn free(&self) -> usize {
+        let heap = self.heap.lock();
+        if heap.size() == 0 { N } else { heap.free() }
+    }
+}
+
+impl<const N: usize> Default for FixedHeap<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+unsafe impl<const N: usize> GlobalAlloc for FixedHeap<N> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let mut heap = self.heap.lock();
+        if heap.size() == 0 {
+            // SAFETY: the arena lives as long as `self` and nothing else uses it.
+            heap.init(self.arena.get() as *mut u8, N);
+        }
+        match heap.allocate_first_fit(layout) {
+            Ok(ptr) => ptr.as_ptr(),
+            Err(()) => ptr::null_mut(),
+        }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        if let Some(ptr) = NonNull::new(ptr) {
+            // SAFETY: `ptr` was returned by `alloc` with the same layout.
+            self.heap.lock().deallocate(ptr, layout);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fixed_heap() {
+        static HEAP: FixedHeap<4096> = FixedHeap::new();
+        assert_eq!(HEAP.free(), 4096);
+        let small = Layout::from_size_align(100, 64).unwrap();
+        let big = Layout::from_size_align(8192, 8).unwrap();
+        unsafe {
+            let a = HEAP.alloc(small);
+            assert!(!a.is_null());
+            assert_eq!(a as usize % 64, 0);
+            assert!(HEAP.used() >= 100);
+            assert!(HEAP.alloc(big).is_null());
+            a.write_bytes(0xAB, 100);
+            HEAP.dealloc(a, small);
+        }
+        assert_eq!(HEAP.used(), 0);
+    }
+}
@@ -0,0 +1,184 @@
+//! `embedded-io` implementations, so files of the image can be handed to firmware code
+//! written against those traits, and `BlockDevice`, the 512-byte block view of storage.
+
+use embedded_io::{Error, ErrorKind};
+#[cfg(feature = "alloc")]
+use embedded_io::{ErrorType, Read, Seek, Write};
+
+use super::error::{
+    BLOCK_OUT_OF_RANGE, DIR_NOT_FOUND, DISK_FULL, FILE_NOT_FOUND, FILE_TOO_LARGE, INVALID_BLOCK_SIZE,
+    INVALID_POSITION, NOT_OPEN_FOR_READING, NOT_OPEN_FOR_WRITING,
+};
+#[cfg(feature = "alloc")]
+use super::file::{Fat32File, SeekFrom};
+#[cfg(feature = "alloc")]
+use super::volume::Fat32Volume;
+
+pub const BLOCK_SIZE: usize = 512;
+
+/// Storage addressed in 512-byte blocks, the shape SD cards and most firmware drivers expose.
+/// Buffers must hold a whole number of blocks. `Fat32Volume::from_device` mounts one.
+pub trait BlockDevice {
+    fn read_blocks(&self, start: u64, buf: &mut [u8]) -> Result<(), &'static str>;
+    fn write_blocks(&mut self, start: u64, buf: &[u8]) -> Result<(), &'static str>;
+    fn num_blocks(&self) -> u64;
+}
+
+/// Checks that `len` bytes from block `start` are whole blocks inside a device of `size`
+/// bytes, and returns the byte range they cover.
+fn block_range(start: u64, len: usize, size: usize) -> Result<core::ops::Range<usize>, &'static str> {
+    if !len.is_multiple_of(BLOCK_SIZE) { return Err(INVALID_BLOCK_SIZE); }
+    let begin = usize::try_from(start).ok().and_then(|s| s.checked_mul(BLOCK_SIZE));
+    match begin.and_then(|b| b.checked_add(len).map(|e| b..e)) {
+        Some(range) if range.end <= size => Ok(range),
+        _ => Err(BLOCK_OUT_OF_RANGE),
+    }
+}
+
+/// An image held in memory.
+impl BlockDevice for [u8] {
+    fn read_blocks(&self, start: u64, buf: &mut [u8]) -> Result<(), &'static str> {
+        let range = block_range(start, buf.len(), self.len())?;
+        buf.copy_from_slice(&self[range]);
+        Ok(())
+    }
+
+    fn write_blocks(&mut self, start: u64, buf: &[u8]) -> Result<(), &'static str> {
+        let range = block_range(start, buf.len(), self.len())?;
+        self[range].copy_from_slice(buf);
+        Ok(())
+    }
+
+    fn num_blocks(&self) -> u64 {
+        (self.len() / BLOCK_SIZE) as u64
+    }
+}
+
+/// Raw access to the image under a mounted volume. Writes touching the FATs
+/// refresh the volume's free-cluster map.
+#[cfg(feature = "alloc")]
+impl BlockDevice for Fat32Volume<'_> {
+    fn read_blocks(&self, start: u64, buf: &mut [u8]) -> Result<(), &'static str> {
+        let range = block_range(start, buf.len(), self.storage.len())?;
+        self.storage.read(range.start, buf)
+    }
+
+    fn write_blocks(&mut self, start: u64, buf: &[u8]) -> Result<(), &'static str> {
+        let range = block_range(start, buf.len(), self.storage.len())?;
+        self.storage.write(range.start, buf)?;
+        if range.start < self.boot_sector.data_start() {
+            self.build_free_map()?;
+        }
+        Ok(())
+    }
+
+    fn num_blocks(&self) -> u64 {
+        (self.storage.len() / BLOCK_SIZE) as u64
+    }
+}
+
+/// A library error carried through the `embedded-io` traits. Its kind comes from the
+/// messages named in `error`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IoError(pub &'static str);
+
+impl Error for IoError {
+    fn kind(&self) -> ErrorKind {
+        match self.0 {
+            FILE_NOT_FOUND | DIR_NOT_FOUND => ErrorKind::NotFound,
+            NOT_OPEN_FOR_READING | NOT_OPEN_FOR_WRITING => ErrorKind::PermissionDenied,
+            INVALID_POSITION | INVALID_BLOCK_SIZE | BLOCK_OUT_OF_RANGE => ErrorKind::InvalidInput,
+            DISK_FULL | FILE_TOO_LARGE => ErrorKind::OutOfMemory,
+            _ => ErrorKind::Other,
+        }
+    }
+}
+
+#[cfg(feature = "alloc")]
+impl ErrorType for Fat32File<'_, '_> {
+    type Error = IoError;
+}
+
+#[cfg(feature = "alloc")]
+impl Read for Fat32File<'_, '_> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, IoError> {
+        Fat32File::read(self, buf).map_err(IoError)
+    }
+}
+
+#[cfg(feature = "alloc")]
+impl Write for Fat32File<'_, '_> {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, IoError> {
+        Fat32File::write(self, buf).map_err(IoError)
+    }
+
+    /// Writes go straight to the image, there is nothing to flush.
+    fn flush(&mut self) -> Result<(), IoError> {
+        Ok(())
+    }
+}
+
+#[cfg(feature = "alloc")]
+impl Seek for Fat32File<'_, '_> {
+    fn seek(&mut self, pos: embedded_io::SeekFrom) -> Result<u64, IoError> {
+        let pos = match pos {
+            embedded_io::SeekFrom::Start(n) => SeekFrom::Start(n),
+            embedded_io::SeekFrom::End(n) => SeekFrom::End(n),
+            embedded_io::SeekFrom::Current(n) => SeekFrom::Current(n),
+        };
+        Fat32File::seek(self, pos).map_err(IoError)
+    }
+}
+
+#[cfg(all(test, feature = "alloc"))]
+mod tests {
+    use super::*;
+    use crate::fat32::file::Fat32OpenOptions;
+    use crate::fat32::volume::tests::create_mock_volume;
+
+    /// Firmware-style code that only knows the embedded-io traits.
+    fn read_tail<R: Read + Seek>(reader: &mut R, n: i64, buf: &mut [u8]) -> usize {
+        reader.seek(embedded_io::SeekFrom::End(-n)).unwrap();
+        reader.read(buf).unwrap()
+    }
+
+    #[test]
+    fn test_file_through_embedded_io() {
+        let mut data = create_mock_volume();
+        let mut volume = Fat32Volume::new(&mut data).unwrap();
+
+        let mut file = Fat32OpenOptions::new().read(true).write(true).create(true).open(&mut volume, "fw.bin").unwrap();
+        file.write_all(b"header:payload").unwrap();
+        let mut buf = [0u8; 16];
+        let n = read_tail(&mut file, 7, &mut buf);
+        assert_eq!(&buf[..n], b"payload");
+        drop(file);
+
+        let mut file = Fat32OpenOptions::new().read(true).open(&mut volume, "fw.bin").unwrap();
+        assert_eq!(embedded_io::Write::write(&mut file, b"x").unwrap_err().kind(), ErrorKind::PermissionDenied);
+        assert_eq!(embedded_io::Seek::seek(&mut file, embedded_io::SeekFrom::Current(-1)).unwrap_err().kind(), ErrorKind::InvalidInput);
+        drop(file);
+        let missing = Fat32OpenOptions::new().read(true).open(&mut volume, "missing.bin").err().unwrap();
+        assert_eq!(IoError(missing).kind(), ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn test_block_device() {
+        let mut data = create_mock_volume();
+        let mut volume = Fat32Volume::new(&mut data).unwrap();
+        assert_eq!(volume.num_blocks(), 2048);
+
+        let mut boot = [0u8; BLOCK_SIZE];
+        volume.read_blocks(0, &mut boot).unwrap();
+        assert_eq!(u16::from_le_bytes([boot[11], boot[12]]), 512);
+        assert_eq!(volume.read_blocks(2048, &mut boot), Err(BLOCK_OUT_OF_RANGE));
+        assert_eq!(volume.read_blocks(0, &mut boot[..100]), Err(INVALID_BLOCK_SIZE));
+
+        // Marking cluster 3 used through the raw FAT block is seen by the allocator.
+        let mut fat = [0u8; BLOCK_SIZE];
+        volume.read_blocks(32, &mut fat).unwrap();
+        fat[12..16].copy_from_slice(&0x0FFFFFFFu32.to_le_bytes());
+        volume.write_blocks(32, &fat).unwrap();
+        assert!(!volume.is_free(3));
+    }
+}
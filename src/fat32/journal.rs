@@ -0,0 +1,420 @@
+//! Crash-safe writes. While a volume is journaled its writes are held in memory; `commit`
+//! first records them in a journal area, then applies them in place. A power cut therefore
+//! leaves either the old state, or a committed journal that is replayed on the next
+//! `enable_journal`.
+//!
+//! A journal is a header block (magic, block count, checksum), the home block numbers
+//! of the changed blocks, then their new contents. The header is written last: until it
+//! is there, nothing has been committed.
+
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::ops::Range;
+use log::{debug, warn};
+
+use super::io::BLOCK_SIZE;
+use super::storage::{DynBlockDevice, Storage};
+use super::structs::BootSector;
+use super::volume::Fat32Volume;
+
+const MAGIC: &[u8; 8] = b"FAT32JNL";
+/// Home block numbers held by one descriptor block.
+const PER_DESCRIPTOR: usize = BLOCK_SIZE / 8;
+
+pub type Block = [u8; BLOCK_SIZE];
+
+/// Where committed writes are recorded before being applied.
+pub enum JournalArea<'a> {
+    /// Blocks of the volume itself, see `JournalArea::reserved`.
+    Reserved(Range<u64>),
+    /// A device of its own, such as a sidecar file.
+    Device(&'a mut DynBlockDevice<'a>),
+}
+
+impl JournalArea<'_> {
+    /// The reserved sectors left unused after the FSInfo sector and the backup boot
+    /// sectors. `None` when there are too few for a journal.
+    pub fn reserved(boot_sector: &BootSector) -> Option<Self> {
+        let reserved = boot_sector.reserved_sectors as u64;
+        let mut first = 1;
+        let fs_info = boot_sector.fs_info_sector as u64;
+        if fs_info > 0 && fs_info < reserved { first = first.max(fs_info + 1); }
+        // The backup boot sector is followed by a backup of the FSInfo sector and a spare one.
+        let backup = boot_sector.backup_boot_sector as u64;
+        if backup > 0 && backup < reserved { first = first.max(backup + 3); }
+
+        let bps = boot_sector.bytes_per_sector as u64;
+        let start = (first * bps).div_ceil(BLOCK_SIZE as u64);
+        let end = reserved * bps / BLOCK_SIZE as u64;
+        if end < start + journal_len(1) as u64 { return None; }
+        Some(JournalArea::Reserved(start..end))
+    }
+}
+
+/// Blocks written in place by commits, and the write requests it took to write them.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct WriteCounters {
+    pub blocks: u64,
+    pub writes: u64,
+}
+
+/// Groups sorted block numbers into runs of adjacent blocks, each written in one request.
+pub fn runs(blocks: impl IntoIterator<Item = u64>) -> Vec<Range<u64>> {
+    let mut runs: Vec<Range<u64>> = Vec::new();
+    for block in blocks {
+        match runs.last_mut() {
+            Some(run) if run.end == block => run.end += 1,
+            _ => runs.push(block..block + 1),
+        }
+    }
+    runs
+}
+
+/// Blocks taken by a journal of `count` changed blocks.
+pub fn journal_len(count: usize) -> usize {
+    1 + count.div_ceil(PER_DESCRIPTOR) + count
+}
+
+fn checksum(bytes: &[u8]) -> u32 {
+    bytes.iter().fold(0x811C9DC5u32, |hash, &b| (hash ^ b as u32).wrapping_mul(0x01000193))
+}
+
+/// Lays `blocks` out as a journal. The header is the first block of the result but must
+/// be written last.
+pub fn encode(blocks: &BTreeMap<u64, Box<Block>>) -> Vec<u8> {
+    let count = blocks.len();
+    let mut out = vec![0u8; journal_len(count) * BLOCK_SIZE];
+    let (header, body) = out.split_at_mut(BLOCK_SIZE);
+    let payload = count.div_ceil(PER_DESCRIPTOR) * BLOCK_SIZE;
+    for (i, (index, block)) in blocks.iter().enumerate() {
+        body[i * 8..i * 8 + 8].copy_from_slice(&index.to_le_bytes());
+        body[payload + i * BLOCK_SIZE..payload + (i + 1) * BLOCK_SIZE].copy_from_slice(&block[..]);
+    }
+    header[0..8].copy_from_slice(MAGIC);
+    header[8..12].copy_from_slice(&(count as u32).to_le_bytes());
+    header[12..16].copy_from_slice(&checksum(body).to_le_bytes());
+    out
+}
+
+/// Reads a journal back, `read(block, buf)` giving its blocks from the header on.
+/// Returns `None` when nothing was committed: no header, or a body that doesn't match
+/// the checksum of the header.
+pub fn decode(mut read: impl FnMut(u64, &mut [u8]) -> Result<(), &'static str>) -> Result<Option<BTreeMap<u64, Box<Block>>>, &'static str> {
+    let mut header = [0u8; BLOCK_SIZE];
+    read(0, &mut header)?;
+    if &header[0..8] != MAGIC { return Ok(None); }
+    let [count, expected] = [8, 12].map(|at| u32::from_le_bytes([header[at], header[at + 1], header[at + 2], header[at + 3]]));
+    let count = count as usize;
+    // A damaged header could claim more blocks than the journal holds: check the last one
+    // is there before making room for them all.
+    if read(journal_len(count) as u64 - 1, &mut [0u8; BLOCK_SIZE]).is_err() {
+        warn!("journal header claims {} blocks, more than the journal holds, ignored", count);
+        return Ok(None);
+    }
+    let mut body = vec![0u8; (journal_len(count) - 1) * BLOCK_SIZE];
+    read(1, &mut body)?;
+    if checksum(&body) != expected {
+        warn!("journal of {} blocks is incomplete, ignored", count);
+        return Ok(None);
+    }
+    let payload = count.div_ceil(PER_DESCRIPTOR) * BLOCK_SIZE;
+    let mut blocks = BTreeMap::new();
+    for i in 0..count {
+        let index = u64::from_le_bytes(core::array::from_fn(|b| body[i * 8 + b]));
+        let mut block = Box::new([0u8; BLOCK_SIZE]);
+        block.copy_from_slice(&body[payload + i * BLOCK_SIZE..payload + (i + 1) * BLOCK_SIZE]);
+        blocks.insert(index, block);
+    }
+    Ok(Some(blocks))
+}
+
+/// A storage whose writes are held back until `commit`.
+pub struct Journaled<'a> {
+    pub(super) inner: Storage<'a>,
+    area: JournalArea<'a>,
+    pending: BTreeMap<u64, Box<Block>>,
+    counters: WriteCounters,
+}
+
+impl<'a> Journaled<'a> {
+    fn area_blocks(&self) -> u64 {
+        match &self.area {
+            JournalArea::Reserved(range) => range.end - range.start,
+            JournalArea::Device(device) => device.num_blocks(),
+        }
+    }
+
+    fn read_area(&self, block: u64, buf: &mut [u8]) -> Result<(), &'static str> {
+        match &self.area {
+            JournalArea::Reserved(range) if range.start + block + buf.len().div_ceil(BLOCK_SIZE) as u64 <= range.end => {
+                self.inner.read((range.start + block) as usize * BLOCK_SIZE, buf)
+            }
+            JournalArea::Reserved(_) => Err("Bloc hors du journal"),
+            JournalArea::Device(device) => device.read_blocks(block, buf),
+        }
+    }
+
+    fn write_area(&mut self, block: u64, buf: &[u8]) -> Result<(), &'static str> {
+        match &mut self.area {
+            JournalArea::Reserved(range) => self.inner.write((range.start + block) as usize * BLOCK_SIZE, buf),
+            JournalArea::Device(device) => device.write_blocks(block, buf),
+        }
+    }
+
+    /// True when a block of `range` (in bytes) has a pending write.
+    pub(super) fn touches(&self, range: &Range<usize>) -> bool {
+        if range.is_empty() { return false; }
+        let first = (range.start / BLOCK_SIZE) as u64;
+        let last = ((range.end - 1) / BLOCK_SIZE) as u64;
+        self.pending.range(first..=last).next().is_some()
+    }
+
+    /// Loads block `index` as it currently reads, pending writes included.
+    fn block(&self, index: u64) -> Result<Box<Block>, &'static str> {
+        if let Some(block) = self.pending.get(&index) { return Ok(block.clone()); }
+        let mut block = Box::new([0u8; BLOCK_SIZE]);
+        let start = index as usize * BLOCK_SIZE;
+        let len = BLOCK_SIZE.min(self.inner.len().saturating_sub(start));
+        self.inner.read(start, &mut block[..len])?;
+        Ok(block)
+    }
+
+    pub(super) fn read(&self, offset: usize, buf: &mut [u8]) -> Result<(), &'static str> {
+        let mut done = 0;
+        while done < buf.len() {
+            let at = offset + done;
+            let (index, skip) = ((at / BLOCK_SIZE) as u64, at % BLOCK_SIZE);
+            let len = (BLOCK_SIZE - skip).min(buf.len() - done);
+            match self.pending.get(&index) {
+                Some(block) => buf[done..done + len].copy_from_slice(&block[skip..skip + len]),
+                None => self.inner.read(at, &mut buf[done..done + len])?,
+            }
+            done += len;
+        }
+        Ok(())
+    }
+
+    pub(super) fn write(&mut self, offset: usize, buf: &[u8]) -> Result<(), &'static str> {
+        let mut done = 0;
+        while done < buf.len() {
+            let at = offset + done;
+            let (index, skip) = ((at / BLOCK_SIZE) as u64, at % BLOCK_SIZE);
+            let len = (BLOCK_SIZE - skip).min(buf.len() - done);
+            if !self.pending.contains_key(&index) && journal_len(self.pending.len() + 1) as u64 > self.area_blocks() {
+                return Err("Journal plein");
+            }
+            let mut block = self.block(index)?;
+            block[skip..skip + len].copy_from_slice(&buf[done..done + len]);
+            self.pending.insert(index, block);
+            done += len;
+        }
+        Ok(())
+    }
+
+    /// Records the pending writes in the journal area, applies them, then clears the
+    /// journal. Returns the number of blocks written.
+    fn commit(&mut self) -> Result<usize, &'static str> {
+        if self.pending.is_empty() { return Ok(0); }
+        let journal = encode(&self.pending);
+        self.write_area(1, &journal[BLOCK_SIZE..])?;
+        self.write_area(0, &journal[..BLOCK_SIZE])?;
+        let count = self.apply()?;
+        debug!("journal: committed {} blocks", count);
+        Ok(count)
+    }
+
+    /// Writes the pending blocks in place, adjacent ones together, and clears the journal header.
+    fn apply(&mut self) -> Result<usize, &'static str> {
+        let pending = core::mem::take(&mut self.pending);
+        let mut bytes = Vec::new();
+        for run in runs(pending.keys().copied()) {
+            bytes.clear();
+            for index in run.clone() { bytes.extend_from_slice(&pending[&index][..]); }
+            self.inner.write(run.start as usize * BLOCK_SIZE, &bytes)?;
+            self.counters.blocks += run.end - run.start;
+            self.counters.writes += 1;
+        }
+        self.write_area(0, &[0u8; BLOCK_SIZE])?;
+        Ok(pending.len())
+    }
+
+    /// Applies a journal committed but not applied before a crash. Returns its block count.
+    fn replay(&mut self) -> Result<usize, &'static str> {
+        let blocks = match decode(|block, buf| self.read_area(block, buf))? {
+            Some(blocks) => blocks,
+            None => return Ok(0),
+        };
+        self.pending = blocks;
+        let count = self.apply()?;
+        warn!("journal: replayed {} blocks left by an interrupted commit", count);
+        Ok(count)
+    }
+}
+
+impl<'a> Fat32Volume<'a> {
+    /// Holds every write back until `commit`, recording it in `area` first. A journal left
+    /// committed by an interrupted session is replayed; the number of blocks it restored is
+    /// returned. Writes not committed when the volume is dropped are lost.
+    pub fn enable_journal(&mut self, area: JournalArea<'a>) -> Result<usize, &'static str> {
+        if self.is_journaled() { return Err("Journal déjà actif"); }
+        let inner = core::mem::replace(&mut *self.storage, Storage::Memory(&mut []));
+        let mut journaled = Journaled { inner, area, pending: BTreeMap::new(), counters: WriteCounters::default() };
+        let replayed = journaled.replay();
+        *self.storage = Storage::Journaled(Box::new(journaled));
+        let replayed = replayed?;
+        if replayed > 0 {
+            let mut sector = [0u8; BLOCK_SIZE];
+            self.storage.read(0, &mut sector)?;
+            self.boot_sector = BootSector::parse(&sector)?;
+            self.build_free_map()?;
+        }
+        Ok(replayed)
+    }
+
+    pub fn is_journaled(&self) -> bool {
+        matches!(*self.storage, Storage::Journaled(_))
+    }
+
+    /// Blocks changed since the last commit.
+    pub fn pending_blocks(&self) -> usize {
+        match &*self.storage {
+            Storage::Journaled(journaled) => journaled.pending.len(),
+            _ => 0,
+        }
+    }
+
+    /// What the commits of this journal have written so far.
+    pub fn write_counters(&self) -> WriteCounters {
+        match &*self.storage {
+            Storage::Journaled(journaled) => journaled.counters,
+            _ => WriteCounters::default(),
+        }
+    }
+
+    /// Makes the writes since the last commit durable, all of them or none.
+    pub fn commit(&mut self) -> Result<usize, &'static str> {
+        match &mut *self.storage {
+            Storage::Journaled(journaled) => journaled.commit(),
+            _ => Ok(0),
+        }
+    }
+
+    /// Drops the writes since the last commit. Returns the number of blocks dropped.
+    pub fn discard(&mut self) -> Result<usize, &'static str> {
+        let dropped = match &mut *self.storage {
+            Storage::Journaled(journaled) => core::mem::take(&mut journaled.pending).len(),
+            _ => return Ok(0),
+        };
+        // Allocations made since the commit are gone with them.
+        self.build_free_map()?;
+        Ok(dropped)
+    }
+
+    /// Commits and goes back to writing in place.
+    pub fn disable_journal(&mut self) -> Result<(), &'static str> {
+        self.commit()?;
+        if let Storage::Journaled(journaled) = core::mem::replace(&mut *self.storage, Storage::Memory(&mut [])) {
+            *self.storage = journaled.inner;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fat32::error::FILE_NOT_FOUND;
+    use crate::fat32::volume::tests::create_mock_volume;
+
+    fn journaled(data: &mut [u8]) -> Fat32Volume<'_> {
+        let mut volume = Fat32Volume::new(data).unwrap();
+        let area = JournalArea::reserved(&volume.boot_sector).unwrap();
+        volume.enable_journal(area).unwrap();
+        volume
+    }
+
+    #[test]
+    fn test_commit_and_discard() {
+        let mut data = create_mock_volume();
+        let pristine = data.clone();
+        {
+            let mut volume = journaled(&mut data);
+            volume.create_file("a.txt", b"first", false).unwrap();
+            assert_eq!(volume.read_file("a.txt").unwrap(), b"first");
+            assert!(volume.pending_blocks() > 0);
+            assert!(volume.discard().unwrap() > 0);
+            assert_eq!(volume.read_file("a.txt"), Err(FILE_NOT_FOUND));
+        }
+        assert_eq!(data, pristine);
+        {
+            let mut volume = journaled(&mut data);
+            volume.create_file("a.txt", b"second", false).unwrap();
+            volume.commit().unwrap();
+            volume.create_file("b.txt", b"lost", false).unwrap();
+        }
+        let volume = Fat32Volume::new(&mut data).unwrap();
+        assert_eq!(volume.read_file("a.txt").unwrap(), b"second");
+        assert_eq!(volume.read_file("b.txt"), Err(FILE_NOT_FOUND));
+        // The journal header is cleared once applied.
+        assert!(data[512..520] != *MAGIC);
+    }
+
+    #[test]
+    fn test_commit_coalesces_writes() {
+        assert_eq!(runs([3, 4, 5, 9, 11, 12]), [3..6, 9..10, 11..13]);
+        assert!(runs([]).is_empty());
+
+        let mut data = create_mock_volume();
+        let mut volume = journaled(&mut data);
+        volume.create_file("big.bin", &[1u8; 8 * 1024], false).unwrap();
+        let pending = volume.pending_blocks() as u64;
+        volume.commit().unwrap();
+        let counters = volume.write_counters();
+        assert_eq!(counters.blocks, pending);
+        // FATs, directory and data each take a single write or so.
+        assert!(counters.writes <= 5, "{:?}", counters);
+    }
+
+    #[test]
+    fn test_replay_after_crash() {
+        let mut data = create_mock_volume();
+        let mut torn = data.clone();
+        {
+            let mut volume = journaled(&mut data);
+            volume.create_file("crash.txt", b"survives", false).unwrap();
+            // Power cut right after the journal was written, before anything was applied.
+            let Storage::Journaled(journaled) = &mut *volume.storage else { unreachable!() };
+            let journal = encode(&journaled.pending);
+            journaled.pending.clear();
+            journaled.write_area(1, &journal[BLOCK_SIZE..]).unwrap();
+            journaled.write_area(0, &journal[..BLOCK_SIZE]).unwrap();
+        }
+        torn[512..512 * 32].copy_from_slice(&data[512..512 * 32]);
+        assert_eq!(Fat32Volume::new(&mut data).unwrap().read_file("crash.txt"), Err(FILE_NOT_FOUND));
+        let mut volume = Fat32Volume::new(&mut data).unwrap();
+        let area = JournalArea::reserved(&volume.boot_sector).unwrap();
+        assert!(volume.enable_journal(area).unwrap() > 0);
+        assert_eq!(volume.read_file("crash.txt").unwrap(), b"survives");
+
+        // A journal whose last block didn't make it is not replayed.
+        torn[3 * 512] ^= 0xFF;
+        let mut volume = Fat32Volume::new(&mut torn).unwrap();
+        let area = JournalArea::reserved(&volume.boot_sector).unwrap();
+        assert_eq!(volume.enable_journal(area), Ok(0));
+        assert_eq!(volume.read_file("crash.txt"), Err(FILE_NOT_FOUND));
+    }
+
+    #[test]
+    fn test_journal_full() {
+        let mut data = create_mock_volume();
+        let mut volume = journaled(&mut data);
+        assert_eq!(volume.create_file("big.bin", &[7u8; 64 * 1024], false), Err("Journal plein"));
+        volume.discard().unwrap();
+        volume.create_file("small.bin", &[7u8; 1024], false).unwrap();
+        volume.disable_journal().unwrap();
+        assert!(!volume.is_journaled());
+        assert_eq!(volume.read_file("small.bin").unwrap(), [7u8; 1024]);
+    }
+}
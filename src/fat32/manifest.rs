@@ -0,0 +1,305 @@
+//! Integrity manifests: every file of a volume with its size, modification time and
+//! SHA-256, as JSON, to check later that an image still holds exactly the same files.
+//!
+//! ```text
+//! {
+//!   "files": [
+//!     {"path": "boot/config.txt", "size": 1544, "mtime": "2024-03-15T13:45:30", "sha256": "…"}
+//!   ]
+//! }
+//! ```
+//!
+//! Paths are relative to the root, without a leading `/`. FAT keeps no time zone, so
+//! neither does `mtime`.
+
+extern crate alloc;
+use alloc::collections::BTreeMap;
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt::Write;
+
+use super::snapshot::{Snapshot, SnapshotEntry};
+use super::time::DateTime;
+use super::volume::Fat32Volume;
+
+const INVALID: &str = "Manifeste invalide";
+
+impl Snapshot {
+    /// The files of the snapshot as a JSON manifest, one per line, by path.
+    pub fn to_manifest(&self) -> String {
+        let files: Vec<String> = self.entries.iter().filter(|(_, e)| !e.is_dir).map(|(path, entry)| {
+            let m = &entry.modified;
+            format!(
+                "    {{\"path\": {}, \"size\": {}, \"mtime\": \"{:04}-{:02}-{:02}T{:02}:{:02}:{:02}\", \"sha256\": \"{}\"}}",
+                json_string(path), entry.size, m.year, m.month, m.day, m.hour, m.minute, m.second, entry.hash
+            )
+        }).collect();
+        format!("{{\n  \"files\": [\n{}\n  ]\n}}\n", files.join(",\n"))
+    }
+
+    /// Reads back what `to_manifest` wrote. Keys may come in any order and unknown ones
+    /// are skipped, so a manifest edited or made by another tool still reads.
+    pub fn from_manifest(text: &str) -> Result<Self, &'static str> {
+        let mut parser = Parser { text: text.as_bytes(), at: 0, depth: 0 };
+        let root = parser.value()?;
+        parser.skip_spaces();
+        if parser.at != parser.text.len() { return Err(INVALID); }
+        let Some(Json::Array(files)) = root.get("files") else { return Err(INVALID) };
+        let mut entries = BTreeMap::new();
+        for file in files {
+            let (Some(Json::String(path)), Some(Json::Number(size)), Some(Json::String(mtime)), Some(Json::String(hash))) =
+                (file.get("path"), file.get("size"), file.get("mtime"), file.get("sha256")) else { return Err(INVALID) };
+            let size = u32::try_from(*size).map_err(|_| INVALID)?;
+            let entry = SnapshotEntry { is_dir: false, size, modified: parse_time(mtime)?, hash: hash.clone() };
+            entries.insert(path.trim_start_matches('/').into(), entry);
+        }
+        Ok(Snapshot { entries })
+    }
+}
+
+impl<'a> Fat32Volume<'a> {
+    /// Every file of the volume with its size, modification time and SHA-256; compare two
+    /// with `Snapshot::diff`.
+    pub fn manifest(&self) -> Result<Snapshot, &'static str> {
+        let mut snapshot = self.snapshot()?;
+        snapshot.entries.retain(|_, entry| !entry.is_dir);
+        Ok(snapshot)
+    }
+}
+
+/// `YYYY-MM-DDThh:mm:ss`.
+fn parse_time(text: &str) -> Result<DateTime, &'static str> {
+    let bytes = text.as_bytes();
+    if bytes.len() != 19 || bytes[4] != b'-' || bytes[7] != b'-' || bytes[10] != b'T' || bytes[13] != b':' || bytes[16] != b':' {
+        return Err(INVALID);
+    }
+    let field = |at: usize, len: usize| text[at..at + len].parse::<u16>().map_err(|_| INVALID);
+    Ok(DateTime {
+        year: field(0, 4)?,
+        month: field(5, 2)? as u8,
+        day: field(8, 2)? as u8,
+        hour: field(11, 2)? as u8,
+        minute: field(14, 2)? as u8,
+        second: field(17, 2)? as u8,
+    })
+}
+
+fn json_string(text: &str) -> String {
+    let mut out = String::from("\"");
+    for c in text.chars() {
+        match c {
+            '"' => out += "\\\"",
+            '\\' => out += "\\\\",
+            c if (c as u32) < 0x20 => { let _ = write!(out, "\\u{:04x}", c as u32); }
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+/// The JSON values a manifest can hold. Numbers are whole and not negative.
+enum Json {
+    Null,
+    Bool,
+    Number(u64),
+    String(String),
+    Array(Vec<Json>),
+    Object(Vec<(String, Json)>),
+}
+
+impl Json {
+    fn get(&self, key: &str) -> Option<&Json> {
+        match self {
+            Json::Object(members) => members.iter().find(|(k, _)| k == key).map(|(_, v)| v),
+            _ => None,
+        }
+    }
+}
+
+/// Objects and arrays may nest this deep, more than a manifest needs, so that a damaged
+/// file can't exhaust the stack.
+const MAX_DEPTH: usize = 32;
+
+struct Parser<'t> {
+    text: &'t [u8],
+    at: usize,
+    depth: usize,
+}
+
+impl Parser<'_> {
+    fn skip_spaces(&mut self) {
+        while self.text.get(self.at).is_some_and(|b| b.is_ascii_whitespace()) { self.at += 1; }
+    }
+
+    fn next(&mut self) -> Result<u8, &'static str> {
+        let b = *self.text.get(self.at).ok_or(INVALID)?;
+        self.at += 1;
+        Ok(b)
+    }
+
+    fn expect(&mut self, word: &[u8]) -> Result<(), &'static str> {
+        if !self.text[self.at..].starts_with(word) { return Err(INVALID); }
+        self.at += word.len();
+        Ok(())
+    }
+
+    fn value(&mut self) -> Result<Json, &'static str> {
+        self.skip_spaces();
+        if self.depth > MAX_DEPTH { return Err(INVALID); }
+        self.depth += 1;
+        let value = self.nested();
+        self.depth -= 1;
+        value
+    }
+
+    fn nested(&mut self) -> Result<Json, &'static str> {
+        match self.text.get(self.at).ok_or(INVALID)? {
+            b'{' => {
+                self.at += 1;
+                let mut members = Vec::new();
+                self.skip_spaces();
+                if self.text.get(self.at) == Some(&b'}') { self.at += 1; return Ok(Json::Object(members)); }
+                loop {
+                    self.skip_spaces();
+                    if self.next()? != b'"' { return Err(INVALID); }
+                    let key = self.string()?;
+                    self.skip_spaces();
+                    if self.next()? != b':' { return Err(INVALID); }
+                    members.push((key, self.value()?));
+                    self.skip_spaces();
+                    match self.next()? {
+                        b',' => continue,
+                        b'}' => return Ok(Json::Object(members)),
+                        _ => return Err(INVALID),
+                    }
+                }
+            }
+            b'[' => {
+                self.at += 1;
+                let mut items = Vec::new();
+                self.skip_spaces();
+                if self.text.get(self.at) == Some(&b']') { self.at += 1; return Ok(Json::Array(items)); }
+                loop {
+                    items.push(self.value()?);
+                    self.skip_spaces();
+                    match self.next()? {
+                        b',' => continue,
+                        b']' => return Ok(Json::Array(items)),
+                        _ => return Err(INVALID),
+                    }
+                }
+            }
+            b'"' => {
+                self.at += 1;
+                Ok(Json::String(self.string()?))
+            }
+            b'0'..=b'9' => {
+                let start = self.at;
+                while self.text.get(self.at).is_some_and(u8::is_ascii_digit) { self.at += 1; }
+                let digits = core::str::from_utf8(&self.text[start..self.at]).map_err(|_| INVALID)?;
+                Ok(Json::Number(digits.parse().map_err(|_| INVALID)?))
+            }
+            b't' => self.expect(b"true").map(|_| Json::Bool),
+            b'f' => self.expect(b"false").map(|_| Json::Bool),
+            b'n' => self.expect(b"null").map(|_| Json::Null),
+            _ => Err(INVALID),
+        }
+    }
+
+    /// The rest of a string whose opening quote was read.
+    fn string(&mut self) -> Result<String, &'static str> {
+        let mut bytes = Vec::new();
+        loop {
+            match self.next()? {
+                b'"' => return String::from_utf8(bytes).map_err(|_| INVALID),
+                b'\\' => {
+                    let c = match self.next()? {
+                        b'"' => '"',
+                        b'\\' => '\\',
+                        b'/' => '/',
+                        b'b' => '\u{8}',
+                        b'f' => '\u{c}',
+                        b'n' => '\n',
+                        b'r' => '\r',
+                        b't' => '\t',
+                        b'u' => {
+                            let unit = self.hex4()?;
+                            let code = if (0xD800..0xDC00).contains(&unit) {
+                                self.expect(b"\\u")?;
+                                let low = self.hex4()?;
+                                if !(0xDC00..0xE000).contains(&low) { return Err(INVALID); }
+                                0x10000 + ((unit - 0xD800) << 10) + (low - 0xDC00)
+                            } else {
+                                unit
+                            };
+                            char::from_u32(code).ok_or(INVALID)?
+                        }
+                        _ => return Err(INVALID),
+                    };
+                    bytes.extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes());
+                }
+                b => bytes.push(b),
+            }
+        }
+    }
+
+    fn hex4(&mut self) -> Result<u32, &'static str> {
+        let digits = self.text.get(self.at..self.at + 4).ok_or(INVALID)?;
+        self.at += 4;
+        u32::from_str_radix(core::str::from_utf8(digits).map_err(|_| INVALID)?, 16).map_err(|_| INVALID)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fat32::snapshot::Change;
+    use crate::fat32::volume::tests::create_mock_volume;
+
+    #[test]
+    fn test_manifest_round_trip_and_verify() {
+        let mut data = create_mock_volume();
+        let mut volume = Fat32Volume::new(&mut data).unwrap();
+        volume.create_directory("boot").unwrap();
+        volume.create_file("boot/config été.txt", b"arm_64bit=1\n", false).unwrap();
+        volume.create_file("kernel.img", &[7u8; 3000], false).unwrap();
+        let golden = volume.manifest().unwrap();
+        assert_eq!(golden.entries.len(), 2);
+        let json = golden.to_manifest();
+        assert!(json.contains("\"path\": \"boot/config été.txt\", \"size\": 12"));
+        assert_eq!(Snapshot::from_manifest(&json).unwrap(), golden);
+
+        volume.create_file("kernel.img", &[8u8; 3000], true).unwrap();
+        volume.create_file("extra.bin", b"", false).unwrap();
+        volume.remove_file("boot/config été.txt").unwrap();
+        assert_eq!(golden.diff(&volume.manifest().unwrap()), [
+            ("boot/config été.txt".into(), Change::Removed),
+            ("extra.bin".into(), Change::Added),
+            ("kernel.img".into(), Change::Modified { content: true, size: false, time: false }),
+        ]);
+    }
+
+    #[test]
+    fn test_manifest_parsing() {
+        let text = r#"{"tool": {"name": "mkimage", "ok": true, "v": null}, "files": [
+            {"sha256": "ab", "mtime": "2024-03-15T13:45:30", "size": 5, "path": "/déjà vu.txt"}
+        ]}"#;
+        let manifest = Snapshot::from_manifest(text).unwrap();
+        let entry = &manifest.entries["déjà vu.txt"];
+        assert_eq!((entry.size, entry.hash.as_str()), (5, "ab"));
+        assert_eq!(entry.modified, DateTime { year: 2024, month: 3, day: 15, hour: 13, minute: 45, second: 30 });
+        assert_eq!(Snapshot::from_manifest("{\"files\": []}").unwrap().entries.len(), 0);
+        assert_eq!(json_string("a\"b\\c\n"), "\"a\\\"b\\\\c\\u000a\"");
+        let escaped = r#"{"files": [{"path": "a\"\u00e9\ud83d\ude00", "size": 0, "mtime": "1980-01-01T00:00:00", "sha256": ""}]}"#;
+        assert!(Snapshot::from_manifest(escaped).unwrap().entries.contains_key("a\"é😀"));
+
+        for bad in ["", "{\"files\": [}", "{\"files\": []} x", "{\"files\": [{\"path\": \"a\"}]}", "{\"files\": 3}",
+            "{\"files\": [{\"path\": \"a\", \"size\": 99999999999, \"mtime\": \"2024-03-15T13:45:30\", \"sha256\": \"\"}]}",
+            "{\"files\": [{\"path\": \"a\", \"size\": 1, \"mtime\": \"yesterday\", \"sha256\": \"\"}]}",
+            &"[".repeat(100_000)] {
+            assert_eq!(Snapshot::from_manifest(bad), Err(INVALID), "{}", bad);
+        }
+    }
+}
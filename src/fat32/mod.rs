@@ -1,2 +1,57 @@
 pub mod structs;
-pub mod volume;
\ No newline at end of file
+pub mod error;
+pub mod bpb;
+#[cfg(feature = "alloc")]
+pub mod volume;
+pub mod fat;
+#[cfg(feature = "alloc")]
+pub mod dir;
+pub mod name;
+pub mod codepage;
+#[cfg(feature = "alloc")]
+pub mod path;
+pub mod time;
+#[cfg(feature = "alloc")]
+pub mod extract;
+#[cfg(feature = "alloc")]
+pub mod tar;
+#[cfg(feature = "alloc")]
+pub mod checksum;
+#[cfg(feature = "alloc")]
+pub mod compare;
+#[cfg(feature = "alloc")]
+pub mod file;
+#[cfg(feature = "alloc")]
+pub mod walk;
+#[cfg(feature = "alloc")]
+pub mod defrag;
+#[cfg(feature = "alloc")]
+pub mod resize;
+#[cfg(feature = "alloc")]
+pub mod recluster;
+#[cfg(feature = "alloc")]
+pub mod snapshot;
+#[cfg(feature = "alloc")]
+pub mod manifest;
+#[cfg(feature = "alloc")]
+pub mod forensic;
+#[cfg(feature = "alloc")]
+pub mod text;
+pub mod format;
+pub mod disk;
+pub mod progress;
+pub mod io;
+pub mod storage;
+#[cfg(feature = "alloc")]
+pub mod journal;
+#[cfg(all(test, feature = "alloc"))]
+pub mod faulty;
+#[cfg(all(feature = "alloc", any(test, feature = "testutil")))]
+pub mod testutil;
+pub mod fixed;
+#[cfg(feature = "heap")]
+pub mod heap;
+#[cfg(feature = "sdmmc")]
+pub mod sdmmc;
+#[cfg(feature = "sdcard")]
+pub mod sdcard;
@@ -0,0 +1,311 @@
+#[cfg(feature = "alloc")]
+use alloc::{format, string::String, vec::Vec};
+use core::fmt::Write;
+
+use super::codepage::Codepage;
+
+/// Number of UTF-16 characters stored in one long-name entry.
+pub const LFN_CHARS_PER_ENTRY: usize = 13;
+/// Byte offsets of the 13 characters inside a long-name entry.
+const LFN_CHAR_OFFSETS: [usize; LFN_CHARS_PER_ENTRY] = [1, 3, 5, 7, 9, 14, 16, 18, 20, 22, 24, 28, 30];
+/// Marks the long-name entry holding the last part of the name (stored first on disk).
+pub const LFN_LAST_ENTRY: u8 = 0x40;
+
+/// NT case flags (byte 12 of an entry): the base name / extension are displayed lowercase.
+pub const CASE_LOWER_BASE: u8 = 0x08;
+pub const CASE_LOWER_EXT: u8 = 0x10;
+
+/// Renders a raw 8.3 field as `NAME.EXT`, decoding non-ASCII bytes with `codepage` and
+/// lowercasing the parts selected by the NT `case_flags`.
+#[cfg(feature = "alloc")]
+pub fn format_name(raw: &[u8; 11], codepage: Codepage, case_flags: u8) -> String {
+    let mut name = String::new();
+    let _ = write_short_name(raw, codepage, case_flags, &mut name);
+    name
+}
+
+/// Writes the rendering of `format_name` to `out`, without allocating.
+pub fn write_short_name(raw: &[u8; 11], codepage: Codepage, case_flags: u8, out: &mut impl Write) -> core::fmt::Result {
+    let mut base = [0u8; 8];
+    base.copy_from_slice(&raw[0..8]);
+    // 0x05 stands for a real 0xE5 first byte, which would otherwise mean "deleted".
+    if base[0] == 0x05 { base[0] = 0xE5; }
+
+    let decode = |bytes: &[u8], lower: bool| -> ([char; 8], usize) {
+        let mut chars = [' '; 8];
+        for (c, &b) in chars.iter_mut().zip(bytes) {
+            let decoded = codepage.decode(b);
+            *c = if lower { decoded.to_ascii_lowercase() } else { decoded };
+        }
+        let len = chars[..bytes.len()].iter().rposition(|c| !c.is_whitespace()).map_or(0, |i| i + 1);
+        (chars, len)
+    };
+    let (name, name_len) = decode(&base, case_flags & CASE_LOWER_BASE != 0);
+    let (ext, ext_len) = decode(&raw[8..11], case_flags & CASE_LOWER_EXT != 0);
+    for &c in &name[..name_len] { out.write_char(c)?; }
+    if ext_len > 0 {
+        out.write_char('.')?;
+        for &c in &ext[..ext_len] { out.write_char(c)?; }
+    }
+    Ok(())
+}
+
+/// Returns the NT case flags that make `short_name` render exactly as `long_name`, or
+/// `None` when no combination does and a long name has to be stored.
+#[cfg(feature = "alloc")]
+pub fn case_flags_for(long_name: &str, short_name: &[u8; 11], codepage: Codepage) -> Option<u8> {
+    [0, CASE_LOWER_BASE, CASE_LOWER_EXT, CASE_LOWER_BASE | CASE_LOWER_EXT]
+        .into_iter()
+        .find(|&flags| format_name(short_name, codepage, flags) == long_name)
+}
+
+/// Characters allowed in a short name besides letters and digits.
+#[cfg(feature = "alloc")]
+fn is_valid_short_char(c: char) -> bool {
+    c.is_ascii_uppercase() || c.is_ascii_digit() || "$%'-_@~`!(){}^#&".contains(c)
+}
+
+/// True when `name` can be stored as a long name.
+pub fn is_valid_long_name(name: &str) -> bool {
+    let trimmed = name.trim_end_matches(['.', ' ']);
+    !trimmed.is_empty()
+        && name.encode_utf16().count() <= 255
+        && name != "." && name != ".."
+        && !name.chars().any(|c| (c as u32) < 0x20 || "\"*/:<>?\\|".contains(c))
+}
+
+/// Converts one part of a long name (base or extension) to short-name bytes, using
+/// `codepage` for non-ASCII characters. Returns the bytes and whether information was lost.
+#[cfg(feature = "alloc")]
+fn convert_part(part: &str, max: usize, codepage: Codepage) -> (Vec<u8>, bool) {
+    let mut out = Vec::new();
+    let mut lossy = false;
+    for c in part.chars() {
+        if c == ' ' || c == '.' { lossy = true; continue; }
+        let mut upper_chars = c.to_uppercase();
+        let upper = match (upper_chars.next(), upper_chars.next()) {
+            (Some(u), None) => u,
+            _ => c,
+        };
+        let encoded = if upper.is_ascii() {
+            Some(upper as u8).filter(|_| is_valid_short_char(upper))
+        } else {
+            codepage.encode(upper)
+        };
+        let byte = match encoded { Some(b) => b, None => { lossy = true; b'_' } };
+        if out.len() == max { lossy = true; break; }
+        out.push(byte);
+    }
+    (out, lossy)
+}
+
+/// Builds the 8.3 short name for `long_name` following the VFAT basis-name algorithm:
+/// illegal characters become `_`, spaces and extra dots are dropped, and a `~N` numeric
+/// tail is appended when the conversion lost information or the name is already `taken`.
+#[cfg(feature = "alloc")]
+pub fn generate_short_name(long_name: &str, taken: &[[u8; 11]], codepage: Codepage) -> [u8; 11] {
+    let stripped = long_name.trim_start_matches('.');
+    let (base, ext) = match stripped.rfind('.') {
+        Some(dot) => (&stripped[..dot], &stripped[dot + 1..]),
+        None => (stripped, ""),
+    };
+
+    let (base, base_lossy) = convert_part(base, 8, codepage);
+    let (ext, ext_lossy) = convert_part(ext, 3, codepage);
+    let lossy = base_lossy || ext_lossy || stripped.len() != long_name.len();
+
+    let mut field = [0x20u8; 11];
+    field[8..8 + ext.len()].copy_from_slice(&ext);
+    let mut base: Vec<u8> = if base.is_empty() { b"_".to_vec() } else { base };
+    // A leading 0xE5 is stored as 0x05 so the entry doesn't read as deleted.
+    if base[0] == 0xE5 { base[0] = 0x05; }
+
+    if !lossy {
+        field[..base.len()].copy_from_slice(&base);
+        if !taken.contains(&field) { return field; }
+    }
+
+    for n in 1..1_000_000u32 {
+        let tail = format!("~{}", n);
+        let keep = base.len().min(8 - tail.len());
+        field[..8].fill(0x20);
+        field[..keep].copy_from_slice(&base[..keep]);
+        field[keep..keep + tail.len()].copy_from_slice(tail.as_bytes());
+        if !taken.contains(&field) { break; }
+    }
+    field
+}
+
+/// Checksum of the short name that every long-name entry of the same file stores.
+pub fn lfn_checksum(short_name: &[u8; 11]) -> u8 {
+    short_name.iter().fold(0u8, |sum, &b| (sum >> 1).wrapping_add(sum << 7).wrapping_add(b))
+}
+
+/// Builds the long-name entries for `long_name`, in on-disk order (last part first).
+#[cfg(feature = "alloc")]
+pub fn encode_lfn(long_name: &str, short_name: &[u8; 11]) -> Vec<[u8; 32]> {
+    let mut units: Vec<u16> = long_name.encode_utf16().collect();
+    if !units.len().is_multiple_of(LFN_CHARS_PER_ENTRY) {
+        units.push(0x0000);
+        while !units.len().is_multiple_of(LFN_CHARS_PER_ENTRY) { units.push(0xFFFF); }
+    }
+
+    let count = units.len() / LFN_CHARS_PER_ENTRY;
+    let checksum = lfn_checksum(short_name);
+    let mut entries = Vec::with_capacity(count);
+
+    for seq in (1..=count).rev() {
+        let mut raw = [0u8; 32];
+        raw[0] = seq as u8 | if seq == count { LFN_LAST_ENTRY } else { 0 };
+        raw[11] = 0x0F;
+        raw[13] = checksum;
+        let chunk = &units[(seq - 1) * LFN_CHARS_PER_ENTRY..seq * LFN_CHARS_PER_ENTRY];
+        for (&offset, unit) in LFN_CHAR_OFFSETS.iter().zip(chunk) {
+            raw[offset..offset + 2].copy_from_slice(&unit.to_le_bytes());
+        }
+        entries.push(raw);
+    }
+    entries
+}
+
+/// Extracts the 13 UTF-16 characters stored in a long-name entry.
+pub fn lfn_chars(raw: &[u8]) -> [u16; LFN_CHARS_PER_ENTRY] {
+    let mut chars = [0u16; LFN_CHARS_PER_ENTRY];
+    for (c, &offset) in chars.iter_mut().zip(LFN_CHAR_OFFSETS.iter()) {
+        *c = u16::from_le_bytes([raw[offset], raw[offset + 1]]);
+    }
+    chars
+}
+
+/// Decodes assembled long-name characters, stopping at the terminator or padding.
+#[cfg(feature = "alloc")]
+pub fn decode_lfn(units: &[u16]) -> String {
+    let end = units.iter().position(|&u| u == 0x0000 || u == 0xFFFF).unwrap_or(units.len());
+    String::from_utf16_lossy(&units[..end])
+}
+
+/// Writes the decoding of `decode_lfn` to `out`, without allocating.
+pub fn write_lfn(units: &[u16], out: &mut impl Write) -> core::fmt::Result {
+    let end = units.iter().position(|&u| u == 0x0000 || u == 0xFFFF).unwrap_or(units.len());
+    for c in char::decode_utf16(units[..end].iter().copied()) {
+        out.write_char(c.unwrap_or(char::REPLACEMENT_CHARACTER))?;
+    }
+    Ok(())
+}
+
+#[cfg(all(test, feature = "alloc"))]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    #[test]
+    fn test_short_name_generation() {
+        assert_eq!(&generate_short_name("readme.txt", &[], Codepage::Cp437), b"README  TXT");
+        assert_eq!(&generate_short_name("my.file.name.txt", &[], Codepage::Cp437), b"MYFILE~1TXT");
+        assert_eq!(&generate_short_name("a+b.c", &[], Codepage::Cp437), b"A_B~1   C  ");
+        assert_eq!(&generate_short_name("Long Document.html", &[], Codepage::Cp437), b"LONGDO~1HTM");
+        assert_eq!(&generate_short_name(".bashrc", &[], Codepage::Cp437), b"BASHRC~1   ");
+
+        let taken = [*b"MYFILE~1TXT"];
+        assert_eq!(&generate_short_name("my.file.other.txt", &taken, Codepage::Cp437), b"MYFILE~2TXT");
+    }
+
+    #[test]
+    fn test_codepage_names() {
+        assert_eq!(format_name(b"CAF\x90    TXT", Codepage::Cp437, 0), "CAFÉ.TXT");
+        assert_eq!(format_name(b"\x9D       BIN", Codepage::Cp437, 0), "¥.BIN");
+        assert_eq!(format_name(b"\x9D       BIN", Codepage::Cp850, 0), "Ø.BIN");
+
+        assert_eq!(&generate_short_name("café.txt", &[], Codepage::Cp850), b"CAF\x90    TXT");
+        assert_eq!(&generate_short_name("Ωmega", &[], Codepage::Cp850), b"_MEGA~1    ");
+    }
+
+    #[test]
+    fn test_case_flags() {
+        assert_eq!(format_name(b"README  TXT", Codepage::Cp437, CASE_LOWER_BASE), "readme.TXT");
+        assert_eq!(case_flags_for("readme.txt", b"README  TXT", Codepage::Cp437), Some(0x18));
+        assert_eq!(case_flags_for("README.txt", b"README  TXT", Codepage::Cp437), Some(CASE_LOWER_EXT));
+        assert_eq!(case_flags_for("ReadMe.txt", b"README  TXT", Codepage::Cp437), None);
+    }
+
+    #[test]
+    fn test_lfn_round_trip() {
+        let short = generate_short_name("Un nom très long.txt", &[], Codepage::Cp437);
+        let entries = encode_lfn("Un nom très long.txt", &short);
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0][0], 2 | LFN_LAST_ENTRY);
+        assert!(entries.iter().all(|e| e[13] == lfn_checksum(&short)));
+
+        let mut units = Vec::new();
+        for e in entries.iter().rev() { units.extend_from_slice(&lfn_chars(e)); }
+        assert_eq!(decode_lfn(&units), "Un nom très long.txt");
+    }
+
+    /// Names a long-name entry can hold: ASCII, characters both codepages know, others
+    /// they don't, one outside the BMP, and the spaces and dots short names drop.
+    const LONG_NAME: &str = "[a-zA-Z0-9 ._~$%'()!#&+,;=@^{}éÉüßΩ€😀-]{1,80}";
+
+    fn codepage() -> impl Strategy<Value = Codepage> {
+        prop_oneof![Just(Codepage::Cp437), Just(Codepage::Cp850)]
+    }
+
+    proptest! {
+        #[test]
+        fn prop_short_name_is_valid(name in LONG_NAME, codepage in codepage()) {
+            let short = generate_short_name(&name, &[], codepage);
+            prop_assert!(short[..8].iter().any(|&b| b != b' '));
+            for &b in &short {
+                prop_assert!(b == b' ' || b >= 0x80 || is_valid_short_char(b as char) || b == 0x05, "{:?}", short);
+            }
+            // The short name stands for itself: generating one from its rendering gives it back.
+            let rendered = format_name(&short, codepage, 0);
+            prop_assert_eq!(generate_short_name(&rendered, &[], codepage), short);
+        }
+
+        #[test]
+        fn prop_short_name_avoids_taken(name in LONG_NAME, codepage in codepage()) {
+            let mut taken = Vec::new();
+            for _ in 0..3 {
+                let short = generate_short_name(&name, &taken, codepage);
+                prop_assert!(!taken.contains(&short));
+                taken.push(short);
+            }
+        }
+
+        #[test]
+        fn prop_case_flags_render_long_name(name in LONG_NAME, codepage in codepage()) {
+            let short = generate_short_name(&name, &[], codepage);
+            if let Some(flags) = case_flags_for(&name, &short, codepage) {
+                prop_assert_eq!(format_name(&short, codepage, flags), name);
+            }
+        }
+
+        #[test]
+        fn prop_lfn_round_trip(name in LONG_NAME) {
+            prop_assume!(!name.trim_end_matches(['.', ' ']).is_empty() && name != "." && name != "..");
+            prop_assert!(is_valid_long_name(&name));
+            let short = generate_short_name(&name, &[], Codepage::Cp437);
+            let entries = encode_lfn(&name, &short);
+            prop_assert_eq!(entries.len(), name.encode_utf16().count().div_ceil(LFN_CHARS_PER_ENTRY));
+            let mut units = Vec::new();
+            for (i, e) in entries.iter().rev().enumerate() {
+                prop_assert_eq!(e[0] & !LFN_LAST_ENTRY, i as u8 + 1);
+                prop_assert_eq!(e[13], lfn_checksum(&short));
+                units.extend_from_slice(&lfn_chars(e));
+            }
+            prop_assert_eq!(entries[0][0] & LFN_LAST_ENTRY, LFN_LAST_ENTRY);
+            prop_assert_eq!(decode_lfn(&units), name);
+        }
+
+        #[test]
+        fn prop_invalid_characters(prefix in LONG_NAME, bad in "[\"*/:<>?\\\\|\\x00-\\x1f]", suffix in LONG_NAME) {
+            let name = format!("{}{}{}", prefix, bad, suffix);
+            prop_assert!(!is_valid_long_name(&name));
+            // Sanitized the same way every time, into an ordinary short name.
+            let short = generate_short_name(&name, &[], Codepage::Cp437);
+            prop_assert_eq!(generate_short_name(&name, &[], Codepage::Cp437), short);
+            prop_assert!(short.iter().all(|&b| !b"\"*/:<>?\\|".contains(&b) && b >= 0x20 || b == 0x05));
+        }
+    }
+}
+
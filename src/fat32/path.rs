@@ -0,0 +1,277 @@
+extern crate alloc;
+use alloc::vec::Vec;
+use alloc::string::String;
+use alloc::vec;
+use alloc::format;
+use log::debug;
+
+use super::dir::DirEntry;
+use super::error::{DIR_NOT_FOUND, FILE_NOT_FOUND};
+use super::volume::Fat32Volume;
+
+/// What a path names, as found by `Fat32Volume::resolve_path`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Resolved {
+    /// A directory, by its first cluster (the root's for `/`, `.`, `..` at the root...).
+    Dir(u32),
+    File(DirEntry),
+    /// Nothing named `name` exists in the directory `parent`, which does exist.
+    NotFound { parent: u32, name: String },
+}
+
+impl<'a> Fat32Volume<'a> {
+    /// Looks up `path`, absolute or relative to the current directory. Fails only when
+    /// one of the directories leading to the last component is missing or is a file.
+    pub fn resolve_path(&self, path: &str) -> Result<Resolved, &'static str> {
+        let resolved = self.lookup(path);
+        debug!("resolve {:?}: {:?}", path, resolved.as_ref().map(|r| match r {
+            Resolved::Dir(cluster) => format!("directory at cluster {}", cluster),
+            Resolved::File(entry) => format!("file at cluster {}, {} bytes", entry.first_cluster, entry.size),
+            Resolved::NotFound { parent, name } => format!("{} not found in cluster {}", name, parent),
+        }));
+        resolved
+    }
+
+    fn lookup(&self, path: &str) -> Result<Resolved, &'static str> {
+        let mut cluster = if path.starts_with('/') { self.boot_sector.root_dir_cluster } else { self.current_cluster };
+        let mut components: Vec<&str> = path.split('/').filter(|c| !c.is_empty() && *c != ".").collect();
+        let Some(last) = components.pop() else { return Ok(Resolved::Dir(cluster)) };
+
+        for component in components {
+            cluster = self.enter_directory(cluster, component)?;
+        }
+        if last == ".." { return Ok(Resolved::Dir(self.enter_directory(cluster, last)?)); }
+        Ok(match self.find_entry(cluster, last)? {
+            Some(entry) if entry.is_dir() => Resolved::Dir(self.dir_cluster(&entry)),
+            Some(entry) => Resolved::File(entry),
+            None => Resolved::NotFound { parent: cluster, name: last.into() },
+        })
+    }
+
+    /// Returns the first cluster of the directory at `path`.
+    pub fn directory_cluster(&self, path: &str) -> Result<u32, &'static str> {
+        match self.resolve_path(path)? {
+            Resolved::Dir(cluster) => Ok(cluster),
+            Resolved::File(_) => Err("Ce n'est pas un dossier"),
+            Resolved::NotFound { .. } => Err(DIR_NOT_FOUND),
+        }
+    }
+
+    /// The absolute path of the directory starting at `cluster`, found by climbing the
+    /// `..` entries and looking the directory up in each parent.
+    pub fn directory_path(&self, mut cluster: u32) -> Result<String, &'static str> {
+        let root = self.boot_sector.root_dir_cluster;
+        let mut names = Vec::new();
+        while cluster != root {
+            // A `..` loop on a damaged image would climb forever.
+            if names.len() > self.cluster_limit() as usize { return Err("Boucle dans l'arborescence"); }
+            let parent = self.enter_directory(cluster, "..")?;
+            let entry = self.read_dir(parent)?.into_iter()
+                .find(|e| e.is_dir() && !e.is_dot() && self.dir_cluster(e) == cluster)
+                .ok_or(DIR_NOT_FOUND)?;
+            names.push(entry.name);
+            cluster = parent;
+        }
+        names.reverse();
+        Ok(format!("/{}", names.join("/")))
+    }
+
+    /// Returns the entry of the file at `path`, failing for directories.
+    pub fn file_entry(&self, path: &str) -> Result<DirEntry, &'static str> {
+        match self.resolve_path(path)? {
+            Resolved::File(entry) => Ok(entry),
+            Resolved::Dir(_) => Err("C'est un dossier, utilisez cd"),
+            Resolved::NotFound { .. } => Err(FILE_NOT_FOUND),
+        }
+    }
+
+    /// Returns the first cluster of the sub-directory `name` of the directory at `cluster`.
+    pub fn enter_directory(&self, cluster: u32, name: &str) -> Result<u32, &'static str> {
+        let root = self.boot_sector.root_dir_cluster;
+        // The root directory has no `..` entry.
+        if name == ".." && cluster == root { return Ok(root); }
+
+        match self.find_entry(cluster, name)? {
+            Some(entry) if entry.is_dir() => Ok(self.dir_cluster(&entry)),
+            Some(_) => Err("Ce n'est pas un dossier"),
+            None => Err(DIR_NOT_FOUND),
+        }
+    }
+
+    /// First cluster of a directory entry; `..` entries store 0 for the root.
+    pub(super) fn dir_cluster(&self, entry: &DirEntry) -> u32 {
+        if entry.first_cluster == 0 { self.boot_sector.root_dir_cluster } else { entry.first_cluster }
+    }
+
+    /// Expands the `*` and `?` wildcards of `pattern` against the directory contents,
+    /// in every component. Matching ignores ASCII case, like name lookups do.
+    /// Returns the matching paths, spelled like `pattern` with each wildcard component
+    /// replaced by the entry name; a pattern without wildcards is returned as is.
+    pub fn glob(&self, pattern: &str) -> Result<Vec<String>, &'static str> {
+        if !has_wildcards(pattern) { return Ok(vec![pattern.into()]); }
+
+        let (cluster, prefix) = if pattern.starts_with('/') {
+            (self.boot_sector.root_dir_cluster, "/")
+        } else {
+            (self.current_cluster, "")
+        };
+        let components: Vec<&str> = pattern.split('/').filter(|c| !c.is_empty()).collect();
+        let mut matches = Vec::new();
+        self.glob_from(cluster, prefix, &components, &mut matches)?;
+        Ok(matches)
+    }
+
+    /// Completions of `partial`, the start of a path: the entries of its parent directory
+    /// whose name begins with its last component, ignoring ASCII case. Each is spelled
+    /// like `partial` with the last component replaced by the name, directories ending
+    /// with `/`, sorted by name.
+    pub fn complete_path(&self, partial: &str) -> Result<Vec<String>, &'static str> {
+        let (dir, start) = match partial.rfind('/') {
+            Some(i) => (&partial[..=i], &partial[i + 1..]),
+            None => ("", partial),
+        };
+        let cluster = if dir.is_empty() { self.current_cluster } else { self.directory_cluster(dir)? };
+        let mut completions: Vec<String> = self.read_dir(cluster)?.into_iter()
+            .filter(|e| !e.is_dot() && e.name.len() >= start.len() && e.name.is_char_boundary(start.len()) && e.name[..start.len()].eq_ignore_ascii_case(start))
+            .map(|e| format!("{}{}{}", dir, e.name, if e.is_dir() { "/" } else { "" }))
+            .collect();
+        completions.sort();
+        Ok(completions)
+    }
+
+    fn glob_from(&self, cluster: u32, prefix: &str, components: &[&str], matches: &mut Vec<String>) -> Result<(), &'static str> {
+        let Some((&first, rest)) = components.split_first() else { return Ok(()) };
+        let join = |name: &str| -> String {
+            if prefix.is_empty() || prefix.ends_with('/') { format!("{}{}", prefix, name) } else { format!("{}/{}", prefix, name) }
+        };
+
+        if !has_wildcards(first) {
+            if rest.is_empty() {
+                matches.push(join(first));
+            } else if first == "." {
+                self.glob_from(cluster, &join(first), rest, matches)?;
+            } else if let Ok(sub) = self.enter_directory(cluster, first) {
+                self.glob_from(sub, &join(first), rest, matches)?;
+            }
+            return Ok(());
+        }
+
+        for entry in self.read_dir(cluster)? {
+            if entry.is_dot() || !(wildcard_match(first, &entry.name) || wildcard_match(first, &entry.alias)) { continue; }
+            if rest.is_empty() {
+                matches.push(join(&entry.name));
+            } else if entry.is_dir() {
+                self.glob_from(self.dir_cluster(&entry), &join(&entry.name), rest, matches)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+pub fn has_wildcards(s: &str) -> bool {
+    s.contains(['*', '?'])
+}
+
+/// Matches `name` against `pattern`, where `*` stands for any run of characters and
+/// `?` for exactly one. ASCII letters match regardless of case.
+pub fn wildcard_match(pattern: &str, name: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let name: Vec<char> = name.chars().collect();
+    let (mut p, mut n) = (0, 0);
+    // Position of the last `*` seen and of the name character it currently stops at.
+    let mut star: Option<(usize, usize)> = None;
+
+    while n < name.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p].eq_ignore_ascii_case(&name[n])) {
+            p += 1;
+            n += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            star = Some((p, n));
+            p += 1;
+        } else if let Some((star_p, star_n)) = star {
+            // Let the last `*` swallow one more character and retry from there.
+            p = star_p + 1;
+            n = star_n + 1;
+            star = Some((star_p, star_n + 1));
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fat32::volume::tests::create_mock_volume;
+
+    #[test]
+    fn test_resolve_path() {
+        let mut data = create_mock_volume();
+        let mut volume = Fat32Volume::new(&mut data).unwrap();
+        let docs = volume.create_directory("docs").unwrap();
+        volume.create_file_in(docs, "a.txt", b"a", false).unwrap();
+
+        assert_eq!(volume.resolve_path("/").unwrap(), Resolved::Dir(2));
+        assert!(matches!(volume.resolve_path("/docs/a.txt").unwrap(), Resolved::File(e) if e.name == "a.txt"));
+        assert_eq!(volume.resolve_path("./docs/../docs/").unwrap(), Resolved::Dir(docs));
+        assert_eq!(volume.resolve_path("docs/..").unwrap(), Resolved::Dir(2));
+        assert_eq!(volume.resolve_path("docs/b.txt").unwrap(), Resolved::NotFound { parent: docs, name: "b.txt".into() });
+        assert_eq!(volume.resolve_path("docs/a.txt/b"), Err("Ce n'est pas un dossier"));
+        assert_eq!(volume.resolve_path("nope/b"), Err(DIR_NOT_FOUND));
+    }
+
+    #[test]
+    fn test_wildcard_match() {
+        assert!(wildcard_match("*.txt", "notes.TXT"));
+        assert!(wildcard_match("IMG_????.JPG", "img_0001.jpg"));
+        assert!(wildcard_match("a*b*c", "aXXbYbc"));
+        assert!(!wildcard_match("*.txt", "notes.txt.bak"));
+        assert!(!wildcard_match("?", ""));
+    }
+
+    #[test]
+    fn test_glob() {
+        let mut data = create_mock_volume();
+        let mut volume = Fat32Volume::new(&mut data).unwrap();
+        let logs = volume.create_directory("logs").unwrap();
+        volume.create_file_in(logs, "a.txt", b"a", false).unwrap();
+        volume.create_file_in(logs, "b.log", b"b", false).unwrap();
+        volume.create_file_in(logs, "c.txt", b"c", false).unwrap();
+        volume.create_file("top.txt", b"t", false).unwrap();
+
+        assert_eq!(volume.glob("logs/*.txt").unwrap(), ["logs/a.txt", "logs/c.txt"]);
+        assert_eq!(volume.glob("/*/?.LOG").unwrap(), ["/logs/b.log"]);
+        assert_eq!(volume.glob("*.txt").unwrap(), ["top.txt"]);
+        assert!(volume.glob("*.tmp").unwrap().is_empty());
+        assert_eq!(volume.glob("plain.txt").unwrap(), ["plain.txt"]);
+    }
+
+    #[test]
+    fn test_complete_path() {
+        let mut data = create_mock_volume();
+        let mut volume = Fat32Volume::new(&mut data).unwrap();
+        let dcim = volume.create_directory("DCIM").unwrap();
+        volume.create_directory_in(dcim, "100CANON").unwrap();
+        volume.create_file_in(dcim, "Thumbs.db", b"t", false).unwrap();
+        volume.create_file("data.bin", b"d", false).unwrap();
+
+        assert_eq!(volume.complete_path("d").unwrap(), ["DCIM/", "data.bin"]);
+        assert_eq!(volume.complete_path("/dcim/1").unwrap(), ["/dcim/100CANON/"]);
+        assert_eq!(volume.complete_path("DCIM/").unwrap(), ["DCIM/100CANON/", "DCIM/Thumbs.db"]);
+        assert!(volume.complete_path("x").unwrap().is_empty());
+        assert_eq!(volume.complete_path("nope/a"), Err(DIR_NOT_FOUND));
+    }
+
+    #[test]
+    fn test_directory_path() {
+        let mut data = create_mock_volume();
+        let mut volume = Fat32Volume::new(&mut data).unwrap();
+        let dcim = volume.create_directory("DCIM").unwrap();
+        let canon = volume.create_directory_in(dcim, "100CANON").unwrap();
+
+        assert_eq!(volume.directory_path(2).unwrap(), "/");
+        assert_eq!(volume.directory_path(dcim).unwrap(), "/DCIM");
+        assert_eq!(volume.directory_path(canon).unwrap(), "/DCIM/100CANON");
+    }
+}
@@ -0,0 +1,40 @@
+/// Receives the progress of long operations (extraction, archiving, defragmentation...).
+pub trait ProgressSink {
+    /// `done` of `total` bytes have been processed; `current` names the file being worked on.
+    fn progress(&mut self, done: u64, total: u64, current: &str);
+
+    /// Called once the operation is over, whether it succeeded or not.
+    fn finish(&mut self) {}
+}
+
+/// Discards progress reports.
+pub struct NoProgress;
+
+impl ProgressSink for NoProgress {
+    fn progress(&mut self, _done: u64, _total: u64, _current: &str) {}
+}
+
+/// Running byte count of an operation over many files, forwarded to a `ProgressSink`.
+pub struct ProgressTracker<'p> {
+    sink: &'p mut dyn ProgressSink,
+    done: u64,
+    total: u64,
+}
+
+impl<'p> ProgressTracker<'p> {
+    pub fn new(sink: &'p mut dyn ProgressSink, total: u64) -> Self {
+        ProgressTracker { sink, done: 0, total }
+    }
+
+    /// Reports the file about to be processed, then counts its bytes as done.
+    pub fn file(&mut self, path: &str, size: u64) {
+        self.sink.progress(self.done, self.total, path);
+        self.done += size;
+    }
+
+    /// Reports the final count and lets the sink clean up.
+    pub fn finish(self) {
+        self.sink.progress(self.done, self.total, "");
+        self.sink.finish();
+    }
+}
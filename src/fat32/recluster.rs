@@ -0,0 +1,267 @@
+extern crate alloc;
+use alloc::vec;
+use alloc::vec::Vec;
+
+use super::dir::DirEntry;
+use super::fat::{CLUSTER_LIMIT, FAT_EOC, MIN_CLUSTERS};
+use super::progress::{ProgressSink, ProgressTracker};
+use super::resize::fat_sectors_for;
+use super::structs::BootSector;
+use super::volume::Fat32Volume;
+
+/// Largest cluster `recluster` writes; most systems read no larger on FAT32.
+const MAX_CLUSTER_SIZE: usize = 64 * 1024;
+
+/// What `recluster` changed.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct ReclusterReport {
+    /// Bytes per cluster, before and after.
+    pub cluster_size: (usize, usize),
+    /// Sectors per FAT, before and after.
+    pub fat_sectors: (u32, u32),
+    /// Clusters in use, before and after.
+    pub used: (u32, u32),
+    pub files: usize,
+    pub directories: usize,
+}
+
+impl<'a> Fat32Volume<'a> {
+    /// Writes to `out` the same volume with clusters of `sectors_per_cluster` sectors, for
+    /// devices that demand a given cluster size. The reserved sectors are kept with the
+    /// cluster size, FAT size and root cluster changed. Directories are copied entry for
+    /// entry, so long names, times, attributes and the volume label stay, and every
+    /// directory and file goes to contiguous clusters, in tree order from cluster 2. Bad
+    /// cluster marks are not carried over: the clusters they named are gone.
+    ///
+    /// `out` must hold the volume; past its used clusters it is left as it was, so to
+    /// change the volume in place, start from a copy of it. Nothing is written when the
+    /// new clusters would number fewer than FAT32's `MIN_CLUSTERS`. The result is read back and
+    /// every path compared with this volume before it is reported done.
+    /// Progress is reported in bytes of clusters copied.
+    pub fn recluster(&self, sectors_per_cluster: u8, out: &mut [u8], progress: &mut dyn ProgressSink) -> Result<ReclusterReport, &'static str> {
+        let boot = self.boot_sector;
+        let bps = boot.bytes_per_sector as usize;
+        if !sectors_per_cluster.is_power_of_two() || sectors_per_cluster as usize * bps > MAX_CLUSTER_SIZE {
+            return Err("Taille de cluster invalide");
+        }
+        let total = boot.total_sectors();
+        if out.len() < total as usize * bps { return Err("Image de sortie trop petite"); }
+        let resized = BootSector { sectors_per_cluster, ..boot };
+        let new_boot = BootSector { sectors_per_fat_32: fat_sectors_for(&resized, total), root_dir_cluster: 2, ..resized };
+        let data_sectors = total as u64 - (new_boot.data_start() / bps) as u64;
+        if data_sectors / sectors_per_cluster as u64 + 2 > CLUSTER_LIMIT as u64 { return Err("Volume trop grand"); }
+        if data_sectors / (sectors_per_cluster as u64) < MIN_CLUSTERS as u64 { return Err("Clusters trop grands pour l'image"); }
+
+        let reserved = boot.fat_start();
+        self.storage.read(0, &mut out[..reserved])?;
+        let backup = boot.backup_boot_sector as usize;
+        for start in [Some(0), (backup > 0 && backup < boot.reserved_sectors as usize).then_some(backup * bps)].into_iter().flatten() {
+            out[start + 13] = sectors_per_cluster;
+            out[start + 36..start + 40].copy_from_slice(&new_boot.sectors_per_fat_32.to_le_bytes());
+            out[start + 44..start + 48].copy_from_slice(&2u32.to_le_bytes());
+        }
+
+        let used = (2..self.cluster_limit()).filter(|&c| !self.is_free(c)).count() as u32;
+        let mut copy = Rebuild {
+            volume: self,
+            fat: vec![0; new_boot.clusters().end as usize],
+            boot: new_boot,
+            out: &mut *out,
+            next: 2,
+            visited: Vec::new(),
+            tracker: ProgressTracker::new(progress, used as u64 * self.cluster_size() as u64),
+            files: 0,
+            directories: 0,
+        };
+        let result = copy.directory(boot.root_dir_cluster, None);
+        let Rebuild { mut fat, next, tracker, files, directories, .. } = copy;
+        tracker.finish();
+        result?;
+
+        fat[0] = self.read_fat_entry(0)?;
+        fat[1] = self.read_fat_entry(1)?;
+        let fat_size = new_boot.sectors_per_fat_32 as usize * bps;
+        for n in 0..boot.number_of_fats as usize {
+            let start = new_boot.fat_start() + n * fat_size;
+            let table = &mut out[start..start + fat_size];
+            table.fill(0);
+            for (slot, value) in table.chunks_exact_mut(4).zip(&fat) {
+                slot.copy_from_slice(&value.to_le_bytes());
+            }
+        }
+
+        let mut rebuilt = Fat32Volume::new(out)?;
+        let sector = boot.fs_info_sector as usize;
+        let copies: Vec<usize> = [Some(sector), (backup > 0).then_some(backup + sector)].into_iter().flatten().filter_map(|s| rebuilt.fsinfo_sector(s)).collect();
+        for info in copies {
+            rebuilt.storage.write(info + 492, &next.to_le_bytes())?;
+        }
+        rebuilt.update_fsinfo()?;
+        if !self.snapshot()?.diff(&rebuilt.snapshot()?).is_empty() {
+            return Err("Vérification échouée après changement de taille de cluster");
+        }
+        Ok(ReclusterReport {
+            cluster_size: (self.cluster_size(), new_boot.cluster_size()),
+            fat_sectors: (boot.sectors_per_fat_32, new_boot.sectors_per_fat_32),
+            used: (used, next - 2),
+            files,
+            directories,
+        })
+    }
+}
+
+/// The volume being written by `recluster`: its FAT, kept in memory until the end, and
+/// the next cluster to hand out.
+struct Rebuild<'v, 'a, 'o, 'p> {
+    volume: &'v Fat32Volume<'a>,
+    boot: BootSector,
+    out: &'o mut [u8],
+    fat: Vec<u32>,
+    next: u32,
+    /// Directories copied so far, by their old first cluster.
+    visited: Vec<u32>,
+    tracker: ProgressTracker<'p>,
+    files: usize,
+    directories: usize,
+}
+
+impl Rebuild<'_, '_, '_, '_> {
+    /// Copies the directory starting at `old` and everything below it, and returns its new
+    /// first cluster. `parent` is the new first cluster of the directory holding it, none
+    /// for the root. Entries past the end marker are dropped, as nothing reads them.
+    fn directory(&mut self, old: u32, parent: Option<u32>) -> Result<u32, &'static str> {
+        if self.visited.contains(&old) { return Err("Boucle dans l'arborescence"); }
+        self.visited.push(old);
+        let volume = self.volume;
+        let cluster_size = volume.cluster_size();
+        let chain = volume.cluster_chain(old)?;
+        let mut raw = vec![0u8; chain.len() * cluster_size];
+        for (&cluster, buf) in chain.iter().zip(raw.chunks_exact_mut(cluster_size)) {
+            volume.storage.read_dir(volume.offset_from_cluster(cluster)?, buf)?;
+        }
+        self.tracker.file("", raw.len() as u64);
+        let len = raw.as_chunks::<32>().0.iter().position(|entry| entry[0] == 0).map_or(raw.len(), |i| i * 32);
+        raw.truncate(len);
+        let first = self.allocate(len.max(32))?;
+        self.directories += 1;
+
+        // Sub-directories of the root name it as cluster 0 in their `..` entry.
+        let here = if parent.is_none() { 0 } else { first };
+        for entry in volume.read_dir(old)? {
+            let cluster = match entry.short_name {
+                [b'.', b' ', ..] => first,
+                [b'.', b'.', b' ', ..] => parent.unwrap_or(0),
+                _ if entry.is_dir() && entry.first_cluster < 2 => continue,
+                _ if entry.is_dir() => self.directory(entry.first_cluster, Some(here))?,
+                _ => self.file(&entry)?,
+            };
+            let at = chain.iter().zip((0..).step_by(cluster_size))
+                .find_map(|(&c, pos)| {
+                    let start = volume.offset_from_cluster(c).ok()?;
+                    (start..start + cluster_size).contains(&entry.offset).then(|| pos + entry.offset - start)
+                })
+                .ok_or("Entrée hors du dossier")?;
+            raw[at + 20..at + 22].copy_from_slice(&((cluster >> 16) as u16).to_le_bytes());
+            raw[at + 26..at + 28].copy_from_slice(&(cluster as u16).to_le_bytes());
+        }
+        let start = self.boot.cluster_offset(first)?;
+        self.out[start..start + raw.len()].copy_from_slice(&raw);
+        Ok(first)
+    }
+
+    /// Copies the clusters of a file that hold its bytes and returns its new first
+    /// cluster, 0 when it is empty.
+    fn file(&mut self, entry: &DirEntry) -> Result<u32, &'static str> {
+        self.files += 1;
+        if entry.first_cluster < 2 { return Ok(0); }
+        let volume = self.volume;
+        let cluster_size = volume.cluster_size();
+        let chain = volume.cluster_chain(entry.first_cluster)?;
+        self.tracker.file(&entry.name, (chain.len() * cluster_size) as u64);
+        let len = (entry.size as usize).min(chain.len() * cluster_size);
+        let first = self.allocate(len)?;
+        if first == 0 { return Ok(0); }
+        let mut at = self.boot.cluster_offset(first)?;
+        for (&cluster, n) in chain.iter().zip((0..len).step_by(cluster_size).map(|done| cluster_size.min(len - done))) {
+            volume.storage.read(volume.offset_from_cluster(cluster)?, &mut self.out[at..at + n])?;
+            at += n;
+        }
+        Ok(first)
+    }
+
+    /// Hands out enough contiguous clusters for `len` bytes, linked in the FAT, and zeroes
+    /// what is past `len` in the last one. Returns the first, or 0 when `len` is 0.
+    fn allocate(&mut self, len: usize) -> Result<u32, &'static str> {
+        let count = len.div_ceil(self.boot.cluster_size()) as u32;
+        if count == 0 { return Ok(0); }
+        let first = self.next;
+        if first as u64 + count as u64 > self.fat.len() as u64 { return Err("Pas assez de place libre"); }
+        for cluster in first..first + count {
+            self.fat[cluster as usize] = if cluster + 1 == first + count { FAT_EOC } else { cluster + 1 };
+        }
+        self.next += count;
+        let start = self.boot.cluster_offset(first)?;
+        self.out[start + len..start + count as usize * self.boot.cluster_size()].fill(0);
+        Ok(first)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fat32::progress::NoProgress;
+    use crate::fat32::testutil::ImageBuilder;
+
+    #[test]
+    fn test_recluster() {
+        let photo: Vec<u8> = (0..20_000).map(|i| (i % 251) as u8).collect();
+        let mut data = ImageBuilder::new()
+            .label(b"CAMERA     ")
+            .file("DCIM/100CANON/IMG_0001.JPG", photo.clone())
+            .dir("DCIM/100CANON/Sub")
+            .fragmented_file("a long file name.txt", *b"fragmented log")
+            .file("empty.txt", *b"")
+            .size(68 * 1024 * 1024)
+            .build();
+        let volume = Fat32Volume::new(&mut data).unwrap();
+        let before = volume.snapshot().unwrap();
+
+        let mut out = vec![0xAAu8; volume.storage.len()];
+        // Clusters of 2 KiB would be too few for FAT32.
+        assert_eq!(volume.recluster(4, &mut out, &mut NoProgress), Err("Clusters trop grands pour l'image"));
+        assert!(out.iter().all(|&b| b == 0xAA));
+
+        let report = volume.recluster(2, &mut out, &mut NoProgress).unwrap();
+        assert_eq!(report.cluster_size, (512, 1024));
+        assert!(report.fat_sectors.1 < report.fat_sectors.0);
+        assert_eq!((report.files, report.directories), (3, 4));
+        // 40 clusters of photo shrink to 20, the root and the others fit in one each.
+        assert_eq!(report.used.1, 1 + 1 + 1 + 20 + 1 + 1);
+
+        let rebuilt = Fat32Volume::new(&mut out).unwrap();
+        assert_eq!(rebuilt.cluster_size(), 1024);
+        assert!(before.diff(&rebuilt.snapshot().unwrap()).is_empty());
+        assert_eq!(rebuilt.read_file("DCIM/100CANON/IMG_0001.JPG").unwrap(), photo);
+        assert_eq!(rebuilt.boot_region().unwrap()[71..82], *b"CAMERA     ");
+        let sub = rebuilt.directory_cluster("DCIM/100CANON/Sub").unwrap();
+        let parent = rebuilt.directory_cluster("DCIM/100CANON").unwrap();
+        assert_eq!(rebuilt.read_dir(sub).unwrap().iter().find(|e| e.name == "..").unwrap().first_cluster, parent);
+        let dcim = rebuilt.read_dir(rebuilt.directory_cluster("DCIM").unwrap()).unwrap();
+        assert_eq!(dcim.iter().find(|e| e.name == "..").unwrap().first_cluster, 0);
+        assert_eq!(rebuilt.compare_fats(), Ok(vec![]));
+        assert_eq!(rebuilt.space().used(), report.used.1 as u64 * 1024);
+        assert_eq!(rebuilt.boot_region().unwrap()[..512], rebuilt.boot_region().unwrap()[6 * 512..7 * 512]);
+        drop(rebuilt);
+
+        // And back, to clusters smaller than the original.
+        let rebuilt = Fat32Volume::new(&mut out).unwrap();
+        let mut back = data.clone();
+        rebuilt.recluster(1, &mut back, &mut NoProgress).unwrap();
+        let volume = Fat32Volume::new(&mut back).unwrap();
+        assert!(before.diff(&volume.snapshot().unwrap()).is_empty());
+
+        assert_eq!(volume.recluster(3, &mut out, &mut NoProgress), Err("Taille de cluster invalide"));
+        assert_eq!(volume.recluster(0, &mut out, &mut NoProgress), Err("Taille de cluster invalide"));
+        assert_eq!(volume.recluster(2, &mut [0u8; 512], &mut NoProgress), Err("Image de sortie trop petite"));
+    }
+}
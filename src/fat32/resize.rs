@@ -0,0 +1,408 @@
+extern crate alloc;
+use alloc::collections::BTreeMap;
+use alloc::vec;
+use alloc::vec::Vec;
+
+use super::fat::{FatValue, CLUSTER_LIMIT, FAT_FREE, MIN_CLUSTERS};
+use super::progress::{ProgressSink, ProgressTracker};
+use super::structs::BootSector;
+use super::volume::Fat32Volume;
+
+/// Bytes copied at a time when data has to move.
+const MOVE_CHUNK: usize = 64 * 1024;
+
+/// What `grow` or `shrink` changed.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct ResizeReport {
+    /// Sectors per FAT, before and after.
+    pub fat_sectors: (u32, u32),
+    /// Clusters of the data region, before and after.
+    pub clusters: (u32, u32),
+    /// Clusters in use past the new end that were copied to free ones before it.
+    pub relocated: usize,
+    /// Bytes of FATs and data moved as the FATs changed size.
+    pub bytes_moved: u64,
+}
+
+impl<'a> Fat32Volume<'a> {
+    /// Extends the volume to `total_sectors`, which the storage must already hold, so that
+    /// an image made small can fill a larger card. When the FATs need more sectors to
+    /// cover the new clusters, the data region is moved up to make room; cluster numbers
+    /// stay the same, so nothing that points to them changes. The total size, the FAT
+    /// size and the FSInfo free count are updated in the boot sector and its backup.
+    /// Progress is reported in bytes moved.
+    pub fn grow(&mut self, total_sectors: u32, progress: &mut dyn ProgressSink) -> Result<ResizeReport, &'static str> {
+        let boot = self.boot_sector;
+        let bps = boot.bytes_per_sector as usize;
+        if total_sectors <= boot.total_sectors() { return Err("Nouvelle taille trop petite"); }
+        if total_sectors as usize * bps > self.storage.len() { return Err("Nouvelle taille au-delà de l'image"); }
+        let old_fat = boot.sectors_per_fat_32;
+        let new_fat = fat_sectors_for(&boot, total_sectors).max(old_fat);
+        let grown = BootSector { total_sectors_16: 0, total_sectors_32: total_sectors, sectors_per_fat_32: new_fat, ..boot };
+        let data_sectors = total_sectors as u64 - (grown.data_start() / bps) as u64;
+        if data_sectors / boot.sectors_per_cluster as u64 + 2 > CLUSTER_LIMIT as u64 { return Err("Volume trop grand"); }
+
+        // Clusters past the last one in use hold nothing worth moving.
+        let old_limit = self.cluster_limit();
+        let used = (2..old_limit).rev().find(|&c| !self.is_free(c)).map_or(0, |last| (last - 1) as usize * self.cluster_size());
+        let old_size = old_fat as usize * bps;
+        let new_size = new_fat as usize * bps;
+        let fats = boot.number_of_fats as usize;
+        let shift = grown.data_start() - boot.data_start();
+        let moved = if shift > 0 { used + (fats - 1) * old_size } else { 0 };
+        let mut tracker = ProgressTracker::new(progress, moved as u64);
+
+        let result = (|| {
+            if shift > 0 {
+                self.move_bytes(boot.data_start(), grown.data_start(), used, &mut tracker)?;
+                // The last copy first: each moves over where the next one was.
+                for copy in (1..fats).rev() {
+                    let from = boot.fat_start() + copy * old_size;
+                    self.move_bytes(from, boot.fat_start() + copy * new_size, old_size, &mut tracker)?;
+                }
+            }
+            // Entries past the old end of the volume become the new clusters: all free.
+            let old_end = boot.clusters().end as usize * 4;
+            for copy in 0..fats {
+                let start = boot.fat_start() + copy * new_size;
+                if old_end < new_size { self.storage.fill(start + old_end, new_size - old_end, 0)?; }
+            }
+            self.write_bpb_field(19, &0u16.to_le_bytes())?;
+            self.write_bpb_field(32, &total_sectors.to_le_bytes())?;
+            self.write_bpb_field(36, &new_fat.to_le_bytes())
+        })();
+        tracker.finish();
+        result?;
+
+        self.remount()?;
+        self.update_fsinfo()?;
+        Ok(ResizeReport {
+            fat_sectors: (old_fat, new_fat),
+            clusters: (boot.clusters().end - 2, self.boot_sector.clusters().end - 2),
+            relocated: 0,
+            bytes_moved: moved as u64,
+        })
+    }
+
+    /// Cuts the volume down to `total_sectors`, so that an image can be handed out no
+    /// larger than its content. Clusters in use past the new end are first copied to free
+    /// ones before it, and the FAT links, directory entries (`.` and `..` included) and
+    /// root cluster pointing to them follow. The FATs shrink to what the remaining clusters
+    /// need, moving the data region down. The storage keeps its size: the caller cuts it.
+    ///
+    /// Fails, with nothing changed, when fewer than `MIN_CLUSTERS` clusters would be left,
+    /// when the clusters in use don't fit, or when some past the end are used by no file. Every file is hashed before and after, and the FAT
+    /// copies compared, so a mistake is reported rather than handed out.
+    pub fn shrink(&mut self, total_sectors: u32, progress: &mut dyn ProgressSink) -> Result<ResizeReport, &'static str> {
+        let boot = self.boot_sector;
+        let bps = boot.bytes_per_sector as usize;
+        if total_sectors >= boot.total_sectors() { return Err("Nouvelle taille trop grande"); }
+        let old_fat = boot.sectors_per_fat_32;
+        let new_fat = fat_sectors_for(&boot, total_sectors).min(old_fat);
+        let shrunk = BootSector { total_sectors_16: 0, total_sectors_32: total_sectors, sectors_per_fat_32: new_fat, ..boot };
+        let new_end = shrunk.clusters().end.min((new_fat as usize * bps / 4) as u32);
+        if new_end < MIN_CLUSTERS + 2 { return Err("Nouvelle taille trop petite"); }
+
+        // Everything that could go wrong is found before anything is written.
+        let old_limit = self.cluster_limit();
+        let referenced = self.referenced_clusters()?;
+        let mut beyond = Vec::new();
+        for cluster in new_end..old_limit {
+            match self.fat_value(cluster)?.1 {
+                FatValue::Free | FatValue::Bad => {}
+                _ if !referenced[cluster as usize] => return Err("Chaîne perdue au-delà de la nouvelle taille"),
+                _ => beyond.push(cluster),
+            }
+        }
+        let spare: Vec<u32> = (2..new_end).filter(|&c| self.is_free(c)).take(beyond.len()).collect();
+        if spare.len() < beyond.len() { return Err("Pas assez de place libre"); }
+        let before = self.manifest()?;
+
+        let relocation: BTreeMap<u32, u32> = beyond.into_iter().zip(spare).collect();
+        let last_used = (2..new_end).rev().find(|&c| !self.is_free(c)).max(relocation.values().max().copied());
+        let used = last_used.map_or(0, |last| (last - 1) as usize * self.cluster_size());
+        let old_size = old_fat as usize * bps;
+        let new_size = new_fat as usize * bps;
+        let fats = boot.number_of_fats as usize;
+        let shift = boot.data_start() - shrunk.data_start();
+        let moved = if shift > 0 { used + (fats - 1) * new_size } else { 0 };
+        let mut tracker = ProgressTracker::new(progress, moved as u64);
+
+        let result = (|| {
+            self.relocate_clusters(&relocation, old_limit)?;
+            if shift > 0 {
+                // The first copy first: each moves over where the one before was.
+                for copy in 1..fats {
+                    let from = boot.fat_start() + copy * old_size;
+                    self.move_bytes(from, boot.fat_start() + copy * new_size, new_size, &mut tracker)?;
+                }
+                self.move_bytes(boot.data_start(), shrunk.data_start(), used, &mut tracker)?;
+            }
+            // Bad clusters past the end go with it.
+            let end = new_end as usize * 4;
+            for copy in 0..fats {
+                let start = boot.fat_start() + copy * new_size;
+                if end < new_size { self.storage.fill(start + end, new_size - end, 0)?; }
+            }
+            self.write_bpb_field(19, &0u16.to_le_bytes())?;
+            self.write_bpb_field(32, &total_sectors.to_le_bytes())?;
+            self.write_bpb_field(36, &new_fat.to_le_bytes())
+        })();
+        tracker.finish();
+        result?;
+
+        self.remount()?;
+        self.update_fsinfo()?;
+        if !self.compare_fats()?.is_empty() || !before.diff(&self.manifest()?).is_empty() {
+            return Err("Vérification échouée après réduction");
+        }
+        Ok(ResizeReport {
+            fat_sectors: (old_fat, new_fat),
+            clusters: (boot.clusters().end - 2, self.boot_sector.clusters().end - 2),
+            relocated: relocation.len(),
+            bytes_moved: moved as u64,
+        })
+    }
+
+    /// Smallest size, in sectors, `shrink` can bring the volume down to: room for the
+    /// clusters in use, and never fewer than `MIN_CLUSTERS`, and FATs covering them.
+    pub fn min_total_sectors(&self) -> u32 {
+        let boot = self.boot_sector;
+        let used = (2..self.cluster_limit()).filter(|&c| !self.is_free(c)).count() as u32;
+        let used = used.max(MIN_CLUSTERS);
+        let fixed = |fat: u32| boot.reserved_sectors as u32 + boot.number_of_fats as u32 * fat + used * boot.sectors_per_cluster as u32;
+        let mut total = fixed(1);
+        loop {
+            let needed = fixed(fat_sectors_for(&boot, total).min(boot.sectors_per_fat_32));
+            if needed <= total { return total; }
+            total = needed;
+        }
+    }
+
+    /// Copies each cluster of `relocation` to the one it maps to, then points everything
+    /// that named the old one to the new: FAT links, directory entries and the root
+    /// cluster. The old clusters are left free.
+    fn relocate_clusters(&mut self, relocation: &BTreeMap<u32, u32>, limit: u32) -> Result<(), &'static str> {
+        if relocation.is_empty() { return Ok(()); }
+        let cluster_size = self.cluster_size();
+        let moved = |cluster: u32| relocation.get(&cluster).copied().unwrap_or(cluster);
+        let link = |value: u32| match FatValue::decode(value) {
+            FatValue::Next(next) => moved(next),
+            _ => value,
+        };
+        for (&old, &new) in relocation {
+            let (src, dst) = (self.offset_from_cluster(old)?, self.offset_from_cluster(new)?);
+            self.storage.copy_within(src..src + cluster_size, dst)?;
+            let value = self.read_fat_entry(old)?;
+            self.write_fat_entry(new, link(value))?;
+        }
+        for cluster in (2..limit).filter(|c| !relocation.contains_key(c)) {
+            let value = self.read_fat_entry(cluster)?;
+            if link(value) != value { self.write_fat_entry(cluster, link(value))?; }
+        }
+        for &old in relocation.keys() {
+            self.write_fat_entry(old, FAT_FREE)?;
+        }
+
+        let root = moved(self.boot_sector.root_dir_cluster);
+        if root != self.boot_sector.root_dir_cluster {
+            self.write_bpb_field(44, &root.to_le_bytes())?;
+            self.boot_sector.root_dir_cluster = root;
+        }
+        self.current_cluster = moved(self.current_cluster);
+        let mut visited = Vec::new();
+        self.relocate_entries(root, relocation, &mut visited)
+    }
+
+    /// Points the entries of the directory at `cluster`, and of those below it, that
+    /// start at a relocated cluster to where it went.
+    fn relocate_entries(&mut self, cluster: u32, relocation: &BTreeMap<u32, u32>, visited: &mut Vec<u32>) -> Result<(), &'static str> {
+        if visited.contains(&cluster) { return Ok(()); }
+        visited.push(cluster);
+        for entry in self.read_dir(cluster)? {
+            let first = relocation.get(&entry.first_cluster).copied().unwrap_or(entry.first_cluster);
+            if first != entry.first_cluster { self.set_entry_cluster(entry.offset, first)?; }
+            if entry.is_dir() && !entry.is_dot() && first >= 2 {
+                self.relocate_entries(first, relocation, visited)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Copies `len` bytes from `from` to `to`. Moving up, the end goes first and moving
+    /// down, the start, so that the copy never overwrites bytes it has yet to read.
+    fn move_bytes(&mut self, from: usize, to: usize, len: usize, tracker: &mut ProgressTracker) -> Result<(), &'static str> {
+        let mut buf = vec![0u8; MOVE_CHUNK.min(len)];
+        let mut done = 0;
+        while done < len {
+            let n = buf.len().min(len - done);
+            let start = if to > from { len - done - n } else { done };
+            self.storage.read(from + start, &mut buf[..n])?;
+            self.storage.write(to + start, &buf[..n])?;
+            tracker.file("", n as u64);
+            done += n;
+        }
+        Ok(())
+    }
+
+    /// Writes `bytes` at `offset` of the boot sector and of its backup, if there is one.
+    fn write_bpb_field(&mut self, offset: usize, bytes: &[u8]) -> Result<(), &'static str> {
+        let backup = self.boot_sector.backup_boot_sector as usize;
+        self.storage.write(offset, bytes)?;
+        if backup > 0 && backup < self.boot_sector.reserved_sectors as usize {
+            self.storage.write(backup * self.boot_sector.bytes_per_sector as usize + offset, bytes)?;
+        }
+        Ok(())
+    }
+
+    /// Reads the boot sector again after it was changed, and everything derived from it.
+    fn remount(&mut self) -> Result<(), &'static str> {
+        let mut sector = [0u8; 512];
+        self.storage.read(0, &mut sector)?;
+        let boot_sector = BootSector::parse(&sector)?;
+        self.boot_sector = boot_sector;
+        self.storage.layout = (boot_sector.fat_start(), boot_sector.data_start());
+        self.build_free_map()
+    }
+}
+
+/// Sectors per FAT needed once `boot` covers `total_sectors`: the fewest with an entry for
+/// every cluster left once the FATs are taken out. The change from the current size is
+/// rounded up to whole clusters, so clusters stay aligned as they were.
+pub(super) fn fat_sectors_for(boot: &BootSector, total_sectors: u32) -> u32 {
+    let spc = boot.sectors_per_cluster as u64;
+    let fats = boot.number_of_fats as u64;
+    let per_sector = boot.bytes_per_sector as u64 / 4;
+    let available = (total_sectors as u64).saturating_sub(boot.reserved_sectors as u64);
+    let covers = |fat: u64| fat * per_sector >= available.saturating_sub(fats * fat) / spc + 2;
+    // From the size that would be exact without rounding, which is at most a sector short.
+    let mut fat = ((available + 2 * spc) / (spc * per_sector + fats)).max(1);
+    while !covers(fat) { fat += 1; }
+    let old = boot.sectors_per_fat_32 as u64;
+    while !(fat.abs_diff(old) * fats).is_multiple_of(spc) { fat += 1; }
+    fat as u32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fat32::progress::NoProgress;
+    use crate::fat32::testutil::{Corruption, ImageBuilder};
+    use alloc::vec::Vec;
+
+    const MB: usize = 1024 * 1024;
+
+    #[test]
+    fn test_grow() {
+        let photo: Vec<u8> = (0..20_000).map(|i| (i % 251) as u8).collect();
+        let mut data = ImageBuilder::new()
+            .file("DCIM/IMG_0001.JPG", photo.clone())
+            .fragmented_file("log.txt", *b"fragmented log")
+            .build();
+        data.resize(64 * MB, 0);
+        let mut volume = Fat32Volume::new(&mut data).unwrap();
+        let before = volume.space();
+        let old_fat = volume.boot_sector.sectors_per_fat_32;
+
+        let report = volume.grow((64 * MB / 512) as u32, &mut NoProgress).unwrap();
+        assert_eq!(report.fat_sectors.0, old_fat);
+        assert!(report.fat_sectors.1 > old_fat && report.bytes_moved > 0);
+        assert!(report.clusters.1 > report.clusters.0 * 3 / 2);
+        assert_eq!({ volume.boot_sector.total_sectors_32 }, (64 * MB / 512) as u32);
+        assert_eq!(volume.read_file("DCIM/IMG_0001.JPG").unwrap(), photo);
+        assert_eq!(volume.read_file("log.txt").unwrap(), b"fragmented log");
+        assert_eq!(volume.compare_fats(), Ok(vec![]));
+        let after = volume.space();
+        assert_eq!(after.used(), before.used());
+        assert_eq!(after.total, report.clusters.1 as u64 * 512);
+
+        // The new room can be used, and the backup boot sector and FSInfo agree.
+        volume.create_file("big.bin", &vec![9u8; 40 * MB], false).unwrap();
+        assert_eq!(volume.read_file("big.bin").unwrap().len(), 40 * MB);
+        assert_eq!(volume.boot_region().unwrap()[..512], volume.boot_region().unwrap()[6 * 512..7 * 512]);
+        drop(volume);
+        let free = u32::from_le_bytes(data[512 + 488..512 + 492].try_into().unwrap());
+        assert_eq!(free as u64 * 512, after.free);
+    }
+
+    #[test]
+    fn test_grow_without_moving() {
+        // The FAT of a fresh volume has room for more clusters than it holds.
+        let mut data = ImageBuilder::new().file("a.txt", *b"a").build();
+        let old_total = BootSector::parse(&data).unwrap().total_sectors();
+        let mut volume = Fat32Volume::new(&mut data).unwrap();
+        assert_eq!(volume.grow(old_total, &mut NoProgress), Err("Nouvelle taille trop petite"));
+        assert_eq!(volume.grow(old_total + 8193, &mut NoProgress), Err("Nouvelle taille au-delà de l'image"));
+        drop(volume);
+
+        data.resize(data.len() + 8 * 512, 0);
+        let mut volume = Fat32Volume::new(&mut data).unwrap();
+        let report = volume.grow(old_total + 8, &mut NoProgress).unwrap();
+        assert_eq!(report.fat_sectors.0, report.fat_sectors.1);
+        assert_eq!((report.bytes_moved, report.clusters.1 - report.clusters.0), (0, 8));
+        assert_eq!(volume.read_file("a.txt").unwrap(), b"a");
+    }
+
+    #[test]
+    fn test_shrink() {
+        let photo: Vec<u8> = (0..20_000).map(|i| (i % 251) as u8).collect();
+        let mut data = ImageBuilder::new()
+            .size(40 * MB)
+            .file("filler.bin", vec![0u8; 34 * MB])
+            .file("DCIM/100CANON/IMG_0001.JPG", photo.clone())
+            .dir("DCIM/100CANON/Sub")
+            .fragmented_file("log.txt", *b"fragmented log")
+            .build();
+        let mut volume = Fat32Volume::new(&mut data).unwrap();
+        // What is left after the filler sits past the smallest the volume can be.
+        volume.remove_file("filler.bin").unwrap();
+        volume.current_cluster = volume.directory_cluster("DCIM/100CANON").unwrap();
+        let used = volume.space().used();
+        let old_fat = volume.boot_sector.sectors_per_fat_32;
+
+        let min = volume.min_total_sectors();
+        let report = volume.shrink(min, &mut NoProgress).unwrap();
+        assert!(report.relocated > 40 && report.bytes_moved > 0);
+        assert!(report.fat_sectors.1 < old_fat && report.fat_sectors.0 == old_fat);
+        assert_eq!(volume.boot_sector.total_sectors(), min);
+        assert_eq!(volume.space().used(), used);
+        assert_eq!(volume.directory_path(volume.current_cluster).unwrap(), "/DCIM/100CANON");
+        let parent = volume.current_cluster;
+        let sub = volume.read_dir(volume.directory_cluster("Sub").unwrap()).unwrap();
+        assert_eq!(sub.iter().find(|e| e.name == "..").unwrap().first_cluster, parent);
+        drop(volume);
+
+        // Cut to its new size, the image still holds everything.
+        data.truncate(min as usize * 512);
+        let volume = Fat32Volume::new(&mut data).unwrap();
+        assert_eq!(volume.read_file("DCIM/100CANON/IMG_0001.JPG").unwrap(), photo);
+        assert_eq!(volume.read_file("log.txt").unwrap(), b"fragmented log");
+        assert_eq!(volume.compare_fats(), Ok(vec![]));
+        assert_eq!(volume.space().total, MIN_CLUSTERS as u64 * 512);
+    }
+
+    #[test]
+    fn test_shrink_refused() {
+        let mut data = ImageBuilder::new().size(40 * MB).file("a.bin", vec![1u8; 36 * MB]).build();
+        let mut volume = Fat32Volume::new(&mut data).unwrap();
+        let total = volume.boot_sector.total_sectors();
+        assert_eq!(volume.shrink(total, &mut NoProgress), Err("Nouvelle taille trop grande"));
+        assert_eq!(volume.shrink(34, &mut NoProgress), Err("Nouvelle taille trop petite"));
+        // Room for the file, but too few clusters for FAT32.
+        assert_eq!(volume.shrink(32 * MB as u32 / 512, &mut NoProgress), Err("Nouvelle taille trop petite"));
+        let min = volume.min_total_sectors();
+        assert_eq!(volume.shrink(min - 1, &mut NoProgress), Err("Pas assez de place libre"));
+        assert_eq!(volume.boot_sector.total_sectors(), total);
+        assert_eq!(volume.read_file("a.bin").unwrap(), vec![1u8; 36 * MB]);
+
+        // A chain no file uses, past the new end, is not dropped silently.
+        let mut data = ImageBuilder::new()
+            .size(40 * MB)
+            .file("a.txt", *b"a")
+            .corrupt(Corruption::FatEntry { cluster: 70_000, value: crate::fat32::fat::FAT_EOC })
+            .build();
+        let mut volume = Fat32Volume::new(&mut data).unwrap();
+        let min = volume.min_total_sectors();
+        assert_eq!(volume.shrink(min, &mut NoProgress), Err("Chaîne perdue au-delà de la nouvelle taille"));
+    }
+}
@@ -0,0 +1,472 @@
+//! SD cards in SPI mode, driven through `embedded-hal`: initialization and single or
+//! multiple block transfers. `SdCard` is a `BlockDevice`, so firmware mounts a card with
+//! `Fat32Volume::from_device(&mut card)`.
+//!
+//! The bus is taken whole (`SpiBus` plus the chip-select pin) because the card must see
+//! clock cycles with chip select high before its first command.
+
+use core::cell::RefCell;
+
+use embedded_hal::delay::DelayNs;
+use embedded_hal::digital::OutputPin;
+use embedded_hal::spi::SpiBus;
+use log::{debug, warn};
+
+use super::error::{BLOCK_OUT_OF_RANGE, INVALID_BLOCK_SIZE};
+use super::io::{BlockDevice, BLOCK_SIZE};
+
+const CMD_GO_IDLE: u8 = 0;
+const CMD_SEND_IF_COND: u8 = 8;
+const CMD_SEND_CSD: u8 = 9;
+const CMD_STOP_TRANSMISSION: u8 = 12;
+const CMD_SET_BLOCKLEN: u8 = 16;
+const CMD_READ_SINGLE: u8 = 17;
+const CMD_READ_MULTIPLE: u8 = 18;
+const CMD_WRITE_SINGLE: u8 = 24;
+const CMD_WRITE_MULTIPLE: u8 = 25;
+const CMD_APP: u8 = 55;
+const CMD_READ_OCR: u8 = 58;
+const ACMD_SEND_OP_COND: u8 = 41;
+
+const R1_IDLE: u8 = 0x01;
+const R1_ILLEGAL_COMMAND: u8 = 0x04;
+const TOKEN_START_BLOCK: u8 = 0xFE;
+const TOKEN_START_MULTI_WRITE: u8 = 0xFC;
+const TOKEN_STOP_MULTI_WRITE: u8 = 0xFD;
+const DATA_ACCEPTED: u8 = 0x05;
+
+/// Polls of the bus before giving up on a card that stays busy or silent.
+const MAX_POLLS: u32 = 50_000;
+
+/// A card on an SPI bus. Commands are serialized through a `RefCell`, as reads only
+/// get `&self`.
+pub struct SdCard<SPI, CS, D> {
+    inner: RefCell<Inner<SPI, CS, D>>,
+    blocks: u64,
+}
+
+struct Inner<SPI, CS, D> {
+    spi: SPI,
+    cs: CS,
+    delay: D,
+    /// SDHC/SDXC cards are addressed in blocks, older ones in bytes.
+    block_addressing: bool,
+}
+
+impl<SPI: SpiBus, CS: OutputPin, D: DelayNs> SdCard<SPI, CS, D> {
+    /// Initializes the card. The bus should run at 400 kHz or less until this returns;
+    /// it can be sped up afterwards.
+    pub fn new(spi: SPI, cs: CS, delay: D) -> Result<Self, &'static str> {
+        let mut inner = Inner { spi, cs, delay, block_addressing: false };
+        let blocks = inner.with_card(Inner::init)?;
+        debug!("SD card ready: {} blocks, block addressing: {}", blocks, inner.block_addressing);
+        Ok(SdCard { inner: RefCell::new(inner), blocks })
+    }
+
+    /// Gives the bus, pin and delay back.
+    pub fn release(self) -> (SPI, CS, D) {
+        let inner = self.inner.into_inner();
+        (inner.spi, inner.cs, inner.delay)
+    }
+}
+
+impl<SPI: SpiBus, CS: OutputPin, D: DelayNs> BlockDevice for SdCard<SPI, CS, D> {
+    fn read_blocks(&self, start: u64, buf: &mut [u8]) -> Result<(), &'static str> {
+        if !buf.len().is_multiple_of(BLOCK_SIZE) { return Err(INVALID_BLOCK_SIZE); }
+        self.check_range(start, buf.len())?;
+        self.inner.borrow_mut().with_card(|card| card.read(start, buf))
+    }
+
+    fn write_blocks(&mut self, start: u64, buf: &[u8]) -> Result<(), &'static str> {
+        if !buf.len().is_multiple_of(BLOCK_SIZE) { return Err(INVALID_BLOCK_SIZE); }
+        self.check_range(start, buf.len())?;
+        self.inner.get_mut().with_card(|card| card.write(start, buf))
+    }
+
+    fn num_blocks(&self) -> u64 {
+        self.blocks
+    }
+}
+
+impl<SPI, CS, D> SdCard<SPI, CS, D> {
+    fn check_range(&self, start: u64, len: usize) -> Result<(), &'static str> {
+        match start.checked_add((len / BLOCK_SIZE) as u64) {
+            Some(end) if end <= self.blocks => Ok(()),
+            _ => Err(BLOCK_OUT_OF_RANGE),
+        }
+    }
+}
+
+impl<SPI: SpiBus, CS: OutputPin, D: DelayNs> Inner<SPI, CS, D> {
+    /// Runs `f` with the card selected, and deselects it whatever the outcome.
+    fn with_card<T>(&mut self, f: impl FnOnce(&mut Self) -> Result<T, &'static str>) -> Result<T, &'static str> {
+        self.cs.set_low().map_err(|_| "Erreur SPI")?;
+        let result = f(self);
+        let released = self.cs.set_high().map_err(|_| "Erreur SPI");
+        // One more byte so the card lets go of the data line.
+        let released = released.and_then(|_| self.exchange(0xFF).map(|_| ()));
+        result.and_then(|value| released.map(|_| value))
+    }
+
+    fn init(&mut self) -> Result<u64, &'static str> {
+        // At least 74 clock cycles with chip select high put the card in SPI mode.
+        self.cs.set_high().map_err(|_| "Erreur SPI")?;
+        self.spi.write(&[0xFF; 10]).map_err(|_| "Erreur SPI")?;
+        self.cs.set_low().map_err(|_| "Erreur SPI")?;
+
+        let mut attempts = 0;
+        while self.command(CMD_GO_IDLE, 0)? != R1_IDLE {
+            attempts += 1;
+            if attempts == 32 { return Err("Carte SD absente"); }
+            self.delay.delay_ms(1);
+        }
+
+        // Version 2 cards echo the check pattern; older ones don't know the command.
+        let version2 = self.command(CMD_SEND_IF_COND, 0x1AA)? & R1_ILLEGAL_COMMAND == 0;
+        if version2 {
+            let mut r7 = [0xFF; 4];
+            self.transfer(&mut r7)?;
+            if r7[3] != 0xAA { return Err("Carte SD non supportée"); }
+        }
+
+        let arg = if version2 { 0x4000_0000 } else { 0 };
+        let mut attempts = 0;
+        while self.app_command(ACMD_SEND_OP_COND, arg)? != 0 {
+            attempts += 1;
+            if attempts == 1000 { return Err("Délai dépassé sur la carte SD"); }
+            self.delay.delay_ms(1);
+        }
+
+        if version2 {
+            if self.command(CMD_READ_OCR, 0)? != 0 { return Err("Carte SD non supportée"); }
+            let mut ocr = [0xFF; 4];
+            self.transfer(&mut ocr)?;
+            self.block_addressing = ocr[0] & 0x40 != 0;
+        }
+        if !self.block_addressing && self.command(CMD_SET_BLOCKLEN, BLOCK_SIZE as u32)? != 0 {
+            return Err("Carte SD non supportée");
+        }
+
+        if self.command(CMD_SEND_CSD, 0)? != 0 { return Err("Carte SD non supportée"); }
+        let mut csd = [0u8; 16];
+        self.read_data(&mut csd)?;
+        csd_blocks(&csd).ok_or("Carte SD non supportée")
+    }
+
+    fn exchange(&mut self, byte: u8) -> Result<u8, &'static str> {
+        let mut buf = [byte];
+        self.spi.transfer_in_place(&mut buf).map_err(|_| "Erreur SPI")?;
+        Ok(buf[0])
+    }
+
+    /// Clocks `buf` out and replaces it with what the card sent back.
+    fn transfer(&mut self, buf: &mut [u8]) -> Result<(), &'static str> {
+        self.spi.transfer_in_place(buf).map_err(|_| "Erreur SPI")
+    }
+
+    /// Waits until the card stops holding the data line low.
+    fn wait_ready(&mut self) -> Result<(), &'static str> {
+        for _ in 0..MAX_POLLS {
+            if self.exchange(0xFF)? == 0xFF { return Ok(()); }
+        }
+        Err("Délai dépassé sur la carte SD")
+    }
+
+    /// Sends a command frame and returns the R1 response.
+    fn command(&mut self, cmd: u8, arg: u32) -> Result<u8, &'static str> {
+        // A multiple block read is stopped while the card is still sending.
+        if cmd != CMD_GO_IDLE && cmd != CMD_STOP_TRANSMISSION { self.wait_ready()?; }
+        // In SPI mode only CMD0 and CMD8 have their CRC checked.
+        let crc = match cmd {
+            CMD_GO_IDLE => 0x95,
+            CMD_SEND_IF_COND => 0x87,
+            _ => 0x01,
+        };
+        let a = arg.to_be_bytes();
+        self.spi.write(&[0x40 | cmd, a[0], a[1], a[2], a[3], crc]).map_err(|_| "Erreur SPI")?;
+        if cmd == CMD_STOP_TRANSMISSION { self.exchange(0xFF)?; }
+
+        for _ in 0..10 {
+            let r1 = self.exchange(0xFF)?;
+            if r1 & 0x80 == 0 { return Ok(r1); }
+        }
+        warn!("SD card didn't answer CMD{}", cmd);
+        Err("Délai dépassé sur la carte SD")
+    }
+
+    fn app_command(&mut self, cmd: u8, arg: u32) -> Result<u8, &'static str> {
+        self.command(CMD_APP, 0)?;
+        self.command(cmd, arg)
+    }
+
+    /// Reads one data packet: start token, `buf.len()` bytes, CRC.
+    fn read_data(&mut self, buf: &mut [u8]) -> Result<(), &'static str> {
+        let mut token = 0xFF;
+        for _ in 0..MAX_POLLS {
+            token = self.exchange(0xFF)?;
+            if token != 0xFF { break; }
+        }
+        if token != TOKEN_START_BLOCK {
+            warn!("SD card sent {:#04x} instead of a data token", token);
+            return Err("Erreur de lecture de la carte");
+        }
+        buf.fill(0xFF);
+        self.transfer(buf)?;
+        self.transfer(&mut [0xFF; 2])
+    }
+
+    /// Sends one data packet after `token` and waits for the card to store it.
+    fn write_data(&mut self, token: u8, block: &[u8]) -> Result<(), &'static str> {
+        self.spi.write(&[token]).map_err(|_| "Erreur SPI")?;
+        self.spi.write(block).map_err(|_| "Erreur SPI")?;
+        self.spi.write(&[0xFF; 2]).map_err(|_| "Erreur SPI")?;
+        let response = self.exchange(0xFF)?;
+        if response & 0x1F != DATA_ACCEPTED {
+            warn!("SD card rejected a block: {:#04x}", response);
+            return Err("Erreur d'écriture de la carte");
+        }
+        self.wait_ready()
+    }
+
+    fn address(&self, block: u64) -> Result<u32, &'static str> {
+        let address = if self.block_addressing { block } else { block * BLOCK_SIZE as u64 };
+        u32::try_from(address).map_err(|_| BLOCK_OUT_OF_RANGE)
+    }
+
+    fn read(&mut self, start: u64, buf: &mut [u8]) -> Result<(), &'static str> {
+        let address = self.address(start)?;
+        if buf.len() == BLOCK_SIZE {
+            if self.command(CMD_READ_SINGLE, address)? != 0 { return Err("Erreur de lecture de la carte"); }
+            return self.read_data(buf);
+        }
+        if self.command(CMD_READ_MULTIPLE, address)? != 0 { return Err("Erreur de lecture de la carte"); }
+        for block in buf.chunks_exact_mut(BLOCK_SIZE) {
+            self.read_data(block)?;
+        }
+        self.command(CMD_STOP_TRANSMISSION, 0)?;
+        Ok(())
+    }
+
+    fn write(&mut self, start: u64, buf: &[u8]) -> Result<(), &'static str> {
+        let address = self.address(start)?;
+        if buf.len() == BLOCK_SIZE {
+            if self.command(CMD_WRITE_SINGLE, address)? != 0 { return Err("Erreur d'écriture de la carte"); }
+            return self.write_data(TOKEN_START_BLOCK, buf);
+        }
+        if self.command(CMD_WRITE_MULTIPLE, address)? != 0 { return Err("Erreur d'écriture de la carte"); }
+        for block in buf.chunks_exact(BLOCK_SIZE) {
+            self.write_data(TOKEN_START_MULTI_WRITE, block)?;
+        }
+        self.spi.write(&[TOKEN_STOP_MULTI_WRITE, 0xFF]).map_err(|_| "Erreur SPI")?;
+        self.wait_ready()
+    }
+}
+
+/// Number of 512-byte blocks described by a CSD register, for both of its layouts.
+fn csd_blocks(csd: &[u8; 16]) -> Option<u64> {
+    match csd[0] >> 6 {
+        0 => {
+            let read_bl_len = (csd[5] & 0x0F) as u32;
+            let c_size = (((csd[6] & 0x03) as u64) << 10) | ((csd[7] as u64) << 2) | ((csd[8] >> 6) as u64);
+            let c_size_mult = (((csd[9] & 0x03) << 1) | (csd[10] >> 7)) as u32;
+            Some(((c_size + 1) << (c_size_mult + 2 + read_bl_len)) / BLOCK_SIZE as u64)
+        }
+        1 => {
+            let c_size = (((csd[7] & 0x3F) as u64) << 16) | ((csd[8] as u64) << 8) | csd[9] as u64;
+            Some((c_size + 1) * 1024)
+        }
+        _ => None,
+    }
+}
+
+#[cfg(all(test, feature = "alloc"))]
+mod tests {
+    use super::*;
+    use alloc::collections::VecDeque;
+    use alloc::vec;
+    use alloc::vec::Vec;
+    use core::convert::Infallible;
+    use crate::fat32::volume::tests::create_mock_volume;
+    use crate::fat32::volume::Fat32Volume;
+
+    enum State {
+        Command,
+        ReadMultiple(usize),
+        /// Block being received, waiting for its start token when empty.
+        Write { block: usize, multiple: bool, data: Vec<u8> },
+    }
+
+    /// Just enough of an SDHC card in SPI mode to serve the driver.
+    struct FakeCard {
+        image: Vec<u8>,
+        frame: Vec<u8>,
+        out: VecDeque<u8>,
+        state: State,
+        op_cond_polls: u32,
+    }
+
+    impl FakeCard {
+        fn new(image: Vec<u8>) -> Self {
+            FakeCard { image, frame: Vec::new(), out: VecDeque::new(), state: State::Command, op_cond_polls: 0 }
+        }
+
+        fn push_block(&mut self, block: usize) {
+            self.out.push_back(TOKEN_START_BLOCK);
+            self.out.extend(&self.image[block * BLOCK_SIZE..(block + 1) * BLOCK_SIZE]);
+            self.out.extend([0, 0]);
+        }
+
+        fn run(&mut self, cmd: u8, arg: u32) {
+            let block = arg as usize;
+            match cmd {
+                CMD_GO_IDLE => self.out.extend([0xFF, R1_IDLE]),
+                CMD_SEND_IF_COND => self.out.extend([R1_IDLE, 0, 0, 0x01, 0xAA]),
+                CMD_APP => self.out.push_back(R1_IDLE),
+                ACMD_SEND_OP_COND => {
+                    self.op_cond_polls += 1;
+                    self.out.push_back(if self.op_cond_polls < 3 { R1_IDLE } else { 0 });
+                }
+                CMD_READ_OCR => self.out.extend([0, 0xC0, 0xFF, 0x80, 0]),
+                CMD_SEND_CSD => {
+                    let c_size = (self.image.len() / BLOCK_SIZE / 1024 - 1) as u32;
+                    let mut csd = [0u8; 16];
+                    csd[0] = 0x40;
+                    csd[7..10].copy_from_slice(&c_size.to_be_bytes()[1..]);
+                    self.out.extend([0, 0xFF, TOKEN_START_BLOCK]);
+                    self.out.extend(csd);
+                    self.out.extend([0, 0]);
+                }
+                CMD_READ_SINGLE => {
+                    self.out.extend([0, 0xFF]);
+                    self.push_block(block);
+                }
+                CMD_READ_MULTIPLE => {
+                    self.out.push_back(0);
+                    self.state = State::ReadMultiple(block);
+                }
+                CMD_STOP_TRANSMISSION => {
+                    self.out.clear();
+                    self.out.extend([0xFF, 0]);
+                    self.state = State::Command;
+                }
+                CMD_WRITE_SINGLE | CMD_WRITE_MULTIPLE => {
+                    self.out.push_back(0);
+                    self.state = State::Write { block, multiple: cmd == CMD_WRITE_MULTIPLE, data: Vec::new() };
+                }
+                _ => self.out.push_back(R1_ILLEGAL_COMMAND),
+            }
+        }
+
+        fn receive(&mut self, byte: u8) {
+            match &mut self.state {
+                State::Write { block, multiple, data } => {
+                    if data.is_empty() && byte == TOKEN_STOP_MULTI_WRITE && *multiple {
+                        self.out.extend([0, 0xFF]);
+                        self.state = State::Command;
+                    } else if !data.is_empty() || byte == TOKEN_START_BLOCK || byte == TOKEN_START_MULTI_WRITE {
+                        data.push(byte);
+                        // Token, block and CRC.
+                        if data.len() == BLOCK_SIZE + 3 {
+                            let at = *block * BLOCK_SIZE;
+                            self.image[at..at + BLOCK_SIZE].copy_from_slice(&data[1..BLOCK_SIZE + 1]);
+                            *block += 1;
+                            data.clear();
+                            self.out.extend([DATA_ACCEPTED, 0, 0xFF]);
+                            if !*multiple { self.state = State::Command; }
+                        }
+                    }
+                }
+                _ if self.frame.is_empty() && byte & 0xC0 != 0x40 => {}
+                _ => {
+                    self.frame.push(byte);
+                    if self.frame.len() == 6 {
+                        let arg = u32::from_be_bytes(self.frame[1..5].try_into().unwrap());
+                        let cmd = self.frame[0] & 0x3F;
+                        self.frame.clear();
+                        self.run(cmd, arg);
+                    }
+                }
+            }
+        }
+    }
+
+    impl embedded_hal::spi::ErrorType for FakeCard {
+        type Error = Infallible;
+    }
+
+    impl SpiBus for FakeCard {
+        fn read(&mut self, words: &mut [u8]) -> Result<(), Infallible> {
+            words.fill(0xFF);
+            self.transfer_in_place(words)
+        }
+
+        fn write(&mut self, words: &[u8]) -> Result<(), Infallible> {
+            self.transfer_in_place(&mut words.to_vec())
+        }
+
+        fn transfer(&mut self, read: &mut [u8], write: &[u8]) -> Result<(), Infallible> {
+            let mut buf = write.to_vec();
+            self.transfer_in_place(&mut buf)?;
+            read.copy_from_slice(&buf[..read.len()]);
+            Ok(())
+        }
+
+        fn transfer_in_place(&mut self, words: &mut [u8]) -> Result<(), Infallible> {
+            for word in words {
+                if let State::ReadMultiple(block) = self.state {
+                    if self.out.is_empty() {
+                        self.push_block(block);
+                        self.state = State::ReadMultiple(block + 1);
+                    }
+                }
+                let reply = self.out.pop_front().unwrap_or(0xFF);
+                self.receive(*word);
+                *word = reply;
+            }
+            Ok(())
+        }
+
+        fn flush(&mut self) -> Result<(), Infallible> {
+            Ok(())
+        }
+    }
+
+    struct Pin;
+
+    impl embedded_hal::digital::ErrorType for Pin {
+        type Error = Infallible;
+    }
+
+    impl OutputPin for Pin {
+        fn set_low(&mut self) -> Result<(), Infallible> { Ok(()) }
+        fn set_high(&mut self) -> Result<(), Infallible> { Ok(()) }
+    }
+
+    struct NoDelay;
+
+    impl DelayNs for NoDelay {
+        fn delay_ns(&mut self, _: u32) {}
+    }
+
+    #[test]
+    fn test_sd_card_over_spi() {
+        let mut card = SdCard::new(FakeCard::new(create_mock_volume()), Pin, NoDelay).unwrap();
+        assert_eq!(card.num_blocks(), 2048);
+
+        let pattern: Vec<u8> = (0..3 * BLOCK_SIZE).map(|i| (i % 251) as u8).collect();
+        card.write_blocks(1000, &pattern).unwrap();
+        let mut back = vec![0u8; 3 * BLOCK_SIZE];
+        card.read_blocks(1000, &mut back).unwrap();
+        assert_eq!(back, pattern);
+        assert_eq!(card.read_blocks(2047, &mut back), Err(BLOCK_OUT_OF_RANGE));
+
+        {
+            let mut volume = Fat32Volume::from_device(&mut card).unwrap();
+            volume.create_file("boot.cfg", b"console=serial0", false).unwrap();
+        }
+        let (spi, _, _) = card.release();
+        let mut image = spi.image;
+        let volume = Fat32Volume::new(&mut image).unwrap();
+        assert_eq!(volume.read_file("boot.cfg").unwrap(), b"console=serial0");
+    }
+}
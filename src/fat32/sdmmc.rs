@@ -0,0 +1,128 @@
+//! Lets firmware that already drives an SD card through `embedded-sdmmc` mount the
+//! card with this crate: `Fat32Volume::from_device(&mut SdmmcDevice(card))`.
+
+use embedded_sdmmc::{Block, BlockDevice as CardDevice, BlockIdx};
+use log::warn;
+
+use super::error::{BLOCK_OUT_OF_RANGE, INVALID_BLOCK_SIZE};
+use super::io::{BlockDevice, BLOCK_SIZE};
+
+/// An `embedded_sdmmc::BlockDevice` seen as a `BlockDevice`. Blocks go through the
+/// driver one at a time, so only one 512-byte buffer lives on the stack.
+pub struct SdmmcDevice<D>(pub D);
+
+fn block_index(start: u64, i: usize) -> Result<BlockIdx, &'static str> {
+    u32::try_from(start + i as u64).map(BlockIdx).map_err(|_| BLOCK_OUT_OF_RANGE)
+}
+
+impl<D: CardDevice> BlockDevice for SdmmcDevice<D> {
+    fn read_blocks(&self, start: u64, buf: &mut [u8]) -> Result<(), &'static str> {
+        if !buf.len().is_multiple_of(BLOCK_SIZE) { return Err(INVALID_BLOCK_SIZE); }
+        let mut block = [Block::new()];
+        for (i, chunk) in buf.chunks_exact_mut(BLOCK_SIZE).enumerate() {
+            self.0.read(&mut block, block_index(start, i)?).map_err(|e| {
+                warn!("SD card read of block {} failed: {:?}", start + i as u64, e);
+                "Erreur de lecture de la carte"
+            })?;
+            chunk.copy_from_slice(&block[0].contents);
+        }
+        Ok(())
+    }
+
+    fn write_blocks(&mut self, start: u64, buf: &[u8]) -> Result<(), &'static str> {
+        if !buf.len().is_multiple_of(BLOCK_SIZE) { return Err(INVALID_BLOCK_SIZE); }
+        let mut block = [Block::new()];
+        for (i, chunk) in buf.chunks_exact(BLOCK_SIZE).enumerate() {
+            block[0].contents.copy_from_slice(chunk);
+            self.0.write(&block, block_index(start, i)?).map_err(|e| {
+                warn!("SD card write of block {} failed: {:?}", start + i as u64, e);
+                "Erreur d'écriture de la carte"
+            })?;
+        }
+        Ok(())
+    }
+
+    /// A card whose size can't be read is treated as empty, so mounting it fails.
+    fn num_blocks(&self) -> u64 {
+        match self.0.num_blocks() {
+            Ok(count) => count.0 as u64,
+            Err(e) => {
+                warn!("SD card size unavailable: {:?}", e);
+                0
+            }
+        }
+    }
+}
+
+#[cfg(all(test, feature = "alloc"))]
+mod tests {
+    use super::*;
+    use alloc::vec;
+    use alloc::vec::Vec;
+    use core::cell::RefCell;
+    use embedded_sdmmc::BlockCount;
+    use crate::fat32::volume::tests::create_mock_volume;
+    use crate::fat32::volume::Fat32Volume;
+
+    /// A card in memory, with the interior mutability real drivers use.
+    struct FakeCard(RefCell<Vec<u8>>);
+
+    impl CardDevice for FakeCard {
+        type Error = ();
+
+        fn read(&self, blocks: &mut [Block], start: BlockIdx) -> Result<(), ()> {
+            let data = self.0.borrow();
+            for (i, block) in blocks.iter_mut().enumerate() {
+                let at = (start.0 as usize + i) * BLOCK_SIZE;
+                block.contents.copy_from_slice(data.get(at..at + BLOCK_SIZE).ok_or(())?);
+            }
+            Ok(())
+        }
+
+        fn write(&self, blocks: &[Block], start: BlockIdx) -> Result<(), ()> {
+            let mut data = self.0.borrow_mut();
+            for (i, block) in blocks.iter().enumerate() {
+                let at = (start.0 as usize + i) * BLOCK_SIZE;
+                data.get_mut(at..at + BLOCK_SIZE).ok_or(())?.copy_from_slice(&block.contents);
+            }
+            Ok(())
+        }
+
+        fn num_blocks(&self) -> Result<BlockCount, ()> {
+            Ok(BlockCount((self.0.borrow().len() / BLOCK_SIZE) as u32))
+        }
+    }
+
+    #[test]
+    fn test_volume_on_sdmmc_card() {
+        let mut card = SdmmcDevice(FakeCard(RefCell::new(create_mock_volume())));
+        {
+            let mut volume = Fat32Volume::from_device(&mut card).unwrap();
+            volume.create_file("config.txt", b"baud=115200", false).unwrap();
+        }
+        let mut image = card.0 .0.into_inner();
+        let volume = Fat32Volume::new(&mut image).unwrap();
+        assert_eq!(volume.read_file("config.txt").unwrap(), b"baud=115200");
+
+        let mut empty = SdmmcDevice(FakeCard(RefCell::new(vec![0u8; 100])));
+        assert!(Fat32Volume::from_device(&mut empty).is_err());
+    }
+
+    #[test]
+    fn test_card_errors() {
+        let mut card = SdmmcDevice(FakeCard(RefCell::new(vec![0u8; 4 * BLOCK_SIZE])));
+        assert_eq!(card.num_blocks(), 4);
+        let mut buf = [7u8; 2 * BLOCK_SIZE];
+        card.write_blocks(2, &buf).unwrap();
+        buf.fill(0);
+        card.read_blocks(2, &mut buf).unwrap();
+        assert!(buf.iter().all(|&b| b == 7));
+
+        // The second block is past the end of the card.
+        assert_eq!(card.read_blocks(3, &mut buf), Err("Erreur de lecture de la carte"));
+        assert_eq!(card.write_blocks(3, &buf), Err("Erreur d'écriture de la carte"));
+        assert_eq!(card.read_blocks(0, &mut buf[..100]), Err(INVALID_BLOCK_SIZE));
+        assert_eq!(card.write_blocks(0, &buf[..100]), Err(INVALID_BLOCK_SIZE));
+        assert_eq!(card.read_blocks(u32::MAX as u64 + 1, &mut buf), Err(BLOCK_OUT_OF_RANGE));
+    }
+}
@@ -0,0 +1,142 @@
+extern crate alloc;
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::vec::Vec;
+use alloc::format;
+
+use super::checksum::HashAlgorithm;
+use super::time::DateTime;
+use super::volume::Fat32Volume;
+
+/// State of one file or directory when the snapshot was taken.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SnapshotEntry {
+    pub is_dir: bool,
+    pub size: u32,
+    pub modified: DateTime,
+    /// SHA-256 of the contents, empty for directories.
+    pub hash: String,
+}
+
+/// Every path of a volume with what is needed to tell later whether it changed.
+/// Paths are relative to the root, without a leading `/`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Snapshot {
+    pub entries: BTreeMap<String, SnapshotEntry>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Change {
+    Added,
+    Removed,
+    /// The entry is still there; the flags say what differs.
+    Modified { content: bool, size: bool, time: bool },
+}
+
+impl Snapshot {
+    /// One line per entry: `d|f size modified hash path`, the time as packed FAT date and
+    /// time and the hash as `-` for directories. The path comes last as it may hold spaces.
+    pub fn to_text(&self) -> String {
+        let mut text = String::new();
+        for (path, entry) in &self.entries {
+            let (date, time) = entry.modified.to_fat();
+            let hash = if entry.hash.is_empty() { "-" } else { &entry.hash };
+            text += &format!("{} {} {:04x}{:04x} {} {}\n", if entry.is_dir { 'd' } else { 'f' }, entry.size, date, time, hash, path);
+        }
+        text
+    }
+
+    pub fn parse(text: &str) -> Result<Self, &'static str> {
+        let mut entries = BTreeMap::new();
+        for line in text.lines().filter(|l| !l.is_empty()) {
+            let mut fields = line.splitn(5, ' ');
+            let mut next = || fields.next().ok_or("Instantané invalide");
+            let is_dir = match next()? { "d" => true, "f" => false, _ => return Err("Instantané invalide") };
+            let size = next()?.parse().map_err(|_| "Instantané invalide")?;
+            let packed = u32::from_str_radix(next()?, 16).map_err(|_| "Instantané invalide")?;
+            let hash = match next()? { "-" => String::new(), hash => hash.into() };
+            let path = next()?;
+            let modified = DateTime::from_fat((packed >> 16) as u16, packed as u16);
+            entries.insert(path.into(), SnapshotEntry { is_dir, size, modified, hash });
+        }
+        Ok(Snapshot { entries })
+    }
+
+    /// What changed from `self` to `newer`, by path in order.
+    pub fn diff(&self, newer: &Snapshot) -> Vec<(String, Change)> {
+        let mut changes = Vec::new();
+        for (path, old) in &self.entries {
+            match newer.entries.get(path) {
+                None => changes.push((path.clone(), Change::Removed)),
+                Some(new) if new.is_dir != old.is_dir => {
+                    changes.push((path.clone(), Change::Removed));
+                    changes.push((path.clone(), Change::Added));
+                }
+                Some(new) => {
+                    let change = Change::Modified { content: new.hash != old.hash, size: new.size != old.size, time: new.modified != old.modified };
+                    if change != (Change::Modified { content: false, size: false, time: false }) {
+                        changes.push((path.clone(), change));
+                    }
+                }
+            }
+        }
+        for path in newer.entries.keys().filter(|p| !self.entries.contains_key(*p)) {
+            changes.push((path.clone(), Change::Added));
+        }
+        changes.sort_by(|a, b| a.0.cmp(&b.0));
+        changes
+    }
+}
+
+impl<'a> Fat32Volume<'a> {
+    /// Records every path of the volume with its size, modification time and hash.
+    pub fn snapshot(&self) -> Result<Snapshot, &'static str> {
+        let mut entries = BTreeMap::new();
+        for item in self.walk("/")? {
+            let (_, path, metadata) = item?;
+            if metadata.is_dir() {
+                let entry = SnapshotEntry { is_dir: true, size: 0, modified: metadata.modified, hash: String::new() };
+                entries.insert(path.trim_start_matches('/').into(), entry);
+            }
+        }
+        let files = self.map_files("/", |volume, metadata| {
+            let hash = volume.hash_chain(metadata.first_cluster, metadata.size, HashAlgorithm::Sha256);
+            (metadata.size, metadata.modified, hash)
+        })?;
+        for (path, (size, modified, hash)) in files {
+            entries.insert(path, SnapshotEntry { is_dir: false, size, modified, hash: hash? });
+        }
+        Ok(Snapshot { entries })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fat32::volume::tests::create_mock_volume;
+
+    #[test]
+    fn test_snapshot_diff() {
+        let mut data = create_mock_volume();
+        let mut volume = Fat32Volume::new(&mut data).unwrap();
+        volume.create_directory("logs").unwrap();
+        volume.create_file("logs/a b.txt", b"one", false).unwrap();
+        volume.create_file("keep.txt", b"same", false).unwrap();
+        volume.create_file("gone.txt", b"bye", false).unwrap();
+        let before = volume.snapshot().unwrap();
+        assert_eq!(Snapshot::parse(&before.to_text()).unwrap(), before);
+
+        volume.create_file("logs/a b.txt", b"two", true).unwrap();
+        volume.remove_file("gone.txt").unwrap();
+        volume.create_file("logs/new.txt", b"", false).unwrap();
+        let after = volume.snapshot().unwrap();
+
+        let changes = before.diff(&after);
+        assert_eq!(changes, [
+            ("gone.txt".into(), Change::Removed),
+            ("logs/a b.txt".into(), Change::Modified { content: true, size: false, time: false }),
+            ("logs/new.txt".into(), Change::Added),
+        ]);
+        assert!(after.diff(&after).is_empty());
+    }
+}
@@ -0,0 +1,517 @@
+//! What a volume reads and writes: an image held in memory, or any `BlockDevice` such as
+//! an SD card. Offsets are in bytes; accesses that don't fall on block boundaries go
+//! through a one-block buffer.
+//!
+//! Every backend goes through this one enum rather than a type parameter on
+//! `Fat32Volume`, so traversal, allocation and listing exist once and the volume type
+//! stays the same whatever holds the image: a slice, a host file behind a `BlockDevice`,
+//! or a card driver.
+
+use core::fmt;
+use core::ops::{AddAssign, Deref, DerefMut, Range};
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use super::error::BLOCK_OUT_OF_RANGE;
+use super::io::{BlockDevice, BLOCK_SIZE};
+#[cfg(feature = "alloc")]
+use super::journal::Journaled;
+
+/// A block device a volume can sit on. It needn't be `Sync`, even with the `parallel`
+/// feature: threads only share images held in memory, see `Storage::Shared`.
+pub type DynBlockDevice<'d> = dyn BlockDevice + 'd;
+
+pub enum Storage<'a> {
+    Memory(&'a mut [u8]),
+    /// An image in memory that is only read, such as the one `map_files` hands to each
+    /// of its threads. Writes fail.
+    Shared(&'a [u8]),
+    Device(&'a mut DynBlockDevice<'a>),
+    /// Another storage whose writes are held back until committed, see `journal`.
+    #[cfg(feature = "alloc")]
+    Journaled(alloc::boxed::Box<Journaled<'a>>),
+}
+
+impl Storage<'_> {
+    /// Size in bytes.
+    pub fn len(&self) -> usize {
+        match self {
+            Storage::Memory(data) => data.len(),
+            Storage::Shared(data) => data.len(),
+            Storage::Device(device) => (device.num_blocks() as usize).saturating_mul(BLOCK_SIZE),
+            #[cfg(feature = "alloc")]
+            Storage::Journaled(journaled) => journaled.inner.len(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The bytes of `range` without copying them, when the image is in memory.
+    pub fn slice(&self, range: Range<usize>) -> Option<&[u8]> {
+        match self {
+            Storage::Memory(data) => data.get(range),
+            Storage::Shared(data) => data.get(range),
+            Storage::Device(_) => None,
+            #[cfg(feature = "alloc")]
+            Storage::Journaled(journaled) if journaled.touches(&range) => None,
+            #[cfg(feature = "alloc")]
+            Storage::Journaled(journaled) => journaled.inner.slice(range),
+        }
+    }
+
+    fn check(&self, offset: usize, len: usize) -> Result<(), &'static str> {
+        match offset.checked_add(len) {
+            Some(end) if end <= self.len() => Ok(()),
+            _ => Err(BLOCK_OUT_OF_RANGE),
+        }
+    }
+
+    pub fn read(&self, offset: usize, buf: &mut [u8]) -> Result<(), &'static str> {
+        self.check(offset, buf.len())?;
+        let device = match self {
+            Storage::Memory(data) => {
+                buf.copy_from_slice(&data[offset..offset + buf.len()]);
+                return Ok(());
+            }
+            Storage::Shared(data) => {
+                buf.copy_from_slice(&data[offset..offset + buf.len()]);
+                return Ok(());
+            }
+            Storage::Device(device) => device,
+            #[cfg(feature = "alloc")]
+            Storage::Journaled(journaled) => return journaled.read(offset, buf),
+        };
+
+        let mut done = 0;
+        let mut block = [0u8; BLOCK_SIZE];
+        while done < buf.len() {
+            let at = offset + done;
+            let (index, skip) = (at / BLOCK_SIZE, at % BLOCK_SIZE);
+            let whole = (buf.len() - done) / BLOCK_SIZE * BLOCK_SIZE;
+            if skip == 0 && whole > 0 {
+                device.read_blocks(index as u64, &mut buf[done..done + whole])?;
+                done += whole;
+                continue;
+            }
+            let len = (BLOCK_SIZE - skip).min(buf.len() - done);
+            device.read_blocks(index as u64, &mut block)?;
+            buf[done..done + len].copy_from_slice(&block[skip..skip + len]);
+            done += len;
+        }
+        Ok(())
+    }
+
+    pub fn write(&mut self, offset: usize, buf: &[u8]) -> Result<(), &'static str> {
+        self.check(offset, buf.len())?;
+        let device = match self {
+            Storage::Memory(data) => {
+                data[offset..offset + buf.len()].copy_from_slice(buf);
+                return Ok(());
+            }
+            Storage::Shared(_) => return Err("Image en lecture seule"),
+            Storage::Device(device) => device,
+            #[cfg(feature = "alloc")]
+            Storage::Journaled(journaled) => return journaled.write(offset, buf),
+        };
+
+        let mut done = 0;
+        let mut block = [0u8; BLOCK_SIZE];
+        while done < buf.len() {
+            let at = offset + done;
+            let (index, skip) = (at / BLOCK_SIZE, at % BLOCK_SIZE);
+            let whole = (buf.len() - done) / BLOCK_SIZE * BLOCK_SIZE;
+            if skip == 0 && whole > 0 {
+                device.write_blocks(index as u64, &buf[done..done + whole])?;
+                done += whole;
+                continue;
+            }
+            // Partial block: keep the bytes around the ones written.
+            let len = (BLOCK_SIZE - skip).min(buf.len() - done);
+            device.read_blocks(index as u64, &mut block)?;
+            block[skip..skip + len].copy_from_slice(&buf[done..done + len]);
+            device.write_blocks(index as u64, &block)?;
+            done += len;
+        }
+        Ok(())
+    }
+
+    /// Sets `len` bytes from `offset` to `byte`.
+    pub fn fill(&mut self, offset: usize, len: usize, byte: u8) -> Result<(), &'static str> {
+        self.check(offset, len)?;
+        if let Storage::Memory(data) = self {
+            data[offset..offset + len].fill(byte);
+            return Ok(());
+        }
+        let block = [byte; BLOCK_SIZE];
+        let mut done = 0;
+        while done < len {
+            let n = (BLOCK_SIZE - (offset + done) % BLOCK_SIZE).min(len - done);
+            self.write(offset + done, &block[..n])?;
+            done += n;
+        }
+        Ok(())
+    }
+
+    /// Copies the bytes of `src` to `dest`. The two ranges may overlap.
+    pub fn copy_within(&mut self, src: Range<usize>, dest: usize) -> Result<(), &'static str> {
+        self.check(src.start, src.len())?;
+        self.check(dest, src.len())?;
+        if let Storage::Memory(data) = self {
+            data.copy_within(src, dest);
+            return Ok(());
+        }
+        // Block by block, from the end when copying forward over the source.
+        let mut block = [0u8; BLOCK_SIZE];
+        let len = src.len();
+        let mut done = 0;
+        while done < len {
+            let n = BLOCK_SIZE.min(len - done);
+            let at = if dest > src.start { len - done - n } else { done };
+            self.read(src.start + at, &mut block[..n])?;
+            self.write(dest + at, &block[..n])?;
+            done += n;
+        }
+        Ok(())
+    }
+}
+
+/// What a volume has done since it was mounted, see `Fat32Volume::stats`. Sectors are
+/// counted in 512-byte blocks, whatever the sector size of the volume.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct IoStats {
+    pub sectors_read: u64,
+    pub sectors_written: u64,
+    pub clusters_allocated: u64,
+    pub clusters_freed: u64,
+}
+
+impl AddAssign for IoStats {
+    fn add_assign(&mut self, other: IoStats) {
+        self.sectors_read += other.sectors_read;
+        self.sectors_written += other.sectors_written;
+        self.clusters_allocated += other.clusters_allocated;
+        self.clusters_freed += other.clusters_freed;
+    }
+}
+
+impl fmt::Display for IoStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, " - Sectors Read: {}", self.sectors_read)?;
+        writeln!(f, " - Sectors Written: {}", self.sectors_written)?;
+        writeln!(f, " - Clusters Allocated: {}", self.clusters_allocated)?;
+        write!(f, " - Clusters Freed: {}", self.clusters_freed)
+    }
+}
+
+/// The counters behind `IoStats`, atomic so that reads through `&self` can bump them.
+#[derive(Debug, Default)]
+pub struct IoCounters {
+    pub(super) sectors_read: AtomicU64,
+    pub(super) sectors_written: AtomicU64,
+    pub(super) clusters_allocated: AtomicU64,
+    pub(super) clusters_freed: AtomicU64,
+}
+
+impl IoCounters {
+    pub(super) fn bump(counter: &AtomicU64, n: u64) {
+        counter.fetch_add(n, Ordering::Relaxed);
+    }
+
+    /// Adds these counts to `total` and starts them again from zero.
+    #[cfg(feature = "parallel")]
+    pub(super) fn drain_into(&self, total: &IoCounters) {
+        let pairs = [
+            (&self.sectors_read, &total.sectors_read),
+            (&self.sectors_written, &total.sectors_written),
+            (&self.clusters_allocated, &total.clusters_allocated),
+            (&self.clusters_freed, &total.clusters_freed),
+        ];
+        for (counter, total) in pairs {
+            IoCounters::bump(total, counter.swap(0, Ordering::Relaxed));
+        }
+    }
+
+    pub fn get(&self) -> IoStats {
+        let get = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+        IoStats {
+            sectors_read: get(&self.sectors_read),
+            sectors_written: get(&self.sectors_written),
+            clusters_allocated: get(&self.clusters_allocated),
+            clusters_freed: get(&self.clusters_freed),
+        }
+    }
+}
+
+/// What part of the volume an access was for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IoReason {
+    BootSector,
+    /// The other reserved sectors: FSInfo, backup boot sector, journal.
+    Reserved,
+    Fat,
+    Directory,
+    Data,
+}
+
+impl IoReason {
+    pub fn name(&self) -> &'static str {
+        match self {
+            IoReason::BootSector => "boot sector",
+            IoReason::Reserved => "reserved",
+            IoReason::Fat => "FAT",
+            IoReason::Directory => "directory",
+            IoReason::Data => "data",
+        }
+    }
+}
+
+/// One read or write, as handed to the tracer set by `Fat32Volume::set_io_tracer`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IoEvent {
+    pub write: bool,
+    /// First 512-byte block accessed, and how many.
+    pub sector: u64,
+    pub count: u64,
+    pub reason: IoReason,
+}
+
+impl fmt::Display for IoEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} sector {} +{} ({})", if self.write { "write" } else { "read " }, self.sector, self.count, self.reason.name())
+    }
+}
+
+pub type IoTracer = fn(&IoEvent);
+
+/// Blocks touched by `len` bytes from `offset`.
+fn blocks(offset: usize, len: usize) -> u64 {
+    if len == 0 { return 0; }
+    ((offset + len - 1) / BLOCK_SIZE - offset / BLOCK_SIZE + 1) as u64
+}
+
+/// A storage that counts the sectors read and written through it, and hands each access
+/// to a tracer when there is one. Everything else goes straight to the storage it wraps.
+pub struct Counted<'a> {
+    inner: Storage<'a>,
+    pub(super) counters: IoCounters,
+    pub(super) tracer: Option<IoTracer>,
+    /// Where the FATs and the data area start, to tell what an access was for.
+    pub(super) layout: (usize, usize),
+}
+
+impl<'a> Counted<'a> {
+    pub fn new(inner: Storage<'a>) -> Self {
+        Counted { inner, counters: IoCounters::default(), tracer: None, layout: (0, 0) }
+    }
+
+    /// Counts an access, and traces it for `reason`, or for the area it falls in.
+    fn record(&self, write: bool, offset: usize, len: usize, reason: Option<IoReason>) {
+        let count = blocks(offset, len);
+        IoCounters::bump(if write { &self.counters.sectors_written } else { &self.counters.sectors_read }, count);
+        let Some(tracer) = self.tracer else { return };
+        let reason = reason.unwrap_or(match offset {
+            o if o < BLOCK_SIZE => IoReason::BootSector,
+            o if o < self.layout.0 => IoReason::Reserved,
+            o if o < self.layout.1 => IoReason::Fat,
+            _ => IoReason::Data,
+        });
+        tracer(&IoEvent { write, sector: (offset / BLOCK_SIZE) as u64, count, reason });
+    }
+
+    pub fn slice(&self, range: Range<usize>) -> Option<&[u8]> {
+        self.record(false, range.start, range.len(), None);
+        self.inner.slice(range)
+    }
+
+    pub fn read(&self, offset: usize, buf: &mut [u8]) -> Result<(), &'static str> {
+        self.record(false, offset, buf.len(), None);
+        self.inner.read(offset, buf)
+    }
+
+    pub fn write(&mut self, offset: usize, buf: &[u8]) -> Result<(), &'static str> {
+        self.record(true, offset, buf.len(), None);
+        self.inner.write(offset, buf)
+    }
+
+    pub fn fill(&mut self, offset: usize, len: usize, byte: u8) -> Result<(), &'static str> {
+        self.record(true, offset, len, None);
+        self.inner.fill(offset, len, byte)
+    }
+
+    pub fn copy_within(&mut self, src: Range<usize>, dest: usize) -> Result<(), &'static str> {
+        self.record(false, src.start, src.len(), None);
+        self.record(true, dest, src.len(), None);
+        self.inner.copy_within(src, dest)
+    }
+
+    /// `read`, for a directory: the data area can't tell them from file contents.
+    pub fn read_dir(&self, offset: usize, buf: &mut [u8]) -> Result<(), &'static str> {
+        self.record(false, offset, buf.len(), Some(IoReason::Directory));
+        self.inner.read(offset, buf)
+    }
+
+    /// `write`, for a directory.
+    pub fn write_dir(&mut self, offset: usize, buf: &[u8]) -> Result<(), &'static str> {
+        self.record(true, offset, buf.len(), Some(IoReason::Directory));
+        self.inner.write(offset, buf)
+    }
+
+    /// `fill`, for a directory.
+    pub fn fill_dir(&mut self, offset: usize, len: usize, byte: u8) -> Result<(), &'static str> {
+        self.record(true, offset, len, Some(IoReason::Directory));
+        self.inner.fill(offset, len, byte)
+    }
+}
+
+impl<'a> Deref for Counted<'a> {
+    type Target = Storage<'a>;
+
+    fn deref(&self) -> &Storage<'a> {
+        &self.inner
+    }
+}
+
+impl DerefMut for Counted<'_> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.inner
+    }
+}
+
+#[cfg(all(test, feature = "alloc"))]
+mod tests {
+    use super::*;
+    use alloc::vec;
+    use alloc::vec::Vec;
+    use alloc::format;
+    use crate::fat32::checksum::HashAlgorithm;
+    use crate::fat32::volume::tests::create_mock_volume;
+    use crate::fat32::volume::Fat32Volume;
+
+    /// A device that only accepts whole blocks, like a card would.
+    struct Card(Vec<u8>);
+
+    impl BlockDevice for Card {
+        fn read_blocks(&self, start: u64, buf: &mut [u8]) -> Result<(), &'static str> {
+            self.0.read_blocks(start, buf)
+        }
+
+        fn write_blocks(&mut self, start: u64, buf: &[u8]) -> Result<(), &'static str> {
+            self.0.write_blocks(start, buf)
+        }
+
+        fn num_blocks(&self) -> u64 {
+            self.0.num_blocks()
+        }
+    }
+
+    #[test]
+    fn test_unaligned_device_access() {
+        let mut card = Card(vec![0u8; 4 * BLOCK_SIZE]);
+        let mut memory = vec![0u8; 4 * BLOCK_SIZE];
+        let pattern: Vec<u8> = (0..1000).map(|i| (i % 253) as u8).collect();
+
+        // The same operations on both storages must leave the same bytes.
+        {
+            let mut storages = [Storage::Device(&mut card), Storage::Memory(&mut memory)];
+            for storage in &mut storages {
+                storage.write(300, &pattern).unwrap();
+                storage.fill(10, 5, 0xAA).unwrap();
+                storage.copy_within(300..1300, 700).unwrap();
+                storage.copy_within(800..1500, 100).unwrap();
+                assert_eq!(storage.write(2000, &pattern), Err(BLOCK_OUT_OF_RANGE));
+            }
+            let mut a = vec![0u8; 1500];
+            let mut b = vec![0u8; 1500];
+            storages[0].read(17, &mut a).unwrap();
+            storages[1].read(17, &mut b).unwrap();
+            assert_eq!(a, b);
+        }
+        assert_eq!(card.0, memory);
+    }
+
+    #[test]
+    fn test_shared_image_is_read_only() {
+        let data = vec![7u8; 2 * BLOCK_SIZE];
+        let mut storage = Storage::Shared(&data);
+        let mut buf = [0u8; 3];
+        storage.read(510, &mut buf).unwrap();
+        assert_eq!(buf, [7; 3]);
+        assert_eq!(storage.write(0, b"x"), Err("Image en lecture seule"));
+        assert_eq!(storage.fill(0, 1, 0), Err("Image en lecture seule"));
+        assert_eq!(storage.slice(0..2), Some(&[7u8, 7][..]));
+    }
+
+    #[test]
+    fn test_volume_on_device() {
+        let mut card = Card(create_mock_volume());
+        {
+            let mut volume = Fat32Volume::from_device(&mut card).unwrap();
+            let dir = volume.create_directory("logs").unwrap();
+            let content: Vec<u8> = (0..3000).map(|i| i as u8).collect();
+            volume.create_file_in(dir, "boot.log", &content, false).unwrap();
+            assert_eq!(volume.read_file("logs/boot.log").unwrap(), content);
+            volume.remove_file("logs/boot.log").unwrap();
+            volume.create_file("logs/boot.log", b"again", false).unwrap();
+            // A device isn't shared between threads, even with the `parallel` feature.
+            let mut manifest = Vec::new();
+            volume.checksum_tree("logs", HashAlgorithm::Md5, &mut |p, d| manifest.push(format!("{}  {}", d, p))).unwrap();
+            assert_eq!(manifest, ["639849f6b368019778991b32434354fc  boot.log"]);
+        }
+        let volume = Fat32Volume::new(&mut card.0).unwrap();
+        assert_eq!(volume.read_file("/logs/boot.log").unwrap(), b"again");
+
+        let mut empty = Card(Vec::new());
+        assert_eq!(Fat32Volume::from_device(&mut empty).err(), Some(BLOCK_OUT_OF_RANGE));
+    }
+
+    #[test]
+    fn test_same_results_on_every_storage() {
+        let content: Vec<u8> = (0..5000).map(|i| (i % 199) as u8).collect();
+        let run = |volume: &mut Fat32Volume| {
+            volume.create_directory("a").unwrap();
+            volume.create_file("a/b.bin", &content, false).unwrap();
+            volume.write_at("a/b.bin", 4990, b"0123456789ABCDEF").unwrap();
+            volume.create_file("c.txt", b"c", false).unwrap();
+            volume.remove_file("c.txt").unwrap();
+            let listing: Vec<_> = volume.walk("/").unwrap().map(|item| item.unwrap().1).collect();
+            (listing, volume.read_file("a/b.bin").unwrap())
+        };
+
+        let mut memory = create_mock_volume();
+        let mut card = Card(memory.clone());
+        let mut journaled = memory.clone();
+        let expected = run(&mut Fat32Volume::new(&mut memory).unwrap());
+        assert_eq!(expected.0, ["/a", "/a/b.bin"]);
+        assert_eq!(run(&mut Fat32Volume::from_device(&mut card).unwrap()), expected);
+        let mut volume = Fat32Volume::new(&mut journaled).unwrap();
+        let area = crate::fat32::journal::JournalArea::reserved(&volume.boot_sector).unwrap();
+        volume.enable_journal(area).unwrap();
+        assert_eq!(run(&mut volume), expected);
+        volume.disable_journal().unwrap();
+        drop(volume);
+        assert_eq!(card.0, memory);
+        // The journal itself sits in the reserved sectors, which hold nothing else here.
+        let fats = 32 * BLOCK_SIZE;
+        assert_eq!(journaled[fats..], memory[fats..]);
+    }
+
+    #[test]
+    fn test_io_tracer() {
+        // One counter per reason, in `IoReason` order.
+        static SEEN: [AtomicU64; 5] = [const { AtomicU64::new(0) }; 5];
+        fn tracer(event: &IoEvent) {
+            SEEN[event.reason as usize].fetch_add(event.count, Ordering::Relaxed);
+        }
+        let mut data = create_mock_volume();
+        let mut volume = Fat32Volume::new(&mut data).unwrap();
+        volume.set_io_tracer(Some(tracer));
+        volume.create_file("a.bin", &[1u8; 1500], false).unwrap();
+        volume.read_file("a.bin").unwrap();
+        let seen: Vec<u64> = SEEN.iter().map(|c| c.load(Ordering::Relaxed)).collect();
+        assert_eq!(seen[IoReason::BootSector as usize], 0);
+        assert!(seen[IoReason::Fat as usize] >= 6);
+        assert!(seen[IoReason::Directory as usize] > 0);
+        assert_eq!(seen[IoReason::Data as usize], 6);
+    }
+}
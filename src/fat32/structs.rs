@@ -1,3 +1,8 @@
+use core::ops::Range;
+
+use super::error::INVALID_CLUSTER;
+use super::fat::CLUSTER_LIMIT;
+
 #[repr(C, packed)]
 #[derive(Debug, Clone, Copy)]
 pub struct BootSector {
@@ -17,4 +22,103 @@ pub struct BootSector {
     pub ext_flags: u16,
     pub fs_version: u16,
     pub root_dir_cluster: u32,
-}
\ No newline at end of file
+    pub fs_info_sector: u16,
+    pub backup_boot_sector: u16,
+}
+
+impl BootSector {
+    /// Reads the BIOS parameter block from the first sector of the volume. Fails when
+    /// `data` is shorter than a sector, or when the geometry it describes can't be used
+    /// to find anything on the volume.
+    pub fn parse(data: &[u8]) -> Result<Self, &'static str> {
+        let data: &[u8; 512] = data.first_chunk().ok_or("Secteur de démarrage tronqué")?;
+        let read_u16 = |offset: usize| u16::from_le_bytes([data[offset], data[offset + 1]]);
+        let read_u32 = |offset: usize| u32::from_le_bytes([data[offset], data[offset + 1], data[offset + 2], data[offset + 3]]);
+        let read_u8 = |offset: usize| data[offset];
+
+        let boot_sector = BootSector {
+            bytes_per_sector: read_u16(11),
+            sectors_per_cluster: read_u8(13),
+            reserved_sectors: read_u16(14),
+            number_of_fats: read_u8(16),
+            root_entries: read_u16(17),
+            total_sectors_16: read_u16(19),
+            media_descriptor: read_u8(21),
+            sectors_per_fat_16: read_u16(22),
+            sectors_per_track: read_u16(24),
+            heads: read_u16(26),
+            hidden_sectors: read_u32(28),
+            total_sectors_32: read_u32(32),
+            sectors_per_fat_32: read_u32(36),
+            ext_flags: read_u16(40),
+            fs_version: read_u16(42),
+            root_dir_cluster: read_u32(44),
+            fs_info_sector: read_u16(48),
+            backup_boot_sector: read_u16(50),
+        };
+        boot_sector.check()?;
+        Ok(boot_sector)
+    }
+
+    /// Rejects the values that would make offsets meaningless: sector or cluster sizes
+    /// that aren't powers of two, no FAT, or a root directory outside the data region.
+    fn check(&self) -> Result<(), &'static str> {
+        let valid = matches!(self.bytes_per_sector, 512 | 1024 | 2048 | 4096)
+            && self.sectors_per_cluster.is_power_of_two()
+            && self.reserved_sectors > 0
+            && self.number_of_fats > 0
+            && self.sectors_per_fat_32 > 0
+            && self.clusters().contains(&{ self.root_dir_cluster });
+        if valid { Ok(()) } else { Err("Secteur de démarrage invalide") }
+    }
+
+    /// Byte offset of the first FAT copy.
+    pub fn fat_start(&self) -> usize {
+        self.reserved_sectors as usize * self.bytes_per_sector as usize
+    }
+
+    /// Bytes from the start of the volume to the end of the last copy of the boot
+    /// sectors: the boot sector, the FSInfo sector, and the backup boot and FSInfo sectors.
+    pub fn boot_region_len(&self) -> usize {
+        let reserved = self.reserved_sectors as usize;
+        let mut last = 0;
+        let fs_info = self.fs_info_sector as usize;
+        if fs_info < reserved { last = last.max(fs_info); }
+        let backup = self.backup_boot_sector as usize;
+        if backup > 0 && backup + 1 < reserved { last = last.max(backup + 1); }
+        (last + 1) * self.bytes_per_sector as usize
+    }
+
+    /// Size in bytes of one cluster.
+    pub fn cluster_size(&self) -> usize {
+        self.sectors_per_cluster as usize * self.bytes_per_sector as usize
+    }
+
+    /// Total sectors, from whichever of the 16 and 32-bit fields is set.
+    pub fn total_sectors(&self) -> u32 {
+        if self.total_sectors_16 != 0 { self.total_sectors_16 as u32 } else { self.total_sectors_32 }
+    }
+
+    fn first_data_sector(&self) -> u64 {
+        self.reserved_sectors as u64 + self.number_of_fats as u64 * self.sectors_per_fat_32 as u64
+    }
+
+    /// Byte offset of the data region, where cluster 2 starts.
+    pub fn data_start(&self) -> usize {
+        (self.first_data_sector() * self.bytes_per_sector as u64) as usize
+    }
+
+    /// Numbers of the clusters the data region holds, from `total_sectors`. Clusters 0 and
+    /// 1 only have FAT entries, and the FAT may have entries past the end of the volume.
+    pub fn clusters(&self) -> Range<u32> {
+        let data_sectors = (self.total_sectors() as u64).saturating_sub(self.first_data_sector());
+        let count = data_sectors / self.sectors_per_cluster.max(1) as u64;
+        2..(2 + count).min(CLUSTER_LIMIT as u64) as u32
+    }
+
+    /// Byte offset of `cluster` in the volume. Fails for a cluster outside the data region.
+    pub fn cluster_offset(&self, cluster: u32) -> Result<usize, &'static str> {
+        if !self.clusters().contains(&cluster) { return Err(INVALID_CLUSTER); }
+        Ok(self.data_start() + (cluster - 2) as usize * self.cluster_size())
+    }
+}
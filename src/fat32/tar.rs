@@ -0,0 +1,130 @@
+extern crate alloc;
+use alloc::format;
+
+use super::dir::DirEntry;
+use super::extract::TreeSink;
+use super::time::DateTime;
+
+const BLOCK: usize = 512;
+
+/// Streams an extracted tree as a ustar archive. Every block is handed to `out`
+/// as soon as it is built, so the archive never has to fit in memory.
+pub struct TarWriter<F: FnMut(&[u8]) -> Result<(), &'static str>> {
+    out: F,
+    to_unix: fn(&DateTime) -> i64,
+}
+
+impl<F: FnMut(&[u8]) -> Result<(), &'static str>> TarWriter<F> {
+    pub fn new(out: F) -> Self {
+        TarWriter { out, to_unix: DateTime::to_unix }
+    }
+
+    /// Converts modification times with `to_unix` instead of reading them as UTC, for
+    /// images written in another time zone.
+    pub fn to_unix(mut self, to_unix: fn(&DateTime) -> i64) -> Self {
+        self.to_unix = to_unix;
+        self
+    }
+
+    /// Writes the two zero blocks that end an archive.
+    pub fn finish(mut self) -> Result<(), &'static str> {
+        (self.out)(&[0u8; BLOCK * 2])
+    }
+
+    fn header(&mut self, path: &str, entry: &DirEntry, typeflag: u8, size: u32) -> Result<(), &'static str> {
+        let mut h = [0u8; BLOCK];
+        let (prefix, name) = split_path(path).ok_or("Chemin trop long pour tar")?;
+        h[..name.len()].copy_from_slice(name.as_bytes());
+        h[345..345 + prefix.len()].copy_from_slice(prefix.as_bytes());
+
+        let mode = if typeflag == b'5' { 0o755 } else { 0o644 };
+        let mtime = if entry.modified.is_set() { (self.to_unix)(&entry.modified).max(0) as u64 } else { 0 };
+        write_octal(&mut h[100..108], mode);
+        write_octal(&mut h[108..116], 0);
+        write_octal(&mut h[116..124], 0);
+        write_octal(&mut h[124..136], size as u64);
+        write_octal(&mut h[136..148], mtime);
+        h[156] = typeflag;
+        h[257..263].copy_from_slice(b"ustar\0");
+        h[263..265].copy_from_slice(b"00");
+
+        // The checksum is computed with its own field filled with spaces.
+        h[148..156].fill(b' ');
+        let sum: u32 = h.iter().map(|&b| b as u32).sum();
+        write_octal(&mut h[148..155], sum as u64);
+        (self.out)(&h)
+    }
+}
+
+impl<F: FnMut(&[u8]) -> Result<(), &'static str>> TreeSink for TarWriter<F> {
+    fn enter_dir(&mut self, path: &str, entry: &DirEntry) -> Result<(), &'static str> {
+        self.header(&format!("{}/", path), entry, b'5', 0)
+    }
+
+    fn file(&mut self, path: &str, entry: &DirEntry, content: &[u8]) -> Result<(), &'static str> {
+        self.header(path, entry, b'0', content.len() as u32)?;
+        (self.out)(content)?;
+        let padding = (BLOCK - content.len() % BLOCK) % BLOCK;
+        (self.out)(&[0u8; BLOCK][..padding])
+    }
+}
+
+/// Splits `path` into the ustar `prefix` (155 bytes) and `name` (100 bytes) fields.
+fn split_path(path: &str) -> Option<(&str, &str)> {
+    if path.len() <= 100 { return Some(("", path)); }
+    path.char_indices()
+        .filter(|&(i, c)| c == '/' && i <= 155 && path.len() - i - 1 <= 100)
+        .map(|(i, _)| (&path[..i], &path[i + 1..]))
+        .next()
+}
+
+/// Writes `value` as zero-padded octal followed by a NUL, filling `field`.
+fn write_octal(field: &mut [u8], mut value: u64) {
+    let digits = field.len() - 1;
+    for i in (0..digits).rev() {
+        field[i] = b'0' + (value & 7) as u8;
+        value >>= 3;
+    }
+    field[digits] = 0;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec::Vec;
+    use crate::fat32::progress::NoProgress;
+    use crate::fat32::volume::Fat32Volume;
+    use crate::fat32::volume::tests::create_mock_volume;
+
+    #[test]
+    fn test_export_tar() {
+        let mut data = create_mock_volume();
+        let mut volume = Fat32Volume::new(&mut data).unwrap();
+        let boot = volume.create_directory("boot").unwrap();
+        volume.create_file_in(boot, "config.txt", b"arm_64bit=1\n", false).unwrap();
+
+        let mut archive = Vec::new();
+        let mut tar = TarWriter::new(|block: &[u8]| { archive.extend_from_slice(block); Ok(()) });
+        volume.extract_tree("/", &mut tar, &mut NoProgress).unwrap();
+        tar.finish().unwrap();
+
+        // dir header, file header, file data, end of archive
+        assert_eq!(archive.len(), 512 * 5);
+        assert_eq!(&archive[0..6], b"boot/\0");
+        assert_eq!(archive[156], b'5');
+        assert_eq!(&archive[512..527], b"boot/config.txt");
+        assert_eq!(&archive[512 + 124..512 + 135], b"00000000014");
+        assert_eq!(&archive[512 + 257..512 + 263], b"ustar\0");
+        assert_eq!(&archive[1024..1036], b"arm_64bit=1\n");
+        assert!(archive[1536..].iter().all(|&b| b == 0));
+    }
+
+    #[test]
+    fn test_split_long_path() {
+        let long = format!("{}/{}", "d".repeat(120), "f".repeat(90));
+        let (prefix, name) = split_path(&long).unwrap();
+        assert_eq!(prefix.len(), 120);
+        assert_eq!(name.len(), 90);
+        assert!(split_path(&"x".repeat(300)).is_none());
+    }
+}
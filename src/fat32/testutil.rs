@@ -0,0 +1,212 @@
+//! Builds FAT32 images in memory for tests: a formatted volume holding the directories
+//! and files asked for, possibly fragmented, then damaged on purpose. Available to the
+//! crate's own tests, and to others with the `testutil` feature.
+
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+
+use super::format::{format, FormatOptions};
+use super::volume::Fat32Volume;
+
+/// Damage done to the image once its content is written.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Corruption {
+    /// Sets the entry of `cluster` in every FAT copy.
+    FatEntry { cluster: u32, value: u32 },
+    /// Overwrites the image from byte `offset` on.
+    Bytes { offset: usize, bytes: Vec<u8> },
+    /// Cuts the image to `len` bytes, as a partial copy would.
+    Truncate(usize),
+}
+
+enum Item {
+    Dir(String),
+    File { path: String, content: Vec<u8>, fragmented: bool },
+}
+
+/// Describes an image, made by `build`:
+///
+/// ```ignore
+/// let image = ImageBuilder::new()
+///     .cluster_size(4096)
+///     .file("DCIM/100CANON/IMG_0001.JPG", jpeg)
+///     .fragmented_file("log.txt", log)
+///     .corrupt(Corruption::FatEntry { cluster: 3, value: 0 })
+///     .build();
+/// ```
+pub struct ImageBuilder {
+    size: usize,
+    format: FormatOptions,
+    items: Vec<Item>,
+    corruptions: Vec<Corruption>,
+}
+
+impl Default for ImageBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ImageBuilder {
+    /// A 34 MiB image with 512-byte clusters and nothing in it, about as small as FAT32
+    /// allows.
+    pub fn new() -> Self {
+        ImageBuilder {
+            size: 34 * 1024 * 1024,
+            format: FormatOptions { sectors_per_cluster: Some(1), ..Default::default() },
+            items: Vec::new(),
+            corruptions: Vec::new(),
+        }
+    }
+
+    /// Size of the image in bytes.
+    pub fn size(mut self, bytes: usize) -> Self {
+        self.size = bytes;
+        self
+    }
+
+    /// Bytes per cluster, a power of two multiple of 512.
+    pub fn cluster_size(mut self, bytes: usize) -> Self {
+        self.format.sectors_per_cluster = Some((bytes / 512) as u8);
+        self
+    }
+
+    pub fn label(mut self, label: &[u8; 11]) -> Self {
+        self.format.label = *label;
+        self
+    }
+
+    /// A directory, created with its missing parents. Any name a long file name entry can
+    /// hold is allowed.
+    pub fn dir(mut self, path: &str) -> Self {
+        self.items.push(Item::Dir(path.into()));
+        self
+    }
+
+    /// A file, with its missing parent directories.
+    pub fn file(mut self, path: &str, content: impl Into<Vec<u8>>) -> Self {
+        self.items.push(Item::File { path: path.into(), content: content.into(), fragmented: false });
+        self
+    }
+
+    /// A file whose clusters each have a free one after them, so none follow each other.
+    pub fn fragmented_file(mut self, path: &str, content: impl Into<Vec<u8>>) -> Self {
+        self.items.push(Item::File { path: path.into(), content: content.into(), fragmented: true });
+        self
+    }
+
+    /// Damage applied after everything else, in the order given.
+    pub fn corrupt(mut self, corruption: Corruption) -> Self {
+        self.corruptions.push(corruption);
+        self
+    }
+
+    /// Makes the image. Panics when the description can't be: it is meant for tests.
+    pub fn build(self) -> Vec<u8> {
+        let mut data = vec![0u8; self.size];
+        format(&mut data, &self.format).expect("image format");
+        let (fat_start, fat_len, fats) = {
+            let mut volume = Fat32Volume::new(&mut data).expect("mount");
+            for item in &self.items {
+                match item {
+                    Item::Dir(path) => create_parents(&mut volume, &alloc::format!("{}/", path)),
+                    Item::File { path, content, fragmented } => {
+                        create_parents(&mut volume, path);
+                        if *fragmented {
+                            write_fragmented(&mut volume, path, content);
+                        } else {
+                            volume.create_file(path, content, false).expect("file");
+                        }
+                    }
+                }
+            }
+            let boot = &volume.boot_sector;
+            let sector = boot.bytes_per_sector as usize;
+            (boot.reserved_sectors as usize * sector, boot.sectors_per_fat_32 as usize * sector, boot.number_of_fats as usize)
+        };
+
+        for corruption in self.corruptions {
+            match corruption {
+                Corruption::FatEntry { cluster, value } => for fat in 0..fats {
+                    let at = fat_start + fat * fat_len + cluster as usize * 4;
+                    data[at..at + 4].copy_from_slice(&value.to_le_bytes());
+                },
+                Corruption::Bytes { offset, bytes } => data[offset..offset + bytes.len()].copy_from_slice(&bytes),
+                Corruption::Truncate(len) => data.truncate(len),
+            }
+        }
+        data
+    }
+}
+
+/// Creates the directories leading to the last component of `path`.
+fn create_parents(volume: &mut Fat32Volume, path: &str) {
+    let Some(end) = path.rfind('/') else { return };
+    for (i, _) in path[..end + 1].match_indices('/').filter(|&(i, _)| i > 0) {
+        if volume.directory_cluster(&path[..i]).is_err() {
+            volume.create_directory(&path[..i]).expect("directory");
+        }
+    }
+}
+
+/// Writes `content` a cluster at a time, taking the cluster after each for a spacer file
+/// deleted at the end.
+fn write_fragmented(volume: &mut Fat32Volume, path: &str, content: &[u8]) {
+    const SPACER: &str = "SPACER.TMP";
+    let cluster_size = volume.cluster_size();
+    volume.create_file(path, &[], false).expect("file");
+    volume.create_file(SPACER, &[], false).expect("spacer");
+    for (i, chunk) in content.chunks(cluster_size).enumerate() {
+        volume.write_at(path, (i * cluster_size) as u64, chunk).expect("file content");
+        volume.write_at(SPACER, (i * cluster_size) as u64, &vec![0u8; cluster_size]).expect("spacer");
+    }
+    volume.remove_file(SPACER).expect("spacer");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fat32::error::FILE_NOT_FOUND;
+    use crate::fat32::fat::is_contiguous;
+
+    #[test]
+    fn test_build_tree() {
+        let content: Vec<u8> = (0..5000).map(|i| i as u8).collect();
+        let mut data = ImageBuilder::new()
+            .size(68 * 1024 * 1024)
+            .cluster_size(1024)
+            .label(b"CAMERA     ")
+            .dir("MISC/Empty folder")
+            .file("DCIM/100CANON/Vacances à la plage.jpg", content.clone())
+            .fragmented_file("log.txt", content.clone())
+            .build();
+        let volume = Fat32Volume::new(&mut data).expect("mount");
+
+        assert_eq!(volume.cluster_size(), 1024);
+        assert!(volume.read_dir(volume.directory_cluster("MISC/Empty folder").unwrap()).unwrap().iter().all(|e| e.is_dot()));
+        assert_eq!(volume.read_file("DCIM/100CANON/Vacances à la plage.jpg").unwrap(), content);
+        assert_eq!(volume.read_file("log.txt").unwrap(), content);
+        let chain = volume.cluster_chain(volume.file_entry("log.txt").unwrap().first_cluster).unwrap();
+        assert_eq!(chain.len(), 5);
+        assert!(chain.windows(2).all(|pair| !is_contiguous(pair)));
+        assert_eq!(volume.file_entry("SPACER.TMP"), Err(FILE_NOT_FOUND));
+    }
+
+    #[test]
+    fn test_corruption() {
+        let clean = ImageBuilder::new().file("a.txt", *b"hello").build();
+        let first = Fat32Volume::new(&mut clean.clone()).expect("mount").file_entry("a.txt").unwrap().first_cluster;
+
+        let mut data = ImageBuilder::new()
+            .file("a.txt", *b"hello")
+            .corrupt(Corruption::FatEntry { cluster: first, value: 0 })
+            .corrupt(Corruption::Bytes { offset: 510, bytes: vec![0, 0] })
+            .corrupt(Corruption::Truncate(1024 * 1024))
+            .build();
+        assert_eq!(data.len(), 1024 * 1024);
+        assert_eq!(&data[510..512], [0, 0]);
+        let volume = Fat32Volume::new(&mut data).expect("mount");
+        assert!(volume.is_free(first));
+    }
+}
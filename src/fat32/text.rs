@@ -0,0 +1,339 @@
+//! Looking into files where they are, without extracting them: their first or last
+//! lines, their line and word counts, what kind of file they are and the text in
+//! binary ones, read a cluster at a time.
+
+extern crate alloc;
+use alloc::vec;
+use alloc::vec::Vec;
+
+use super::dir::DirEntry;
+use super::volume::Fat32Volume;
+
+/// What `wc` counts in a file.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct WordCount {
+    /// Newlines, so a last line without one isn't counted.
+    pub lines: u64,
+    /// Runs of characters between ASCII whitespace.
+    pub words: u64,
+    pub bytes: u64,
+}
+
+/// What `file` recognizes a file as, from its first bytes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileType {
+    Empty,
+    Elf,
+    Jpeg,
+    Png,
+    Gif,
+    Pdf,
+    Zip,
+    Gzip,
+    /// Text starting with a UTF-16 byte order mark.
+    Utf16,
+    /// Printable ASCII, with tabs and line breaks.
+    Ascii,
+    /// Printable UTF-8 that isn't all ASCII.
+    Utf8,
+    /// Anything else.
+    Data,
+}
+
+/// The first bytes of the formats recognized by `FileType::detect`.
+const MAGIC: &[(&[u8], FileType)] = &[
+    (b"\x7fELF", FileType::Elf),
+    (b"\xff\xd8\xff", FileType::Jpeg),
+    (b"\x89PNG\r\n\x1a\n", FileType::Png),
+    (b"GIF87a", FileType::Gif),
+    (b"GIF89a", FileType::Gif),
+    (b"%PDF-", FileType::Pdf),
+    (b"PK\x03\x04", FileType::Zip),
+    (b"PK\x05\x06", FileType::Zip),
+    (b"\x1f\x8b", FileType::Gzip),
+    (b"\xff\xfe", FileType::Utf16),
+    (b"\xfe\xff", FileType::Utf16),
+];
+
+impl FileType {
+    /// Recognizes the start of a file: a known magic number, or else text when the bytes
+    /// are printable UTF-8. `head` may end in the middle of a character.
+    pub fn detect(head: &[u8]) -> Self {
+        if head.is_empty() { return FileType::Empty; }
+        if let Some((_, kind)) = MAGIC.iter().find(|(magic, _)| head.starts_with(magic)) { return *kind; }
+        let text = match core::str::from_utf8(head) {
+            Ok(text) => text,
+            Err(e) if e.error_len().is_none() => core::str::from_utf8(&head[..e.valid_up_to()]).unwrap_or_default(),
+            Err(_) => return FileType::Data,
+        };
+        let printable = |c: char| !c.is_control() || matches!(c, '\t' | '\n' | '\r' | '\x0c' | '\x1b');
+        match text {
+            _ if !text.chars().all(printable) => FileType::Data,
+            _ if text.is_ascii() => FileType::Ascii,
+            _ => FileType::Utf8,
+        }
+    }
+
+    pub fn description(&self) -> &'static str {
+        match self {
+            FileType::Empty => "empty",
+            FileType::Elf => "ELF executable",
+            FileType::Jpeg => "JPEG image",
+            FileType::Png => "PNG image",
+            FileType::Gif => "GIF image",
+            FileType::Pdf => "PDF document",
+            FileType::Zip => "Zip archive",
+            FileType::Gzip => "gzip compressed data",
+            FileType::Utf16 => "UTF-16 text",
+            FileType::Ascii => "ASCII text",
+            FileType::Utf8 => "UTF-8 text",
+            FileType::Data => "data",
+        }
+    }
+}
+
+/// Where the last `lines` lines of `text` start, when it holds that many. A newline
+/// ending the text doesn't start another line.
+fn last_lines_start(text: &[u8], lines: usize) -> Option<usize> {
+    let body = text.strip_suffix(b"\n").unwrap_or(text);
+    body.iter().enumerate().rev().filter(|(_, &b)| b == b'\n').nth(lines - 1).map(|(i, _)| i + 1)
+}
+
+impl<'a> Fat32Volume<'a> {
+    /// Hands the content of the file of `entry` to `f` a cluster at a time, in order,
+    /// until `f` returns false.
+    pub(super) fn read_clusters(&self, entry: &DirEntry, mut f: impl FnMut(&[u8]) -> bool) -> Result<(), &'static str> {
+        let cluster_size = self.cluster_size();
+        let mut remaining = entry.size as usize;
+        let mut buf = vec![0u8; cluster_size];
+        for cluster in self.cluster_chain(entry.first_cluster)? {
+            if remaining == 0 { break; }
+            let len = remaining.min(cluster_size);
+            remaining -= len;
+            self.storage.read(self.offset_from_cluster(cluster)?, &mut buf[..len])?;
+            if !f(&buf[..len]) { break; }
+        }
+        Ok(())
+    }
+
+    /// The first `lines` lines of the file at `path`, reading only the clusters that
+    /// hold them.
+    pub fn head(&self, path: &str, lines: usize) -> Result<Vec<u8>, &'static str> {
+        let entry = self.file_entry(path)?;
+        let mut head = Vec::new();
+        if lines == 0 { return Ok(head); }
+        let mut found = 0;
+        self.read_clusters(&entry, |chunk| {
+            for (i, _) in chunk.iter().enumerate().filter(|(_, &b)| b == b'\n') {
+                found += 1;
+                if found == lines {
+                    head.extend_from_slice(&chunk[..=i]);
+                    return false;
+                }
+            }
+            head.extend_from_slice(chunk);
+            true
+        })?;
+        Ok(head)
+    }
+
+    /// Counts the lines, words and bytes of the file at `path`, a cluster at a time.
+    pub fn word_count(&self, path: &str) -> Result<WordCount, &'static str> {
+        let entry = self.file_entry(path)?;
+        let mut count = WordCount::default();
+        let mut in_word = false;
+        self.read_clusters(&entry, |chunk| {
+            for &b in chunk {
+                if b == b'\n' { count.lines += 1; }
+                let space = b.is_ascii_whitespace();
+                if !space && !in_word { count.words += 1; }
+                in_word = !space;
+            }
+            count.bytes += chunk.len() as u64;
+            true
+        })?;
+        Ok(count)
+    }
+
+    /// What the file at `path` holds, judging from its first 512 bytes.
+    pub fn file_type(&self, path: &str) -> Result<FileType, &'static str> {
+        let mut head = [0u8; 512];
+        let len = self.read_at(path, 0, &mut head)?;
+        Ok(FileType::detect(&head[..len]))
+    }
+
+    /// Calls `found` with each run of at least `min_len` printable characters of the file
+    /// at `path`, ASCII or UTF-8, and its byte offset, as the `strings` tool does.
+    /// Runs spanning clusters are found whole. Returns the number of runs.
+    pub fn strings(&self, path: &str, min_len: usize, found: &mut dyn FnMut(u64, &str)) -> Result<usize, &'static str> {
+        let entry = self.file_entry(path)?;
+        let mut run: Vec<u8> = Vec::new();
+        let mut start = 0;
+        let mut count = 0;
+        let mut offset = 0u64;
+        let mut flush = |run: &mut Vec<u8>, start: u64| {
+            // Bytes that aren't part of a valid character split the run.
+            let mut at = start;
+            for chunk in run.utf8_chunks() {
+                if chunk.valid().chars().count() >= min_len {
+                    found(at, chunk.valid());
+                    count += 1;
+                }
+                at += (chunk.valid().len() + chunk.invalid().len()) as u64;
+            }
+            run.clear();
+        };
+        self.read_clusters(&entry, |chunk| {
+            for &b in chunk {
+                // Printable ASCII and tabs, or any byte of a multi-byte UTF-8 character.
+                if b == b'\t' || (0x20..0x7f).contains(&b) || b >= 0x80 {
+                    if run.is_empty() { start = offset; }
+                    run.push(b);
+                } else if !run.is_empty() {
+                    flush(&mut run, start);
+                }
+                offset += 1;
+            }
+            true
+        })?;
+        if !run.is_empty() { flush(&mut run, start); }
+        Ok(count)
+    }
+
+    /// The last `lines` lines of the file at `path`. Only the FAT is followed from the
+    /// start of the file; its clusters are read from the last one back, until they
+    /// hold enough lines.
+    pub fn tail(&self, path: &str, lines: usize) -> Result<Vec<u8>, &'static str> {
+        let entry = self.file_entry(path)?;
+        if lines == 0 || entry.size == 0 { return Ok(Vec::new()); }
+        let chain = self.cluster_chain(entry.first_cluster)?;
+        let cluster_size = self.cluster_size();
+        let size = entry.size as usize;
+        let used = size.div_ceil(cluster_size);
+        if chain.len() < used { return Err("Chaîne trop courte"); }
+
+        let mut tail = Vec::new();
+        for index in (0..used).rev() {
+            let mut chunk = vec![0u8; (size - index * cluster_size).min(cluster_size)];
+            self.storage.read(self.offset_from_cluster(chain[index])?, &mut chunk)?;
+            chunk.extend_from_slice(&tail);
+            tail = chunk;
+            if let Some(start) = last_lines_start(&tail, lines) {
+                tail.drain(..start);
+                break;
+            }
+        }
+        Ok(tail)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::format;
+    use alloc::string::String;
+    use crate::fat32::error::FILE_NOT_FOUND;
+    use crate::fat32::volume::tests::create_mock_volume;
+
+    /// 200 numbered lines of 11 bytes, over 5 clusters of the mock volume.
+    fn log() -> String {
+        (0..200).map(|i| format!("line {:05}\n", i)).collect()
+    }
+
+    #[test]
+    fn test_head() {
+        let mut data = create_mock_volume();
+        let mut volume = Fat32Volume::new(&mut data).unwrap();
+        volume.create_file("log.txt", log().as_bytes(), false).unwrap();
+        volume.create_file("short.txt", b"one\ntwo", false).unwrap();
+
+        volume.reset_stats();
+        assert_eq!(volume.head("log.txt", 2).unwrap(), b"line 00000\nline 00001\n");
+        let sectors = volume.stats().sectors_read;
+        assert_eq!(volume.head("log.txt", 60).unwrap(), log().as_bytes()[..660]);
+        assert!(volume.stats().sectors_read > sectors * 2);
+        assert_eq!(volume.head("short.txt", 10).unwrap(), b"one\ntwo");
+        assert!(volume.head("log.txt", 0).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_tail() {
+        let mut data = create_mock_volume();
+        let mut volume = Fat32Volume::new(&mut data).unwrap();
+        volume.create_file("log.txt", log().as_bytes(), false).unwrap();
+        volume.create_file("short.txt", b"one\ntwo", false).unwrap();
+        volume.create_file("empty.txt", b"", false).unwrap();
+
+        volume.reset_stats();
+        assert_eq!(volume.tail("log.txt", 2).unwrap(), b"line 00198\nline 00199\n");
+        let last_cluster = volume.stats().sectors_read;
+        volume.reset_stats();
+        volume.read_file("log.txt").unwrap();
+        assert!(volume.stats().sectors_read > last_cluster);
+
+        // Lines spanning the start of the last cluster need the one before.
+        let lines: String = (150..200).map(|i| format!("line {:05}\n", i)).collect();
+        assert_eq!(volume.tail("log.txt", 50).unwrap(), lines.as_bytes());
+        assert_eq!(volume.tail("short.txt", 1).unwrap(), b"two");
+        assert_eq!(volume.tail("short.txt", 5).unwrap(), b"one\ntwo");
+        assert!(volume.tail("empty.txt", 5).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_word_count() {
+        let mut data = create_mock_volume();
+        let mut volume = Fat32Volume::new(&mut data).unwrap();
+        volume.create_file("log.txt", log().as_bytes(), false).unwrap();
+        // A word straddling the end of the first cluster is counted once.
+        let mut split = [b'x'; 600];
+        split[10] = b' ';
+        volume.create_file("split.txt", &split, false).unwrap();
+        volume.create_file("empty.txt", b"", false).unwrap();
+
+        assert_eq!(volume.word_count("log.txt").unwrap(), WordCount { lines: 200, words: 400, bytes: 2200 });
+        assert_eq!(volume.word_count("split.txt").unwrap(), WordCount { lines: 0, words: 2, bytes: 600 });
+        assert_eq!(volume.word_count("empty.txt").unwrap(), WordCount::default());
+    }
+
+    #[test]
+    fn test_detect_file_type() {
+        assert_eq!(FileType::detect(b""), FileType::Empty);
+        assert_eq!(FileType::detect(b"\x7fELF\x02\x01\x01"), FileType::Elf);
+        assert_eq!(FileType::detect(b"\x1f\x8b\x08\x00"), FileType::Gzip);
+        assert_eq!(FileType::detect(b"\xff\xfeh\0i\0"), FileType::Utf16);
+        assert_eq!(FileType::detect(b"[all]\r\n\tarm_64bit=1\n"), FileType::Ascii);
+        // Cut in the middle of a two-byte character.
+        assert_eq!(FileType::detect(b"caf\xc3\xa9 cr\xc3"), FileType::Utf8);
+        assert_eq!(FileType::detect(b"text\0with a NUL"), FileType::Data);
+        assert_eq!(FileType::detect(b"\xc3\x28"), FileType::Data);
+    }
+
+    #[test]
+    fn test_file_type() {
+        let mut data = create_mock_volume();
+        let mut volume = Fat32Volume::new(&mut data).unwrap();
+        volume.create_file("photo.jpg", b"\xff\xd8\xff\xe0\0\x10JFIF", false).unwrap();
+        volume.create_file("log.txt", log().as_bytes(), false).unwrap();
+        assert_eq!(volume.file_type("photo.jpg"), Ok(FileType::Jpeg));
+        assert_eq!(volume.file_type("log.txt"), Ok(FileType::Ascii));
+        assert_eq!(volume.file_type("missing"), Err(FILE_NOT_FOUND));
+    }
+
+    #[test]
+    fn test_strings() {
+        let mut data = create_mock_volume();
+        let mut volume = Fat32Volume::new(&mut data).unwrap();
+        let mut blob = vec![0u8; 1200];
+        blob[8..20].copy_from_slice(b"U-Boot 2024\0");
+        blob[100..103].copy_from_slice(b"abc");
+        // Straddles the end of the first cluster.
+        blob[505..519].copy_from_slice("version é 1.2".as_bytes());
+        blob[1185..1200].copy_from_slice(b"\xfftail\xff\xfeword\x01end");
+        volume.create_file("fw.bin", &blob, false).unwrap();
+
+        let mut runs = Vec::new();
+        assert_eq!(volume.strings("fw.bin", 4, &mut |offset, text| runs.push((offset, String::from(text)))), Ok(4));
+        assert_eq!(runs, [(8, "U-Boot 2024".into()), (505, "version é 1.2".into()), (1186, "tail".into()), (1192, "word".into())]);
+        assert_eq!(volume.strings("fw.bin", 3, &mut |_, _| {}), Ok(6));
+    }
+}
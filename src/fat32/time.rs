@@ -0,0 +1,130 @@
+use core::fmt;
+
+/// A date and time as stored in directory entries: local time, 2-second resolution,
+/// years 1980 to 2107.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
+pub struct DateTime {
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+}
+
+impl DateTime {
+    /// Decodes the packed FAT date (`yyyyyyym mmmddddd`) and time (`hhhhhmmm mmmsssss`).
+    pub fn from_fat(date: u16, time: u16) -> Self {
+        DateTime {
+            year: 1980 + (date >> 9),
+            month: ((date >> 5) & 0x0F) as u8,
+            day: (date & 0x1F) as u8,
+            hour: (time >> 11) as u8,
+            minute: ((time >> 5) & 0x3F) as u8,
+            second: ((time & 0x1F) * 2) as u8,
+        }
+    }
+
+    /// Encodes back to the packed `(date, time)` pair.
+    pub fn to_fat(&self) -> (u16, u16) {
+        let date = ((self.year.saturating_sub(1980)) << 9) | ((self.month as u16) << 5) | self.day as u16;
+        let time = ((self.hour as u16) << 11) | ((self.minute as u16) << 5) | (self.second as u16 / 2);
+        (date, time)
+    }
+
+    /// False for the all-zero date that entries written without a clock carry.
+    pub fn is_set(&self) -> bool {
+        self.month != 0 && self.day != 0
+    }
+
+    /// Seconds since 1970-01-01 00:00:00, reading the stored time as UTC.
+    pub fn to_unix(&self) -> i64 {
+        // Days from civil, see http://howardhinnant.github.io/date_algorithms.html
+        let y = self.year as i64 - if self.month <= 2 { 1 } else { 0 };
+        let m = self.month as i64;
+        let era = y.div_euclid(400);
+        let yoe = y - era * 400;
+        let doy = (153 * (if m > 2 { m - 3 } else { m + 9 }) + 2) / 5 + self.day as i64 - 1;
+        let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+        let days = era * 146097 + doe - 719468;
+
+        days * 86400 + self.hour as i64 * 3600 + self.minute as i64 * 60 + self.second as i64
+    }
+
+    /// The date and time `millis` milliseconds after the Unix epoch, with the hundredths
+    /// of a second past it that creation times keep (0 to 199).
+    pub fn from_unix_millis(millis: i64) -> (Self, u8) {
+        let time = DateTime::from_unix(millis.div_euclid(1000));
+        let past = (millis - time.to_unix() * 1000) / 10;
+        (time, past.clamp(0, 199) as u8)
+    }
+
+    /// The inverse of `to_unix`: the date and time `secs` seconds after 1970-01-01 00:00:00.
+    /// Seconds are rounded down to the 2-second resolution and years clamped to what FAT
+    /// can store.
+    pub fn from_unix(secs: i64) -> Self {
+        let min = DateTime { year: 1980, month: 1, day: 1, hour: 0, minute: 0, second: 0 };
+        let max = DateTime { year: 2107, month: 12, day: 31, hour: 23, minute: 59, second: 58 };
+        if secs < min.to_unix() { return min; }
+        if secs > max.to_unix() { return max; }
+
+        // Civil from days, the inverse of the algorithm above.
+        let (days, rem) = (secs.div_euclid(86400), secs.rem_euclid(86400));
+        let z = days + 719468;
+        let era = z.div_euclid(146097);
+        let doe = z - era * 146097;
+        let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+        let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+        let mp = (5 * doy + 2) / 153;
+        let day = doy - (153 * mp + 2) / 5 + 1;
+        let month = if mp < 10 { mp + 3 } else { mp - 9 };
+        let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+        DateTime {
+            year: year as u16,
+            month: month as u8,
+            day: day as u8,
+            hour: (rem / 3600) as u8,
+            minute: (rem / 60 % 60) as u8,
+            second: (rem % 60 / 2 * 2) as u8,
+        }
+    }
+}
+
+impl fmt::Display for DateTime {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+            self.year, self.month, self.day, self.hour, self.minute, self.second)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fat_round_trip_and_unix() {
+        // 2024-03-15 13:45:30
+        let date = (44 << 9) | (3 << 5) | 15;
+        let time = (13 << 11) | (45 << 5) | 15;
+        let dt = DateTime::from_fat(date, time);
+
+        assert_eq!(dt, DateTime { year: 2024, month: 3, day: 15, hour: 13, minute: 45, second: 30 });
+        assert_eq!(dt.to_fat(), (date, time));
+        assert_eq!(dt.to_unix(), 1710510330);
+        assert!(!DateTime::from_fat(0, 0).is_set());
+    }
+
+    #[test]
+    fn test_from_unix() {
+        let dt = DateTime { year: 2024, month: 3, day: 15, hour: 13, minute: 45, second: 30 };
+        assert_eq!(DateTime::from_unix(1710510330), dt);
+        assert_eq!(DateTime::from_unix(1710510331), dt);
+        // Leap day, and a shift to a UTC+2 wall clock crossing midnight.
+        let leap = DateTime { year: 2024, month: 2, day: 29, hour: 23, minute: 0, second: 0 };
+        assert_eq!(DateTime::from_unix(leap.to_unix()), leap);
+        assert_eq!(DateTime::from_unix(leap.to_unix() + 2 * 3600).day, 1);
+        assert_eq!(DateTime::from_unix(0).year, 1980);
+        assert_eq!(DateTime::from_unix(i64::MAX).year, 2107);
+        assert_eq!(DateTime::from_unix_millis(1_710_510_331_250), (dt, 125));
+    }
+}
@@ -1,45 +1,84 @@
 extern crate alloc;
 use alloc::vec::Vec;
-use alloc::string::{String, ToString};
+use alloc::vec;
+use alloc::string::String;
 use alloc::format;
-use core::convert::TryInto;
+use log::{trace, warn};
 
+use super::bpb::BiosParameterBlock;
+use super::codepage::Codepage;
+use super::error::{ALREADY_EXISTS, BLOCK_OUT_OF_RANGE, DISK_FULL, FILE_NOT_FOUND, INVALID_CLUSTER};
+use super::dir::{sort_entries, DirEntry, ListOptions, ATTR_ARCHIVE, ATTR_DIRECTORY};
+use super::path::Resolved;
 use super::structs::BootSector;
+use super::time::DateTime;
+use super::fat::{FAT_EOC, FAT_FREE};
+use super::io::BLOCK_SIZE;
+use super::storage::{Counted, DynBlockDevice, IoCounters, IoStats, IoTracer, Storage};
+use super::name::{case_flags_for, encode_lfn, generate_short_name, is_valid_long_name};
+
+/// Settings that change how a mounted volume behaves, not what is on it.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Fat32Options {
+    /// Start new files on clusters whose position on the card, counting the hidden
+    /// sectors before the volume, is a multiple of this many bytes (e.g. the 4 MiB erase
+    /// block of an SD card). Falls back to any free cluster when no aligned one is free.
+    pub allocation_alignment: Option<u32>,
+    /// Current local time, stamped on entries as they are created or modified, and the
+    /// hundredths of a second past it (0 to 199) kept with creation times. Entries keep
+    /// the all-zero date without one, as there is no clock to ask in `no_std`.
+    pub clock: Option<fn() -> (DateTime, u8)>,
+    /// Update the last-access date of files as they are read through `Fat32File` or
+    /// `record_access`. Off by default (like `noatime`): reads then never write the image.
+    pub update_access_date: bool,
+}
 
 pub struct Fat32Volume<'a> {
-    data: &'a mut [u8], 
+    pub(super) storage: Counted<'a>,
     pub boot_sector: BootSector,
     pub current_cluster: u32,
+    /// OEM codepage used for the non-ASCII bytes of short names.
+    pub codepage: Codepage,
+    /// One bit per cluster, set when the cluster is free. Kept in sync by `write_fat_entry`.
+    pub(super) free_map: Vec<u64>,
+    /// Where the next allocation starts looking, so successive allocations don't rescan.
+    /// Starts at the FSInfo hint when the volume has one.
+    pub(super) next_free: u32,
+    pub options: Fat32Options,
 }
 
 impl<'a> Fat32Volume<'a> {
-    
-    pub fn new(data: &'a mut [u8]) -> Self {
-        let read_u16 = |offset| u16::from_le_bytes(data[offset..offset+2].try_into().unwrap());
-        let read_u32 = |offset| u32::from_le_bytes(data[offset..offset+4].try_into().unwrap());
-        let read_u8 = |offset| data[offset];
-
-        let boot_sector = BootSector {
-            bytes_per_sector: read_u16(11),
-            sectors_per_cluster: read_u8(13),
-            reserved_sectors: read_u16(14),
-            number_of_fats: read_u8(16),
-            root_entries: read_u16(17),
-            total_sectors_16: read_u16(19),
-            media_descriptor: read_u8(21),
-            sectors_per_fat_16: read_u16(22),
-            sectors_per_track: read_u16(24),
-            heads: read_u16(26),
-            hidden_sectors: read_u32(28),
-            total_sectors_32: read_u32(32),
-            sectors_per_fat_32: read_u32(36),
-            ext_flags: read_u16(40),
-            fs_version: read_u16(42),
-            root_dir_cluster: read_u32(44),
-        };
+    /// Mounts an image held in memory. Fails only on a boot sector that can't be used; a
+    /// FAT cut short by a truncated image is tolerated, so what is left can still be read.
+    pub fn new(data: &'a mut [u8]) -> Result<Self, &'static str> {
+        let boot_sector = BootSector::parse(data)?;
+        let mut volume = Fat32Volume::with_storage(Storage::Memory(data), boot_sector);
+        if let Err(e) = volume.build_free_map() {
+            warn!("FAT unreadable ({}), no cluster will be allocated", e);
+        }
+        Ok(volume)
+    }
 
+    /// Mounts the volume stored on `device`, reading and writing it in place.
+    pub fn from_device(device: &'a mut DynBlockDevice<'a>) -> Result<Self, &'static str> {
+        let mut sector = [0u8; BLOCK_SIZE];
+        device.read_blocks(0, &mut sector)?;
+        let mut volume = Fat32Volume::with_storage(Storage::Device(device), BootSector::parse(&sector)?);
+        volume.build_free_map()?;
+        Ok(volume)
+    }
+
+    /// Mounts an image in memory that is only read, whose boot sector is already parsed.
+    #[cfg(feature = "parallel")]
+    pub(super) fn shared(image: &'a [u8], boot_sector: BootSector) -> Self {
+        Fat32Volume::with_storage(Storage::Shared(image), boot_sector)
+    }
+
+    fn with_storage(storage: Storage<'a>, boot_sector: BootSector) -> Self {
         let root = boot_sector.root_dir_cluster;
-        Fat32Volume { data, boot_sector, current_cluster: root }
+        let mut storage = Counted::new(storage);
+        storage.layout = (boot_sector.fat_start(), boot_sector.data_start());
+        Fat32Volume { storage, boot_sector, current_cluster: root, codepage: Codepage::default(), free_map: Vec::new(), next_free: 2, options: Fat32Options::default() }
     }
 
     pub fn get_info(&self) -> String {
@@ -49,213 +88,702 @@ impl<'a> Fat32Volume<'a> {
         let spc = self.boot_sector.sectors_per_cluster;
         let root_cluster = self.boot_sector.root_dir_cluster;
 
-        format!(
+        let mut info = format!(
             "Info:\n - Sector Size: {}\n - Cluster Size: {}\n - Root Cluster: {}\n - Current Cluster: {}",
             bps, 
             spc, 
             root_cluster,
             self.current_cluster
-        )
+        );
+        if let Ok(Some(serial)) = self.volume_serial() {
+            info += &format!("\n - Volume Serial: {:04X}-{:04X}", serial >> 16, serial & 0xFFFF);
+        }
+        info
     }
 
-    fn offset_from_cluster(&self, cluster: u32) -> usize {
-        let reserved = self.boot_sector.reserved_sectors as u64;
-        let fats = self.boot_sector.number_of_fats as u64;
-        let spf = self.boot_sector.sectors_per_fat_32 as u64;
-        let spc = self.boot_sector.sectors_per_cluster as u64;
-        let bps = self.boot_sector.bytes_per_sector as u64;
+    /// The volume ID of the extended boot record, `None` when the boot sector has none.
+    pub fn volume_serial(&self) -> Result<Option<u32>, &'static str> {
+        let bpb = self.bpb()?;
+        Ok(Some(bpb.volume_serial).filter(|_| bpb.boot_signature == 0x29))
+    }
 
-        let first_data_sector = reserved + (fats * spf);
-        let cluster_num = if cluster < 2 { 2 } else { cluster }; 
-        let cluster_offset = (cluster_num as u64 - 2) * spc;
-        
-        let total_sectors = first_data_sector + cluster_offset;
-        (total_sectors * bps) as usize
+    /// Gives the volume a new ID, in the boot sector and its backup, so that a cloned
+    /// image isn't taken for the original.
+    pub fn set_volume_serial(&mut self, serial: u32) -> Result<(), &'static str> {
+        if self.volume_serial()?.is_none() { return Err("Pas de BPB étendu"); }
+        let bps = self.boot_sector.bytes_per_sector as usize;
+        let backup = self.boot_sector.backup_boot_sector as usize;
+        self.storage.write(67, &serial.to_le_bytes())?;
+        if backup > 0 && backup < self.boot_sector.reserved_sectors as usize {
+            self.storage.write(backup * bps + 67, &serial.to_le_bytes())?;
+        }
+        Ok(())
+    }
+
+    /// Every boot sector field, read again from the image, then what looks wrong about them.
+    pub fn get_full_info(&self) -> Result<String, &'static str> {
+        let bpb = self.bpb()?;
+        let mut info = format!("Info:\n{}", bpb);
+        for warning in bpb.warnings(self.storage.len() as u64) {
+            info += &format!("\nWarning: {}", warning);
+        }
+        Ok(info)
+    }
+
+    /// Sectors, cache and cluster activity since the volume was mounted or `reset_stats`.
+    pub fn stats(&self) -> IoStats {
+        self.storage.counters.get()
+    }
+
+    pub fn reset_stats(&mut self) {
+        self.storage.counters = IoCounters::default();
     }
 
-    fn allocate_cluster(&mut self) -> Option<u32> {
-        let fat_start = (self.boot_sector.reserved_sectors as u64 * self.boot_sector.bytes_per_sector as u64) as usize;
-        let total_clusters = (self.boot_sector.sectors_per_fat_32 * self.boot_sector.bytes_per_sector as u32) / 4;
+    /// Calls `tracer` on every read and write from now on, with what it was for.
+    pub fn set_io_tracer(&mut self, tracer: Option<IoTracer>) {
+        self.storage.tracer = tracer;
+    }
+
+    pub fn bpb(&self) -> Result<BiosParameterBlock, &'static str> {
+        let mut sector = [0u8; BLOCK_SIZE];
+        self.storage.read(0, &mut sector)?;
+        BiosParameterBlock::parse(&sector)
+    }
+
+    /// The boot sector, FSInfo sector and their backups, as `restore_boot_region` takes them.
+    pub fn boot_region(&self) -> Result<Vec<u8>, &'static str> {
+        let len = self.boot_sector.boot_region_len();
+        if len > self.storage.len() { return Err(BLOCK_OUT_OF_RANGE); }
+        let mut region = vec![0u8; len];
+        self.storage.read(0, &mut region)?;
+        Ok(region)
+    }
+
+    /// Writes back boot sectors saved by `boot_region`, repairing a damaged header, and
+    /// mounts the volume again from them.
+    pub fn restore_boot_region(&mut self, saved: &[u8]) -> Result<(), &'static str> {
+        if saved.len() < BLOCK_SIZE || saved[510..512] != [0x55, 0xAA] { return Err("Sauvegarde invalide"); }
+        let boot_sector = BootSector::parse(saved).map_err(|_| "Sauvegarde invalide")?;
+        if saved.len() != boot_sector.boot_region_len() {
+            return Err("Sauvegarde invalide");
+        }
+        self.storage.write(0, saved)?;
+        self.boot_sector = boot_sector;
+        self.storage.layout = (boot_sector.fat_start(), boot_sector.data_start());
+        self.build_free_map()
+    }
+
+    /// Byte offset of `cluster`, which must be in the data region and have a FAT entry.
+    pub(super) fn offset_from_cluster(&self, cluster: u32) -> Result<usize, &'static str> {
+        if cluster >= self.fat_entries() { return Err(INVALID_CLUSTER); }
+        self.boot_sector.cluster_offset(cluster)
+    }
 
-        for i in 3..total_clusters {
-            let offset = fat_start + (i as usize * 4);
-            let entry = u32::from_le_bytes(self.data[offset..offset+4].try_into().unwrap());
-            if entry == 0 {
-                let eof: u32 = 0x0FFFFFFF;
-                self.data[offset..offset+4].copy_from_slice(&eof.to_le_bytes());
-                return Some(i);
+    /// Allocates the first free cluster from `next_free` on, wrapping around to cluster 2.
+    pub(super) fn allocate_cluster(&mut self) -> Result<u32, &'static str> {
+        let cluster = self.next_free_cluster(self.next_free.max(2))
+            .or_else(|| self.next_free_cluster(2))
+            .ok_or(DISK_FULL)?;
+        self.write_fat_entry(cluster, FAT_EOC)?;
+        self.next_free = cluster + 1;
+        Ok(cluster)
+    }
+
+    /// True when `cluster` starts on an `allocation_alignment` boundary, or when no
+    /// alignment is set.
+    pub(super) fn is_aligned(&self, cluster: u32) -> bool {
+        let Some(align) = self.options.allocation_alignment.filter(|&a| a > 0) else { return true };
+        let start = self.boot_sector.hidden_sectors as u64 * self.boot_sector.bytes_per_sector as u64;
+        self.offset_from_cluster(cluster).is_ok_and(|offset| (start + offset as u64).is_multiple_of(align as u64))
+    }
+
+    /// Allocates the first cluster of a new file, on an alignment boundary when possible.
+    /// The clusters allocated after it then follow on from there.
+    pub(super) fn allocate_first_cluster(&mut self) -> Result<u32, &'static str> {
+        if self.options.allocation_alignment.is_none() { return self.allocate_cluster(); }
+        let limit = self.cluster_limit();
+        let aligned = |volume: &Self, from: u32| (from..limit).find(|&c| volume.is_free(c) && volume.is_aligned(c));
+        match aligned(self, self.next_free.max(2)).or_else(|| aligned(self, 2)) {
+            Some(cluster) => {
+                self.write_fat_entry(cluster, FAT_EOC)?;
+                self.next_free = cluster + 1;
+                Ok(cluster)
             }
+            None => self.allocate_cluster(),
         }
-        None
-    }
-
-    pub fn list_current(&self) -> Vec<String> {
-        self.list_directory(self.current_cluster)
-    }
-
-    fn list_directory(&self, cluster: u32) -> Vec<String> {
-        let start_offset = self.offset_from_cluster(cluster);
-        let mut cursor = start_offset;
-        let mut files = Vec::new();
-
-        for _ in 0..128 { 
-            if cursor + 32 > self.data.len() { break; }
-            let entry = &self.data[cursor..cursor+32];
-            
-            if entry[0] == 0 { break; } 
-            if entry[0] == 0xE5 { cursor += 32; continue; } 
-
-            let attr = entry[11];
-            if attr != 0x0F && (attr & 0x08) == 0 {
-                let name = String::from_utf8_lossy(&entry[0..8]).trim().to_string();
-                let ext = String::from_utf8_lossy(&entry[8..11]).trim().to_string();
-                
-                let is_dir = (attr & 0x10) != 0;
-                let type_str = if is_dir { "<DIR>" } else { "     " };
-                
-                let full_name = if is_dir || ext.is_empty() { name } else { format!("{}.{}", name, ext) };
-                let size = u32::from_le_bytes(entry[28..32].try_into().unwrap());
-                
-                files.push(format!("{} {} ({} bytes)", type_str, full_name, size));
+    }
+
+    /// Allocates a chain large enough for `content`, copies the content into it and returns
+    /// the first cluster. Nothing stays allocated on failure. Empty content gets no chain
+    /// and cluster 0, as FAT wants for empty files.
+    fn write_chain(&mut self, content: &[u8]) -> Result<u32, &'static str> {
+        let mut rest = content;
+        self.write_chain_with(content.len(), |_, buf| {
+            let (chunk, tail) = rest.split_at(buf.len());
+            buf.copy_from_slice(chunk);
+            rest = tail;
+            Ok(())
+        })
+    }
+
+    /// `write_chain` for `len` bytes that `fill` hands over a cluster at a time: it is
+    /// called for each cluster in turn with a buffer to fill, cut to the bytes left for
+    /// the last one, and the volume to read them from when they are on it.
+    fn write_chain_with(&mut self, len: usize, mut fill: impl FnMut(&Self, &mut [u8]) -> Result<(), &'static str>) -> Result<u32, &'static str> {
+        if len == 0 { return Ok(0); }
+        let cluster_size = self.cluster_size();
+        let count = len.div_ceil(cluster_size);
+        let mut chain: Vec<u32> = Vec::with_capacity(count);
+        let mut buf = vec![0u8; cluster_size];
+
+        for i in 0..count {
+            let cluster = if i == 0 { self.allocate_first_cluster() } else { self.allocate_cluster() };
+            let written = cluster.and_then(|cluster| {
+                chain.push(cluster);
+                if let Some(&prev) = chain.iter().rev().nth(1) { self.write_fat_entry(prev, cluster)?; }
+                let buf = &mut buf[..cluster_size.min(len - i * cluster_size)];
+                fill(self, buf)?;
+                self.storage.write(self.offset_from_cluster(cluster)?, buf)
+            });
+            if let Err(e) = written {
+                for &c in &chain { self.write_fat_entry(c, FAT_FREE)?; }
+                return Err(e);
             }
-            cursor += 32;
-        }
-        files
-    }
-
-    pub fn change_directory(&mut self, dirname: &str) -> Result<(), &'static str> {
-        let start_offset = self.offset_from_cluster(self.current_cluster);
-        let mut cursor = start_offset;
-
-        if dirname == "." { return Ok(()); }
-
-        for _ in 0..128 {
-            if cursor + 32 > self.data.len() { break; }
-            let entry = &self.data[cursor..cursor+32];
-            if entry[0] == 0 { break; } 
-
-            let name = String::from_utf8_lossy(&entry[0..8]).trim().to_string();
-            let ext = String::from_utf8_lossy(&entry[8..11]).trim().to_string();
-            let mut full_name = name.clone();
-            if !ext.is_empty() { full_name = format!("{}.{}", name, ext); }
-
-            if name.eq_ignore_ascii_case(dirname) || full_name.eq_ignore_ascii_case(dirname) {
-                let attr = entry[11];
-                if (attr & 0x10) != 0 { 
-                    let cluster_hi = u16::from_le_bytes(entry[20..22].try_into().unwrap());
-                    let cluster_lo = u16::from_le_bytes(entry[26..28].try_into().unwrap());
-                    let mut cluster = ((cluster_hi as u32) << 16) | (cluster_lo as u32);
-                    
-                    if cluster == 0 { cluster = self.boot_sector.root_dir_cluster; }
-                    
-                    self.current_cluster = cluster;
-                    return Ok(());
-                } else {
-                    return Err("Ce n'est pas un dossier");
+        }
+        Ok(chain[0])
+    }
+
+    /// Makes the chain starting at `first` the content, `size` bytes long, of the file at
+    /// `path`: that of a new file, or of the file already there, whose old chain is then
+    /// freed. The chain is freed instead when it can't be put in place.
+    fn attach_chain(&mut self, path: &str, first: u32, size: u32) -> Result<(), &'static str> {
+        let attached = match self.resolve_path(path) {
+            Ok(Resolved::NotFound { name, .. }) if !is_valid_long_name(&name) => Err("Nom de fichier invalide"),
+            Ok(Resolved::NotFound { parent, name }) => self.write_dir_entry(parent, &name, ATTR_ARCHIVE, first, size),
+            Ok(Resolved::File(mut entry)) => match self.set_entry_cluster(entry.offset, first) {
+                Ok(()) => {
+                    self.set_entry_size(entry.offset, size)?;
+                    self.mark_modified(&mut entry)?;
+                    return self.free_chain(entry.first_cluster);
                 }
-            }
-            cursor += 32;
-        }
-        Err("Dossier introuvable")
-    }
-
-    pub fn read_file(&self, filename: &str) -> Result<Vec<u8>, &'static str> {
-        let start_offset = self.offset_from_cluster(self.current_cluster);
-        let mut cursor = start_offset;
-
-        for _ in 0..128 {
-            if cursor + 32 > self.data.len() { break; }
-            let entry = &self.data[cursor..cursor+32];
-            if entry[0] == 0 { break; } 
-            
-            let name = String::from_utf8_lossy(&entry[0..8]).trim().to_string();
-            let ext = String::from_utf8_lossy(&entry[8..11]).trim().to_string();
-            let full_name = if ext.is_empty() { name.clone() } else { format!("{}.{}", name, ext) };
-
-            if full_name.eq_ignore_ascii_case(filename) {
-                let attr = entry[11];
-                if (attr & 0x10) != 0 { return Err("C'est un dossier, utilisez cd"); }
-
-                let cluster_hi = u16::from_le_bytes(entry[20..22].try_into().unwrap());
-                let cluster_lo = u16::from_le_bytes(entry[26..28].try_into().unwrap());
-                let cluster = ((cluster_hi as u32) << 16) | (cluster_lo as u32);
-                let size = u32::from_le_bytes(entry[28..32].try_into().unwrap());
-
-                let data_offset = self.offset_from_cluster(cluster);
-                if data_offset + size as usize <= self.data.len() {
-                    let mut content = Vec::new();
-                    content.extend_from_slice(&self.data[data_offset..data_offset + size as usize]);
-                    return Ok(content);
+                Err(e) => Err(e),
+            },
+            Ok(Resolved::Dir(_)) => Err("C'est un dossier"),
+            Err(e) => Err(e),
+        };
+        if attached.is_err() { self.free_chain(first)?; }
+        attached
+    }
+
+    /// Reads `size` bytes by following the chain starting at `cluster`.
+    pub(super) fn read_chain(&self, cluster: u32, size: u32) -> Result<Vec<u8>, &'static str> {
+        // The size comes from the directory entry: a damaged one can't make us reserve more
+        // than the image holds.
+        let mut content = Vec::with_capacity((size as usize).min(self.storage.len()));
+        self.for_each_chunk(cluster, size, |chunk| content.extend_from_slice(chunk))?;
+        if content.len() < size as usize {
+            warn!("chain at cluster {} holds {} of the {} bytes of its file", cluster, content.len(), size);
+        }
+        Ok(content)
+    }
+
+    /// Calls `f` on the first `size` bytes of the chain starting at `cluster`, one
+    /// cluster at a time. Images in memory are handed over without copying.
+    pub(super) fn for_each_chunk(&self, cluster: u32, size: u32, mut f: impl FnMut(&[u8])) -> Result<(), &'static str> {
+        let cluster_size = self.cluster_size();
+        let mut remaining = size as usize;
+        let mut buf = Vec::new();
+        for c in self.cluster_chain(cluster)? {
+            if remaining == 0 { break; }
+            let offset = self.offset_from_cluster(c)?;
+            let len = remaining.min(cluster_size);
+            remaining -= len;
+            trace!("read cluster {} ({} bytes)", c, len);
+            match self.storage.slice(offset..offset + len) {
+                Some(chunk) => f(chunk),
+                None => {
+                    buf.resize(len, 0);
+                    self.storage.read(offset, &mut buf)?;
+                    f(&buf);
                 }
             }
-            cursor += 32;
         }
-        Err("Fichier introuvable")
+        Ok(())
     }
 
-    pub fn create_file(&mut self, filename: &str, content: &[u8]) -> Result<(), &'static str> {
-        let free_cluster = self.allocate_cluster().ok_or("Disque plein")?;
-        let data_offset = self.offset_from_cluster(free_cluster);
-        
-        self.data[data_offset..data_offset + content.len()].copy_from_slice(content);
-
-        let dir_offset = self.offset_from_cluster(self.current_cluster);
-        self.write_dir_entry(dir_offset, filename, free_cluster, content.len() as u32)
-    }
-
-    fn write_dir_entry(&mut self, dir_offset: usize, filename: &str, cluster: u32, size: u32) -> Result<(), &'static str> {
-        let mut cursor = dir_offset;
-        for _ in 0..64 {
-            let marker = self.data[cursor];
-            if marker == 0x00 || marker == 0xE5 {
-                let parts: Vec<&str> = filename.split('.').collect();
-                let name = parts.get(0).unwrap_or(&"UNKNOWN");
-                let ext = parts.get(1).unwrap_or(&"   ");
-                
-                let mut name_field = [0x20u8; 11]; 
-                for (i, b) in name.as_bytes().iter().take(8).enumerate() { name_field[i] = b.to_ascii_uppercase(); }
-                for (i, b) in ext.as_bytes().iter().take(3).enumerate() { name_field[8 + i] = b.to_ascii_uppercase(); }
-
-                self.data[cursor..cursor+11].copy_from_slice(&name_field);
-                self.data[cursor+11] = 0x20; 
-                let high = ((cluster >> 16) as u16).to_le_bytes();
-                self.data[cursor+20] = high[0]; self.data[cursor+21] = high[1];
-                let low = (cluster as u16).to_le_bytes();
-                self.data[cursor+26] = low[0]; self.data[cursor+27] = low[1];
-                self.data[cursor+28..cursor+32].copy_from_slice(&size.to_le_bytes());
-                return Ok(());
+    pub fn list_current(&self) -> Result<Vec<String>, &'static str> {
+        Ok(self.list_directory(self.current_cluster, &ListOptions::default())?.iter().map(DirEntry::list_line).collect())
+    }
+
+    /// Lists the directory at `path`, or describes the file it names. Hidden entries
+    /// and `.`/`..` are left out, as `list_entries` does by default.
+    pub fn list_path(&self, path: &str) -> Result<Vec<String>, &'static str> {
+        Ok(self.list_entries(path, &ListOptions::default())?.iter().map(DirEntry::list_line).collect())
+    }
+
+    /// The entries of the directory at `path`, or the entry of the file it names, chosen
+    /// and ordered as `options` says.
+    pub fn list_entries(&self, path: &str, options: &ListOptions) -> Result<Vec<DirEntry>, &'static str> {
+        match self.resolve_path(path)? {
+            Resolved::Dir(cluster) => self.list_directory(cluster, options),
+            Resolved::File(entry) => Ok(vec![entry]),
+            Resolved::NotFound { .. } => Err(FILE_NOT_FOUND),
+        }
+    }
+
+    pub(super) fn list_directory(&self, cluster: u32, options: &ListOptions) -> Result<Vec<DirEntry>, &'static str> {
+        let mut entries = if options.all {
+            self.read_dir_with_labels(cluster)?
+        } else {
+            self.read_dir(cluster)?.into_iter().filter(|e| !e.is_dot() && !e.metadata().is_hidden()).collect()
+        };
+        sort_entries(&mut entries, options.sort, options.reverse);
+        Ok(entries)
+    }
+
+    /// Sets or clears the archive attribute of the file at `path`. Backup tools clear it
+    /// once they copied the file; any later change of its content sets it again.
+    pub fn set_archive(&mut self, path: &str, archive: bool) -> Result<(), &'static str> {
+        let entry = self.file_entry(path)?;
+        let attr = if archive { entry.attr | ATTR_ARCHIVE } else { entry.attr & !ATTR_ARCHIVE };
+        self.storage.write_dir(entry.offset + 11, &[attr])
+    }
+
+    pub fn change_directory(&mut self, path: &str) -> Result<(), &'static str> {
+        self.current_cluster = self.directory_cluster(path)?;
+        Ok(())
+    }
+
+    /// Reads the file at `path`, absolute or relative to the current directory.
+    pub fn read_file(&self, path: &str) -> Result<Vec<u8>, &'static str> {
+        let entry = self.file_entry(path)?;
+        self.read_chain(entry.first_cluster, entry.size)
+    }
+
+    /// Notes that the file at `path` was read, for the reads `read_file` and friends can't
+    /// record as they borrow the volume immutably. See `Fat32Options::update_access_date`.
+    pub fn record_access(&mut self, path: &str) -> Result<(), &'static str> {
+        if !self.options.update_access_date { return Ok(()); }
+        let mut entry = self.file_entry(path)?;
+        self.stamp_accessed(&mut entry)
+    }
+
+    /// The content of the file at `path` as a slice of the image, without copying it.
+    /// Only for images in memory and files whose clusters follow each other on disk;
+    /// `file_chunks` handles fragmented ones.
+    pub fn read_file_borrowed(&self, path: &str) -> Result<&[u8], &'static str> {
+        let mut chunks = self.file_chunks(path)?;
+        let content = chunks.next().unwrap_or(&[]);
+        if chunks.next().is_some() { return Err("Fichier fragmenté"); }
+        Ok(content)
+    }
+
+    /// The content of the file at `path` as slices of the image, one per run of
+    /// contiguous clusters. Only for images in memory.
+    pub fn file_chunks(&self, path: &str) -> Result<vec::IntoIter<&[u8]>, &'static str> {
+        let entry = self.file_entry(path)?;
+        let mut chunks = Vec::new();
+        if entry.size == 0 { return Ok(chunks.into_iter()); }
+        let cluster_size = self.cluster_size();
+        let mut remaining = entry.size as usize;
+        let chain = self.cluster_chain(entry.first_cluster)?;
+        let mut clusters = chain.iter().peekable();
+        while remaining > 0 {
+            let &first = clusters.next().ok_or("Chaîne trop courte")?;
+            let mut run = 1;
+            while run * cluster_size < remaining && clusters.next_if(|&&c| c == first + run as u32).is_some() {
+                run += 1;
+            }
+            let len = remaining.min(run * cluster_size);
+            let offset = self.offset_from_cluster(first)?;
+            chunks.push(self.storage.slice(offset..offset + len).ok_or("Image hors mémoire")?);
+            remaining -= len;
+        }
+        Ok(chunks.into_iter())
+    }
+
+    /// Creates the file `path` (its parent must exist). When it already exists, fails
+    /// unless `overwrite` is set, in which case its content is replaced.
+    pub fn create_file(&mut self, path: &str, content: &[u8], overwrite: bool) -> Result<(), &'static str> {
+        match self.resolve_path(path)? {
+            Resolved::NotFound { parent, name } => self.create_file_in(parent, &name, content, overwrite),
+            Resolved::File(mut entry) if overwrite => self.replace_content(&mut entry, content),
+            Resolved::File(_) => Err(ALREADY_EXISTS),
+            Resolved::Dir(_) if overwrite => Err("C'est un dossier"),
+            Resolved::Dir(_) => Err(ALREADY_EXISTS),
+        }
+    }
+
+    /// Same as `create_file`, in the directory starting at `dir_cluster`.
+    pub fn create_file_in(&mut self, dir_cluster: u32, filename: &str, content: &[u8], overwrite: bool) -> Result<(), &'static str> {
+        if !is_valid_long_name(filename) { return Err("Nom de fichier invalide"); }
+        let existing = self.find_entry(dir_cluster, filename)?;
+
+        if let Some(mut entry) = existing {
+            if !overwrite { return Err(ALREADY_EXISTS); }
+            if entry.is_dir() { return Err("C'est un dossier"); }
+            return self.replace_content(&mut entry, content);
+        }
+
+        let free_cluster = self.write_chain(content)?;
+
+        let result = self.write_dir_entry(dir_cluster, filename, ATTR_ARCHIVE, free_cluster, content.len() as u32);
+        if result.is_err() { self.free_chain(free_cluster)?; }
+        result
+    }
+
+    /// Creates the file `path` with `size` bytes of zeros, its clusters reserved up front
+    /// so later writes only fill them in. With `contiguous`, the clusters form a single run,
+    /// as bootloaders reading files by raw sector arithmetic need. Returns the first cluster.
+    pub fn allocate(&mut self, path: &str, size: u32, contiguous: bool) -> Result<u32, &'static str> {
+        let (parent, name) = match self.resolve_path(path)? {
+            Resolved::NotFound { parent, name } => (parent, name),
+            Resolved::File(_) => return Err(ALREADY_EXISTS),
+            Resolved::Dir(_) => return Err("C'est un dossier"),
+        };
+        if !is_valid_long_name(&name) { return Err("Nom de fichier invalide"); }
+        let cluster_size = self.cluster_size();
+        let count = (size as usize).div_ceil(cluster_size).max(1);
+
+        let mut chain: Vec<u32> = Vec::with_capacity(count);
+        let run = if contiguous { Some(self.find_free_run(count as u32).ok_or("Pas de zone contiguë assez grande")?) } else { None };
+        let mut reserve = |volume: &mut Self| -> Result<(), &'static str> {
+            for i in 0..count {
+                let cluster = match run {
+                    Some(first) => { volume.write_fat_entry(first + i as u32, FAT_EOC)?; first + i as u32 }
+                    None if i == 0 => volume.allocate_first_cluster()?,
+                    None => volume.allocate_cluster()?,
+                };
+                chain.push(cluster);
+                if let Some(&prev) = chain.iter().rev().nth(1) { volume.write_fat_entry(prev, cluster)?; }
+                volume.storage.fill(volume.offset_from_cluster(cluster)?, cluster_size, 0)?;
+            }
+            volume.write_dir_entry(parent, &name, ATTR_ARCHIVE, chain[0], size)
+        };
+        if let Err(e) = reserve(self) {
+            for &c in &chain { self.write_fat_entry(c, FAT_FREE)?; }
+            return Err(e);
+        }
+        Ok(chain[0])
+    }
+
+    /// Gives the file of `entry` a new chain holding `content` and frees the old one.
+    fn replace_content(&mut self, entry: &mut DirEntry, content: &[u8]) -> Result<(), &'static str> {
+        let free_cluster = self.write_chain(content)?;
+        self.free_chain(entry.first_cluster)?;
+        self.set_entry_cluster(entry.offset, free_cluster)?;
+        self.set_entry_size(entry.offset, content.len() as u32)?;
+        self.mark_modified(entry)
+    }
+
+    /// Like POSIX `touch`: creates an empty file at `path`, which takes no cluster, or
+    /// sets the modification and access dates of the file there to the time of
+    /// `options.clock`.
+    pub fn touch(&mut self, path: &str) -> Result<(), &'static str> {
+        match self.resolve_path(path)? {
+            Resolved::NotFound { parent, name } => self.create_file_in(parent, &name, &[], false),
+            Resolved::File(mut entry) => self.stamp_modified(&mut entry),
+            Resolved::Dir(_) => Err("C'est un dossier"),
+        }
+    }
+
+    /// Deletes the file at `path`: its clusters are freed and its entries marked as deleted.
+    pub fn remove_file(&mut self, path: &str) -> Result<(), &'static str> {
+        let entry = self.file_entry(path)?;
+        self.delete_entry(&entry)
+    }
+
+    /// Frees the clusters of `entry` and marks its directory entries as deleted.
+    fn delete_entry(&mut self, entry: &DirEntry) -> Result<(), &'static str> {
+        self.free_chain(entry.first_cluster)?;
+        for &offset in entry.lfn_offsets.iter().chain([&entry.offset]) {
+            self.storage.write_dir(offset, &[0xE5])?;
+        }
+        Ok(())
+    }
+
+    /// Deletes the file or directory at `path` and, for a directory, everything under it,
+    /// deepest first. Returns the paths deleted in that order, or with `dry_run` the paths
+    /// that would be, changing nothing. The whole tree is read before anything is deleted,
+    /// so a directory loop on a damaged image leaves it untouched.
+    pub fn remove_tree(&mut self, path: &str, dry_run: bool) -> Result<Vec<String>, &'static str> {
+        let trimmed = path.trim_end_matches('/');
+        let (parent, name) = match trimmed.rfind('/') {
+            Some(i) => (&trimmed[..=i], &trimmed[i + 1..]),
+            None => ("", trimmed),
+        };
+        if matches!(name, "" | "." | "..") { return Err("Impossible de supprimer ce dossier"); }
+        let parent = if parent.is_empty() { self.current_cluster } else { self.directory_cluster(parent)? };
+        let entry = self.find_entry(parent, name)?.ok_or(FILE_NOT_FOUND)?;
+
+        let mut doomed = Vec::new();
+        let mut visited = Vec::new();
+        self.plan_removal(entry, String::from(trimmed), &mut visited, &mut doomed)?;
+        if visited.contains(&self.current_cluster) { return Err("Le dossier courant serait supprimé"); }
+        if !dry_run {
+            for (_, entry) in &doomed { self.delete_entry(entry)?; }
+        }
+        Ok(doomed.into_iter().map(|(path, _)| path).collect())
+    }
+
+    /// Copies the file at `src` to `dst`, or into `dst` when it is a directory, replacing
+    /// any file there. The data goes to a new chain a cluster at a time, so the file is
+    /// never held in memory whole, and a file at `dst` is only replaced once it is all
+    /// written: when the copy fails, it is left as it was.
+    pub fn copy_file(&mut self, src: &str, dst: &str) -> Result<(), &'static str> {
+        let entry = self.file_entry(src)?;
+        let target = match self.resolve_path(dst)? {
+            Resolved::Dir(_) if dst.is_empty() => entry.name.clone(),
+            Resolved::Dir(_) => format!("{}/{}", dst.trim_end_matches('/'), entry.name),
+            _ => String::from(dst),
+        };
+        if let Resolved::File(existing) = self.resolve_path(&target)? {
+            if existing.offset == entry.offset { return Err("Source et destination identiques"); }
+        }
+
+        let mut source = self.cluster_chain(entry.first_cluster)?.into_iter();
+        let first = self.write_chain_with(entry.size as usize, |volume, buf| {
+            let cluster = source.next().ok_or("Chaîne trop courte")?;
+            volume.storage.read(volume.offset_from_cluster(cluster)?, buf)
+        })?;
+        self.attach_chain(&target, first, entry.size)
+    }
+
+    /// Writes `size` bytes coming from `chunks` to the file at `path`, created or
+    /// replaced, such as the `file_chunks` of a file on another volume. They are written
+    /// as they come, and a file at `path` is only replaced once they all are.
+    pub fn write_file_from<'c>(&mut self, path: &str, size: u32, chunks: impl IntoIterator<Item = &'c [u8]>) -> Result<(), &'static str> {
+        let mut chunks = chunks.into_iter();
+        let mut pending: &[u8] = &[];
+        let first = self.write_chain_with(size as usize, |_, buf| {
+            let mut done = 0;
+            while done < buf.len() {
+                if pending.is_empty() { pending = chunks.next().ok_or("Contenu trop court")?; }
+                let n = pending.len().min(buf.len() - done);
+                buf[done..done + n].copy_from_slice(&pending[..n]);
+                pending = &pending[n..];
+                done += n;
+            }
+            Ok(())
+        })?;
+        self.attach_chain(path, first, size)
+    }
+
+    /// Copies the directory at `src` and everything under it to `dst`, or into `dst`
+    /// when that directory exists. The tree is read before the copy is made, so copying
+    /// a directory into itself stops. Returns the number of files copied.
+    pub fn copy_tree(&mut self, src: &str, dst: &str) -> Result<usize, &'static str> {
+        self.directory_cluster(src)?;
+        let src = src.trim_end_matches('/');
+        let name = src.rsplit('/').next().filter(|n| !n.is_empty() && *n != "." && *n != "..").ok_or("Impossible de copier ce dossier")?;
+        let base = match self.resolve_path(dst)? {
+            Resolved::Dir(_) if dst.is_empty() => String::from(name),
+            Resolved::Dir(_) => format!("{}/{}", dst.trim_end_matches('/'), name),
+            Resolved::File(_) => return Err(ALREADY_EXISTS),
+            Resolved::NotFound { .. } => String::from(dst.trim_end_matches('/')),
+        };
+        let tree: Vec<(String, bool)> = self.walk(src)?
+            .map(|item| item.map(|(_, path, metadata)| (path, metadata.is_dir())))
+            .collect::<Result<_, _>>()?;
+
+        self.create_directory(&base)?;
+        let mut copied = 0;
+        for (path, is_dir) in tree {
+            let target = format!("{}{}", base, &path[src.len()..]);
+            if is_dir {
+                self.create_directory(&target)?;
+            } else {
+                self.copy_file(&path, &target)?;
+                copied += 1;
+            }
+        }
+        Ok(copied)
+    }
+
+    /// Appends `entry` to `doomed` after everything under it, when it is a directory.
+    fn plan_removal(&self, entry: DirEntry, path: String, visited: &mut Vec<u32>, doomed: &mut Vec<(String, DirEntry)>) -> Result<(), &'static str> {
+        if entry.is_dir() {
+            let cluster = self.dir_cluster(&entry);
+            if visited.contains(&cluster) { return Err("Boucle dans l'arborescence"); }
+            visited.push(cluster);
+            for child in self.read_dir(cluster)?.into_iter().filter(|e| !e.is_dot()) {
+                let child_path = format!("{}/{}", path, child.name);
+                self.plan_removal(child, child_path, visited, doomed)?;
+            }
+        }
+        doomed.push((path, entry));
+        Ok(())
+    }
+
+    /// Like `remove_file`, but first overwrites every cluster of the file with `pattern`,
+    /// and leaves only the deleted marker of its directory entries, the rest zeroed.
+    pub fn shred_file(&mut self, path: &str, pattern: u8) -> Result<(), &'static str> {
+        let entry = self.file_entry(path)?;
+        if entry.first_cluster >= 2 {
+            for cluster in self.cluster_chain(entry.first_cluster)? {
+                self.storage.fill(self.offset_from_cluster(cluster)?, self.cluster_size(), pattern)?;
             }
-            cursor += 32;
         }
-        Err("Répertoire plein")
+        self.free_chain(entry.first_cluster)?;
+        for &offset in entry.lfn_offsets.iter().chain([&entry.offset]) {
+            let mut raw = [0u8; 32];
+            raw[0] = 0xE5;
+            self.storage.write_dir(offset, &raw)?;
+        }
+        Ok(())
+    }
+
+    /// Creates the directory `path` (its parent must exist) and returns its first cluster.
+    pub fn create_directory(&mut self, path: &str) -> Result<u32, &'static str> {
+        match self.resolve_path(path)? {
+            Resolved::NotFound { parent, name } => self.create_directory_in(parent, &name),
+            _ => Err(ALREADY_EXISTS),
+        }
+    }
+
+    /// Creates the sub-directory `name` in the directory starting at `parent`,
+    /// with its `.` and `..` entries, and returns its first cluster.
+    pub fn create_directory_in(&mut self, parent: u32, name: &str) -> Result<u32, &'static str> {
+        if !is_valid_long_name(name) { return Err("Nom de fichier invalide"); }
+        if self.find_entry(parent, name)?.is_some() { return Err(ALREADY_EXISTS); }
+
+        let cluster = self.allocate_cluster()?;
+        let offset = self.offset_from_cluster(cluster)?;
+        self.storage.fill_dir(offset, self.cluster_size(), 0)?;
+
+        // `..` points to cluster 0 when the parent is the root directory.
+        let parent_ref = if parent == self.boot_sector.root_dir_cluster { 0 } else { parent };
+        for (i, (dots, target)) in [(b".          ", cluster), (b"..         ", parent_ref)].into_iter().enumerate() {
+            let mut entry = [0u8; 12];
+            entry[..11].copy_from_slice(dots);
+            entry[11] = ATTR_DIRECTORY;
+            self.storage.write_dir(offset + i * 32, &entry)?;
+            self.set_entry_cluster(offset + i * 32, target)?;
+        }
+
+        let result = self.write_dir_entry(parent, name, ATTR_DIRECTORY, cluster, 0);
+        if result.is_err() { self.free_chain(cluster)?; }
+        result.map(|_| cluster)
+    }
+
+    /// Adds an entry for `filename` to the directory at `dir_cluster`. A unique short name
+    /// is generated, preceded by long-name entries when the short form can't represent it.
+    fn write_dir_entry(&mut self, dir_cluster: u32, filename: &str, attr: u8, cluster: u32, size: u32) -> Result<(), &'static str> {
+        let taken: Vec<[u8; 11]> = self.read_dir(dir_cluster)?.iter().map(|e| e.short_name).collect();
+        let short_name = generate_short_name(filename, &taken, self.codepage);
+
+        let case_flags = case_flags_for(filename, &short_name, self.codepage);
+
+        let mut slots = if case_flags.is_some() { Vec::new() } else { encode_lfn(filename, &short_name) };
+        let mut entry = [0u8; 32];
+        entry[0..11].copy_from_slice(&short_name);
+        entry[11] = attr;
+        entry[12] = case_flags.unwrap_or(0);
+        if let Some((now, hundredths)) = self.options.clock.map(|clock| clock()) {
+            let (date, time) = now.to_fat();
+            entry[13] = hundredths.min(199);
+            for at in [14, 22] { entry[at..at + 2].copy_from_slice(&time.to_le_bytes()); }
+            for at in [16, 18, 24] { entry[at..at + 2].copy_from_slice(&date.to_le_bytes()); }
+        }
+        entry[20..22].copy_from_slice(&((cluster >> 16) as u16).to_le_bytes());
+        entry[26..28].copy_from_slice(&(cluster as u16).to_le_bytes());
+        entry[28..32].copy_from_slice(&size.to_le_bytes());
+        slots.push(entry);
+
+        // Long-name entries must directly precede their short entry, so look for a run of
+        // free slots anywhere in the directory chain, growing the directory when there is none.
+        let mut chain = self.cluster_chain(dir_cluster)?;
+        let mut free_slots: Vec<usize> = Vec::new();
+        for &c in &chain { self.collect_dir_slots(c, &mut free_slots)?; }
+
+        let run_start = loop {
+            if let Some(start) = free_run(&free_slots, slots.len()) { break start; }
+
+            let new_cluster = self.allocate_cluster()?;
+            self.storage.fill_dir(self.offset_from_cluster(new_cluster)?, self.cluster_size(), 0)?;
+            self.write_fat_entry(*chain.last().unwrap(), new_cluster)?;
+            chain.push(new_cluster);
+            self.collect_dir_slots(new_cluster, &mut free_slots)?;
+        };
+
+        for (j, slot) in slots.iter().enumerate() {
+            self.storage.write_dir(free_slots[run_start + j], slot)?;
+        }
+        Ok(())
+    }
+
+    /// Appends the offset of every slot of the directory cluster to `slots`,
+    /// using `usize::MAX` for the ones in use so that runs of free slots stay detectable.
+    fn collect_dir_slots(&self, cluster: u32, slots: &mut Vec<usize>) -> Result<(), &'static str> {
+        let start = self.offset_from_cluster(cluster)?;
+        let mut raw = vec![0u8; self.cluster_size()];
+        self.storage.read_dir(start, &mut raw)?;
+        for (i, entry) in raw.chunks_exact(32).enumerate() {
+            let marker = entry[0];
+            slots.push(if marker == 0x00 || marker == 0xE5 { start + i * 32 } else { usize::MAX });
+        }
+        Ok(())
+    }
+}
+
+/// Index of the first run of `len` free slots (see `collect_dir_slots`).
+fn free_run(slots: &[usize], len: usize) -> Option<usize> {
+    let mut run_len = 0;
+    for (i, &slot) in slots.iter().enumerate() {
+        if slot == usize::MAX { run_len = 0; continue; }
+        run_len += 1;
+        if run_len == len { return Some(i + 1 - len); }
     }
+    None
 }
 
 // ----------------------------------------------------------------
 // TESTS (Mandatory)
 // ----------------------------------------------------------------
 #[cfg(test)]
-mod tests {
+pub(crate) mod tests {
     use super::*;
     use alloc::vec;
+    use crate::fat32::dir::{CompactReport, DirSlots, SortOrder, ATTR_HIDDEN, ATTR_VOLUME_ID};
+    use crate::fat32::fat::is_contiguous;
+    use crate::fat32::file::Fat32OpenOptions;
 
-    fn create_mock_volume() -> Vec<u8> {
+    pub(crate) fn create_mock_volume() -> Vec<u8> {
         let mut data = vec![0u8; 1024 * 1024]; 
         
         data[11] = 0x00; data[12] = 0x02; // 512 bytes per sector
         data[13] = 1;                     // 1 sector per cluster
         data[14] = 32; data[15] = 0;      // 32 reserved
         data[16] = 2;                     // 2 FATs
+        data[32..36].copy_from_slice(&2048u32.to_le_bytes()); // 2048 sectors in all
         data[36] = 100; data[37] = 0; data[38] = 0; data[39] = 0; // 100 sectors per FAT
         data[44] = 2; data[45] = 0; data[46] = 0; data[47] = 0;   // Root at 2
 
+        // FAT[0], FAT[1] reserved, FAT[2] = end of the root directory chain, in both copies
+        for fat in [32 * 512, 132 * 512] {
+            data[fat..fat+4].copy_from_slice(&0x0FFFFFF8u32.to_le_bytes());
+            data[fat+4..fat+8].copy_from_slice(&FAT_EOC.to_le_bytes());
+            data[fat+8..fat+12].copy_from_slice(&FAT_EOC.to_le_bytes());
+        }
+
         data
     }
 
+    /// Writes a raw short entry (`name` already in 8.3 padded form) at `offset`.
+    pub(crate) fn put_raw_entry(volume: &mut Fat32Volume, offset: usize, name: &[u8; 11], attr: u8, cluster: u32, size: u32) {
+        let mut raw = [0u8; 32];
+        raw[0..11].copy_from_slice(name);
+        raw[11] = attr;
+        raw[20..22].copy_from_slice(&((cluster >> 16) as u16).to_le_bytes());
+        raw[26..28].copy_from_slice(&(cluster as u16).to_le_bytes());
+        raw[28..32].copy_from_slice(&size.to_le_bytes());
+        volume.storage.write(offset, &raw).unwrap();
+    }
+
     #[test]
     fn test_volume_initialization() {
         let mut data = create_mock_volume();
-        let volume = Fat32Volume::new(&mut data);
+        let volume = Fat32Volume::new(&mut data).unwrap();
         
         let bps = volume.boot_sector.bytes_per_sector;
         let root = volume.boot_sector.root_dir_cluster;
@@ -265,11 +793,596 @@ mod tests {
         assert_eq!(volume.current_cluster, 2);
     }
 
+    #[test]
+    fn test_malformed_images() {
+        assert_eq!(Fat32Volume::new(&mut [0u8; 100]).err(), Some("Secteur de démarrage tronqué"));
+        for (offset, value) in [(12, 0), (12, 0x03), (13, 3), (16, 0), (36, 0), (44, 1)] {
+            let mut data = create_mock_volume();
+            data[offset] = value;
+            assert_eq!(Fat32Volume::new(&mut data).err(), Some("Secteur de démarrage invalide"), "byte {}", offset);
+        }
+
+        // A file claiming 4 GiB over a chain looping on itself, a directory doing the same.
+        let mut data = create_mock_volume();
+        let len = data.len();
+        let mut volume = Fat32Volume::new(&mut data).unwrap();
+        let root = volume.offset_from_cluster(2).unwrap();
+        put_raw_entry(&mut volume, root, b"HUGE    BIN", 0, 3, u32::MAX);
+        put_raw_entry(&mut volume, root + 32, b"LOOP       ", ATTR_DIRECTORY, 4, 0);
+        volume.write_fat_entry(3, 3).unwrap();
+        volume.write_fat_entry(4, 4).unwrap();
+        let dir = volume.offset_from_cluster(4).unwrap();
+        for slot in 0..16 {
+            put_raw_entry(&mut volume, dir + slot * 32, b"SAME    TXT", 0, 0, 0);
+        }
+        assert!(volume.read_file("HUGE.BIN").unwrap().len() <= len);
+        assert!(volume.read_dir(4).unwrap().len() <= crate::fat32::dir::MAX_DIR_ENTRIES);
+        assert!(volume.walk("/").unwrap().count() > 0);
+    }
+
     #[test]
     fn test_offset_calculation() {
         let mut data = create_mock_volume();
-        let volume = Fat32Volume::new(&mut data);
-        let offset = volume.offset_from_cluster(2);
+        let volume = Fat32Volume::new(&mut data).unwrap();
+        let offset = volume.offset_from_cluster(2).unwrap();
         assert_eq!(offset, 118784);
     }
+
+    #[test]
+    fn test_create_file_spanning_many_clusters() {
+        let mut data = create_mock_volume();
+        let mut volume = Fat32Volume::new(&mut data).unwrap();
+
+        let content: Vec<u8> = (0..100 * 1024).map(|i| (i % 251) as u8).collect();
+        volume.create_file("big.bin", &content, false).unwrap();
+
+        assert_eq!(volume.read_file("BIG.BIN").unwrap(), content);
+        assert_eq!(volume.cluster_chain(3).unwrap().len(), 200);
+        assert!(!volume.is_free(202));
+        assert!(volume.is_free(203));
+    }
+
+    #[test]
+    fn test_create_file_rejects_duplicates() {
+        let mut data = create_mock_volume();
+        let mut volume = Fat32Volume::new(&mut data).unwrap();
+
+        volume.create_file("a.txt", b"first", false).unwrap();
+        assert_eq!(volume.create_file("A.TXT", b"second", false), Err(ALREADY_EXISTS));
+
+        volume.create_file("a.txt", b"replaced", true).unwrap();
+        assert_eq!(volume.read_dir(2).unwrap().len(), 1);
+        assert_eq!(volume.read_file("a.txt").unwrap(), b"replaced");
+        assert!(volume.is_free(3));
+    }
+
+    #[test]
+    fn test_long_names_are_stored_and_resolved() {
+        let mut data = create_mock_volume();
+        let mut volume = Fat32Volume::new(&mut data).unwrap();
+
+        volume.create_file("my.file.name.txt", b"one", false).unwrap();
+        volume.create_file("my.file.other.txt", b"two", false).unwrap();
+
+        let entries = volume.read_dir(2).unwrap();
+        assert_eq!(entries[0].name, "my.file.name.txt");
+        assert_eq!(&entries[0].short_name, b"MYFILE~1TXT");
+        assert_eq!(&entries[1].short_name, b"MYFILE~2TXT");
+        assert_eq!(volume.read_file("MY.FILE.OTHER.TXT").unwrap(), b"two");
+        assert_eq!(volume.read_file("MYFILE~1.TXT").unwrap(), b"one");
+        assert_eq!(volume.create_file("bad|name", b"", false), Err("Nom de fichier invalide"));
+    }
+
+    #[test]
+    fn test_lowercase_short_names_use_case_flags() {
+        let mut data = create_mock_volume();
+        let mut volume = Fat32Volume::new(&mut data).unwrap();
+
+        volume.create_file("readme.txt", b"", false).unwrap();
+        volume.create_file("Notes.TXT", b"", false).unwrap();
+
+        let entries = volume.read_dir(2).unwrap();
+        assert_eq!(entries[0].name, "readme.txt");
+        assert!(entries[0].lfn_offsets.is_empty());
+        assert_eq!(entries[1].name, "Notes.TXT");
+        assert_eq!(entries[1].lfn_offsets.len(), 1);
+    }
+
+    #[test]
+    fn test_full_directory_grows() {
+        let mut data = create_mock_volume();
+        let mut volume = Fat32Volume::new(&mut data).unwrap();
+
+        // A 512-byte cluster holds 16 entries.
+        for i in 0..20 {
+            volume.create_file(&format!("F{}.TXT", i), b"x", false).unwrap();
+        }
+
+        assert_eq!(volume.read_dir(2).unwrap().len(), 20);
+        assert_eq!(volume.cluster_chain(2).unwrap().len(), 2);
+        assert_eq!(volume.read_file("F19.TXT").unwrap(), b"x");
+    }
+
+    #[test]
+    fn test_dir_slots() {
+        let mut data = create_mock_volume();
+        let mut volume = Fat32Volume::new(&mut data).unwrap();
+        for i in 0..20 {
+            volume.create_file(&format!("F{}.TXT", i), b"x", false).unwrap();
+        }
+        volume.create_file("A rather long name.txt", b"", false).unwrap();
+        volume.remove_file("F3.TXT").unwrap();
+        volume.remove_file("F4.TXT").unwrap();
+
+        let slots = volume.dir_slots(2).unwrap();
+        assert_eq!(slots, DirSlots { clusters: 2, used: 19, long_name: 2, deleted: 2, free: 9 });
+        assert_eq!(slots.total(), 32);
+    }
+
+    #[test]
+    fn test_compact_dir() {
+        let mut data = create_mock_volume();
+        let mut volume = Fat32Volume::new(&mut data).unwrap();
+        volume.create_directory("Sub").unwrap();
+        let sub = volume.directory_cluster("Sub").unwrap();
+        volume.create_directory("Sub/Inner").unwrap();
+        for i in 0..40 {
+            volume.create_file(&format!("Sub/File number {}.txt", i), b"x", false).unwrap();
+        }
+        for i in (0..40).filter(|i| i % 8 != 0) {
+            volume.remove_file(&format!("Sub/File number {}.txt", i)).unwrap();
+        }
+        let before = volume.read_dir(sub).unwrap();
+        let clusters = volume.dir_slots(sub).unwrap().clusters;
+        let free = volume.space().free;
+
+        let report = volume.compact_dir(sub).unwrap();
+        assert_eq!(report.removed, 35 * 3);
+        let slots = volume.dir_slots(sub).unwrap();
+        assert_eq!((slots.deleted, slots.used, slots.long_name), (0, 8, 11));
+        assert_eq!(slots.clusters, 2);
+        assert_eq!(report.freed_clusters, clusters - 2);
+        assert_eq!(volume.space().free, free + report.freed_clusters as u64 * 512);
+
+        let after = volume.read_dir(sub).unwrap();
+        let names = |entries: &[DirEntry]| entries.iter().map(|e| e.name.clone()).collect::<Vec<_>>();
+        assert_eq!(names(&after), names(&before));
+        assert_eq!(volume.read_file("Sub/File number 32.txt").unwrap(), b"x");
+        assert_eq!(volume.directory_path(volume.directory_cluster("Sub/Inner/..").unwrap()).unwrap(), "/Sub");
+        assert_eq!(volume.compact_dir(sub).unwrap(), CompactReport { removed: 0, freed_clusters: 0 });
+    }
+
+    #[test]
+    fn test_create_directory_and_nested_file() {
+        let mut data = create_mock_volume();
+        let mut volume = Fat32Volume::new(&mut data).unwrap();
+
+        let boot = volume.create_directory("/boot").unwrap();
+        let overlays = volume.create_directory("boot/overlays").unwrap();
+        volume.create_file_in(overlays, "dtb.txt", b"dtb", false).unwrap();
+        assert_eq!(volume.create_directory("/boot"), Err(ALREADY_EXISTS));
+
+        let dots = volume.read_dir(overlays).unwrap();
+        assert_eq!(dots[0].name, ".");
+        assert_eq!(dots[1].first_cluster, boot);
+        assert_eq!(volume.read_dir(boot).unwrap()[1].first_cluster, 0);
+
+        volume.change_directory("/boot/overlays").unwrap();
+        assert_eq!(volume.read_file("dtb.txt").unwrap(), b"dtb");
+        volume.change_directory("../..").unwrap();
+        assert_eq!(volume.current_cluster, 2);
+    }
+
+    #[test]
+    fn test_remove_file() {
+        let mut data = create_mock_volume();
+        let mut volume = Fat32Volume::new(&mut data).unwrap();
+        volume.create_file("Un nom très long.txt", b"content", false).unwrap();
+        let entry = volume.find_entry(2, "Un nom très long.txt").unwrap().unwrap();
+
+        volume.remove_file("Un nom très long.txt").unwrap();
+        assert!(volume.read_dir(2).unwrap().is_empty());
+        assert!(volume.is_free(entry.first_cluster));
+        assert_eq!(volume.remove_file("Un nom très long.txt"), Err(FILE_NOT_FOUND));
+    }
+
+    #[test]
+    fn test_remove_tree() {
+        let mut data = create_mock_volume();
+        let mut volume = Fat32Volume::new(&mut data).unwrap();
+        let free = (2..volume.cluster_limit()).filter(|&c| volume.is_free(c)).count();
+        let dcim = volume.create_directory("DCIM").unwrap();
+        let canon = volume.create_directory_in(dcim, "100CANON").unwrap();
+        volume.create_file_in(canon, "IMG_0001.JPG", &[1u8; 2000], false).unwrap();
+        volume.create_file_in(dcim, "empty.txt", b"", false).unwrap();
+        volume.create_file("keep.txt", b"keep", false).unwrap();
+
+        let expected = ["DCIM/100CANON/IMG_0001.JPG", "DCIM/100CANON", "DCIM/empty.txt", "DCIM"];
+        assert_eq!(volume.remove_tree("DCIM/", true).unwrap(), expected);
+        assert_eq!(volume.read_file("DCIM/100CANON/IMG_0001.JPG").unwrap(), [1u8; 2000]);
+
+        volume.current_cluster = canon;
+        assert_eq!(volume.remove_tree("/DCIM", false), Err("Le dossier courant serait supprimé"));
+        volume.current_cluster = 2;
+        assert_eq!(volume.remove_tree("DCIM", false).unwrap(), expected);
+        assert_eq!(volume.read_dir(2).unwrap().len(), 1);
+        assert_eq!((2..volume.cluster_limit()).filter(|&c| volume.is_free(c)).count(), free - 1);
+        assert_eq!(volume.remove_tree("DCIM", false), Err(FILE_NOT_FOUND));
+        assert_eq!(volume.remove_tree("/", false), Err("Impossible de supprimer ce dossier"));
+    }
+
+    #[test]
+    fn test_copy() {
+        let mut data = create_mock_volume();
+        let mut volume = Fat32Volume::new(&mut data).unwrap();
+        let content: Vec<u8> = (0..3000).map(|i| (i % 251) as u8).collect();
+        let dcim = volume.create_directory("DCIM").unwrap();
+        let canon = volume.create_directory_in(dcim, "100CANON").unwrap();
+        volume.create_file_in(canon, "IMG_0001.JPG", &content, false).unwrap();
+        volume.create_file_in(dcim, "empty.txt", b"", false).unwrap();
+
+        volume.copy_file("DCIM/100CANON/IMG_0001.JPG", "/").unwrap();
+        assert_eq!(volume.read_file("IMG_0001.JPG").unwrap(), content);
+        let copy = volume.file_entry("IMG_0001.JPG").unwrap();
+        assert_ne!(copy.first_cluster, volume.file_entry("DCIM/100CANON/IMG_0001.JPG").unwrap().first_cluster);
+        volume.copy_file("DCIM/empty.txt", "IMG_0001.JPG").unwrap();
+        assert_eq!(volume.file_entry("IMG_0001.JPG").unwrap().size, 0);
+        assert!(volume.is_free(copy.first_cluster));
+        assert_eq!(volume.copy_file("DCIM/empty.txt", "DCIM"), Err("Source et destination identiques"));
+
+        assert_eq!(volume.copy_tree("DCIM", "backup"), Ok(2));
+        assert_eq!(volume.read_file("backup/100CANON/IMG_0001.JPG").unwrap(), content);
+        assert_eq!(volume.copy_tree("DCIM/", "DCIM"), Ok(2));
+        assert_eq!(volume.read_file("DCIM/DCIM/100CANON/IMG_0001.JPG").unwrap(), content);
+        assert!(volume.read_dir(volume.directory_cluster("DCIM/DCIM").unwrap()).unwrap().iter().all(|e| e.name != "DCIM"));
+        assert_eq!(volume.copy_tree("DCIM", "backup"), Ok(4));
+        assert_eq!(volume.copy_tree("IMG_0001.JPG", "x"), Err("Ce n'est pas un dossier"));
+        assert_eq!(volume.copy_tree("DCIM", "IMG_0001.JPG"), Err(ALREADY_EXISTS));
+    }
+
+    #[test]
+    fn test_copy_keeps_destination_on_failure() {
+        let mut data = create_mock_volume();
+        let mut volume = Fat32Volume::new(&mut data).unwrap();
+        let free = |volume: &Fat32Volume| (2..volume.cluster_limit()).filter(|&c| volume.is_free(c)).count();
+        let big = vec![7u8; free(&volume) * 2 / 3 * volume.cluster_size()];
+        volume.create_file("big.bin", &big, false).unwrap();
+        volume.create_file("keep.txt", b"keep", false).unwrap();
+
+        let before = free(&volume);
+        assert!(volume.copy_file("big.bin", "keep.txt").is_err());
+        assert_eq!(volume.read_file("keep.txt").unwrap(), b"keep");
+        assert_eq!(free(&volume), before);
+
+        let mut other = create_mock_volume();
+        let mut other = Fat32Volume::new(&mut other).unwrap();
+        other.create_file("keep.txt", b"old", false).unwrap();
+        let content: Vec<u8> = (0..3000).map(|i| (i % 251) as u8).collect();
+        volume.create_file("keep.txt", &content, true).unwrap();
+        let entry = volume.file_entry("keep.txt").unwrap();
+        other.write_file_from("keep.txt", entry.size, volume.file_chunks("keep.txt").unwrap()).unwrap();
+        assert_eq!(other.read_file("keep.txt").unwrap(), content);
+        other.write_file_from("new.txt", 0, []).unwrap();
+        assert_eq!(other.file_entry("new.txt").unwrap().size, 0);
+        assert_eq!(other.write_file_from("short.txt", 10, [&b"abc"[..]]), Err("Contenu trop court"));
+        assert_eq!(other.resolve_path("short.txt").map(|r| matches!(r, Resolved::NotFound { .. })), Ok(true));
+    }
+
+    #[test]
+    fn test_allocate() {
+        let mut data = create_mock_volume();
+        let mut volume = Fat32Volume::new(&mut data).unwrap();
+        volume.create_file("a.txt", b"a", false).unwrap();
+        volume.create_file("b.txt", b"b", false).unwrap();
+        volume.remove_file("a.txt").unwrap();
+
+        let first = volume.allocate("fw.bin", 3000, true).unwrap();
+        let chain = volume.cluster_chain(first).unwrap();
+        assert_eq!(chain.len(), 6);
+        assert!(is_contiguous(&chain));
+        assert_eq!(volume.read_file("fw.bin").unwrap(), [0u8; 3000]);
+        assert_eq!(volume.allocate("fw.bin", 10, false), Err(ALREADY_EXISTS));
+        assert_eq!(volume.allocate("huge.bin", 1 << 30, true), Err("Pas de zone contiguë assez grande"));
+
+        // The run skips the one-cluster hole left by a.txt.
+        assert!(chain[0] > volume.file_entry("b.txt").unwrap().first_cluster);
+        let first = volume.allocate("log.bin", 1024, false).unwrap();
+        assert_eq!(volume.cluster_chain(first).unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_read_file_borrowed() {
+        let mut data = create_mock_volume();
+        let mut volume = Fat32Volume::new(&mut data).unwrap();
+        let content: Vec<u8> = (0..2500).map(|i| (i % 241) as u8).collect();
+        volume.create_file("whole.bin", &content, false).unwrap();
+        volume.create_file("empty.bin", b"", false).unwrap();
+        assert_eq!(volume.read_file_borrowed("whole.bin").unwrap(), &content[..]);
+        assert_eq!(volume.read_file_borrowed("empty.bin").unwrap(), b"");
+
+        // Split the file in two runs by moving its last cluster.
+        let chain = volume.cluster_chain(volume.file_entry("whole.bin").unwrap().first_cluster).unwrap();
+        let (last, moved) = (chain[4], chain[4] + 5);
+        let from = volume.offset_from_cluster(last).unwrap();
+        volume.storage.copy_within(from..from + 512, volume.offset_from_cluster(moved).unwrap()).unwrap();
+        volume.write_fat_entry(chain[3], moved).unwrap();
+        volume.write_fat_entry(moved, FAT_EOC).unwrap();
+        volume.write_fat_entry(last, FAT_FREE).unwrap();
+        assert_eq!(volume.read_file_borrowed("whole.bin"), Err("Fichier fragmenté"));
+        let chunks: Vec<&[u8]> = volume.file_chunks("whole.bin").unwrap().collect();
+        assert_eq!(chunks.iter().map(|c| c.len()).collect::<Vec<_>>(), [2048, 452]);
+        assert_eq!(chunks.concat(), content);
+    }
+
+    #[test]
+    fn test_restore_boot_region() {
+        let mut data = vec![0u8; 34 * 1024 * 1024];
+        crate::fat32::format::format(&mut data, &Default::default()).unwrap();
+        let mut volume = Fat32Volume::new(&mut data).unwrap();
+        volume.create_file("keep.txt", b"kept", false).unwrap();
+        let saved = volume.boot_region().unwrap();
+        // Boot sector, FSInfo, and the backups at sectors 6 and 7.
+        assert_eq!(saved.len(), 8 * 512);
+
+        volume.storage.fill(0, 1024, 0).unwrap();
+        assert_eq!(volume.restore_boot_region(&saved[..512]), Err("Sauvegarde invalide"));
+        assert_eq!(volume.restore_boot_region(&[0u8; 4096]), Err("Sauvegarde invalide"));
+        volume.restore_boot_region(&saved).unwrap();
+        drop(volume);
+        assert_eq!(Fat32Volume::new(&mut data).unwrap().read_file("keep.txt").unwrap(), b"kept");
+    }
+
+    #[test]
+    fn test_volume_serial() {
+        let mut data = vec![0u8; 34 * 1024 * 1024];
+        crate::fat32::format::format(&mut data, &crate::fat32::format::FormatOptions { volume_id: 0x1234ABCD, ..Default::default() }).unwrap();
+        let mut volume = Fat32Volume::new(&mut data).unwrap();
+        assert_eq!(volume.volume_serial(), Ok(Some(0x1234ABCD)));
+        assert!(volume.get_info().ends_with("Volume Serial: 1234-ABCD"));
+        volume.set_volume_serial(0xCAFEF00D).unwrap();
+        assert_eq!(volume.volume_serial(), Ok(Some(0xCAFEF00D)));
+        drop(volume);
+        assert_eq!(data[6 * 512 + 67..6 * 512 + 71], 0xCAFEF00Du32.to_le_bytes());
+
+        let mut data = create_mock_volume();
+        let mut volume = Fat32Volume::new(&mut data).unwrap();
+        assert_eq!(volume.volume_serial(), Ok(None));
+        assert_eq!(volume.set_volume_serial(1), Err("Pas de BPB étendu"));
+    }
+
+    #[test]
+    fn test_stats() {
+        let mut data = create_mock_volume();
+        let mut volume = Fat32Volume::new(&mut data).unwrap();
+        volume.reset_stats();
+        volume.create_file("a.bin", &[1u8; 1500], false).unwrap();
+        let stats = volume.stats();
+        assert_eq!((stats.clusters_allocated, stats.clusters_freed), (3, 0));
+        // Three data sectors, two FAT sectors and a directory sector at least.
+        assert!(stats.sectors_written >= 6);
+
+        volume.reset_stats();
+        assert_eq!(volume.read_file("a.bin").unwrap().len(), 1500);
+        assert_eq!(volume.stats().sectors_written, 0);
+        assert!(volume.stats().sectors_read >= 3);
+        volume.remove_file("a.bin").unwrap();
+        assert_eq!(volume.stats().clusters_freed, 3);
+    }
+
+    #[test]
+    fn test_clock_stamps_entries() {
+        const T1: DateTime = DateTime { year: 2024, month: 3, day: 15, hour: 13, minute: 45, second: 30 };
+        const T2: DateTime = DateTime { year: 2025, month: 1, day: 2, hour: 8, minute: 0, second: 0 };
+        let mut data = create_mock_volume();
+        let mut volume = Fat32Volume::new(&mut data).unwrap();
+        volume.create_file("old.txt", b"no clock", false).unwrap();
+        assert!(!volume.file_entry("old.txt").unwrap().modified.is_set());
+
+        volume.options.clock = Some(|| (T1, 150));
+        volume.create_file("a.txt", b"first", false).unwrap();
+        let entry = volume.file_entry("a.txt").unwrap();
+        assert_eq!((entry.created, entry.modified, entry.accessed), (T1, T1, DateTime { hour: 0, minute: 0, second: 0, ..T1 }));
+
+        volume.options.clock = Some(|| (T2, 0));
+        volume.create_file("a.txt", b"second", true).unwrap();
+        let entry = volume.file_entry("a.txt").unwrap();
+        assert_eq!((entry.created, entry.created_hundredths, entry.modified), (T1, 150, T2));
+    }
+
+    #[test]
+    fn test_touch() {
+        const T1: DateTime = DateTime { year: 2024, month: 3, day: 15, hour: 13, minute: 45, second: 30 };
+        let mut data = create_mock_volume();
+        let mut volume = Fat32Volume::new(&mut data).unwrap();
+        let free = (2..volume.cluster_limit()).filter(|&c| volume.is_free(c)).count();
+        volume.touch("empty.txt").unwrap();
+        let entry = volume.file_entry("empty.txt").unwrap();
+        assert_eq!((entry.first_cluster, entry.size), (0, 0));
+        assert_eq!((2..volume.cluster_limit()).filter(|&c| volume.is_free(c)).count(), free);
+
+        volume.create_file("a.txt", b"content", false).unwrap();
+        volume.set_archive("a.txt", false).unwrap();
+        volume.options.clock = Some(|| (T1, 0));
+        volume.touch("a.txt").unwrap();
+        let entry = volume.file_entry("a.txt").unwrap();
+        assert_eq!((entry.modified, entry.created.is_set()), (T1, false));
+        assert!(!entry.metadata().is_archive());
+        assert_eq!(volume.read_file("a.txt").unwrap(), b"content");
+        assert_eq!(volume.touch("/"), Err("C'est un dossier"));
+    }
+
+    #[test]
+    fn test_set_modified() {
+        const T1: DateTime = DateTime { year: 2024, month: 3, day: 15, hour: 13, minute: 45, second: 30 };
+        let mut data = create_mock_volume();
+        let mut volume = Fat32Volume::new(&mut data).unwrap();
+        volume.create_file("a.txt", b"content", false).unwrap();
+        volume.set_modified("a.txt", T1).unwrap();
+        let entry = volume.file_entry("a.txt").unwrap();
+        assert_eq!((entry.modified, entry.accessed), (T1, DateTime { hour: 0, minute: 0, second: 0, ..T1 }));
+        assert!(!entry.created.is_set());
+        assert_eq!(volume.set_modified("/", T1), Err("C'est un dossier, utilisez cd"));
+    }
+
+    #[test]
+    fn test_list_entries_hides_hidden() {
+        let mut data = create_mock_volume();
+        let mut volume = Fat32Volume::new(&mut data).unwrap();
+        let root = volume.offset_from_cluster(2).unwrap();
+        put_raw_entry(&mut volume, root, b"MYDISK     ", ATTR_VOLUME_ID, 0, 0);
+        put_raw_entry(&mut volume, root + 32, b"SECRET  TXT", ATTR_ARCHIVE | ATTR_HIDDEN, 0, 0);
+        volume.create_file("shown.txt", b"x", false).unwrap();
+        let sub = volume.create_directory("sub").unwrap();
+
+        let names = |entries: Vec<DirEntry>| entries.into_iter().map(|e| e.name).collect::<Vec<_>>();
+        let all = ListOptions { all: true, ..Default::default() };
+        assert_eq!(names(volume.list_entries("/", &ListOptions::default()).unwrap()), ["shown.txt", "sub"]);
+        assert_eq!(names(volume.list_entries("/", &all).unwrap()), ["MYDISK", "SECRET.TXT", "shown.txt", "sub"]);
+        assert_eq!(volume.list_path("/").unwrap()[0], "      shown.txt (1 bytes)");
+        assert_eq!(volume.list_entries("/", &all).unwrap()[0].list_line(), "<VOL> MYDISK (0 bytes)");
+        // A hidden file named on its own is still listed.
+        assert_eq!(names(volume.list_entries("SECRET.TXT", &ListOptions::default()).unwrap()), ["SECRET.TXT"]);
+
+        volume.change_directory("sub").unwrap();
+        assert!(volume.list_current().unwrap().is_empty());
+        assert_eq!(names(volume.list_entries(".", &all).unwrap()), [".", ".."]);
+        assert_eq!(sub, volume.current_cluster);
+    }
+
+    #[test]
+    fn test_list_entries_sorted() {
+        const OLD: DateTime = DateTime { year: 2020, month: 1, day: 1, hour: 0, minute: 0, second: 0 };
+        const NEW: DateTime = DateTime { year: 2024, month: 1, day: 1, hour: 0, minute: 0, second: 0 };
+        let mut data = create_mock_volume();
+        let mut volume = Fat32Volume::new(&mut data).unwrap();
+        volume.options.clock = Some(|| (NEW, 0));
+        volume.create_file("b.txt", &[0; 10], false).unwrap();
+        volume.options.clock = Some(|| (OLD, 0));
+        volume.create_file("C.txt", &[0; 30], false).unwrap();
+        volume.create_file("a.txt", &[0; 10], false).unwrap();
+        let sub = volume.create_directory("sub").unwrap();
+        volume.create_file_in(sub, "x", b"", false).unwrap();
+        volume.create_file_in(sub, "y", b"", false).unwrap();
+
+        let names = |volume: &Fat32Volume, path: &str, sort, reverse| -> Vec<String> {
+            let options = ListOptions { all: true, sort, reverse };
+            volume.list_entries(path, &options).unwrap().into_iter().map(|e| e.name).collect()
+        };
+        assert_eq!(names(&volume, "/", SortOrder::Name, false), ["a.txt", "b.txt", "C.txt", "sub"]);
+        assert_eq!(names(&volume, "/", SortOrder::Name, true), ["sub", "C.txt", "b.txt", "a.txt"]);
+        // Equal sizes keep their directory order in both directions.
+        assert_eq!(names(&volume, "/", SortOrder::Size, false), ["C.txt", "b.txt", "a.txt", "sub"]);
+        assert_eq!(names(&volume, "/", SortOrder::Size, true), ["sub", "b.txt", "a.txt", "C.txt"]);
+        assert_eq!(names(&volume, "/", SortOrder::Time, false), ["b.txt", "C.txt", "a.txt", "sub"]);
+        assert_eq!(names(&volume, "sub", SortOrder::Disk, true), [".", "..", "y", "x"]);
+        assert_eq!(names(&volume, "sub", SortOrder::Name, true), [".", "..", "y", "x"]);
+    }
+
+    #[test]
+    fn test_archive_bit() {
+        let mut data = create_mock_volume();
+        let mut volume = Fat32Volume::new(&mut data).unwrap();
+        volume.create_file("a.txt", b"content", false).unwrap();
+        assert!(volume.file_entry("a.txt").unwrap().metadata().is_archive());
+
+        volume.set_archive("a.txt", false).unwrap();
+        assert!(!volume.file_entry("a.txt").unwrap().metadata().is_archive());
+        let mut file = Fat32OpenOptions::new().read(true).open(&mut volume, "a.txt").unwrap();
+        file.read(&mut [0u8; 16]).unwrap();
+        drop(file);
+        assert!(!volume.file_entry("a.txt").unwrap().metadata().is_archive());
+
+        let mut file = Fat32OpenOptions::new().append(true).open(&mut volume, "a.txt").unwrap();
+        file.write(b"!").unwrap();
+        drop(file);
+        assert!(volume.file_entry("a.txt").unwrap().metadata().is_archive());
+
+        volume.set_archive("a.txt", false).unwrap();
+        volume.create_file("a.txt", b"replaced", true).unwrap();
+        let entry = volume.file_entry("a.txt").unwrap();
+        assert!(entry.metadata().is_archive());
+        assert!(!entry.is_dir());
+    }
+
+    #[test]
+    fn test_update_access_date() {
+        const T1: DateTime = DateTime { year: 2024, month: 3, day: 15, hour: 13, minute: 45, second: 30 };
+        let mut data = create_mock_volume();
+        let mut volume = Fat32Volume::new(&mut data).unwrap();
+        volume.create_file("a.txt", b"content", false).unwrap();
+        volume.options.clock = Some(|| (T1, 0));
+
+        // Off by default: reading writes nothing.
+        volume.reset_stats();
+        let mut file = Fat32OpenOptions::new().read(true).open(&mut volume, "a.txt").unwrap();
+        file.read(&mut [0u8; 16]).unwrap();
+        drop(file);
+        volume.record_access("a.txt").unwrap();
+        assert_eq!(volume.stats().sectors_written, 0);
+        assert!(!volume.file_entry("a.txt").unwrap().accessed.is_set());
+
+        volume.options.update_access_date = true;
+        let mut file = Fat32OpenOptions::new().read(true).open(&mut volume, "a.txt").unwrap();
+        file.read(&mut [0u8; 4]).unwrap();
+        file.read(&mut [0u8; 4]).unwrap();
+        drop(file);
+        assert_eq!(volume.stats().sectors_written, 1);
+        let entry = volume.file_entry("a.txt").unwrap();
+        assert_eq!(entry.accessed, DateTime { hour: 0, minute: 0, second: 0, ..T1 });
+        assert!(!entry.modified.is_set());
+    }
+
+    #[test]
+    fn test_allocation_alignment() {
+        let mut data = create_mock_volume();
+        let mut volume = Fat32Volume::new(&mut data).unwrap();
+        volume.options.allocation_alignment = Some(8192);
+        for name in ["a.bin", "b.bin", "c.bin"] {
+            volume.create_file(name, &[1; 1500], false).unwrap();
+            let chain = volume.cluster_chain(volume.file_entry(name).unwrap().first_cluster).unwrap();
+            assert_eq!(volume.offset_from_cluster(chain[0]).unwrap() % 8192, 0);
+            assert!(is_contiguous(&chain));
+        }
+        let first = volume.allocate("fw.bin", 3000, true).unwrap();
+        assert_eq!(volume.offset_from_cluster(first).unwrap() % 8192, 0);
+
+        // Without an aligned cluster left, any free one will do.
+        volume.options.allocation_alignment = Some(1 << 30);
+        volume.create_file("d.bin", b"d", false).unwrap();
+        assert_eq!(volume.read_file("d.bin").unwrap(), b"d");
+    }
+
+    #[test]
+    fn test_shred_file() {
+        let mut data = create_mock_volume();
+        let mut volume = Fat32Volume::new(&mut data).unwrap();
+        volume.create_file("Secret notes.txt", &[b'x'; 700], false).unwrap();
+        let entry = volume.find_entry(2, "Secret notes.txt").unwrap().unwrap();
+        let start = volume.offset_from_cluster(entry.first_cluster).unwrap();
+
+        volume.shred_file("Secret notes.txt", 0).unwrap();
+        assert!(volume.read_dir(2).unwrap().is_empty());
+        assert!(volume.is_free(entry.first_cluster));
+        let mut raw = vec![0xFFu8; 1024];
+        volume.storage.read(start, &mut raw).unwrap();
+        assert!(raw.iter().all(|&b| b == 0));
+        for offset in entry.lfn_offsets.iter().chain([&entry.offset]) {
+            volume.storage.read(*offset, &mut raw[..32]).unwrap();
+            assert_eq!(raw[0], 0xE5);
+            assert!(raw[1..32].iter().all(|&b| b == 0));
+        }
+    }
+
+    #[test]
+    fn test_allocation_reuses_freed_clusters() {
+        let mut data = create_mock_volume();
+        let mut volume = Fat32Volume::new(&mut data).unwrap();
+
+        let limit = volume.cluster_limit();
+        for c in 3..limit { volume.write_fat_entry(c, FAT_EOC).unwrap(); }
+        volume.write_fat_entry(10, FAT_FREE).unwrap();
+
+        assert_eq!(volume.allocate_cluster(), Ok(10));
+        assert_eq!(volume.allocate_cluster(), Err(DISK_FULL));
+    }
 }
\ No newline at end of file
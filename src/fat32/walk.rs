@@ -0,0 +1,330 @@
+extern crate alloc;
+use alloc::vec::Vec;
+use alloc::vec;
+use alloc::string::String;
+use alloc::format;
+use alloc::collections::BTreeMap;
+use log::warn;
+
+use super::dir::{DirEntry, ListOptions, Metadata};
+use super::error::FILE_NOT_FOUND;
+use super::path::Resolved;
+use super::volume::Fat32Volume;
+
+/// Depth-first iterator over a tree of the image, returned by `Fat32Volume::walk`.
+/// Yields `(depth, full_path, metadata)`; the direct children of the walked directory
+/// are at depth 1, and a walked file is yielded alone at depth 0. A directory that
+/// can't be read yields its error, and the walk goes on with its siblings.
+pub struct Walk<'v, 'a> {
+    volume: &'v Fat32Volume<'a>,
+    /// One level per directory being listed: its depth, its path and the entries left.
+    stack: Vec<(usize, String, vec::IntoIter<DirEntry>)>,
+    single: Option<(String, Metadata)>,
+    visited: Vec<u32>,
+    max_depth: usize,
+    skip_hidden: bool,
+}
+
+impl<'v, 'a> Walk<'v, 'a> {
+    /// Doesn't yield anything deeper than `depth`.
+    pub fn max_depth(mut self, depth: usize) -> Self {
+        self.max_depth = depth;
+        self
+    }
+
+    /// Leaves out entries with the hidden attribute, and everything below hidden directories.
+    pub fn skip_hidden(mut self, skip: bool) -> Self {
+        self.skip_hidden = skip;
+        self
+    }
+}
+
+impl<'v, 'a> Iterator for Walk<'v, 'a> {
+    type Item = Result<(usize, String, Metadata), &'static str>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some((path, metadata)) = self.single.take() {
+            return Some(Ok((0, path, metadata)));
+        }
+
+        loop {
+            let (depth, prefix, entries) = self.stack.last_mut()?;
+            let depth = *depth;
+            let Some(entry) = entries.next() else {
+                self.stack.pop();
+                continue;
+            };
+            if entry.is_dot() || depth > self.max_depth { continue; }
+
+            let metadata = entry.metadata();
+            if self.skip_hidden && metadata.is_hidden() { continue; }
+            let path = join(prefix, &entry.name);
+
+            // A corrupted directory pointing back to one of its parents would loop forever.
+            let cluster = entry.first_cluster;
+            if entry.is_dir() && depth < self.max_depth && cluster >= 2 && !self.visited.contains(&cluster) {
+                self.visited.push(cluster);
+                match self.volume.read_dir(cluster) {
+                    Ok(children) => self.stack.push((depth + 1, path.clone(), children.into_iter())),
+                    Err(e) => return Some(Err(e)),
+                }
+            }
+            return Some(Ok((depth, path, metadata)));
+        }
+    }
+}
+
+/// `Send` with the `parallel` feature, where `map_files` runs on several threads.
+#[cfg(feature = "parallel")]
+pub trait MaybeSend: Send {}
+#[cfg(feature = "parallel")]
+impl<T: Send + ?Sized> MaybeSend for T {}
+#[cfg(not(feature = "parallel"))]
+pub trait MaybeSend {}
+#[cfg(not(feature = "parallel"))]
+impl<T: ?Sized> MaybeSend for T {}
+
+/// `Sync` with the `parallel` feature, for the same reason.
+#[cfg(feature = "parallel")]
+pub trait MaybeSync: Sync {}
+#[cfg(feature = "parallel")]
+impl<T: Sync + ?Sized> MaybeSync for T {}
+#[cfg(not(feature = "parallel"))]
+pub trait MaybeSync {}
+#[cfg(not(feature = "parallel"))]
+impl<T: ?Sized> MaybeSync for T {}
+
+/// Moves the innermost directory of `totals` to `usage`, adding its bytes to its parent.
+fn finish(totals: &mut Vec<(usize, String, u64)>, usage: &mut Vec<(String, u64)>) {
+    let (_, path, bytes) = totals.pop().unwrap();
+    if let Some(parent) = totals.last_mut() { parent.2 += bytes; }
+    usage.push((path, bytes));
+}
+
+fn join(prefix: &str, name: &str) -> String {
+    if prefix.is_empty() { name.into() } else { format!("{}/{}", prefix.trim_end_matches('/'), name) }
+}
+
+impl<'a> Fat32Volume<'a> {
+    /// Walks everything below `path`, depth first. Full paths start with `path` as given.
+    pub fn walk(&self, path: &str) -> Result<Walk<'_, 'a>, &'static str> {
+        match self.resolve_path(path)? {
+            Resolved::Dir(cluster) => self.walk_dir(cluster, path),
+            Resolved::File(entry) => {
+                let mut walk = self.walk_dir(0, "")?;
+                walk.stack.clear();
+                walk.single = Some((path.into(), entry.metadata()));
+                Ok(walk)
+            }
+            Resolved::NotFound { .. } => Err(FILE_NOT_FOUND),
+        }
+    }
+
+    /// The listing of the directory at `path` (as `list_entries` gives it) followed by
+    /// those of every directory below it, depth first, as `ls -R` shows them: a
+    /// directory's sub-directories come in the order of its own listing. Hidden
+    /// directories are only entered with `options.all`. Each directory is listed once,
+    /// so a corrupted one pointing back to a parent doesn't loop, and one that can't be
+    /// read is left out with a warning.
+    pub fn list_recursive(&self, path: &str, options: &ListOptions) -> Result<Vec<(String, Vec<DirEntry>)>, &'static str> {
+        let top = self.list_entries(path, options)?;
+        let Resolved::Dir(start) = self.resolve_path(path)? else { return Ok(vec![(path.into(), top)]) };
+        // The walk finds the directories, and yields one pointing back to a parent
+        // without entering it.
+        let mut listed = vec![start];
+        let mut below = BTreeMap::new();
+        for item in self.walk(path)?.skip_hidden(!options.all) {
+            match item {
+                Ok((_, path, metadata)) if metadata.is_dir() => {
+                    if metadata.first_cluster < 2 || listed.contains(&metadata.first_cluster) {
+                        warn!("{}: directory already listed, skipped", path);
+                        continue;
+                    }
+                    listed.push(metadata.first_cluster);
+                    match self.list_directory(metadata.first_cluster, options) {
+                        Ok(entries) => { below.insert(path, entries); }
+                        Err(e) => warn!("{}: {}", path, e),
+                    }
+                }
+                Ok(_) => {}
+                Err(e) => warn!("{}", e),
+            }
+        }
+
+        let mut listings = Vec::new();
+        let mut pending = vec![(String::from(path), top)];
+        while let Some((dir, entries)) = pending.pop() {
+            let children: Vec<_> = entries.iter()
+                .filter(|e| e.is_dir() && !e.is_dot())
+                .filter_map(|e| { let child = join(&dir, &e.name); below.remove(&child).map(|entries| (child, entries)) })
+                .collect();
+            pending.extend(children.into_iter().rev());
+            listings.push((dir, entries));
+        }
+        Ok(listings)
+    }
+
+    /// Bytes allocated below `path`, as `du` counts them: whole clusters of every file
+    /// and directory. One `(path, bytes)` pair per directory, each after the ones below
+    /// it, ending with `path` itself.
+    pub fn disk_usage(&self, path: &str) -> Result<Vec<(String, u64)>, &'static str> {
+        let cluster_size = self.cluster_size() as u64;
+        let mut usage = Vec::new();
+        let mut totals = vec![(0, String::from(path), match self.resolve_path(path)? {
+            Resolved::Dir(cluster) => self.cluster_chain(cluster)?.len() as u64 * cluster_size,
+            _ => 0,
+        })];
+        for item in self.walk(path)? {
+            let (depth, path, metadata) = item?;
+            // Directories deeper than the new item are complete.
+            while totals.len() > depth.max(1) { finish(&mut totals, &mut usage); }
+            if metadata.is_dir() {
+                let size = if metadata.first_cluster >= 2 { self.cluster_chain(metadata.first_cluster)?.len() as u64 * cluster_size } else { 0 };
+                totals.push((depth, path, size));
+            } else {
+                totals.last_mut().unwrap().2 += (metadata.size as u64).div_ceil(cluster_size) * cluster_size;
+            }
+        }
+        while !totals.is_empty() { finish(&mut totals, &mut usage); }
+        Ok(usage)
+    }
+
+    fn walk_dir(&self, cluster: u32, prefix: &str) -> Result<Walk<'_, 'a>, &'static str> {
+        Ok(Walk {
+            volume: self,
+            stack: vec![(1, prefix.into(), self.read_dir(cluster)?.into_iter())],
+            single: None,
+            visited: vec![cluster],
+            max_depth: usize::MAX,
+            skip_hidden: false,
+        })
+    }
+
+    /// Runs `work` on every file below the directory `path` and returns the results
+    /// paired with the file paths (relative to `path`), in walk order. `work` is given
+    /// the volume to read the file through.
+    ///
+    /// With the `parallel` feature the calls are spread over rayon's thread pool when the
+    /// image is in memory, each thread reading it through a volume of its own. The image
+    /// is only read while they run, so they need no locking between them. A volume on a
+    /// device, which threads may not share, is read on this one.
+    pub fn map_files<T, F>(&self, path: &str, work: F) -> Result<Vec<(String, T)>, &'static str>
+    where
+        T: MaybeSend,
+        F: Fn(&Fat32Volume, &Metadata) -> T + MaybeSync + MaybeSend,
+    {
+        let cluster = self.directory_cluster(path)?;
+        let mut files: Vec<(String, Metadata)> = Vec::new();
+        for item in self.walk_dir(cluster, "")? {
+            let (_, path, metadata) = item?;
+            if !metadata.is_dir() { files.push((path, metadata)); }
+        }
+
+        #[cfg(feature = "parallel")]
+        if let Some(image) = (*self.storage).slice(0..self.storage.len()) {
+            use rayon::prelude::*;
+            let (boot_sector, codepage, options, tracer) = (self.boot_sector, self.codepage, self.options, self.storage.tracer);
+            let counters = &self.storage.counters;
+            let view = || {
+                let mut view = Fat32Volume::shared(image, boot_sector);
+                (view.codepage, view.options, view.storage.tracer) = (codepage, options, tracer);
+                view
+            };
+            return Ok(files.into_par_iter().map_init(view, |view, (path, metadata)| {
+                let r = work(view, &metadata);
+                view.storage.counters.drain_into(counters);
+                (path, r)
+            }).collect());
+        }
+        Ok(files.into_iter().map(|(path, metadata)| { let r = work(self, &metadata); (path, r) }).collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fat32::dir::{SortOrder, ATTR_HIDDEN};
+    use crate::fat32::volume::tests::create_mock_volume;
+
+    #[test]
+    fn test_walk() {
+        let mut data = create_mock_volume();
+        let mut volume = Fat32Volume::new(&mut data).unwrap();
+        let docs = volume.create_directory("docs").unwrap();
+        let deep = volume.create_directory("docs/deep").unwrap();
+        volume.create_file_in(deep, "z.txt", b"zz", false).unwrap();
+        volume.create_file_in(docs, "a.txt", b"a", false).unwrap();
+        volume.create_file("top.txt", b"top", false).unwrap();
+
+        let all: Vec<(usize, String)> = volume.walk("/").unwrap().map(|i| { let (d, p, _) = i.unwrap(); (d, p) }).collect();
+        assert_eq!(all, [
+            (1, "/docs".into()), (2, "/docs/deep".into()), (3, "/docs/deep/z.txt".into()),
+            (2, "/docs/a.txt".into()), (1, "/top.txt".into()),
+        ]);
+
+        let shallow: Vec<String> = volume.walk("docs").unwrap().max_depth(1).map(|i| i.unwrap().1).collect();
+        assert_eq!(shallow, ["docs/deep", "docs/a.txt"]);
+
+        let sizes = volume.map_files("docs", |_, metadata| metadata.size).unwrap();
+        assert_eq!(sizes, [("deep/z.txt".into(), 2), ("a.txt".into(), 1)]);
+
+        let (depth, path, metadata) = volume.walk("docs/a.txt").unwrap().next().unwrap().unwrap();
+        assert_eq!((depth, path.as_str(), metadata.size), (0, "docs/a.txt", 1));
+
+        let entry = volume.find_entry(2, "docs").unwrap().unwrap();
+        volume.storage.write(entry.offset + 11, &[entry.attr | ATTR_HIDDEN]).unwrap();
+        let visible: Vec<String> = volume.walk("/").unwrap().skip_hidden(true).map(|i| i.unwrap().1).collect();
+        assert_eq!(visible, ["/top.txt"]);
+    }
+
+    #[test]
+    fn test_disk_usage() {
+        let mut data = create_mock_volume();
+        let mut volume = Fat32Volume::new(&mut data).unwrap();
+        let docs = volume.create_directory("docs").unwrap();
+        let deep = volume.create_directory("docs/deep").unwrap();
+        volume.create_file_in(deep, "z.txt", &[1; 1000], false).unwrap();
+        volume.create_file_in(docs, "a.txt", b"a", false).unwrap();
+        volume.create_file("top.txt", b"", false).unwrap();
+
+        // 512-byte clusters: one per directory, two for z.txt and one for a.txt.
+        let usage = volume.disk_usage("/").unwrap();
+        assert_eq!(usage, [("/docs/deep".into(), 3 * 512), ("/docs".into(), 5 * 512), ("/".into(), 6 * 512)]);
+        assert_eq!(volume.disk_usage("docs/a.txt").unwrap(), [("docs/a.txt".into(), 512)]);
+
+        let space = volume.space();
+        assert_eq!(space.used(), space.total - space.free);
+        volume.create_file("big.bin", &[0; 2048], false).unwrap();
+        assert_eq!(volume.space().free, space.free - 2048);
+    }
+
+    #[test]
+    fn test_list_recursive() {
+        let mut data = create_mock_volume();
+        let mut volume = Fat32Volume::new(&mut data).unwrap();
+        let docs = volume.create_directory("docs").unwrap();
+        let deep = volume.create_directory("docs/deep").unwrap();
+        volume.create_directory("empty").unwrap();
+        volume.create_file_in(deep, "z.txt", b"zz", false).unwrap();
+        volume.create_file_in(docs, "a.txt", b"a", false).unwrap();
+        // A corrupted entry pointing back at the root directory.
+        volume.create_directory("docs/deep/loop").unwrap();
+        let entry = volume.find_entry(deep, "loop").unwrap().unwrap();
+        volume.set_entry_cluster(entry.offset, 2).unwrap();
+
+        let listings = volume.list_recursive(".", &ListOptions::default()).unwrap();
+        let headers: Vec<&str> = listings.iter().map(|(path, _)| path.as_str()).collect();
+        assert_eq!(headers, [".", "./docs", "./docs/deep", "./empty"]);
+        let names: Vec<&str> = listings[1].1.iter().map(|e| e.name.as_str()).collect();
+        assert_eq!(names, ["deep", "a.txt"]);
+        assert!(listings[3].1.is_empty());
+        let all = ListOptions { all: true, ..Default::default() };
+        assert_eq!(volume.list_recursive(".", &all).unwrap()[3].1.len(), 2);
+        assert_eq!(volume.list_recursive("docs/a.txt", &all).unwrap().len(), 1);
+
+        // Sub-directories follow the order of their parent's listing.
+        let by_name = ListOptions { sort: SortOrder::Name, reverse: true, ..Default::default() };
+        let headers: Vec<String> = volume.list_recursive(".", &by_name).unwrap().into_iter().map(|(path, _)| path).collect();
+        assert_eq!(headers, [".", "./empty", "./docs", "./docs/deep"]);
+    }
+}
@@ -1,4 +1,5 @@
 #![no_std]
+#[cfg(any(feature = "alloc", test))]
 extern crate alloc;
 
 pub mod fat32;
\ No newline at end of file
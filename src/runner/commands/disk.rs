@@ -0,0 +1,330 @@
+//! Low-level commands: the FAT, boot sectors, raw sectors and clusters, and the layout
+//! of files and directories on disk.
+
+use alloc::format;
+use alloc::vec;
+use alloc::string::String;
+use alloc::vec::Vec;
+use fat32::fat32::dir::MAX_DIR_ENTRIES;
+use fat32::fat32::io::{BlockDevice, BLOCK_SIZE};
+use fat32::fat32::volume::Fat32Volume;
+use crate::host::read_host_file;
+use crate::shell::{parse_byte, parse_size, print_error};
+use crate::sys::{sys_close, sys_create, sys_mkdir, sys_print, sys_print_raw, sys_read_line, sys_write, sys_write_file};
+use crate::term::{confirm, print_hexdump, ProgressBar};
+
+/// Runs `command` if it is one of the low-level ones; false otherwise.
+pub fn run(volume: &mut Fat32Volume, command: &str, args: &[&str]) -> bool {
+    let arg1 = args.first().copied();
+    match command {
+        "fat" | "fatdump" => {
+            let numbers: Vec<Option<u32>> = args.iter().map(|a| a.parse().ok()).collect();
+            let range = match (command, numbers.as_slice()) {
+                ("fat", [Some(cluster)]) => Some((*cluster, 1)),
+                ("fatdump", [Some(start), Some(count)]) => Some((*start, *count)),
+                _ => None,
+            };
+            match range {
+                Some((start, count)) => {
+                    let end = start.saturating_add(count).min(volume.fat_entries());
+                    if start >= end { print_error("Cluster hors de la FAT"); }
+                    for cluster in start..end {
+                        match volume.fat_value(cluster) {
+                            Ok((raw, value)) => sys_print(&format!("FAT[{}] = {:#010x}  {}", cluster, raw, value)),
+                            Err(e) => { print_error(e); break; }
+                        }
+                    }
+                }
+                None => print_error("Usage: fat <cluster> | fatdump <start> <count>"),
+            }
+        }
+        "fatcmp" => {
+            match volume.compare_fats() {
+                Ok(divergences) if divergences.is_empty() => sys_print(&format!("All {} FAT copies are identical.", volume.boot_sector.number_of_fats)),
+                Ok(divergences) => for d in divergences {
+                    const SHOWN: usize = 8;
+                    let mut ranges: Vec<String> = d.ranges.iter().take(SHOWN).map(|r| match r.len() {
+                        1 => format!("{}", r.start),
+                        _ => format!("{}-{}", r.start, r.end - 1),
+                    }).collect();
+                    if d.ranges.len() > SHOWN { ranges.push(format!("... ({} more)", d.ranges.len() - SHOWN)); }
+                    sys_print(&format!("FAT {} differs from FAT 1 in {} entries: {}", d.copy + 1, d.entries(), ranges.join(", ")));
+                },
+                Err(e) => print_error(e),
+            }
+        }
+        "fatsync" => {
+            match args {
+                ["--from", n] => match n.parse::<usize>().ok().filter(|&n| n > 0) {
+                    Some(from) => match volume.sync_fats(from - 1) {
+                        Ok(()) => sys_print(&format!("FAT copies rewritten from FAT {}.", from)),
+                        Err(e) => print_error(e),
+                    },
+                    None => print_error("Invalid FAT copy"),
+                },
+                _ => print_error("Usage: fatsync --from <copy>"),
+            }
+        }
+        "serial" | "set-serial" => {
+            match (command, args) {
+                ("serial", []) => match volume.volume_serial() {
+                    Ok(Some(serial)) => sys_print(&format!("Volume Serial: {:04X}-{:04X}", serial >> 16, serial & 0xFFFF)),
+                    Ok(None) => sys_print("No volume serial"),
+                    Err(e) => print_error(e),
+                },
+                ("set-serial", [hex]) => match u32::from_str_radix(&hex.replace('-', ""), 16) {
+                    Ok(serial) => match volume.set_volume_serial(serial) {
+                        Ok(()) => sys_print("Volume serial changed."),
+                        Err(e) => print_error(e),
+                    },
+                    Err(_) => print_error("Usage: set-serial <hex>"),
+                },
+                _ => print_error("Usage: serial | set-serial <hex>"),
+            }
+        }
+        "backup-bootsector" => {
+            match (args, volume.boot_region()) {
+                ([out], Ok(region)) if sys_write_file(out, &region) => sys_print(&format!("{} sectors saved to {}.", region.len() / volume.boot_sector.bytes_per_sector as usize, out)),
+                ([_], Ok(_)) => print_error("Cannot write host file"),
+                ([_], Err(e)) => print_error(e),
+                _ => print_error("Usage: backup-bootsector <out.bin>"),
+            }
+        }
+        "restore-bootsector" => {
+            match args {
+                [input] => match read_host_file(input).and_then(|saved| volume.restore_boot_region(&saved)) {
+                    Ok(()) => sys_print("Boot sectors restored."),
+                    Err(e) => print_error(e),
+                },
+                _ => print_error("Usage: restore-bootsector <in.bin>"),
+            }
+        }
+        "readsector" => {
+            // readsector <lba> [<count>] [<host_file>]: hex dump, or raw bytes to a host file.
+            let lba = arg1.and_then(|a| a.parse::<u64>().ok());
+            let count = args.get(1).and_then(|c| c.parse::<usize>().ok());
+            let host_file = args.get(if count.is_some() { 2 } else { 1 });
+            match lba {
+                Some(lba) if args.len() <= 3 => {
+                    let mut bytes = vec![0u8; count.unwrap_or(1) * BLOCK_SIZE];
+                    match volume.read_blocks(lba, &mut bytes) {
+                        Ok(()) => match host_file {
+                            Some(path) if sys_write_file(path, &bytes) => sys_print(&format!("{} sectors written to {}.", bytes.len() / BLOCK_SIZE, path)),
+                            Some(_) => print_error("Cannot write host file"),
+                            None => print_hexdump(lba as usize * BLOCK_SIZE, &bytes),
+                        },
+                        Err(e) => print_error(e),
+                    }
+                }
+                _ => print_error("Usage: readsector <lba> [<count>] [<host_file>]"),
+            }
+        }
+        "writesector" => {
+            // writesector <lba> <host_file>: whole sectors from a host file, from `lba` on.
+            match (args, arg1.and_then(|a| a.parse::<u64>().ok())) {
+                ([_, host_file], Some(lba)) => match read_host_file(host_file) {
+                    Ok(bytes) if bytes.is_empty() || bytes.len() % BLOCK_SIZE != 0 => print_error("Host file is not a whole number of sectors"),
+                    Ok(bytes) => match volume.write_blocks(lba, &bytes) {
+                        Ok(()) => sys_print(&format!("{} sectors written.", bytes.len() / BLOCK_SIZE)),
+                        Err(e) => print_error(e),
+                    },
+                    Err(e) => print_error(e),
+                },
+                _ => print_error("Usage: writesector <lba> <host_file>"),
+            }
+        }
+        "edit-sector" => {
+            match (args.len(), arg1.and_then(|a| a.parse::<u64>().ok())) {
+                (1, Some(lba)) => if let Err(e) = edit_sector(volume, lba) { print_error(e); },
+                _ => print_error("Usage: edit-sector <lba>"),
+            }
+        }
+        "ddclusters" => {
+            let numbers = (arg1.and_then(|a| a.parse::<u32>().ok()), args.get(1).and_then(|c| c.parse::<u32>().ok()));
+            match (args, numbers) {
+                ([_, _, output], (Some(first), Some(count))) => {
+                    // Created on the first write, so a run out of range leaves no file behind.
+                    let mut fd = -1;
+                    let result = volume.dump_clusters(first, count, &mut |bytes| {
+                        if fd < 0 { fd = sys_create(output); }
+                        if fd < 0 { return Err("Cannot create host file"); }
+                        if sys_write(fd, bytes) { Ok(()) } else { Err("Cannot write host file") }
+                    });
+                    if fd >= 0 { sys_close(fd); }
+                    match result {
+                        Ok(()) => sys_print(&format!(
+                            "{} clusters ({} bytes) written to {}.", count, count as usize * volume.cluster_size(), output
+                        )),
+                        Err(e) => print_error(e),
+                    }
+                }
+                _ => print_error("Usage: ddclusters <start> <count> <out.bin>"),
+            }
+        }
+        "carve" => {
+            let max = match args.iter().position(|a| *a == "--max") {
+                Some(i) => args.get(i + 1).and_then(|s| parse_size(s)),
+                None => Some(64 << 20),
+            };
+            let dirs: Vec<&&str> = args.iter().enumerate()
+                .filter(|(i, a)| !a.starts_with("--") && (*i == 0 || args[i - 1] != "--max"))
+                .map(|(_, a)| a).collect();
+            match (dirs.as_slice(), max) {
+                ([host_dir], Some(max)) if sys_mkdir(host_dir) => {
+                    let result = volume.carve(max, &mut |carved| {
+                        let name = format!("{}/carved_{}.{}", host_dir, carved.cluster, carved.kind.extension());
+                        let note = if carved.complete { "" } else { " (truncated)" };
+                        if sys_write_file(&name, &carved.data) {
+                            sys_print(&format!("{}: {} bytes{}", name, carved.data.len(), note));
+                        } else {
+                            print_error(&format!("Cannot write {}", name));
+                        }
+                    });
+                    match result {
+                        Ok(count) => sys_print(&format!("{} files carved.", count)),
+                        Err(e) => print_error(e),
+                    }
+                }
+                ([host_dir], Some(_)) => print_error(&format!("Cannot create {}", host_dir)),
+                _ => print_error("Usage: carve [--max <size>] <host_dir>"),
+            }
+        }
+        "slack" => {
+            match args {
+                [path] => match volume.slack(path) {
+                    Ok(slack) if slack.bytes.is_empty() => sys_print("No slack: the file fills its last cluster."),
+                    Ok(slack) => {
+                        let zero = slack.bytes.iter().all(|&b| b == 0);
+                        sys_print(&format!("{} bytes of slack in cluster {}{}", slack.bytes.len(), slack.cluster, if zero { ", all zero" } else { "" }));
+                        if !zero { print_hexdump(slack.offset, &slack.bytes); }
+                    }
+                    Err(e) => print_error(e),
+                },
+                _ => print_error("Usage: slack <path>"),
+            }
+        }
+        "chain" => {
+            match args {
+                [path] => match volume.chain_report(path) {
+                    Ok(report) => {
+                        let runs = report.runs();
+                        let ranges: Vec<String> = runs.iter()
+                            .map(|&(first, len)| if len == 1 { format!("{}", first) } else { format!("{}-{}", first, first + len as u32 - 1) })
+                            .collect();
+                        sys_print(&format!("{} clusters: {}", report.clusters.len(), ranges.join(" ")));
+                        if runs.len() > 1 {
+                            sys_print(&format!("FRAGMENTED: {} fragments", runs.len()));
+                        } else {
+                            sys_print("contiguous");
+                        }
+                        if let Some(expected) = report.expected {
+                            let verdict = if expected == report.clusters.len() { "OK" } else { "MISMATCH" };
+                            sys_print(&format!("size needs {} clusters: {}", expected, verdict));
+                        }
+                        if !report.terminated { sys_print("BROKEN: no end-of-chain marker"); }
+                    }
+                    Err(e) => print_error(e),
+                },
+                _ => print_error("Usage: chain <path>"),
+            }
+        }
+        "dirstat" => {
+            match args {
+                [] | [_] => match volume.directory_cluster(arg1.unwrap_or(".")).and_then(|cluster| volume.dir_slots(cluster)) {
+                    Ok(slots) => {
+                        sys_print(&format!("{} clusters, {} slots of 32 bytes", slots.clusters, slots.total()));
+                        sys_print(&format!("used:    {} ({} short, {} long-name)", slots.used + slots.long_name, slots.used, slots.long_name));
+                        sys_print(&format!("deleted: {}", slots.deleted));
+                        sys_print(&format!("free:    {}", slots.free));
+                        sys_print(&format!("room for {} more slots before the {} slot limit", MAX_DIR_ENTRIES.saturating_sub(slots.total()), MAX_DIR_ENTRIES));
+                    }
+                    Err(e) => print_error(e),
+                },
+                _ => print_error("Usage: dirstat [<path>]"),
+            }
+        }
+        "compactdir" => {
+            match args {
+                [path] => match volume.directory_cluster(path).and_then(|cluster| volume.compact_dir(cluster)) {
+                    Ok(report) => sys_print(&format!(
+                        "{} deleted entries removed, {} clusters freed.", report.removed, report.freed_clusters
+                    )),
+                    Err(e) => print_error(e),
+                },
+                _ => print_error("Usage: compactdir <path>"),
+            }
+        }
+        "allocate" => {
+            let contiguous = args.contains(&"-c");
+            match args.iter().filter(|a| **a != "-c").collect::<Vec<_>>().as_slice() {
+                [path, size] => match parse_size(size).and_then(|s| u32::try_from(s).ok()) {
+                    Some(size) => match volume.allocate(path, size, contiguous)
+                        .and_then(|first| Ok((first, volume.boot_sector.cluster_offset(first)?)))
+                    {
+                        Ok((first, offset)) => sys_print(&format!(
+                            "Allocated {} bytes from cluster {} (byte offset {}).",
+                            size, first, offset
+                        )),
+                        Err(e) => print_error(e),
+                    },
+                    None => print_error("Invalid size"),
+                },
+                _ => print_error("Usage: allocate [-c] <path> <size>"),
+            }
+        }
+        "zerofree" => {
+            match volume.zero_free(&mut ProgressBar::new()) {
+                Ok(count) => sys_print(&format!("{} free clusters zeroed.", count)),
+                Err(e) => print_error(e),
+            }
+        }
+        "defrag" => {
+            let compact = arg1 == Some("-c");
+            match volume.defrag(compact, &mut ProgressBar::new()) {
+                Ok(r) => sys_print(&format!(
+                    "Defrag done: {} files moved ({} clusters), {} skipped for lack of a free run, {} fragmented directories not moved.",
+                    r.files_moved, r.clusters_moved, r.files_skipped, r.dirs_fragmented
+                )),
+                Err(e) => print_error(e),
+            }
+        }
+        _ => return false,
+    }
+    true
+}
+
+/// Shows sector `lba`, then reads `<offset> <byte>...` patches until an empty line or
+/// `done`, and writes the patched sector once confirmed.
+fn edit_sector(volume: &mut Fat32Volume, lba: u64) -> Result<(), &'static str> {
+    let mut sector = [0u8; BLOCK_SIZE];
+    volume.read_blocks(lba, &mut sector)?;
+    let original = sector;
+    print_hexdump(lba as usize * BLOCK_SIZE, &sector);
+    sys_print("Enter <offset> <byte>... to patch, an empty line or `done` to finish.");
+    loop {
+        sys_print_raw("edit> ");
+        let line = sys_read_line();
+        if line.is_empty() || line == "done" { break; }
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let offset = words.first().and_then(|w| parse_size(w).or_else(|| w.strip_prefix("0x").and_then(|h| usize::from_str_radix(h, 16).ok())));
+        let bytes: Option<Vec<u8>> = words[1..].iter().map(|w| parse_byte(w)).collect();
+        match (offset, bytes) {
+            (Some(offset), Some(bytes)) if !bytes.is_empty() && offset + bytes.len() <= BLOCK_SIZE => {
+                sector[offset..offset + bytes.len()].copy_from_slice(&bytes);
+            }
+            _ => sys_print("Usage: <offset> <byte>..., inside the sector"),
+        }
+    }
+    if sector == original {
+        sys_print("Sector unchanged.");
+        return Ok(());
+    }
+    print_hexdump(lba as usize * BLOCK_SIZE, &sector);
+    if !confirm(&format!("edit-sector: write sector {}?", lba)) {
+        sys_print("Sector left unchanged.");
+        return Ok(());
+    }
+    volume.write_blocks(lba, &sector)?;
+    sys_print("Sector written.");
+    Ok(())
+}
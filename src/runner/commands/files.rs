@@ -0,0 +1,435 @@
+//! Commands reading and changing the files and directories of a volume.
+
+use alloc::format;
+use alloc::vec;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::ffi::c_void;
+use fat32::fat32::compare::Comparison;
+use fat32::fat32::dir::DirEntry;
+use fat32::fat32::path::{has_wildcards, Resolved};
+use fat32::fat32::tar::TarWriter;
+use fat32::fat32::volume::Fat32Volume;
+use crate::host::{get_file, HostSink, open_or_create_dir, put_tree, read_host_file};
+use crate::listing::{format_size, list_short, list_table, LsOptions};
+use crate::shell::{expand_globs, parse_byte, print_error};
+use crate::sync::{sync_trees, SyncOptions};
+use crate::sys::{sys_close, sys_create, sys_mkdir, sys_open_read, sys_print, sys_write};
+use crate::term::{confirm, page, ProgressBar};
+use crate::tz::{fat_to_unix, format_created, format_time};
+
+/// Runs `command` if it lists, reads or changes files and directories; false otherwise.
+pub fn run(volume: &mut Fat32Volume, command: &str, args: &[&str]) -> bool {
+    let arg1 = args.first().copied();
+    match command {
+        "ls" => match LsOptions::parse(args) {
+            Ok(ls) => {
+                let listings = if ls.recursive {
+                    volume.list_recursive(ls.path, &ls.list)
+                } else {
+                    volume.list_entries(ls.path, &ls.list).map(|entries| vec![(String::new(), entries)])
+                };
+                match listings {
+                    Ok(listings) => {
+                        let mut lines = Vec::new();
+                        for (i, (dir, entries)) in listings.iter().enumerate() {
+                            if ls.recursive {
+                                if i > 0 { lines.push(String::new()); }
+                                lines.push(format!("{}:", dir));
+                            }
+                            let shown: Vec<&DirEntry> = entries.iter().filter(|e| !ls.archived || (!e.is_dir() && e.metadata().is_archive())).collect();
+                            match &ls.columns {
+                                Some(columns) => lines.extend(list_table(&shown, columns, ls.human)),
+                                None => lines.extend(shown.into_iter().map(|entry| list_short(entry, ls.human))),
+                            }
+                        }
+                        page(&lines);
+                    }
+                    Err(e) => print_error(e),
+                }
+            }
+            Err(e) => print_error(&e),
+        },
+        "du" => {
+            let (flags, paths): (Vec<&str>, Vec<&str>) = args.iter().partition(|a| a.starts_with('-'));
+            let flags: String = flags.iter().map(|f| &f[1..]).collect();
+            let (human, summary) = (flags.contains('h'), flags.contains('s'));
+            match paths.as_slice() {
+                _ if flags.chars().any(|c| !matches!(c, 'h' | 's')) => print_error("Usage: du [-hs] [path]"),
+                [] | [_] => match volume.disk_usage(paths.first().copied().unwrap_or(".")) {
+                    Ok(usage) => {
+                        let shown = if summary { &usage[usage.len() - 1..] } else { &usage[..] };
+                        for (path, bytes) in shown { sys_print(&format!("{:<10} {}", format_size(*bytes, human), path)); }
+                    }
+                    Err(e) => print_error(e),
+                },
+                _ => print_error("Usage: du [-hs] [path]"),
+            }
+        }
+        "df" => match args {
+            [] | ["-h"] => {
+                let human = arg1 == Some("-h");
+                let space = volume.space();
+                let percent = (space.used() * 100).checked_div(space.total).unwrap_or(0);
+                sys_print(&format!("{:>10} {:>10} {:>10} {:>4}", "Size", "Used", "Avail", "Use%"));
+                sys_print(&format!("{:>10} {:>10} {:>10} {:>3}%", format_size(space.total, human), format_size(space.used(), human), format_size(space.free, human), percent));
+            }
+            _ => print_error("Usage: df [-h]"),
+        },
+        "tree" => match volume.walk(arg1.unwrap_or(".")) {
+            Ok(walk) => {
+                let mut lines = vec![String::from(arg1.unwrap_or("."))];
+                for item in walk {
+                    match item {
+                        Ok((depth, path, metadata)) => {
+                            let name = path.rsplit('/').next().unwrap_or(&path);
+                            let suffix = if metadata.is_dir() { "/" } else { "" };
+                            lines.push(format!("{}{}{}", "  ".repeat(depth), name, suffix));
+                        }
+                        Err(e) => lines.push(String::from(e)),
+                    }
+                }
+                page(&lines);
+            }
+            Err(e) => print_error(e),
+        },
+        "find" => {
+            let archived = args.contains(&"--archived");
+            let path = args.iter().find(|a| !a.starts_with('-')).copied().unwrap_or(".");
+            match volume.walk(path) {
+                Ok(walk) => for item in walk {
+                    match item {
+                        Ok((_, path, metadata)) if !archived || (!metadata.is_dir() && metadata.is_archive()) => sys_print(&path),
+                        Ok(_) => {}
+                        Err(e) => print_error(e),
+                    }
+                },
+                Err(e) => print_error(e),
+            }
+        }
+        "archive" => match args {
+            [path] => match volume.file_entry(path) {
+                Ok(entry) => sys_print(if entry.metadata().is_archive() { "Archive bit set: changed since the last backup." } else { "Archive bit clear." }),
+                Err(e) => print_error(e),
+            },
+            [path, flag @ ("on" | "off")] => match volume.set_archive(path, *flag == "on") {
+                Ok(_) => sys_print(if *flag == "on" { "Archive bit set." } else { "Archive bit cleared." }),
+                Err(e) => print_error(e),
+            },
+            _ => print_error("Usage: archive <path> [on|off]"),
+        },
+        "stat" => match arg1.map(|path| volume.file_entry(path)) {
+            Some(Ok(entry)) => {
+                let accessed = &entry.accessed;
+                let fields = [
+                    ("Name", entry.name.clone()),
+                    ("Short name", entry.alias.clone()),
+                    ("Type", if entry.is_dir() { "directory" } else { "file" }.into()),
+                    ("Size", format!("{} bytes", entry.size)),
+                    ("Attributes", format!("{:#04x}", entry.attr)),
+                    ("Cluster", format!("{}", entry.first_cluster)),
+                    ("Created", format_created(&entry)),
+                    ("Modified", format_time(&entry.modified)),
+                    // FAT keeps no time of day for the last access.
+                    ("Accessed", if accessed.is_set() { format!("{:04}-{:02}-{:02}", accessed.year, accessed.month, accessed.day) } else { "-".into() }),
+                ];
+                for (label, value) in fields { sys_print(&format!("{:<11} {}", format!("{}:", label), value)); }
+            }
+            Some(Err(e)) => print_error(e),
+            None => print_error("Usage: stat <path>"),
+        },
+        "cd" => {
+            if let Some(dirname) = arg1 {
+                match volume.change_directory(dirname) {
+                    Ok(_) => sys_print("Directory changed."),
+                    Err(e) => print_error(e),
+                }
+            } else { print_error("Usage: cd <dirname>"); }
+        }
+        "cat" => for_each_path(volume, args, "Usage: cat <filename>...", |volume, path| {
+            match volume.read_file(path) {
+                Ok(content) => {
+                    let text = String::from_utf8_lossy(&content);
+                    page(&text.lines().map(String::from).collect::<Vec<_>>());
+                    if let Err(e) = volume.record_access(path) { print_error(e); }
+                }
+                Err(e) => print_error(e),
+            }
+        }),
+        "head" | "tail" => {
+            let (lines, path) = match args {
+                [path] => (Some(10), *path),
+                ["-n", n, path] => (n.parse::<usize>().ok(), *path),
+                _ => (None, ""),
+            };
+            match lines {
+                Some(lines) => {
+                    let result = if command == "head" { volume.head(path, lines) } else { volume.tail(path, lines) };
+                    match result {
+                        Ok(content) => {
+                            for line in String::from_utf8_lossy(&content).lines() { sys_print(line); }
+                            if let Err(e) = volume.record_access(path) { print_error(e); }
+                        }
+                        Err(e) => print_error(e),
+                    }
+                }
+                None => print_error(&format!("Usage: {} [-n <lines>] <file>", command)),
+            }
+        }
+        "wc" => for_each_path(volume, args, "Usage: wc <file>...", |volume, path| {
+            match volume.word_count(path) {
+                Ok(count) => sys_print(&format!("{:>8} {:>8} {:>10} {}", count.lines, count.words, count.bytes, path)),
+                Err(e) => print_error(&format!("{}: {}", path, e)),
+            }
+        }),
+        "file" => for_each_path(volume, args, "Usage: file <path>...", |volume, path| {
+            let kind = match volume.resolve_path(path) {
+                Ok(Resolved::Dir(_)) => Ok("directory"),
+                _ => volume.file_type(path).map(|kind| kind.description()),
+            };
+            match kind {
+                Ok(kind) => sys_print(&format!("{}: {}", path, kind)),
+                Err(e) => print_error(&format!("{}: {}", path, e)),
+            }
+        }),
+        "strings" => {
+            let min_len = match args {
+                [_] => Some(4),
+                [_, n] => n.parse::<usize>().ok().filter(|&n| n > 0),
+                _ => None,
+            };
+            match (arg1, min_len) {
+                (Some(path), Some(min_len)) => {
+                    let mut lines = Vec::new();
+                    match volume.strings(path, min_len, &mut |_, text| lines.push(String::from(text))) {
+                        Ok(_) => page(&lines),
+                        Err(e) => print_error(e),
+                    }
+                }
+                _ => print_error("Usage: strings <path> [min-len]"),
+            }
+        }
+        "rm" => {
+            // -r deletes directories and all they hold, -n only lists what would go.
+            // --shred overwrites the contents first, with zeros or the byte given by --pattern.
+            let (mut recursive, mut dry_run, mut yes, mut shred) = (false, false, false, false);
+            let mut pattern = Some(0);
+            let mut paths = Vec::new();
+            let mut valid = true;
+            let mut rest = args.iter();
+            while let Some(&arg) = rest.next() {
+                match arg {
+                    "--yes" => yes = true,
+                    "--dry-run" => dry_run = true,
+                    "--shred" => shred = true,
+                    "--pattern" => pattern = rest.next().and_then(|p| parse_byte(p)),
+                    _ if arg.starts_with("--") => valid = false,
+                    _ if arg.starts_with('-') && arg.len() > 1 => for flag in arg[1..].chars() {
+                        match flag {
+                            'r' | 'R' => recursive = true,
+                            'n' => dry_run = true,
+                            'f' => yes = true,
+                            _ => valid = false,
+                        }
+                    },
+                    _ => paths.push(arg),
+                }
+            }
+            if paths.is_empty() || pattern.is_none() || !valid || (shred && recursive) || (dry_run && !recursive) {
+                print_error("Usage: rm [-f|--yes] [-r [-n|--dry-run]] [--shred [--pattern <byte>]] <path>...");
+            } else {
+                match expand_globs(volume, &paths) {
+                    Ok(paths) => {
+                        let mut removed = 0;
+                        for path in paths {
+                            let result = if dry_run {
+                                volume.remove_tree(&path, true).map(|doomed| {
+                                    for p in &doomed { sys_print(&format!("would remove {}", p)); }
+                                    0
+                                })
+                            } else if recursive {
+                                let question = match volume.resolve_path(&path) {
+                                    Ok(Resolved::Dir(_)) => format!("rm: delete '{}' and everything in it?", path),
+                                    _ => format!("rm: delete '{}'?", path),
+                                };
+                                if !yes && !confirm(&question) { continue; }
+                                volume.remove_tree(&path, false).map(|doomed| doomed.len())
+                            } else {
+                                if !yes && !confirm(&format!("rm: delete '{}'?", path)) { continue; }
+                                let result = if shred { volume.shred_file(&path, pattern.unwrap_or(0)) } else { volume.remove_file(&path) };
+                                result.map(|_| 1)
+                            };
+                            match result {
+                                Ok(n) => removed += n,
+                                Err(e) => print_error(&format!("{}: {}", path, e)),
+                            }
+                        }
+                        if !dry_run { sys_print(&format!("{} files removed.", removed)); }
+                    }
+                    Err(e) => print_error(&e),
+                }
+            }
+        }
+        "touch" => {
+            let overwrite = arg1 == Some("-f");
+            match &args[overwrite as usize..] {
+                // Without text, an empty file is created, or an existing one gets the current time.
+                [filename] if !filename.is_empty() => {
+                    let exists = matches!(volume.resolve_path(filename), Ok(Resolved::File(_)));
+                    match volume.touch(filename) {
+                        Ok(_) => sys_print(if exists { "Timestamps updated." } else { "File created." }),
+                        Err(e) => print_error(e),
+                    }
+                }
+                [filename, text @ ..] if !filename.is_empty() => match volume.create_file(filename, text.join(" ").as_bytes(), overwrite) {
+                    Ok(_) => sys_print("File created."),
+                    Err(e) => print_error(e),
+                },
+                _ => print_error("Usage: touch [-f] <filename> [text]"),
+            }
+        }
+        "mkdir" => {
+            if let Some(path) = arg1 {
+                match volume.create_directory(path) {
+                    Ok(_) => sys_print("Directory created."),
+                    Err(e) => print_error(e),
+                }
+            } else { print_error("Usage: mkdir <path>"); }
+        }
+        "put" => {
+            let yes = args.iter().any(|a| matches!(*a, "-f" | "--yes"));
+            let args: Vec<&str> = args.iter().copied().filter(|a| !matches!(*a, "-f" | "--yes")).collect();
+            match args.as_slice() {
+                ["-r", host_dir, image_dir] => {
+                    match open_or_create_dir(volume, image_dir).and_then(|dir| put_tree(volume, host_dir, dir, &mut ProgressBar::new())) {
+                        Ok(n) => sys_print(&format!("{} files copied.", n)),
+                        Err(e) => print_error(e),
+                    }
+                }
+                [host_file, rest @ ..] if rest.len() <= 1 && *host_file != "-r" => {
+                    let base = host_file.rsplit('/').next().unwrap_or(host_file);
+                    let target = match rest.first() {
+                        None => Ok(String::from(base)),
+                        Some(path) => volume.resolve_path(path).map(|resolved| match resolved {
+                            Resolved::Dir(_) => format!("{}/{}", path.trim_end_matches('/'), base),
+                            _ => String::from(*path),
+                        }),
+                    };
+                    let exists = |path: &String| matches!(volume.resolve_path(path), Ok(Resolved::File(_)));
+                    if target.as_ref().is_ok_and(|path| !yes && exists(path) && !confirm(&format!("put: overwrite '{}'?", path))) {
+                        sys_print("Nothing copied.");
+                    } else {
+                        let content = read_host_file(host_file);
+                        match target.and_then(|path| volume.create_file(&path, &content?, true)) {
+                            Ok(_) => sys_print("File copied."),
+                            Err(e) => print_error(e),
+                        }
+                    }
+                }
+                _ => print_error("Usage: put [-f|--yes] <host-file> [image-path] | put -r <host-dir> <image-dir>"),
+            }
+        }
+        "get" => {
+            match args {
+                ["-r", image_dir, host_dir] => {
+                    let result = if sys_mkdir(host_dir) {
+                        volume.extract_tree(image_dir, &mut HostSink { root: host_dir }, &mut ProgressBar::new())
+                    } else { Err("Cannot create host directory") };
+                    match result {
+                        Ok(n) => sys_print(&format!("{} files extracted.", n)),
+                        Err(e) => print_error(e),
+                    }
+                }
+                [pattern, rest @ ..] if rest.len() <= 1 && has_wildcards(pattern) => {
+                    let host_dir = rest.first().copied().unwrap_or(".");
+                    let result = if sys_mkdir(host_dir) {
+                        expand_globs(volume, &[pattern])
+                    } else { Err(String::from("Cannot create host directory")) };
+                    match result {
+                        Ok(paths) => {
+                            let mut count = 0;
+                            for path in paths {
+                                let base = path.rsplit('/').next().unwrap_or(&path);
+                                match get_file(volume, &path, &format!("{}/{}", host_dir, base)) {
+                                    Ok(_) => count += 1,
+                                    Err(e) => print_error(&format!("{}: {}", path, e)),
+                                }
+                            }
+                            sys_print(&format!("{} files extracted.", count));
+                        }
+                        Err(e) => print_error(&e),
+                    }
+                }
+                [image_file, rest @ ..] if rest.len() <= 1 && *image_file != "-r" => {
+                    let base = image_file.rsplit('/').next().unwrap_or(image_file);
+                    let host_path = rest.first().copied().unwrap_or(base);
+                    match get_file(volume, image_file, host_path) {
+                        Ok(_) => sys_print("File extracted."),
+                        Err(e) => print_error(e),
+                    }
+                }
+                _ => print_error("Usage: get <image-file> [host-path] | get <pattern> [host-dir] | get -r <image-dir> <host-dir>"),
+            }
+        }
+        "sync" => match SyncOptions::parse(args) {
+            Some((options, host_dir, image_dir)) => match sync_trees(volume, host_dir, image_dir, &options) {
+                Ok(r) => sys_print(&r.summary(options.dry_run)),
+                Err(e) => print_error(e),
+            },
+            None => print_error(SyncOptions::USAGE),
+        },
+        "export-tar" => {
+            if let [image_path, output] = args {
+                let fd = sys_create(output);
+                if fd < 0 {
+                    print_error("Cannot create host file");
+                } else {
+                    let mut tar = TarWriter::new(|block: &[u8]| {
+                        if sys_write(fd, block) { Ok(()) } else { Err("Cannot write host file") }
+                    }).to_unix(fat_to_unix);
+                    let result = volume.extract_tree(image_path, &mut tar, &mut ProgressBar::new()).and_then(|n| tar.finish().map(|_| n));
+                    sys_close(fd);
+                    match result {
+                        Ok(n) => sys_print(&format!("{} files archived.", n)),
+                        Err(e) => print_error(e),
+                    }
+                }
+            } else { print_error("Usage: export-tar <image-path> <output.tar>"); }
+        }
+        "diff" => {
+            if let [image_path, host_path] = args {
+                let fd = sys_open_read(host_path);
+                if fd < 0 {
+                    print_error("Cannot open host file");
+                } else {
+                    let result = volume.compare_file(image_path, &mut |buf: &mut [u8]| {
+                        // SAFETY: buf is a valid mutable slice of buf.len() bytes.
+                        let n = unsafe { libc::read(fd, buf.as_mut_ptr() as *mut c_void, buf.len()) };
+                        if n < 0 { Err("Cannot read host file") } else { Ok(n as usize) }
+                    });
+                    sys_close(fd);
+                    match result {
+                        Ok(Comparison::Identical) => sys_print("identical"),
+                        Ok(Comparison::DiffersAt(offset)) => sys_print(&format!("differ: first difference at byte {}", offset)),
+                        Err(e) => print_error(e),
+                    }
+                }
+            } else { print_error("Usage: diff <image-path> <host-path>"); }
+        }
+        _ => return false,
+    }
+    true
+}
+
+/// Runs `each` on every path the wildcards of `args` match, or prints `usage` when there
+/// are no arguments.
+fn for_each_path(volume: &mut Fat32Volume, args: &[&str], usage: &str, mut each: impl FnMut(&mut Fat32Volume, &str)) {
+    if args.is_empty() {
+        print_error(usage);
+        return;
+    }
+    match expand_globs(volume, args) {
+        Ok(paths) => for path in paths { each(volume, &path) },
+        Err(e) => print_error(&e),
+    }
+}
@@ -0,0 +1,383 @@
+//! Commands working on whole images or the set of mounted ones, rather than on the
+//! files of a volume, and `runner create`.
+
+use alloc::format;
+use alloc::vec;
+use alloc::string::String;
+use alloc::vec::Vec;
+use fat32::fat32::file::Fat32OpenOptions;
+use fat32::fat32::format::{format, FormatOptions};
+use fat32::fat32::io::BLOCK_SIZE;
+use fat32::fat32::recluster::ReclusterReport;
+use fat32::fat32::resize::ResizeReport;
+use fat32::fat32::volume::Fat32Volume;
+use crate::host::put_tree;
+use crate::mount::{block_hashes, copy_file, copy_tree, Mount, mount_image, Placement};
+#[cfg(feature = "serve")]
+use crate::serve::{nbd::serve_nbd, ServeArgs};
+use crate::shell::{parse_size, print_error};
+use crate::sys::{sys_now_us, sys_print, sys_write_file};
+use crate::term::ProgressBar;
+use crate::tz::fat_now;
+
+/// Runs `words` if it is a command on the set of mounted images, and tells whether it was.
+pub fn run(mounts: &mut Vec<Mount>, words: &[&str], overlay: bool) -> bool {
+    match words {
+        ["mount"] => {
+            for m in mounts.iter() {
+                let mode = match m.decompressor {
+                    Some(program) => format!(", {} compressed, read-only, {} blocks changed", program, m.changed_blocks()),
+                    None if m.overlay => format!(", overlay, {} blocks changed", m.changed_blocks()),
+                    None => String::new(),
+                };
+                let mode = match &m.span {
+                    span if *span != (0..m.data.len()) => format!("{}, volume of {} bytes at byte {}", mode, span.len(), span.start),
+                    _ => mode,
+                };
+                let saved = if m.written.blocks > 0 { format!(", {} blocks saved in {} writes", m.written.blocks, m.written.writes) } else { String::new() };
+                sys_print(&format!("{}: ({} bytes{}{})", m.name, m.data.len(), mode, saved));
+            }
+        }
+        ["mount", path, name, rest @ ..] if rest.len() <= 1 => {
+            let placement = match rest.first().map(|arg| Placement::parse(arg)) {
+                None => Placement::Find,
+                Some(Some(Ok(placement))) => placement,
+                Some(Some(Err(e))) => { print_error(e); return true; }
+                Some(None) => { print_error("Usage: mount <image> <name> [--offset=<bytes>|--esp]"); return true; }
+            };
+            if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric()) {
+                print_error("Invalid mount name");
+            } else if mounts.iter().any(|m| m.name == *name) {
+                print_error("Name already mounted");
+            } else {
+                match mount_image(path, name, placement) {
+                    Ok(mut m) => {
+                        sys_print(&format!("Mounted {} as {}:", path, name));
+                        m.overlay |= overlay;
+                        mounts.push(m);
+                    }
+                    Err(e) => print_error(e),
+                }
+            }
+        }
+        ["umount", name] => {
+            match mounts.iter().position(|m| m.name == *name) {
+                Some(0) => print_error("Cannot unmount the session image"),
+                Some(i) => {
+                    mounts.remove(i).close();
+                    sys_print("Unmounted.");
+                }
+                None => print_error("Not mounted"),
+            }
+        }
+        ["commit" | "discard", names @ ..] if names.len() <= 1 => {
+            for m in selected(mounts, names.first().copied()) {
+                if words[0] == "commit" && m.decompressor.is_some() {
+                    print_error(&format!("{}: compressed image is read-only", m.name));
+                } else if words[0] == "commit" {
+                    let writes = m.written.writes;
+                    let count = m.save();
+                    sys_print(&format!("{}: {} blocks committed in {} writes", m.name, count, m.written.writes - writes));
+                } else {
+                    match m.discard() {
+                        Ok(count) => sys_print(&format!("{}: {} blocks discarded", m.name, count)),
+                        Err(e) => print_error(&format!("{}: {}", m.name, e)),
+                    }
+                }
+            }
+        }
+        ["commit" | "discard", ..] => {
+            print_error("Usage: commit|discard [<name>]");
+        }
+        ["reload", rest @ ..] => {
+            let force = rest.first() == Some(&"-f");
+            match rest.get(force as usize..).unwrap_or_default() {
+                names @ ([] | [_]) => {
+                    for m in selected(mounts, names.first().copied()) {
+                        let changed = m.changed_blocks();
+                        if changed > 0 && !force {
+                            print_error(&format!("{}: {} blocks not saved; commit, discard or reload -f", m.name, changed));
+                            continue;
+                        }
+                        match m.reload() {
+                            Ok(()) => sys_print(&format!("{}: reloaded ({} bytes)", m.name, m.data.len())),
+                            Err(e) => print_error(&format!("{}: {}", m.name, e)),
+                        }
+                    }
+                }
+                _ => print_error("Usage: reload [-f] [<name>]"),
+            }
+        }
+        ["mount" | "umount", ..] => {
+            print_error("Usage: mount [<image> <name> [--offset=<bytes>|--esp]] | umount <name>");
+        }
+        ["cp", src, dst] => {
+            match copy_file(mounts, src, dst) {
+                Ok(_) => sys_print("File copied."),
+                Err(e) => print_error(e),
+            }
+        }
+        ["cp", "-r", src, dst] => {
+            match copy_tree(mounts, src, dst) {
+                Ok(n) => sys_print(&format!("{} files copied.", n)),
+                Err(e) => print_error(e),
+            }
+        }
+        ["cp", ..] => {
+            print_error("Usage: cp [-r] <source> <destination>");
+        }
+        ["resize", "--grow", size, rest @ ..] if rest.len() <= 1 => {
+            match (find_mount(mounts, rest.first().copied()), parse_size(size)) {
+                (None, _) => print_error("Not mounted"),
+                (_, None) => print_error("Invalid size"),
+                (Some(i), Some(bytes)) => match grow_image(&mut mounts[i], bytes) {
+                    Ok(r) => sys_print(&format!(
+                        "{}: grown from {} to {} clusters, FAT from {} to {} sectors, {} bytes moved.",
+                        mounts[i].name, r.clusters.0, r.clusters.1, r.fat_sectors.0, r.fat_sectors.1, r.bytes_moved
+                    )),
+                    Err(e) => print_error(e),
+                },
+            }
+        }
+        ["resize", "--shrink", size, rest @ ..] if rest.len() <= 1 => {
+            let bytes = if *size == "min" { Some(None) } else { parse_size(size).map(Some) };
+            match (find_mount(mounts, rest.first().copied()), bytes) {
+                (None, _) => print_error("Not mounted"),
+                (_, None) => print_error("Invalid size"),
+                (Some(i), Some(bytes)) => match shrink_image(&mut mounts[i], bytes) {
+                    Ok(r) => sys_print(&format!(
+                        "{}: shrunk from {} to {} clusters, FAT from {} to {} sectors, {} clusters relocated, {} bytes moved.",
+                        mounts[i].name, r.clusters.0, r.clusters.1, r.fat_sectors.0, r.fat_sectors.1, r.relocated, r.bytes_moved
+                    )),
+                    Err(e) => print_error(e),
+                },
+            }
+        }
+        ["resize", ..] => {
+            print_error("Usage: resize --grow|--shrink <size> [<name>]");
+        }
+        ["recluster", sectors, rest @ ..] if rest != ["-o"] && matches!(rest, [] | [_] | ["-o", _] | ["-o", _, _]) => {
+            let (output, name) = match rest {
+                ["-o", output, name @ ..] => (Some(*output), name.first().copied()),
+                name => (None, name.first().copied()),
+            };
+            let sectors = sectors.parse::<u8>().ok();
+            match (find_mount(mounts, name), sectors) {
+                (None, _) => print_error("Not mounted"),
+                (_, None) => print_error("Invalid cluster size"),
+                (Some(i), Some(sectors)) => match recluster_image(&mut mounts[i], sectors, output) {
+                    Ok(r) => sys_print(&format!(
+                        "{}: clusters from {} to {} bytes, FAT from {} to {} sectors, {} files and {} directories in {} clusters.",
+                        output.unwrap_or(&mounts[i].name), r.cluster_size.0, r.cluster_size.1, r.fat_sectors.0, r.fat_sectors.1,
+                        r.files, r.directories, r.used.1
+                    )),
+                    Err(e) => print_error(e),
+                },
+            }
+        }
+        ["recluster", ..] => {
+            print_error("Usage: recluster <sectors-per-cluster> [-o <out.img>] [<name>]");
+        }
+        #[cfg(feature = "serve")]
+        ["serve-nbd", rest @ ..] => {
+            let serve = ServeArgs::parse(rest, 10809, true);
+            match serve.map(|serve| (find_mount(mounts, serve.name), serve)) {
+                None => print_error("Usage: serve-nbd [--port <port>] [--partition <n>] [--read-only] [<name>]"),
+                Some((None, _)) => print_error("Not mounted"),
+                Some((Some(i), serve)) => {
+                    let range = match serve.partition {
+                        None => Some(0..mounts[i].data.len()),
+                        Some(n) => n.checked_sub(1).and_then(|n| fat32::fat32::disk::partitions(&mounts[i].data).nth(n)).map(|p| p.range),
+                    };
+                    match range {
+                        Some(range) => if let Err(e) = serve_nbd(&mut mounts[i], serve.port, range, serve.read_only) { print_error(e) },
+                        None => print_error("No such partition"),
+                    }
+                }
+            }
+        }
+        #[cfg(not(feature = "serve"))]
+        ["serve-nbd", ..] => {
+            print_error("Built without the serve feature");
+        }
+        ["bench", rest @ ..] if rest.len() <= 1 => {
+            match find_mount(mounts, rest.first().copied()) {
+                Some(i) => run_bench(&mounts[i]),
+                None => print_error("Not mounted"),
+            }
+        }
+        _ => return false,
+    }
+    true
+}
+
+/// Index of the image mounted as `name`, or of the session image when there is no name.
+fn find_mount(mounts: &[Mount], name: Option<&str>) -> Option<usize> {
+    mounts.iter().position(|m| name.is_none_or(|name| m.name == name))
+}
+
+/// The image mounted as `name`, or all of them when there is no name; tells when none is.
+fn selected<'m>(mounts: &'m mut [Mount], name: Option<&str>) -> Vec<&'m mut Mount> {
+    let selected: Vec<&mut Mount> = mounts.iter_mut().filter(|m| name.is_none_or(|name| m.name == name)).collect();
+    if selected.is_empty() { print_error("Not mounted"); }
+    selected
+}
+
+/// Resizing moves the end of the image file, where a volume in a partition or a VHD doesn't end.
+const IN_CONTAINER: &str = "Cannot resize a volume inside a partition or VHD";
+
+/// Extends the image of `mount` to `bytes` and grows its volume to fill them. The image
+/// file grows when the change is saved.
+fn grow_image(mount: &mut Mount, bytes: usize) -> Result<ResizeReport, &'static str> {
+    if mount.span != (0..mount.data.len()) { return Err(IN_CONTAINER); }
+    let old_len = mount.data.len();
+    let bps = mount.volume()?.boot_sector.bytes_per_sector as usize;
+    let sectors = u32::try_from(bytes / bps).map_err(|_| "Size too large for FAT32")?;
+    mount.data.resize(old_len.max(bytes), 0);
+    mount.span = 0..mount.data.len();
+    let result = mount.volume().and_then(|mut volume| volume.grow(sectors, &mut ProgressBar::new()));
+    match result {
+        // The added blocks are the zeros the extended file will hold, unchanged until written.
+        Ok(_) => {
+            let zero = block_hashes(&[0u8; BLOCK_SIZE])[0];
+            mount.saved.resize(mount.data.len().div_ceil(BLOCK_SIZE), zero);
+        }
+        Err(_) => {
+            mount.data.truncate(old_len);
+            mount.span = 0..old_len;
+        }
+    }
+    result
+}
+
+/// Shrinks the volume of `mount` to `bytes`, or to the least its content needs when
+/// `bytes` is `None`, and cuts the image to match. The image file is cut when the change
+/// is saved.
+fn shrink_image(mount: &mut Mount, bytes: Option<usize>) -> Result<ResizeReport, &'static str> {
+    if mount.span != (0..mount.data.len()) { return Err(IN_CONTAINER); }
+    let mut volume = mount.volume()?;
+    let bps = volume.boot_sector.bytes_per_sector as usize;
+    let sectors = match bytes {
+        Some(bytes) => u32::try_from(bytes / bps).map_err(|_| "Size too large for FAT32")?,
+        None => volume.min_total_sectors(),
+    };
+    let report = volume.shrink(sectors, &mut ProgressBar::new())?;
+    // Relocation may have moved the current directory.
+    let cwd = volume.current_cluster;
+    mount.cwd = cwd;
+    mount.data.truncate(sectors as usize * bps);
+    mount.span = 0..mount.data.len();
+    mount.saved.truncate(mount.data.len().div_ceil(BLOCK_SIZE));
+    Ok(report)
+}
+
+/// Rewrites the volume of `mount` with clusters of `sectors_per_cluster` sectors, to the
+/// host file `output` when given, otherwise in place, keeping the current directory.
+fn recluster_image(mount: &mut Mount, sectors_per_cluster: u8, output: Option<&str>) -> Result<ReclusterReport, &'static str> {
+    let mut rebuilt = mount.data.clone();
+    let span = mount.span.clone();
+    let volume = mount.volume()?;
+    let cwd = volume.directory_path(volume.current_cluster)?;
+    let report = volume.recluster(sectors_per_cluster, &mut rebuilt[span], &mut ProgressBar::new())?;
+    match output {
+        Some(path) => if !sys_write_file(path, &rebuilt) { return Err("Cannot write host file"); },
+        None => {
+            mount.data = rebuilt;
+            let cwd = Fat32Volume::new(&mut mount.data[mount.span.clone()])?.directory_cluster(&cwd)?;
+            mount.cwd = cwd;
+        }
+    }
+    Ok(report)
+}
+
+/// Times reading every file, listing every directory, resolving every path and
+/// allocating a 1 MiB file, on a scratch copy of the image so it is left untouched.
+fn run_bench(mount: &Mount) {
+    let mut scratch = match Mount::new(&mount.name, -1, mount.data[mount.span.clone()].to_vec(), None, Placement::Find) {
+        Ok(scratch) => scratch,
+        Err(e) => return print_error(e),
+    };
+    scratch.codepage = mount.codepage;
+    scratch.options = mount.options;
+    let Ok(mut volume) = scratch.volume() else { return };
+    let entries: Vec<(String, bool)> = match volume.walk("/") {
+        Ok(walk) => walk.flatten().map(|(_, path, metadata)| (path, metadata.is_dir())).collect(),
+        Err(e) => return print_error(e),
+    };
+
+    let start = sys_now_us();
+    let mut bytes = 0;
+    for (path, _) in entries.iter().filter(|(_, dir)| !dir) {
+        bytes += volume.read_file(path).map(|c| c.len()).unwrap_or(0);
+    }
+    let elapsed = (sys_now_us() - start).max(1);
+    sys_print(&format!("read:     {} bytes in {} us ({} MB/s)", bytes, elapsed, bytes as u64 / elapsed));
+
+    let start = sys_now_us();
+    let mut listed = volume.list_current().map(|lines| lines.len()).unwrap_or(0);
+    for (path, _) in entries.iter().filter(|(_, dir)| *dir) {
+        listed += volume.list_path(path).map(|lines| lines.len()).unwrap_or(0);
+    }
+    sys_print(&format!("list:     {} entries in {} us", listed, sys_now_us() - start));
+
+    let start = sys_now_us();
+    for (path, _) in &entries { let _ = volume.resolve_path(path); }
+    sys_print(&format!("resolve:  {} paths in {} us", entries.len(), sys_now_us() - start));
+
+    let start = sys_now_us();
+    let result = Fat32OpenOptions::new().write(true).create(true).open(&mut volume, "/BENCH.TMP")
+        .and_then(|mut file| file.set_len(1 << 20));
+    match result {
+        Ok(_) => sys_print(&format!("allocate: {} clusters in {} us", (1 << 20) / volume.cluster_size(), sys_now_us() - start)),
+        Err(e) => sys_print(&format!("allocate: {}", e)),
+    }
+}
+
+/// `create [--from-dir <dir>] --size <size> <out.img>`: formats a new image file and
+/// fills it with the content of a host directory.
+pub fn create_image(args: &[String]) -> isize {
+    let mut from_dir = None;
+    let mut size = None;
+    let mut output = None;
+    let mut i = 0;
+    while i < args.len() {
+        match args[i].as_str() {
+            "--from-dir" if i + 1 < args.len() => { from_dir = Some(args[i + 1].as_str()); i += 1; }
+            "--size" if i + 1 < args.len() => { size = parse_size(&args[i + 1]); i += 1; }
+            other if output.is_none() && !other.starts_with("--") => output = Some(other),
+            _ => { output = None; break; }
+        }
+        i += 1;
+    }
+    let (Some(size), Some(output)) = (size, output) else {
+        sys_print("Usage: create [--from-dir <dir>] --size <size> <out.img>");
+        return 1;
+    };
+
+    let mut data = vec![0u8; size];
+    // SAFETY: time accepts a null pointer and only returns the current time.
+    let now = unsafe { libc::time(core::ptr::null_mut()) };
+    if let Err(e) = format(&mut data, &FormatOptions { volume_id: now as u32, ..Default::default() }) {
+        sys_print(e);
+        return 1;
+    }
+
+    let mut volume = match Fat32Volume::new(&mut data) {
+        Ok(volume) => volume,
+        Err(e) => { sys_print(e); return 1; }
+    };
+    volume.options.clock = Some(fat_now);
+    let root = volume.boot_sector.root_dir_cluster;
+    let copied = match from_dir {
+        Some(dir) => put_tree(&mut volume, dir, root, &mut ProgressBar::new()),
+        None => Ok(0),
+    };
+    match copied {
+        Ok(n) if sys_write_file(output, &data) => {
+            sys_print(&format!("{} files copied into {}.", n, output));
+            0
+        }
+        Ok(_) => { sys_print("Cannot write host file"); 1 }
+        Err(e) => { sys_print(e); 1 }
+    }
+}
@@ -0,0 +1,118 @@
+//! Hashes, snapshots and manifests, to tell whether files changed.
+
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+use fat32::fat32::checksum::HashAlgorithm;
+use fat32::fat32::snapshot::{Change, Snapshot};
+use fat32::fat32::volume::Fat32Volume;
+use crate::host::read_host_file;
+use crate::shell::print_error;
+use crate::sys::{sys_print, sys_write_file};
+
+/// Runs `command` if it hashes files or saves or checks a snapshot or manifest; false otherwise.
+pub fn run(volume: &mut Fat32Volume, command: &str, args: &[&str]) -> bool {
+    let arg1 = args.first().copied();
+    match command {
+        "sha256" | "md5" => {
+            let algorithm = if command == "md5" { HashAlgorithm::Md5 } else { HashAlgorithm::Sha256 };
+            if let Some(path) = arg1 {
+                match volume.hash_file(path, algorithm) {
+                    Ok(digest) => sys_print(&format!("{}  {}", digest, path)),
+                    Err(e) => print_error(e),
+                }
+            } else { print_error(&format!("Usage: {} <path>", command)); }
+        }
+        "checksum" => {
+            let algorithm = if args.contains(&"--md5") { HashAlgorithm::Md5 } else { HashAlgorithm::Sha256 };
+            let recursive = args.contains(&"-r");
+            match args.iter().find(|a| !a.starts_with('-')) {
+                Some(path) if recursive => {
+                    let result = volume.checksum_tree(path, algorithm, &mut |p, digest| {
+                        sys_print(&format!("{}  {}", digest, p));
+                    });
+                    if let Err(e) = result { print_error(e); }
+                }
+                Some(path) => match volume.hash_file(path, algorithm) {
+                    Ok(digest) => sys_print(&format!("{}  {}", digest, path)),
+                    Err(e) => print_error(e),
+                },
+                None => print_error("Usage: checksum [-r] [--md5] <path>"),
+            }
+        }
+        "snapshot" => {
+            match args {
+                ["save", name] => match volume.snapshot() {
+                    Ok(snapshot) => {
+                        let file = format!("{}.snap", name);
+                        if sys_write_file(&file, snapshot.to_text().as_bytes()) {
+                            sys_print(&format!("{} entries saved to {}", snapshot.entries.len(), file));
+                        } else {
+                            print_error("Cannot write the snapshot");
+                        }
+                    }
+                    Err(e) => print_error(e),
+                },
+                ["diff", name] => {
+                    let old = read_host_file(&format!("{}.snap", name))
+                        .and_then(|text| Snapshot::parse(&String::from_utf8_lossy(&text)));
+                    match old.and_then(|old| Ok((old, volume.snapshot()?))) {
+                        Ok((old, new)) => {
+                            let changes = old.diff(&new);
+                            print_changes(&changes, ["A ", "D ", "M "], ["content", "size", "time"]);
+                            if changes.is_empty() { sys_print("No changes."); }
+                        }
+                        Err(e) => print_error(e),
+                    }
+                }
+                _ => print_error("Usage: snapshot save|diff <name>"),
+            }
+        }
+        "manifest" => {
+            match args {
+                ["create", out] => match volume.manifest() {
+                    Ok(manifest) if sys_write_file(out, manifest.to_manifest().as_bytes()) => {
+                        sys_print(&format!("{} files written to {}", manifest.entries.len(), out));
+                    }
+                    Ok(_) => print_error("Cannot write host file"),
+                    Err(e) => print_error(e),
+                },
+                ["verify", input] => {
+                    let expected = read_host_file(input)
+                        .and_then(|text| Snapshot::from_manifest(&String::from_utf8_lossy(&text)));
+                    match expected.and_then(|expected| Ok((expected, volume.manifest()?))) {
+                        Ok((expected, actual)) => {
+                            let changes = expected.diff(&actual);
+                            print_changes(&changes, ["unexpected: ", "missing: ", "mismatch: "], ["sha256", "size", "mtime"]);
+                            match changes.len() {
+                                0 => sys_print(&format!("OK: all {} files match.", expected.entries.len())),
+                                n => print_error(&format!("FAILED: {} files differ from the manifest.", n)),
+                            }
+                        }
+                        Err(e) => print_error(e),
+                    }
+                }
+                _ => print_error("Usage: manifest create <out.json> | manifest verify <in.json>"),
+            }
+        }
+        _ => return false,
+    }
+    true
+}
+
+/// Prints a line per change, starting with the label of its kind: added, removed or
+/// modified. A modified file also gets which of its content, size and time changed, as
+/// `fields` name them.
+fn print_changes(changes: &[(String, Change)], labels: [&str; 3], fields: [&str; 3]) {
+    for (path, change) in changes {
+        sys_print(&match change {
+            Change::Added => format!("{}{}", labels[0], path),
+            Change::Removed => format!("{}{}", labels[1], path),
+            Change::Modified { content, size, time } => {
+                let what: Vec<&str> = [*content, *size, *time].iter().zip(fields)
+                    .filter(|(changed, _)| **changed).map(|(_, what)| what).collect();
+                format!("{}{} ({})", labels[2], path, what.join(", "))
+            }
+        });
+    }
+}
@@ -0,0 +1,22 @@
+//! The shell commands, by what they work on. Each module's `run` tells whether the
+//! command was one of its own, so a line goes through them in turn.
+
+pub mod disk;
+pub mod files;
+pub mod images;
+pub mod integrity;
+pub mod session;
+
+use fat32::fat32::storage::IoStats;
+use fat32::fat32::volume::Fat32Volume;
+use crate::shell::print_error;
+
+/// Runs a command working on one volume; `session_stats` is the I/O of the commands run
+/// on it before.
+pub fn run(volume: &mut Fat32Volume, command: &str, args: &[&str], session_stats: IoStats) {
+    let known = files::run(volume, command, args)
+        || integrity::run(volume, command, args)
+        || disk::run(volume, command, args)
+        || session::run(volume, command, args, session_stats);
+    if !known { print_error("Unknown command."); }
+}
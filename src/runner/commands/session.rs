@@ -0,0 +1,98 @@
+//! Commands showing or changing how the session works with a volume.
+
+use alloc::format;
+use core::sync::atomic::Ordering;
+use fat32::fat32::codepage::Codepage;
+use fat32::fat32::storage::IoStats;
+use fat32::fat32::volume::Fat32Volume;
+#[cfg(feature = "serve")]
+use crate::serve::{http::serve_http, ninep::serve_9p, ServeArgs};
+use crate::shell::{parse_size, print_error};
+use crate::sys::sys_print;
+use crate::tz::{parse_tz, TZ_MINUTES, tz_name};
+
+/// Runs `command` if it shows or changes a setting, or serves the volume; false otherwise.
+/// `stats` adds `session_stats`, the I/O of the earlier commands on this volume.
+pub fn run(volume: &mut Fat32Volume, command: &str, args: &[&str], session_stats: IoStats) -> bool {
+    let arg1 = args.first().copied();
+    match command {
+        "info" => match args {
+            [] => sys_print(&volume.get_info()),
+            ["--full"] => match volume.get_full_info() {
+                Ok(info) => sys_print(&info),
+                Err(e) => print_error(e),
+            },
+            _ => print_error("Usage: info [--full]"),
+        },
+        "tz" => match arg1 {
+            None => sys_print(&format!("Timestamps are read and written as {}.", tz_name(TZ_MINUTES.load(Ordering::Relaxed)))),
+            Some(zone) => match parse_tz(zone) {
+                Some(tz) => {
+                    TZ_MINUTES.store(tz, Ordering::Relaxed);
+                    sys_print(&format!("Timestamps are read and written as {}.", tz_name(tz)));
+                }
+                None => print_error("Usage: tz [local|UTC|+HH:MM]"),
+            },
+        },
+        #[cfg(feature = "serve")]
+        "serve" => match ServeArgs::parse(args, 8080, false) {
+            Some(serve) => if let Err(e) = serve_http(volume, serve.port) { print_error(e) },
+            None => print_error("Usage: serve [--port <port>]"),
+        },
+        #[cfg(feature = "serve")]
+        "serve-9p" => match ServeArgs::parse(args, 564, false) {
+            Some(serve) => if let Err(e) = serve_9p(volume, serve.port) { print_error(e) },
+            None => print_error("Usage: serve-9p [--port <port>]"),
+        },
+        #[cfg(not(feature = "serve"))]
+        "serve" | "serve-9p" => print_error("Built without the serve feature"),
+        "codepage" => {
+            match arg1 {
+                None => sys_print(&format!("Codepage: {}", volume.codepage.number())),
+                Some(n) => match n.parse().ok().and_then(Codepage::from_number) {
+                    Some(cp) => {
+                        volume.codepage = cp;
+                        sys_print("Codepage changed.");
+                    }
+                    None => print_error("Usage: codepage [437|850]"),
+                },
+            }
+        }
+        "stats" => {
+            let mut stats = session_stats;
+            stats += volume.stats();
+            sys_print(&format!("I/O since mounted:\n{}", stats));
+        }
+        "align" => {
+            match arg1 {
+                None => match volume.options.allocation_alignment {
+                    Some(align) => sys_print(&format!("New files start on {} byte boundaries.", align)),
+                    None => sys_print("Allocation alignment is off."),
+                },
+                Some("off") => {
+                    volume.options.allocation_alignment = None;
+                    sys_print("Allocation alignment is off.");
+                }
+                Some(size) => match parse_size(size).and_then(|s| u32::try_from(s).ok()).filter(|&s| s > 0) {
+                    Some(align) => {
+                        volume.options.allocation_alignment = Some(align);
+                        sys_print(&format!("New files start on {} byte boundaries.", align));
+                    }
+                    None => print_error("Usage: align [<size>|off]"),
+                },
+            }
+        }
+        "atime" => {
+            match arg1 {
+                Some("on") => volume.options.update_access_date = true,
+                Some("off") => volume.options.update_access_date = false,
+                _ => {}
+            }
+            if matches!(arg1, None | Some("on" | "off")) {
+                sys_print(if volume.options.update_access_date { "Reads update access dates." } else { "Reads leave access dates alone (noatime)." });
+            } else { print_error("Usage: atime [on|off]"); }
+        }
+        _ => return false,
+    }
+    true
+}
@@ -0,0 +1,107 @@
+//! Copies between host files and the image: `put`, `get` and what `sync` builds on.
+
+use alloc::format;
+use alloc::vec::Vec;
+use fat32::fat32::dir::DirEntry;
+use fat32::fat32::extract::TreeSink;
+use fat32::fat32::path::Resolved;
+use fat32::fat32::progress::{ProgressSink, ProgressTracker};
+use fat32::fat32::volume::Fat32Volume;
+use crate::sys::{sys_close, sys_create, sys_file_size, sys_is_dir, sys_list_dir, sys_mkdir, sys_open_read, sys_read_all, sys_set_times, sys_write, sys_write_file};
+
+/// Recreates an extracted image tree below a host directory.
+pub struct HostSink<'p> {
+    pub root: &'p str,
+}
+
+impl TreeSink for HostSink<'_> {
+    fn enter_dir(&mut self, path: &str, _entry: &DirEntry) -> Result<(), &'static str> {
+        if sys_mkdir(&format!("{}/{}", self.root, path)) { Ok(()) } else { Err("Cannot create host directory") }
+    }
+
+    fn leave_dir(&mut self, path: &str, entry: &DirEntry) -> Result<(), &'static str> {
+        sys_set_times(&format!("{}/{}", self.root, path), entry.accessed, entry.modified);
+        Ok(())
+    }
+
+    fn file(&mut self, path: &str, entry: &DirEntry, content: &[u8]) -> Result<(), &'static str> {
+        let host_path = format!("{}/{}", self.root, path);
+        if !sys_write_file(&host_path, content) { return Err("Cannot write host file"); }
+        sys_set_times(&host_path, entry.accessed, entry.modified);
+        Ok(())
+    }
+}
+
+/// Returns the image directory named by `path`, creating its last component if needed.
+pub fn open_or_create_dir(volume: &mut Fat32Volume, path: &str) -> Result<u32, &'static str> {
+    match volume.resolve_path(path)? {
+        Resolved::Dir(cluster) => Ok(cluster),
+        Resolved::File(_) => Err("Ce n'est pas un dossier"),
+        Resolved::NotFound { parent, name } => volume.create_directory_in(parent, &name),
+    }
+}
+
+/// Copies the host file `host_path` to `name` in the image directory at `dir_cluster`.
+pub fn put_file(volume: &mut Fat32Volume, host_path: &str, dir_cluster: u32, name: &str) -> Result<(), &'static str> {
+    let content = read_host_file(host_path)?;
+    volume.create_file_in(dir_cluster, name, &content, true)
+}
+
+pub fn read_host_file(path: &str) -> Result<Vec<u8>, &'static str> {
+    let fd = sys_open_read(path);
+    if fd < 0 { return Err("Cannot open host file"); }
+    let content = sys_read_all(fd);
+    sys_close(fd);
+    Ok(content)
+}
+
+/// Mirrors the host directory `host_dir` into the image directory at `dir_cluster`.
+/// Returns the number of files copied.
+pub fn put_tree(volume: &mut Fat32Volume, host_dir: &str, dir_cluster: u32, progress: &mut dyn ProgressSink) -> Result<usize, &'static str> {
+    let mut tracker = ProgressTracker::new(progress, host_tree_size(host_dir));
+    let result = put_dir(volume, host_dir, dir_cluster, &mut tracker);
+    tracker.finish();
+    result
+}
+
+fn put_dir(volume: &mut Fat32Volume, host_dir: &str, dir_cluster: u32, tracker: &mut ProgressTracker) -> Result<usize, &'static str> {
+    let names = sys_list_dir(host_dir).ok_or("Cannot open host directory")?;
+    let mut count = 0;
+    for name in names {
+        let host_path = format!("{}/{}", host_dir, name);
+        if sys_is_dir(&host_path) {
+            let sub = match volume.find_entry(dir_cluster, &name)? {
+                Some(_) => volume.enter_directory(dir_cluster, &name)?,
+                None => volume.create_directory_in(dir_cluster, &name)?,
+            };
+            count += put_dir(volume, &host_path, sub, tracker)?;
+        } else {
+            tracker.file(&host_path, sys_file_size(&host_path));
+            put_file(volume, &host_path, dir_cluster, &name)?;
+            count += 1;
+        }
+    }
+    Ok(count)
+}
+
+/// Total size of the files below a host directory.
+fn host_tree_size(host_dir: &str) -> u64 {
+    sys_list_dir(host_dir).unwrap_or_default().iter().map(|name| {
+        let path = format!("{}/{}", host_dir, name);
+        if sys_is_dir(&path) { host_tree_size(&path) } else { sys_file_size(&path) }
+    }).sum()
+}
+
+/// Copies the image file `image_path` to `host_path`, keeping its timestamps.
+pub fn get_file(volume: &mut Fat32Volume, image_path: &str, host_path: &str) -> Result<(), &'static str> {
+    let entry = volume.file_entry(image_path)?;
+    // The image is in memory, the file goes out straight from it.
+    let chunks = volume.file_chunks(image_path)?;
+    let fd = sys_create(host_path);
+    if fd < 0 { return Err("Cannot write host file"); }
+    let written = chunks.into_iter().all(|chunk| sys_write(fd, chunk));
+    sys_close(fd);
+    if !written { return Err("Cannot write host file"); }
+    sys_set_times(host_path, entry.accessed, entry.modified);
+    volume.record_access(image_path)
+}
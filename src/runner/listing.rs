@@ -0,0 +1,183 @@
+//! How `ls` shows entries: its options, sizes, colors and columns.
+
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, Ordering};
+use fat32::fat32::dir::{ATTR_ARCHIVE, ATTR_HIDDEN, ATTR_READ_ONLY, ATTR_SYSTEM, DirEntry, ListOptions, SortOrder};
+use crate::tz::format_time;
+
+/// What `ls` was asked to show.
+pub struct LsOptions<'s> {
+    pub path: &'s str,
+    /// Fields of a long listing: `-l` shows `Column::DEFAULT`, `--columns=` picks them.
+    pub columns: Option<Vec<Column>>,
+    /// `-a`, `--sort=<name|size|time>` and `--reverse`.
+    pub list: ListOptions,
+    /// `-R`: every directory below `path` as well, each under a `path:` header.
+    pub recursive: bool,
+    /// `-h`: sizes as `1.4K`, `23.7M`...
+    pub human: bool,
+    /// `--archived`: only files whose archive bit is set.
+    pub archived: bool,
+}
+
+impl<'s> LsOptions<'s> {
+    /// Parses `ls` arguments; short flags may be grouped as in `-la`.
+    pub fn parse(args: &[&'s str]) -> Result<Self, String> {
+        let mut ls = LsOptions { path: ".", columns: None, list: ListOptions::default(), recursive: false, human: false, archived: false };
+        for &arg in args {
+            match arg {
+                "--archived" => ls.archived = true,
+                "--reverse" => ls.list.reverse = true,
+                _ if arg.starts_with("--sort=") => ls.list.sort = match &arg["--sort=".len()..] {
+                    "name" => SortOrder::Name,
+                    "size" => SortOrder::Size,
+                    "time" => SortOrder::Time,
+                    "none" => SortOrder::Disk,
+                    other => return Err(format!("Unknown sort order {}. {}", other, LS_USAGE)),
+                },
+                _ if arg.starts_with("--columns=") => {
+                    let names = arg["--columns=".len()..].split(',');
+                    let columns: Option<Vec<Column>> = names.map(Column::parse).collect();
+                    match columns {
+                        Some(columns) if !columns.is_empty() => ls.columns = Some(columns),
+                        _ => return Err(format!("Unknown column in {}. Columns: type, attr, size, cluster, created, modified, accessed, name, short", arg)),
+                    }
+                }
+                _ if arg.starts_with("--") => return Err(format!("Unknown option {}. {}", arg, LS_USAGE)),
+                _ if arg.starts_with('-') && arg.len() > 1 => for flag in arg[1..].chars() {
+                    match flag {
+                        'l' => { ls.columns.get_or_insert_with(|| Column::DEFAULT.to_vec()); }
+                        'a' => ls.list.all = true,
+                        'R' => ls.recursive = true,
+                        'h' => ls.human = true,
+                        _ => return Err(format!("Unknown option -{}. {}", flag, LS_USAGE)),
+                    }
+                },
+                _ => ls.path = arg,
+            }
+        }
+        Ok(ls)
+    }
+}
+
+const LS_USAGE: &str = "Usage: ls [-laRh] [--columns=<col>,...] [--archived] [--sort=name|size|time|none] [--reverse] [path]";
+
+/// A byte count as is, or with `human` as `1.4K`, `23.7M`, `1.2G` (powers of 1024).
+pub fn format_size(bytes: u64, human: bool) -> String {
+    if !human || bytes < 1024 { return format!("{}", bytes); }
+    let mut value = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < 3 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1}{}", value, ['K', 'M', 'G', 'T'][unit])
+}
+
+/// Whether listings are colored: on when stdout is a terminal, off with `--no-color`
+/// or `NO_COLOR` set.
+pub static COLOR: AtomicBool = AtomicBool::new(false);
+
+/// `name` in the color of `entry`: blue for directories, dim for hidden entries, bold for
+/// volume labels. Read-only files get a `[ro]` marker, colored or not.
+fn paint_name(entry: &DirEntry, name: &str) -> String {
+    let marker = if entry.attr & ATTR_READ_ONLY != 0 && !entry.is_dir() { " [ro]" } else { "" };
+    if !COLOR.load(Ordering::Relaxed) { return format!("{}{}", name, marker); }
+    let mut style = String::new();
+    if entry.metadata().is_hidden() { style += "2;"; }
+    if entry.is_volume_label() { style += "1;"; } else if entry.is_dir() { style += "34;"; }
+    if style.is_empty() { return format!("{}{}", name, marker); }
+    format!("\x1b[{}m{}\x1b[0m{}", style.trim_end_matches(';'), name, marker)
+}
+
+/// One `ls` line, as `DirEntry::list_line` but with the name painted and, with `human`,
+/// the size as `format_size` gives it.
+pub fn list_short(entry: &DirEntry, human: bool) -> String {
+    let kind = if entry.is_volume_label() { "<VOL>" } else if entry.is_dir() { "<DIR>" } else { "     " };
+    let size = if human { format_size(entry.size as u64, true) } else { format!("{} bytes", entry.size) };
+    format!("{} {} ({})", kind, paint_name(entry, &entry.name), size)
+}
+
+/// A field `ls -l` can show, picked with `--columns`.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Column {
+    Type,
+    Attributes,
+    Size,
+    Cluster,
+    Created,
+    Modified,
+    Accessed,
+    Name,
+    ShortName,
+}
+
+impl Column {
+    /// The columns of a plain `ls -l`.
+    const DEFAULT: [Column; 4] = [Column::Type, Column::Size, Column::Modified, Column::Name];
+
+    fn parse(name: &str) -> Option<Self> {
+        Some(match name {
+            "type" => Column::Type,
+            "attr" => Column::Attributes,
+            "size" => Column::Size,
+            "cluster" => Column::Cluster,
+            "created" => Column::Created,
+            "modified" | "time" => Column::Modified,
+            "accessed" => Column::Accessed,
+            "name" => Column::Name,
+            "short" => Column::ShortName,
+            _ => return None,
+        })
+    }
+
+    /// Numbers line up on the right, text on the left.
+    fn right_aligned(self) -> bool {
+        matches!(self, Column::Size | Column::Cluster)
+    }
+
+    fn cell(self, entry: &DirEntry, human: bool) -> String {
+        let flag = |bit: u8, c| if entry.attr & bit != 0 { c } else { '-' };
+        match self {
+            Column::Type => String::from(if entry.is_volume_label() { "v" } else if entry.is_dir() { "d" } else { "-" }),
+            Column::Attributes => [flag(ATTR_READ_ONLY, 'r'), flag(ATTR_HIDDEN, 'h'), flag(ATTR_SYSTEM, 's'), flag(ATTR_ARCHIVE, 'a')].iter().collect(),
+            Column::Size => format_size(entry.size as u64, human),
+            Column::Cluster => format!("{}", entry.first_cluster),
+            Column::Created => format_time(&entry.created),
+            Column::Modified => format_time(&entry.modified),
+            Column::Accessed if entry.accessed.is_set() => format!("{:04}-{:02}-{:02}", entry.accessed.year, entry.accessed.month, entry.accessed.day),
+            Column::Accessed => "-".into(),
+            Column::Name => entry.name.clone(),
+            Column::ShortName => entry.alias.clone(),
+        }
+    }
+}
+
+/// `ls -l` lines for `entries`: each column as wide as its widest cell, long names
+/// included, and the last one left unpadded.
+pub fn list_table(entries: &[&DirEntry], columns: &[Column], human: bool) -> Vec<String> {
+    let rows: Vec<Vec<String>> = entries.iter().map(|e| columns.iter().map(|c| c.cell(e, human)).collect()).collect();
+    let widths: Vec<usize> = (0..columns.len())
+        .map(|i| rows.iter().map(|row| row[i].chars().count()).max().unwrap_or(0))
+        .collect();
+    rows.iter().enumerate().map(|(row_index, row)| {
+        let mut line = String::new();
+        for (i, (cell, column)) in row.iter().zip(columns).enumerate() {
+            if i > 0 { line.push(' '); }
+            let pad = " ".repeat(widths[i] - cell.chars().count());
+            if column.right_aligned() {
+                line += &pad;
+                line += cell;
+            } else if *column == Column::Name {
+                // Escape codes and markers go in after measuring, so they don't shift the columns.
+                line += &paint_name(entries[row_index], cell);
+            } else {
+                line += cell;
+                if i + 1 < row.len() { line += &pad; }
+            }
+        }
+        line
+    }).collect()
+}
@@ -0,0 +1,406 @@
+/*Some help to code this project:
+* https://alonely0.github.io/blog/unions/
+* https://www.reddit.com/r/embedded/comments/1myrsqj/i_wrote_a_minimal_embedded_fat32_file_system/?tl=fr
+* https://www.reddit.com/r/rust/comments/9eyc21/noob_what_exactly_is_no_std_and_why_is_it_so/?tl=fr
+* https://docs.rs/hadris-fat/latest/hadris_fat/
+* https://crates.io/crates/fat32rs
+* https://github.com/Spxg/fat32
+* https://github.com/rafalh/rust-fatfs
+* and others github projects...
+*
+* Some (really big if we are honest) help to debug and safety comment:
+* Github Copilot
+* Google Gemini
+*/
+
+#![no_std]
+#![no_main]
+
+extern crate alloc;
+
+mod commands;
+mod host;
+mod listing;
+mod mount;
+mod rc;
+#[cfg(feature = "serve")]
+mod serve;
+mod shell;
+mod sync;
+mod sys;
+mod term;
+mod tz;
+
+use alloc::format;
+use alloc::vec;
+use alloc::string::String;
+use alloc::vec::Vec;
+#[cfg(not(feature = "heap"))]
+use core::alloc::{GlobalAlloc, Layout};
+use core::ffi::{c_void, CStr};
+use core::sync::atomic::Ordering;
+use crate::commands::images::create_image;
+use crate::listing::COLOR;
+use crate::mount::{Mount, mount_prefix, Placement, TRACE_FD};
+use crate::rc::Rc;
+use crate::shell::{FAILED, print_error, split_chain, tokenize};
+use crate::sync::{sync_watch, SyncOptions};
+use crate::sys::{read_image, sys_create, sys_lock, sys_open_rw, sys_print, sys_print_raw};
+use crate::term::{COMMANDS, PAGER, read_line_completing};
+use crate::tz::{parse_tz, TZ_MINUTES};
+
+#[link(name = "c")]
+extern "C" {}
+
+/// # Safety
+/// This function relies on raw pointer arithmetic.
+/// The caller must ensure that `dest` and `src` are valid pointers,
+/// that they do not overlap, and that the memory regions have at least `n` bytes.
+#[no_mangle]
+pub unsafe extern "C" fn memcpy(dest: *mut u8, src: *const u8, n: usize) -> *mut u8 {
+    let mut i = 0;
+    while i < n {
+        *dest.add(i) = *src.add(i);
+        i += 1;
+    }
+    dest
+}
+
+/// # Safety
+/// The caller must ensure that `s` is a valid pointer to a memory region
+/// of at least `n` bytes.
+#[no_mangle]
+pub unsafe extern "C" fn memset(s: *mut u8, c: i32, n: usize) -> *mut u8 {
+    let mut i = 0;
+    while i < n {
+        *s.add(i) = c as u8;
+        i += 1;
+    }
+    s
+}
+
+/// # Safety
+/// The caller must ensure that `s1` and `s2` are valid pointers to memory regions
+/// of at least `n` bytes.
+#[no_mangle]
+pub unsafe extern "C" fn memcmp(s1: *const u8, s2: *const u8, n: usize) -> i32 {
+    let mut i = 0;
+    while i < n {
+        let a = *s1.add(i);
+        let b = *s2.add(i);
+        if a != b { return a as i32 - b as i32; }
+        i += 1;
+    }
+    0
+}
+
+// With the `parallel` feature the library pulls in std, which provides these itself.
+#[cfg(not(feature = "parallel"))]
+#[no_mangle]
+pub extern "C" fn rust_eh_personality() {}
+
+#[cfg(not(feature = "parallel"))]
+#[no_mangle]
+pub extern "C" fn _Unwind_Resume() -> ! {
+    // SAFETY: abort never returns and needs no cleanup, which is all we can do here.
+    unsafe { libc::abort() }
+}
+
+#[cfg(not(feature = "heap"))]
+struct LibcAllocator;
+
+#[cfg(not(feature = "heap"))]
+unsafe impl GlobalAlloc for LibcAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        // malloc only guarantees 16-byte alignment; larger ones (e.g. cache-padded
+        // structures of the thread pool) go through posix_memalign.
+        if layout.align() > 16 {
+            let mut ptr: *mut c_void = core::ptr::null_mut();
+            // SAFETY: the alignment is a power of two and a multiple of the pointer size.
+            if libc::posix_memalign(&mut ptr, layout.align(), layout.size()) != 0 { return core::ptr::null_mut(); }
+            return ptr as *mut u8;
+        }
+        // SAFETY: calling libc malloc is safe if the standard library is present.
+        // We cast the result to u8 ptr as required by GlobalAlloc.
+        libc::malloc(layout.size()) as *mut u8
+    }
+    unsafe fn dealloc(&self, ptr: *mut u8, _layout: Layout) {
+        // SAFETY: calling libc free is safe on a pointer previously allocated by malloc.
+        libc::free(ptr as *mut c_void);
+    }
+}
+
+#[cfg(not(feature = "heap"))]
+#[global_allocator]
+static ALLOCATOR: LibcAllocator = LibcAllocator;
+
+/// Without malloc, the image and everything else live in this arena. It sits in .bss,
+/// so pages the runner never touches cost nothing.
+#[cfg(feature = "heap")]
+#[global_allocator]
+static ALLOCATOR: fat32::fat32::heap::FixedHeap<{ 512 << 20 }> = fat32::fat32::heap::FixedHeap::new();
+
+/// Writes straight to a file descriptor, without allocating, for the panic handler.
+#[cfg(not(feature = "parallel"))]
+struct FdWriter(i32);
+
+#[cfg(not(feature = "parallel"))]
+impl core::fmt::Write for FdWriter {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        // SAFETY: s is a valid string slice, pointer and length are guaranteed valid.
+        unsafe { libc::write(self.0, s.as_ptr() as *const c_void, s.len()); }
+        Ok(())
+    }
+}
+
+#[cfg(not(feature = "parallel"))]
+static PANICKING: core::sync::atomic::AtomicBool = core::sync::atomic::AtomicBool::new(false);
+
+#[cfg(not(feature = "parallel"))]
+#[panic_handler]
+fn panic(info: &core::panic::PanicInfo) -> ! {
+    use core::fmt::Write;
+    // A panic while formatting the first one only gets the header.
+    let first = !PANICKING.swap(true, core::sync::atomic::Ordering::SeqCst);
+    let mut out = FdWriter(2);
+    let _ = out.write_str("!!! KERNEL PANIC !!!\n");
+    if first {
+        match info.location() {
+            Some(location) => { let _ = write!(out, "panicked at {}:{}:{}", location.file(), location.line(), location.column()); }
+            None => { let _ = out.write_str("panicked"); }
+        }
+        let _ = writeln!(out, ": {}", info.message());
+    }
+    // SAFETY: exit is a standard syscall to terminate the process.
+    unsafe { libc::exit(1) };
+}
+
+/// Prints log records on stderr; the level is picked by the `-v` flags.
+struct StderrLogger;
+
+impl log::Log for StderrLogger {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        metadata.level() <= log::max_level()
+    }
+
+    fn log(&self, record: &log::Record) {
+        if !self.enabled(record.metadata()) { return; }
+        let line = format!("[{}] {}\n", record.level(), record.args());
+        // SAFETY: line is a valid string; writing to stderr (2) is a standard operation.
+        unsafe { libc::write(2, line.as_ptr() as *const c_void, line.len()); }
+    }
+
+    fn flush(&self) {}
+}
+
+static LOGGER: StderrLogger = StderrLogger;
+
+/// # Safety
+/// Called by the C runtime, which guarantees that `argv` holds `argc` valid,
+/// null-terminated strings.
+#[no_mangle]
+pub unsafe extern "C" fn main(argc: isize, argv: *const *const u8) -> isize {
+    let args: Vec<String> = (0..argc.max(0) as usize).map(|i| {
+        // SAFETY: i < argc, so argv[i] is one of the strings the runtime passed.
+        let arg = unsafe { CStr::from_ptr(*argv.add(i) as *const core::ffi::c_char) };
+        String::from_utf8_lossy(arg.to_bytes()).into_owned()
+    }).collect();
+
+    // -v / --verbose show debug messages, -vv also traces every cluster access.
+    let verbosity: usize = args.iter().map(|a| match a.as_str() { "-v" | "--verbose" => 1, "-vv" => 2, _ => 0 }).sum();
+    // --overlay keeps every change in memory until `commit`; the images are left untouched otherwise.
+    let overlay = args.iter().any(|a| a == "--overlay");
+    // --trace-io logs every sector access and what it was for to stderr, --trace-io=<file> to a file.
+    for arg in args.iter().filter(|a| a.starts_with("--trace-io")) {
+        let fd = match arg.strip_prefix("--trace-io=") {
+            Some(path) => sys_create(path),
+            None => 2,
+        };
+        if fd < 0 { sys_print(&format!("Error: cannot create {}", &arg["--trace-io=".len()..])); }
+        TRACE_FD.store(fd, Ordering::Relaxed);
+    }
+    // --tz=<local|UTC|+HH:MM> is the time zone timestamps are shown and written in.
+    for arg in args.iter().filter_map(|a| a.strip_prefix("--tz=")) {
+        match parse_tz(arg) {
+            Some(tz) => TZ_MINUTES.store(tz, Ordering::Relaxed),
+            None => sys_print(&format!("Error: unknown time zone {}", arg)),
+        }
+    }
+    // --offset=<bytes> is where the volume starts in the image, when it isn't found on its
+    // own in a fixed VHD, a partition or an ISO; --esp picks the EFI System Partition.
+    let mut placement = Placement::Find;
+    for arg in args.iter() {
+        match Placement::parse(arg) {
+            Some(Ok(found)) => placement = found,
+            Some(Err(e)) => sys_print(&format!("Error: {} {}", e, arg)),
+            None => {}
+        }
+    }
+    // Listings are colored on a terminal, or as the rc file says, unless --no-color is
+    // given or NO_COLOR is set.
+    // SAFETY: isatty only inspects the descriptor, getenv reads a null-terminated name.
+    let terminal = unsafe { libc::isatty(1) == 1 && libc::getenv(c"NO_COLOR".as_ptr()).is_null() };
+    let rc = Rc::load();
+    COLOR.store(rc.color.unwrap_or(terminal) && !args.iter().any(|a| a == "--no-color"), Ordering::Relaxed);
+    // --no-pager prints long output at once instead of a screen at a time.
+    PAGER.store(!args.iter().any(|a| a == "--no-pager"), Ordering::Relaxed);
+    let args: Vec<String> = args.into_iter()
+        .filter(|a| !matches!(a.as_str(), "-v" | "-vv" | "--verbose" | "--overlay" | "--no-color" | "--no-pager") && !a.starts_with("--trace-io") && !a.starts_with("--tz=") && Placement::parse(a).is_none())
+        .collect();
+    let _ = log::set_logger(&LOGGER);
+    log::set_max_level(match verbosity {
+        0 => log::LevelFilter::Warn,
+        1 => log::LevelFilter::Debug,
+        _ => log::LevelFilter::Trace,
+    });
+
+    if args.get(1).map(String::as_str) == Some("create") {
+        return create_image(&args[2..]);
+    }
+
+    let img_path = rc.image.as_deref().unwrap_or("fat32.img");
+    
+    sys_print("--- FAT32 Shell (100% No-Std / LibC) ---");
+    sys_print_raw("Opening image... ");
+    
+    let fd = sys_open_rw(img_path);
+    if fd < 0 {
+        sys_print(&format!("Error: Cannot open {}", img_path));
+        return 1;
+    }
+    if !sys_lock(fd) {
+        sys_print(&format!("Error: {} is in use by another process", img_path));
+        return 1;
+    }
+    sys_print("OK.");
+
+    // CHARGEMENT DISQUE
+    let (disk_memory, decompressor) = match read_image(fd) {
+        Ok(read) => read,
+        Err(e) => {
+            sys_print(&format!("Error: {}", e));
+            return 1;
+        }
+    };
+    if disk_memory.is_empty() {
+        sys_print("Error: Empty image.");
+        return 1;
+    }
+
+    // The session image is mounted as `a`; more can be added with `mount`.
+    let journal = decompressor.is_none().then(|| format!("{}.journal", img_path));
+    let mut mounts = match Mount::new("a", fd, disk_memory, journal, placement) {
+        Ok(mount) => vec![Mount { decompressor, overlay: decompressor.is_some(), ..mount }],
+        Err(e) => {
+            sys_print(&format!("Error: {}", e));
+            return 1;
+        }
+    };
+    mounts[0].overlay |= overlay;
+
+    // Commands of the line being run, last first, each with whether it only runs when
+    // the one before succeeded.
+    let mut pending: Vec<(String, bool)> = Vec::new();
+    loop {
+        if pending.is_empty() {
+            let cwd = mounts[0].volume()
+                .and_then(|session| session.directory_path(session.current_cluster))
+                .unwrap_or_else(|_| "?".into());
+            let image = img_path.rsplit('/').next().unwrap_or(img_path);
+            let prompt = rc.prompt.replace("{image}", image).replace("{cwd}", &cwd);
+            let line = read_line_completing(&prompt, &mut |word, first| {
+                if first {
+                    let names = COMMANDS.iter().copied().chain(rc.aliases.iter().map(|(name, _)| name.as_str()));
+                    let mut offers: Vec<String> = names.filter(|c| c.starts_with(word)).map(String::from).collect();
+                    offers.sort();
+                    return offers;
+                }
+                // Paths complete on the image their `name:` prefix picks, if any.
+                let (index, path) = mount_prefix(&mounts, word).unwrap_or((0, word));
+                let prefix = &word[..word.len() - path.len()];
+                let offers = mounts[index].volume().and_then(|volume| volume.complete_path(path)).unwrap_or_default();
+                offers.into_iter().map(|offer| format!("{}{}", prefix, offer)).collect()
+            });
+            match split_chain(&line) {
+                Ok(commands) => pending = commands.into_iter().rev().map(|(c, after)| (String::from(c), after)).collect(),
+                Err(e) => print_error(e),
+            }
+        }
+        let Some((line, after_success)) = pending.pop() else { continue };
+        // A failed command skips the rest of its `&&` chain, up to the next `;`.
+        if after_success && FAILED.load(Ordering::Relaxed) { continue; }
+        FAILED.store(false, Ordering::Relaxed);
+
+        let tokens = match tokenize(&line).and_then(|words| rc.expand_alias(words)) {
+            Ok(tokens) if !tokens.is_empty() => tokens,
+            Ok(_) => continue,
+            Err(e) => { print_error(e); continue; }
+        };
+
+        for m in mounts.iter_mut().filter(|m| !m.warned && m.changed_on_disk()) {
+            if rc.auto_reload && m.changed_blocks() == 0 {
+                match m.reload() {
+                    Ok(()) => sys_print(&format!("{}: image changed on disk, reloaded", m.name)),
+                    Err(e) => sys_print(&format!("{}: image changed on disk, cannot reload: {}", m.name, e)),
+                }
+            } else {
+                sys_print(&format!("{}: image changed on disk; `reload {}` reads it again", m.name, m.name));
+            }
+            m.warned = m.changed_on_disk();
+        }
+
+        // Commands working on the set of mounted images rather than on one of them.
+        let words: Vec<&str> = tokens.iter().map(String::as_str).collect();
+        if commands::images::run(&mut mounts, &words, overlay) { continue; }
+
+        // Any other command runs on a single image, picked by the `name:` prefixes of its
+        // arguments, which are stripped before the command sees them.
+        let mut target = None;
+        let mut mixed = false;
+        let words: Vec<&str> = words.iter().map(|token| match mount_prefix(&mounts, token) {
+            Some((index, path)) => {
+                mixed |= target.is_some_and(|t| t != index);
+                target = Some(index);
+                path
+            }
+            None => token,
+        }).collect();
+        if mixed {
+            print_error("Only cp works across images.");
+            continue;
+        }
+        let target = target.unwrap_or(0);
+        if matches!(words[0], "exit" | "quit") { break; }
+        // It saves the image as it goes, which needs the whole mount rather than a volume.
+        if words[0] == "sync" && words.contains(&"--watch") {
+            match SyncOptions::parse(&words[1..]) {
+                Some((options, host_dir, image_dir)) => if let Err(e) = sync_watch(&mut mounts[target], host_dir, image_dir, &options) { print_error(e) },
+                None => print_error(SyncOptions::USAGE),
+            }
+            continue;
+        }
+        let session_stats = mounts[target].stats;
+        let mut volume = match mounts[target].volume() {
+            Ok(volume) => volume,
+            Err(e) => {
+                print_error(&format!("{}: {}", mounts[target].name, e));
+                continue;
+            }
+        };
+
+        commands::run(&mut volume, words[0], &words[1..], session_stats);
+
+        let (cwd, codepage, options, stats) = (volume.current_cluster, volume.codepage, volume.options, volume.stats());
+        mounts[target].cwd = cwd;
+        mounts[target].codepage = codepage;
+        mounts[target].options = options;
+        mounts[target].stats += stats;
+    }
+
+    sys_print("Saving...");
+    for m in &mut mounts {
+        m.close();
+    }
+    sys_print("Bye.");
+    0
+}
@@ -0,0 +1,321 @@
+//! The images mounted in the session, how they are found, saved and read again, and
+//! copies between them.
+
+use alloc::format;
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::ops::Range;
+use core::sync::atomic::{AtomicI32, Ordering};
+use fat32::fat32::codepage::Codepage;
+use fat32::fat32::disk::{locate_esp, locate_volume};
+use fat32::fat32::error::BLOCK_OUT_OF_RANGE;
+use fat32::fat32::io::BLOCK_SIZE;
+use fat32::fat32::journal::{self, Block, WriteCounters};
+use fat32::fat32::path::Resolved;
+use fat32::fat32::storage::{IoEvent, IoStats};
+use fat32::fat32::volume::{Fat32Options, Fat32Volume};
+use crate::shell::parse_size;
+use crate::sys::{read_image, sys_close, sys_create, sys_fsync, sys_ftruncate, sys_lock, sys_open_read, sys_open_rw, sys_print, sys_pwrite, sys_read_all, sys_stamp, sys_unlink, sys_write};
+use crate::tz::fat_now;
+
+/// Where `--trace-io` sends a line per sector access, -1 when tracing is off.
+pub static TRACE_FD: AtomicI32 = AtomicI32::new(-1);
+
+fn trace_io(event: &IoEvent) {
+    sys_write(TRACE_FD.load(Ordering::Relaxed), format!("{}\n", event).as_bytes());
+}
+
+/// An image opened in the shell session, reachable through `name:` path prefixes.
+/// The volume is rebuilt around `data` for each command, so only the state it can't
+/// recompute from the image is kept here.
+pub struct Mount {
+    pub name: String,
+    pub fd: i32,
+    pub data: Vec<u8>,
+    pub cwd: u32,
+    pub codepage: Codepage,
+    pub options: Fat32Options,
+    /// Sidecar file saves are journaled to, `None` for scratch copies that are never saved.
+    pub journal: Option<String>,
+    /// Hash of each block as last saved, so a save only writes the blocks that changed.
+    pub saved: Vec<u64>,
+    /// With `--overlay`, changes stay in memory until `commit` and are dropped otherwise.
+    pub overlay: bool,
+    /// Blocks saved this session and the `pwrite` calls that took.
+    pub written: WriteCounters,
+    /// I/O of the commands run so far, each on a volume of its own.
+    pub stats: IoStats,
+    /// `sys_stamp` of the image file when last read or saved, to notice other writers.
+    pub stamp: Option<(i64, i64, i64)>,
+    /// Set once the user was told the file changed, so they are told only once.
+    pub warned: bool,
+    /// Program the image file was decompressed with. Such an image is read-only: changes
+    /// stay in memory, as with `--overlay`, and can't be committed.
+    pub decompressor: Option<&'static str>,
+    /// Bytes of `data` the volume spans: all of them, unless it sits in a partition or
+    /// before the footer of a VHD.
+    pub span: Range<usize>,
+}
+
+impl Mount {
+    pub fn new(name: &str, fd: i32, mut data: Vec<u8>, journal: Option<String>, placement: Placement) -> Result<Self, &'static str> {
+        if let Some(journal) = &journal { replay_journal(journal, fd, &mut data); }
+        let span = placement.locate(&data)?;
+        let cwd = Fat32Volume::new(&mut data[span.clone()])?.current_cluster;
+        let saved = block_hashes(&data);
+        Ok(Mount { name: name.into(), fd, data, cwd, codepage: Codepage::default(), options: Fat32Options { clock: Some(fat_now), ..Default::default() }, journal, saved, overlay: false, written: WriteCounters::default(), stats: IoStats::default(), stamp: sys_stamp(fd), warned: false, decompressor: None, span })
+    }
+
+    /// True when another program wrote to the image file since it was last read or saved.
+    pub fn changed_on_disk(&self) -> bool {
+        sys_stamp(self.fd) != self.stamp
+    }
+
+    /// Reads the image file again, dropping the changes not saved yet, and mounts what it
+    /// now holds. The current directory is kept when it is still one.
+    pub fn reload(&mut self) -> Result<(), &'static str> {
+        let (mut data, _) = read_image(self.fd)?;
+        let span = self.span_in(&data);
+        let volume = Fat32Volume::new(data.get_mut(span.clone()).ok_or("Image shorter than its volume")?)?;
+        let cwd = match volume.directory_path(self.cwd) {
+            Ok(_) => self.cwd,
+            Err(_) => volume.boot_sector.root_dir_cluster,
+        };
+        self.cwd = cwd;
+        self.saved = block_hashes(&data);
+        self.data = data;
+        self.span = span;
+        self.stamp = sys_stamp(self.fd);
+        self.warned = false;
+        Ok(())
+    }
+
+    /// Fails when a command left the boot sector unusable; `discard` then gets the saved one back.
+    pub fn volume(&mut self) -> Result<Fat32Volume<'_>, &'static str> {
+        let mut volume = Fat32Volume::new(self.data.get_mut(self.span.clone()).ok_or("Image shorter than its volume")?)?;
+        volume.current_cluster = self.cwd;
+        volume.codepage = self.codepage;
+        volume.options = self.options;
+        if TRACE_FD.load(Ordering::Relaxed) >= 0 { volume.set_io_tracer(Some(trace_io)); }
+        Ok(volume)
+    }
+
+    /// Number of blocks that differ from the image file.
+    pub fn changed_blocks(&self) -> usize {
+        block_hashes(&self.data).iter().enumerate().filter(|(i, hash)| self.saved.get(*i) != Some(hash)).count()
+    }
+
+    /// Where the volume lies in `data`, read again from the image file: all of it for a
+    /// plain image, whose size `resize` may have changed, the same bytes otherwise.
+    fn span_in(&self, data: &[u8]) -> Range<usize> {
+        if self.span == (0..self.data.len()) { 0..data.len() } else { self.span.clone() }
+    }
+
+    /// Drops the changes made since the last save by reading the image file again. The
+    /// changes are kept when it can't be read.
+    pub fn discard(&mut self) -> Result<usize, &'static str> {
+        let changed = self.changed_blocks();
+        if changed > 0 {
+            let (mut data, _) = read_image(self.fd)?;
+            let span = self.span_in(&data);
+            if let Some(volume) = data.get_mut(span.clone()).and_then(|data| Fat32Volume::new(data).ok()) {
+                self.cwd = volume.current_cluster;
+            }
+            self.saved = block_hashes(&data);
+            self.data = data;
+            self.span = span;
+            self.stamp = sys_stamp(self.fd);
+        }
+        Ok(changed)
+    }
+
+    /// Saves the image unless it is an overlay, then closes it.
+    pub fn close(&mut self) {
+        if !self.overlay {
+            self.save();
+        } else if self.changed_blocks() > 0 {
+            sys_print(&format!("{}: uncommitted changes discarded", self.name));
+        }
+        sys_close(self.fd);
+    }
+
+    /// Writes the changed blocks back and returns their number. They go to the sidecar
+    /// journal first, so a save cut short is finished on the next start instead of
+    /// leaving a half-written image.
+    pub fn save(&mut self) -> usize {
+        let Some(journal) = &self.journal else { return 0 };
+        let hashes = block_hashes(&self.data);
+        let dirty: BTreeMap<u64, Box<Block>> = hashes.iter().enumerate()
+            .filter(|(i, hash)| self.saved.get(*i) != Some(hash))
+            .map(|(i, _)| {
+                let chunk = &self.data[i * BLOCK_SIZE..((i + 1) * BLOCK_SIZE).min(self.data.len())];
+                let mut block = Box::new([0u8; BLOCK_SIZE]);
+                block[..chunk.len()].copy_from_slice(chunk);
+                (i as u64, block)
+            })
+            .collect();
+        if dirty.is_empty() { return 0; }
+
+        // A grown image is extended first, so that a journal replayed after a crash finds
+        // room for every block.
+        if sys_stamp(self.fd).is_some_and(|(_, _, size)| (size as usize) < self.data.len()) && !sys_ftruncate(self.fd, self.data.len()) {
+            sys_print(&format!("Error: cannot extend {}, left unsaved", self.name));
+            return 0;
+        }
+
+        // The header goes last: until it is on disk the journal is ignored.
+        let bytes = journal::encode(&dirty);
+        let jfd = sys_create(journal);
+        let written = jfd >= 0
+            && sys_write(jfd, &bytes[BLOCK_SIZE..]) && sys_fsync(jfd)
+            && sys_pwrite(jfd, 0, &bytes[..BLOCK_SIZE]) && sys_fsync(jfd);
+        if jfd >= 0 { sys_close(jfd); }
+        if !written {
+            sys_print(&format!("Error: cannot write {}, {} left unsaved", journal, self.name));
+            return 0;
+        }
+        // Adjacent blocks, such as the sectors of one FAT, go in a single call.
+        for run in journal::runs(dirty.keys().copied()) {
+            let at = run.start as usize * BLOCK_SIZE;
+            sys_pwrite(self.fd, at, &self.data[at..(run.end as usize * BLOCK_SIZE).min(self.data.len())]);
+            self.written.blocks += run.end - run.start;
+            self.written.writes += 1;
+        }
+        // A shrunk image is cut only once everything it keeps is written.
+        if sys_stamp(self.fd).is_some_and(|(_, _, size)| (size as usize) > self.data.len()) && !sys_ftruncate(self.fd, self.data.len()) {
+            sys_print(&format!("Error: cannot truncate {}", self.name));
+        }
+        sys_fsync(self.fd);
+        sys_unlink(journal);
+        self.saved = hashes;
+        self.stamp = sys_stamp(self.fd);
+        dirty.len()
+    }
+}
+
+pub fn block_hashes(data: &[u8]) -> Vec<u64> {
+    data.chunks(BLOCK_SIZE)
+        .map(|block| block.iter().fold(0xCBF29CE484222325u64, |hash, &b| (hash ^ b as u64).wrapping_mul(0x100000001B3)))
+        .collect()
+}
+
+/// Finishes a save interrupted after its journal was written, then removes the journal.
+fn replay_journal(path: &str, fd: i32, data: &mut [u8]) {
+    let jfd = sys_open_read(path);
+    if jfd < 0 { return; }
+    let bytes = sys_read_all(jfd);
+    sys_close(jfd);
+    let read = |block: u64, buf: &mut [u8]| {
+        let start = block as usize * BLOCK_SIZE;
+        bytes.get(start..start + buf.len()).map(|b| buf.copy_from_slice(b)).ok_or(BLOCK_OUT_OF_RANGE)
+    };
+    // Without a complete journal the image was never touched.
+    if let Ok(Some(blocks)) = journal::decode(read) {
+        for (index, block) in &blocks {
+            let at = *index as usize * BLOCK_SIZE;
+            if at >= data.len() { continue; }
+            let len = BLOCK_SIZE.min(data.len() - at);
+            data[at..at + len].copy_from_slice(&block[..len]);
+        }
+        for run in journal::runs(blocks.keys().copied()) {
+            let at = run.start as usize * BLOCK_SIZE;
+            if at >= data.len() { continue; }
+            sys_pwrite(fd, at, &data[at..(run.end as usize * BLOCK_SIZE).min(data.len())]);
+        }
+        sys_fsync(fd);
+        sys_print(&format!("Replayed {} blocks from {}", blocks.len(), path));
+    }
+    sys_unlink(path);
+}
+
+/// Where to look for the volume in an image file.
+#[derive(Debug, Clone, Copy)]
+pub enum Placement {
+    /// The whole file, or the first FAT32 partition or boot image in it.
+    Find,
+    /// From `--offset=<bytes>` to the end of the disk.
+    Offset(usize),
+    /// With `--esp`, the EFI System Partition of a UEFI bootable disk or ISO.
+    Esp,
+}
+
+impl Placement {
+    /// Reads `--offset=<bytes>` or `--esp`; `None` for any other argument.
+    pub fn parse(arg: &str) -> Option<Result<Self, &'static str>> {
+        match arg {
+            "--esp" => Some(Ok(Placement::Esp)),
+            _ => arg.strip_prefix("--offset=").map(|bytes| parse_size(bytes).map(Placement::Offset).ok_or("Invalid offset")),
+        }
+    }
+
+    fn locate(self, data: &[u8]) -> Result<Range<usize>, &'static str> {
+        match self {
+            Placement::Find => locate_volume(data, None),
+            Placement::Offset(offset) => locate_volume(data, Some(offset)),
+            Placement::Esp => locate_esp(data),
+        }
+    }
+}
+
+pub fn mount_image(path: &str, name: &str, placement: Placement) -> Result<Mount, &'static str> {
+    let fd = sys_open_rw(path);
+    if fd < 0 { return Err("Cannot open image"); }
+    if !sys_lock(fd) {
+        sys_close(fd);
+        return Err("Image in use by another process");
+    }
+    let (data, decompressor) = match read_image(fd) {
+        Ok(read) => read,
+        Err(e) => {
+            sys_close(fd);
+            return Err(e);
+        }
+    };
+    if matches!(placement, Placement::Find) && (data.len() < 512 || data[510..512] != [0x55, 0xAA]) {
+        sys_close(fd);
+        return Err("Not a FAT32 image");
+    }
+    let journal = decompressor.is_none().then(|| format!("{}.journal", path));
+    let mount = Mount::new(name, fd, data, journal, placement).map(|m| Mount { decompressor, overlay: decompressor.is_some(), ..m });
+    if mount.is_err() { sys_close(fd); }
+    mount
+}
+
+/// Splits a `name:path` argument into the index of the mounted image and the path.
+pub fn mount_prefix<'p>(mounts: &[Mount], arg: &'p str) -> Option<(usize, &'p str)> {
+    let (name, path) = arg.split_once(':')?;
+    let index = mounts.iter().position(|m| m.name == name)?;
+    Some((index, path))
+}
+
+/// Copies the file `src` to `dst`, or into `dst` when it is a directory.
+/// Both paths may carry a mount prefix; unprefixed ones refer to the session image.
+pub fn copy_file(mounts: &mut [Mount], src: &str, dst: &str) -> Result<(), &'static str> {
+    let (src_mount, src) = mount_prefix(mounts, src).unwrap_or((0, src));
+    let (dst_mount, dst) = mount_prefix(mounts, dst).unwrap_or((0, dst));
+    if src_mount == dst_mount { return mounts[src_mount].volume()?.copy_file(src, dst); }
+
+    // The file goes over a run of clusters at a time, straight from one image to the other.
+    let (low, high) = mounts.split_at_mut(src_mount.max(dst_mount));
+    let (source, dest) = if src_mount < dst_mount { (&mut low[src_mount], &mut high[0]) } else { (&mut high[0], &mut low[dst_mount]) };
+    let source = source.volume()?;
+    let entry = source.file_entry(src)?;
+
+    let mut volume = dest.volume()?;
+    let target = match volume.resolve_path(dst)? {
+        Resolved::Dir(_) if dst.is_empty() => entry.name,
+        Resolved::Dir(_) => format!("{}/{}", dst.trim_end_matches('/'), entry.name),
+        _ => String::from(dst),
+    };
+    volume.write_file_from(&target, entry.size, source.file_chunks(src)?)
+}
+
+/// Copies the directory `src` and everything in it to `dst`, both on the same image.
+pub fn copy_tree(mounts: &mut [Mount], src: &str, dst: &str) -> Result<usize, &'static str> {
+    let (src_mount, src) = mount_prefix(mounts, src).unwrap_or((0, src));
+    let (dst_mount, dst) = mount_prefix(mounts, dst).unwrap_or((0, dst));
+    if src_mount != dst_mount { return Err("cp -r only copies within one image"); }
+    mounts[src_mount].volume()?.copy_tree(src, dst)
+}
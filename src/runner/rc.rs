@@ -0,0 +1,109 @@
+//! `~/.fatreaderrc`, the settings and aliases read when the shell starts.
+
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::ffi::CStr;
+use crate::shell::tokenize;
+use crate::sys::{sys_close, sys_open_read, sys_print, sys_read_all};
+
+/// Settings read from `~/.fat32readerrc` at startup:
+///
+/// ```text
+/// # Image opened when none is given, instead of fat32.img.
+/// image = card.img
+/// # on, off, or auto to color on a terminal only.
+/// color = auto
+/// # {image} is the image file, {cwd} the current directory.
+/// prompt = "{image}:{cwd}> "
+/// # on to read an image again when another program changed it and the shell has no
+/// # unsaved changes to it, off to only warn.
+/// auto-reload = off
+///
+/// [aliases]
+/// ll = ls -l
+/// ```
+pub struct Rc {
+    pub image: Option<String>,
+    /// Forced on or off, `None` to color on a terminal only.
+    pub color: Option<bool>,
+    pub prompt: String,
+    pub auto_reload: bool,
+    pub aliases: Vec<(String, String)>,
+}
+
+impl Default for Rc {
+    fn default() -> Self {
+        Rc { image: None, color: None, prompt: "> ".into(), auto_reload: false, aliases: Vec::new() }
+    }
+}
+
+impl Rc {
+    /// Reads `~/.fat32readerrc`, printing what is wrong in it. The defaults when there
+    /// is none.
+    pub fn load() -> Self {
+        // SAFETY: getenv reads a null-terminated name and returns a null-terminated value.
+        let home = unsafe {
+            let home = libc::getenv(c"HOME".as_ptr());
+            if home.is_null() { return Rc::default(); }
+            CStr::from_ptr(home).to_string_lossy().into_owned()
+        };
+        let path = format!("{}/.fat32readerrc", home);
+        let fd = sys_open_read(&path);
+        if fd < 0 { return Rc::default(); }
+        let text = sys_read_all(fd);
+        sys_close(fd);
+        let (rc, errors) = Rc::parse(&String::from_utf8_lossy(&text));
+        for (line, error) in errors { sys_print(&format!("{}:{}: {}", path, line, error)); }
+        rc
+    }
+
+    /// Settings from the text of an rc file, and the numbered lines that made no sense.
+    fn parse(text: &str) -> (Self, Vec<(usize, &'static str)>) {
+        let mut rc = Rc::default();
+        let mut errors = Vec::new();
+        let mut in_aliases = false;
+        for (i, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') { continue; }
+            if line.starts_with('[') {
+                in_aliases = line == "[aliases]";
+                if !in_aliases { errors.push((i + 1, "unknown section")); }
+                continue;
+            }
+            let Some((key, value)) = line.split_once('=') else {
+                errors.push((i + 1, "expected <key> = <value>"));
+                continue;
+            };
+            let (key, value) = (key.trim(), value.trim());
+            // Quotes keep the spaces at the ends of a value.
+            let value = value.strip_prefix('"').and_then(|v| v.strip_suffix('"')).unwrap_or(value);
+            match key {
+                _ if in_aliases => rc.aliases.push((key.into(), value.into())),
+                "image" => rc.image = Some(value.into()),
+                "color" => match value {
+                    "on" => rc.color = Some(true),
+                    "off" => rc.color = Some(false),
+                    "auto" => rc.color = None,
+                    _ => errors.push((i + 1, "color is on, off or auto")),
+                },
+                "prompt" => rc.prompt = value.into(),
+                "auto-reload" => match value {
+                    "on" => rc.auto_reload = true,
+                    "off" => rc.auto_reload = false,
+                    _ => errors.push((i + 1, "auto-reload is on or off")),
+                },
+                _ => errors.push((i + 1, "unknown setting")),
+            }
+        }
+        (rc, errors)
+    }
+
+    /// `words` with an alias in first place replaced by what it stands for.
+    pub fn expand_alias(&self, words: Vec<String>) -> Result<Vec<String>, &'static str> {
+        match self.aliases.iter().find(|(name, _)| Some(name) == words.first()) {
+            Some((_, command)) => Ok(tokenize(command)?.into_iter().chain(words.into_iter().skip(1)).collect()),
+            None => Ok(words),
+        }
+    }
+}
@@ -0,0 +1,190 @@
+//! `serve`: a read-only HTTP server for the files of a volume.
+
+use alloc::format;
+use alloc::vec;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::ffi::c_void;
+use fat32::fat32::dir::{DirEntry, SortOrder};
+use fat32::fat32::file::Fat32OpenOptions;
+use fat32::fat32::path::Resolved;
+use fat32::fat32::volume::Fat32Volume;
+use crate::serve::{serve_until_interrupted, sys_listen, sys_send};
+use crate::shell::print_error;
+use crate::sys::{sys_close, sys_print};
+
+/// Serves `volume` read-only over HTTP on `port` until Ctrl-C.
+pub fn serve_http(volume: &mut Fat32Volume, port: u16) -> Result<(), &'static str> {
+    let listener = sys_listen(port);
+    if listener < 0 { return Err("Cannot listen on that port"); }
+    sys_print(&format!("Serving the image read-only on http://0.0.0.0:{}/ (Ctrl-C to stop).", port));
+    serve_until_interrupted(listener, Some(30), &mut |fd| {
+        http_request(volume, fd);
+        sys_close(fd);
+    });
+    sys_close(listener);
+    Ok(())
+}
+
+/// Content types by file extension, compared without case; anything else is sent as
+/// `application/octet-stream`.
+const CONTENT_TYPES: &[(&str, &str)] = &[
+    ("htm", "text/html; charset=utf-8"), ("html", "text/html; charset=utf-8"), ("txt", "text/plain; charset=utf-8"),
+    ("md", "text/plain; charset=utf-8"), ("cfg", "text/plain; charset=utf-8"), ("conf", "text/plain; charset=utf-8"),
+    ("json", "application/json"), ("xml", "application/xml"), ("pdf", "application/pdf"), ("zip", "application/zip"),
+    ("png", "image/png"), ("jpg", "image/jpeg"), ("jpeg", "image/jpeg"), ("gif", "image/gif"), ("svg", "image/svg+xml"),
+];
+
+/// Answers one request read from `fd`: a file of the image, or the listing of a directory,
+/// as HTML or, with `?format=json`, as JSON. Only GET and HEAD are allowed.
+fn http_request(volume: &mut Fat32Volume, fd: i32) {
+    let mut head = Vec::new();
+    let mut chunk = [0u8; 1024];
+    while !head.windows(4).any(|w| w == b"\r\n\r\n") {
+        // SAFETY: chunk is a valid buffer of chunk.len() bytes.
+        let n = unsafe { libc::recv(fd, chunk.as_mut_ptr() as *mut c_void, chunk.len(), 0) };
+        if n <= 0 || head.len() > 16 * 1024 { return; }
+        head.extend_from_slice(&chunk[..n as usize]);
+    }
+    let head = String::from_utf8_lossy(&head);
+    let mut request = head.lines().next().unwrap_or("").split(' ');
+    let (method, target) = (request.next().unwrap_or(""), request.next().unwrap_or(""));
+    let (raw_path, query) = target.split_once('?').unwrap_or((target, ""));
+    let json = query.split('&').any(|p| p == "format=json");
+    let with_body = method == "GET";
+
+    let status = match (method, percent_decode(raw_path)) {
+        ("GET" | "HEAD", Some(path)) if path.starts_with('/') => match volume.resolve_path(&path) {
+            Ok(Resolved::Dir(_)) if !path.ends_with('/') => {
+                let location = if query.is_empty() { format!("{}/", raw_path) } else { format!("{}/?{}", raw_path, query) };
+                http_reply(fd, "301 Moved Permanently", "text/plain; charset=utf-8", &format!("Location: {}\r\n", location), b"", with_body)
+            }
+            Ok(Resolved::Dir(cluster)) => match volume.read_dir(cluster) {
+                Ok(mut entries) => {
+                    entries.retain(|e| !e.is_dot());
+                    fat32::fat32::dir::sort_entries(&mut entries, SortOrder::Name, false);
+                    let (content_type, body) = if json {
+                        ("application/json", http_listing_json(&path, &entries))
+                    } else {
+                        ("text/html; charset=utf-8", http_listing_html(&path, &entries))
+                    };
+                    http_reply(fd, "200 OK", content_type, "", body.as_bytes(), with_body)
+                }
+                Err(e) => http_reply(fd, "500 Internal Server Error", "text/plain; charset=utf-8", "", e.as_bytes(), with_body),
+            },
+            Ok(Resolved::File(entry)) => http_file(volume, fd, &path, &entry, with_body),
+            Ok(Resolved::NotFound { .. }) | Err(_) => http_reply(fd, "404 Not Found", "text/plain; charset=utf-8", "", b"Not found\n", with_body),
+        },
+        ("GET" | "HEAD", _) => http_reply(fd, "400 Bad Request", "text/plain; charset=utf-8", "", b"Bad request\n", with_body),
+        _ => http_reply(fd, "405 Method Not Allowed", "text/plain; charset=utf-8", "Allow: GET, HEAD\r\n", b"Method not allowed\n", true),
+    };
+    sys_print(&format!("{} {} {}", method, target, status));
+}
+
+/// Sends a whole response and returns its status.
+fn http_reply(fd: i32, status: &'static str, content_type: &str, headers: &str, body: &[u8], with_body: bool) -> &'static str {
+    let head = format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\n{}Connection: close\r\n\r\n",
+        status, content_type, body.len(), headers
+    );
+    if sys_send(fd, head.as_bytes()) && with_body { sys_send(fd, body); }
+    status
+}
+
+/// Sends the file of `entry` a cluster at a time, so a large file never has to fit in memory.
+/// The handle follows the cluster chain once, when it is opened, rather than for every read.
+fn http_file(volume: &mut Fat32Volume, fd: i32, path: &str, entry: &DirEntry, with_body: bool) -> &'static str {
+    let extension = path.rsplit_once('.').map_or("", |(_, ext)| ext);
+    let content_type = CONTENT_TYPES.iter().find(|(ext, _)| ext.eq_ignore_ascii_case(extension)).map_or("application/octet-stream", |&(_, t)| t);
+    let mut buffer = vec![0u8; volume.cluster_size()];
+    let mut file = match Fat32OpenOptions::new().read(true).open(volume, path) {
+        Ok(file) => file,
+        Err(e) => return http_reply(fd, "500 Internal Server Error", "text/plain; charset=utf-8", "", e.as_bytes(), with_body),
+    };
+    let head = format!(
+        "HTTP/1.1 200 OK\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        content_type, entry.size
+    );
+    if !sys_send(fd, head.as_bytes()) || !with_body { return "200 OK"; }
+    loop {
+        match file.read(&mut buffer) {
+            Ok(0) => break,
+            Ok(n) => if !sys_send(fd, &buffer[..n]) { break },
+            // The length is already sent: cutting the body short tells the client.
+            Err(e) => { print_error(&format!("{}: {}", path, e)); break; }
+        }
+    }
+    "200 OK"
+}
+
+fn http_listing_html(path: &str, entries: &[DirEntry]) -> String {
+    let title = html_escape(path);
+    let mut html = format!("<!DOCTYPE html>\n<html><head><meta charset=\"utf-8\"><title>Index of {0}</title></head>\n<body><h1>Index of {0}</h1>\n<ul>\n", title);
+    if path != "/" { html += "<li><a href=\"../\">../</a></li>\n"; }
+    for entry in entries {
+        let suffix = if entry.is_dir() { "/" } else { "" };
+        let size = if entry.is_dir() { String::new() } else { format!(" ({} bytes)", entry.size) };
+        html += &format!("<li><a href=\"{}{}\">{}{}</a>{}</li>\n", percent_encode(&entry.name), suffix, html_escape(&entry.name), suffix, size);
+    }
+    html + "</ul></body></html>\n"
+}
+
+fn http_listing_json(path: &str, entries: &[DirEntry]) -> String {
+    let items: Vec<String> = entries.iter().map(|entry| {
+        let m = &entry.modified;
+        format!(
+            "{{\"name\": {}, \"dir\": {}, \"size\": {}, \"mtime\": \"{:04}-{:02}-{:02}T{:02}:{:02}:{:02}\"}}",
+            json_escape(&entry.name), entry.is_dir(), entry.size, m.year, m.month, m.day, m.hour, m.minute, m.second
+        )
+    }).collect();
+    format!("{{\"path\": {}, \"entries\": [{}]}}\n", json_escape(path), items.join(", "))
+}
+
+/// Decodes the `%XX` escapes of a URL path; `None` when they don't make UTF-8.
+fn percent_decode(text: &str) -> Option<String> {
+    let bytes = text.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let hex = core::str::from_utf8(bytes.get(i + 1..i + 3)?).ok()?;
+            out.push(u8::from_str_radix(hex, 16).ok()?);
+            i += 3;
+        } else {
+            out.push(bytes[i]);
+            i += 1;
+        }
+    }
+    String::from_utf8(out).ok()
+}
+
+/// Escapes everything but unreserved characters, for a name in a link.
+fn percent_encode(text: &str) -> String {
+    let mut out = String::new();
+    for b in text.bytes() {
+        if b.is_ascii_alphanumeric() || matches!(b, b'-' | b'.' | b'_' | b'~') {
+            out.push(b as char);
+        } else {
+            out += &format!("%{:02X}", b);
+        }
+    }
+    out
+}
+
+fn html_escape(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
+
+fn json_escape(text: &str) -> String {
+    let mut out = String::from("\"");
+    for c in text.chars() {
+        match c {
+            '"' => out += "\\\"",
+            '\\' => out += "\\\\",
+            c if (c as u32) < 0x20 => out += &format!("\\u{:04x}", c as u32),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
@@ -0,0 +1,105 @@
+//! Serving a volume over the network, until Ctrl-C: HTTP, NBD and 9P.
+
+use core::ffi::c_void;
+use core::sync::atomic::Ordering;
+pub mod http;
+pub mod nbd;
+pub mod ninep;
+
+use crate::sys::{catch_interrupt, INTERRUPTED, release_interrupt};
+
+/// The options of the `serve` commands: `--port` for them all, and for `serve-nbd` which
+/// partition to export, whether to refuse writes and which image to serve.
+pub struct ServeArgs<'w> {
+    pub port: u16,
+    pub partition: Option<usize>,
+    pub read_only: bool,
+    pub name: Option<&'w str>,
+}
+
+impl<'w> ServeArgs<'w> {
+    /// Reads `args`, with `port` unless `--port` says otherwise; `None` when an option
+    /// is unknown or lacks its value. Only `serve-nbd`, with `nbd`, takes more than a port.
+    pub fn parse(args: &[&'w str], port: u16, nbd: bool) -> Option<Self> {
+        let mut serve = ServeArgs { port, partition: None, read_only: false, name: None };
+        let mut args = args.iter();
+        while let Some(&arg) = args.next() {
+            match arg {
+                "--port" => serve.port = args.next()?.parse().ok()?,
+                "--partition" if nbd => serve.partition = Some(args.next()?.parse().ok()?),
+                "--read-only" if nbd => serve.read_only = true,
+                _ if nbd && serve.name.is_none() && !arg.starts_with('-') => serve.name = Some(arg),
+                _ => return None,
+            }
+        }
+        Some(serve)
+    }
+}
+
+/// A TCP socket listening on `port` on every interface, or -1.
+pub fn sys_listen(port: u16) -> i32 {
+    // SAFETY: addr and one are valid for the sizes passed along with them; the socket is
+    // closed again when it can't be bound.
+    unsafe {
+        let fd = libc::socket(libc::AF_INET, libc::SOCK_STREAM | libc::SOCK_CLOEXEC, 0);
+        if fd < 0 { return -1; }
+        let one: libc::c_int = 1;
+        libc::setsockopt(fd, libc::SOL_SOCKET, libc::SO_REUSEADDR, &one as *const _ as *const c_void, size_of::<libc::c_int>() as u32);
+        let mut addr: libc::sockaddr_in = core::mem::zeroed();
+        addr.sin_family = libc::AF_INET as libc::sa_family_t;
+        addr.sin_port = port.to_be();
+        addr.sin_addr.s_addr = libc::INADDR_ANY;
+        let bound = libc::bind(fd, &addr as *const _ as *const libc::sockaddr, size_of::<libc::sockaddr_in>() as u32) == 0;
+        if !bound || libc::listen(fd, 16) != 0 {
+            libc::close(fd);
+            return -1;
+        }
+        fd
+    }
+}
+
+/// Sends all of `data` on the socket `fd`; false once the peer is gone.
+pub fn sys_send(fd: i32, mut data: &[u8]) -> bool {
+    while !data.is_empty() {
+        // SAFETY: data points to data.len() valid bytes. MSG_NOSIGNAL turns a closed peer
+        // into an error instead of a SIGPIPE.
+        let n = unsafe { libc::send(fd, data.as_ptr() as *const c_void, data.len(), libc::MSG_NOSIGNAL) };
+        if n <= 0 { return false; }
+        data = &data[n as usize..];
+    }
+    true
+}
+
+/// Fills `buf` from the socket `fd`; false once the peer is gone, idle for too long, or
+/// the server is interrupted.
+pub fn sys_recv_exact(fd: i32, buf: &mut [u8]) -> bool {
+    let mut filled = 0;
+    while filled < buf.len() {
+        // SAFETY: the unfilled part of buf is valid for buf.len() - filled bytes.
+        let n = unsafe { libc::recv(fd, buf[filled..].as_mut_ptr() as *mut c_void, buf.len() - filled, 0) };
+        if n <= 0 { return false; }
+        filled += n as usize;
+    }
+    true
+}
+
+/// Accepts connections on `listener` one at a time and hands each to `handle`, which
+/// closes it, until Ctrl-C. With `idle_seconds`, a client idle for that long is dropped
+/// so it can't hold the others up.
+pub fn serve_until_interrupted(listener: i32, idle_seconds: Option<i64>, handle: &mut dyn FnMut(i32)) {
+    let previous = catch_interrupt();
+    while !INTERRUPTED.load(Ordering::Relaxed) {
+        // SAFETY: no peer address is asked for.
+        let fd = unsafe { libc::accept4(listener, core::ptr::null_mut(), core::ptr::null_mut(), libc::SOCK_CLOEXEC) };
+        if fd < 0 { continue; }
+        if let Some(seconds) = idle_seconds {
+            let timeout = libc::timeval { tv_sec: seconds, tv_usec: 0 };
+            for option in [libc::SO_RCVTIMEO, libc::SO_SNDTIMEO] {
+                // SAFETY: timeout is a valid timeval.
+                unsafe { libc::setsockopt(fd, libc::SOL_SOCKET, option, &timeout as *const _ as *const c_void, size_of::<libc::timeval>() as u32); }
+            }
+        }
+        handle(fd);
+    }
+    release_interrupt(previous);
+}
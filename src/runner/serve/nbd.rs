@@ -0,0 +1,154 @@
+//! `serve-nbd`: the whole image, or one of its partitions, as an NBD export.
+
+use alloc::format;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::ops::Range;
+use crate::mount::Mount;
+use crate::serve::{serve_until_interrupted, sys_listen, sys_recv_exact, sys_send};
+use crate::sys::{sys_close, sys_print};
+
+/// "IHAVEOPT", which starts the greeting and every option of an NBD handshake.
+const NBD_OPTION_MAGIC: &[u8; 8] = b"IHAVEOPT";
+
+const NBD_REPLY_MAGIC: u64 = 0x0003_E889_0455_65A9;
+
+const NBD_REQUEST_MAGIC: u32 = 0x2560_9513;
+
+const NBD_SIMPLE_REPLY_MAGIC: u32 = 0x6744_6698;
+
+/// Largest read or write a client may ask for, as the Linux client does.
+const NBD_MAX_REQUEST: usize = 32 << 20;
+
+/// Exports `range` of the image of `mount` as a network block device on `port` until
+/// Ctrl-C, logging every request. Writes change the image as any command would, and a
+/// flush from the client saves it, unless it is an overlay.
+pub fn serve_nbd(mount: &mut Mount, port: u16, range: Range<usize>, read_only: bool) -> Result<(), &'static str> {
+    let listener = sys_listen(port);
+    if listener < 0 { return Err("Cannot listen on that port"); }
+    sys_print(&format!(
+        "Serving {} bytes of {}{} over NBD on port {} (Ctrl-C to stop).",
+        range.len(), mount.name, if read_only { ", read-only," } else { "" }, port
+    ));
+    serve_until_interrupted(listener, None, &mut |fd| {
+        if nbd_handshake(fd, range.len() as u64, read_only) {
+            sys_print("NBD client connected.");
+            nbd_transmission(mount, fd, range.clone(), read_only);
+            sys_print("NBD client disconnected.");
+        }
+        sys_close(fd);
+    });
+    sys_close(listener);
+    Ok(())
+}
+
+/// Negotiates the export with a fixed newstyle client, whatever export name it asks for.
+/// True when the client goes on to send requests.
+fn nbd_handshake(fd: i32, size: u64, read_only: bool) -> bool {
+    // NBD_FLAG_FIXED_NEWSTYLE | NBD_FLAG_NO_ZEROES
+    let mut greeting = b"NBDMAGIC".to_vec();
+    greeting.extend_from_slice(NBD_OPTION_MAGIC);
+    greeting.extend_from_slice(&3u16.to_be_bytes());
+    let mut client_flags = [0u8; 4];
+    if !sys_send(fd, &greeting) || !sys_recv_exact(fd, &mut client_flags) { return false; }
+    let no_zeroes = u32::from_be_bytes(client_flags) & 2 != 0;
+    // NBD_FLAG_HAS_FLAGS | NBD_FLAG_SEND_FLUSH, plus NBD_FLAG_READ_ONLY
+    let flags: u16 = 1 | 4 | if read_only { 2 } else { 0 };
+
+    let reply = |option: u32, kind: u32, data: &[u8]| {
+        let mut bytes = NBD_REPLY_MAGIC.to_be_bytes().to_vec();
+        for field in [option, kind, data.len() as u32] { bytes.extend_from_slice(&field.to_be_bytes()); }
+        bytes.extend_from_slice(data);
+        sys_send(fd, &bytes)
+    };
+    // NBD_REP_INFO with NBD_INFO_EXPORT, then NBD_REP_ACK.
+    let info = |option: u32| {
+        let mut info = 0u16.to_be_bytes().to_vec();
+        info.extend_from_slice(&size.to_be_bytes());
+        info.extend_from_slice(&flags.to_be_bytes());
+        reply(option, 3, &info) && reply(option, 1, &[])
+    };
+    loop {
+        let mut header = [0u8; 16];
+        if !sys_recv_exact(fd, &mut header) || &header[..8] != NBD_OPTION_MAGIC { return false; }
+        let option = u32::from_be_bytes([header[8], header[9], header[10], header[11]]);
+        let len = u32::from_be_bytes([header[12], header[13], header[14], header[15]]) as usize;
+        let mut data = vec![0u8; len.min(4096)];
+        if len > data.len() || !sys_recv_exact(fd, &mut data) { return false; }
+        let sent = match option {
+            // NBD_OPT_EXPORT_NAME: no reply header, and no way to refuse.
+            1 => {
+                let mut bytes = size.to_be_bytes().to_vec();
+                bytes.extend_from_slice(&flags.to_be_bytes());
+                if !no_zeroes { bytes.extend_from_slice(&[0u8; 124]); }
+                return sys_send(fd, &bytes);
+            }
+            // NBD_OPT_ABORT
+            2 => { reply(option, 1, &[]); return false; }
+            // NBD_OPT_LIST: a single export, with an empty name.
+            3 => reply(option, 2, &0u32.to_be_bytes()) && reply(option, 1, &[]),
+            // NBD_OPT_INFO, and NBD_OPT_GO which then starts transmission.
+            6 => info(option),
+            7 => return info(option),
+            // NBD_REP_ERR_UNSUP
+            _ => reply(option, 0x8000_0001, &[]),
+        };
+        if !sent { return false; }
+    }
+}
+
+/// Answers the requests of a client until it disconnects.
+fn nbd_transmission(mount: &mut Mount, fd: i32, range: Range<usize>, read_only: bool) {
+    let reply = |error: u32, cookie: &[u8], data: &[u8]| {
+        let mut bytes = NBD_SIMPLE_REPLY_MAGIC.to_be_bytes().to_vec();
+        bytes.extend_from_slice(&error.to_be_bytes());
+        bytes.extend_from_slice(cookie);
+        bytes.extend_from_slice(data);
+        sys_send(fd, &bytes)
+    };
+    let mut payload = Vec::new();
+    loop {
+        let mut request = [0u8; 28];
+        if !sys_recv_exact(fd, &mut request) || u32::from_be_bytes([request[0], request[1], request[2], request[3]]) != NBD_REQUEST_MAGIC { return; }
+        let kind = u16::from_be_bytes([request[6], request[7]]);
+        let cookie = &request[8..16];
+        let offset = u64::from_be_bytes(core::array::from_fn(|i| request[16 + i])) as usize;
+        let len = u32::from_be_bytes([request[24], request[25], request[26], request[27]]) as usize;
+        let within = offset.checked_add(len).filter(|&end| end <= range.len()).map(|end| range.start + offset..range.start + end);
+        let sent = match kind {
+            // NBD_CMD_READ
+            0 => {
+                sys_print(&format!("read  {} bytes at {:#x}", len, offset));
+                match within {
+                    Some(bytes) if len <= NBD_MAX_REQUEST => reply(0, cookie, &mount.data[bytes]),
+                    _ => reply(libc::EINVAL as u32, cookie, &[]),
+                }
+            }
+            // NBD_CMD_WRITE: the data follows the request and has to be read even when refused.
+            1 => {
+                if len > NBD_MAX_REQUEST { return; }
+                payload.resize(len, 0);
+                if !sys_recv_exact(fd, &mut payload) { return; }
+                sys_print(&format!("write {} bytes at {:#x}", len, offset));
+                match within {
+                    _ if read_only => reply(libc::EPERM as u32, cookie, &[]),
+                    Some(bytes) => {
+                        mount.data[bytes].copy_from_slice(&payload);
+                        reply(0, cookie, &[])
+                    }
+                    None => reply(libc::ENOSPC as u32, cookie, &[]),
+                }
+            }
+            // NBD_CMD_DISC
+            2 => return,
+            // NBD_CMD_FLUSH
+            3 => {
+                let saved = if mount.overlay { 0 } else { mount.save() };
+                sys_print(&format!("flush, {} blocks saved", saved));
+                reply(0, cookie, &[])
+            }
+            _ => reply(libc::EINVAL as u32, cookie, &[]),
+        };
+        if !sent { return; }
+    }
+}
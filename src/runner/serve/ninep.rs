@@ -0,0 +1,286 @@
+//! `serve-9p`: the files of a volume over 9P2000.L.
+
+use alloc::format;
+use alloc::vec;
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::vec::Vec;
+use fat32::fat32::dir::DirEntry;
+use fat32::fat32::time::DateTime;
+use fat32::fat32::volume::Fat32Volume;
+use crate::serve::{serve_until_interrupted, sys_listen, sys_recv_exact, sys_send};
+use crate::sys::{sys_close, sys_print};
+use crate::tz::fat_to_unix;
+
+/// Largest 9P message, either way; clients ask for less or are told to.
+const NINEP_MAX_MESSAGE: u32 = 128 * 1024;
+
+/// `Rgetattr` mask of the fields filled in: everything up to the block count (P9_GETATTR_BASIC).
+const NINEP_GETATTR_BASIC: u64 = 0x7FF;
+
+/// A fid of a 9P session: a file or directory of the image, by path.
+struct NinepFid {
+    /// Absolute path in the image, `/` for the root.
+    path: String,
+    /// Entry of the file or directory, `None` for the root.
+    entry: Option<DirEntry>,
+    /// Entries of an opened directory, read once by `Tlopen` so that `Treaddir` offsets
+    /// keep pointing at the same entries.
+    listing: Vec<DirEntry>,
+    /// Clusters of an opened file, followed once by `Tlopen` so that each `Tread` goes
+    /// straight to the data.
+    chain: Vec<u32>,
+}
+
+impl NinepFid {
+    fn is_dir(&self) -> bool {
+        self.entry.as_ref().is_none_or(|e| e.is_dir())
+    }
+
+    /// Type, version and path of the file. Entries are told apart by where they sit in
+    /// the image, which doesn't change while it is served.
+    fn qid(&self) -> [u8; 13] {
+        let mut qid = [0u8; 13];
+        qid[0] = if self.is_dir() { 0x80 } else { 0 };
+        qid[5..].copy_from_slice(&self.entry.as_ref().map_or(0, |e| e.offset as u64 + 1).to_le_bytes());
+        qid
+    }
+}
+
+/// Reads the fields of a 9P message, little-endian; `None` past its end.
+struct NinepFields<'m> {
+    bytes: &'m [u8],
+    at: usize,
+}
+
+impl NinepFields<'_> {
+    fn take(&mut self, len: usize) -> Option<&[u8]> {
+        let field = self.bytes.get(self.at..self.at + len)?;
+        self.at += len;
+        Some(field)
+    }
+
+    fn u16(&mut self) -> Option<u16> { self.take(2).map(|b| u16::from_le_bytes([b[0], b[1]])) }
+    fn u32(&mut self) -> Option<u32> { self.take(4).map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]])) }
+    fn u64(&mut self) -> Option<u64> { self.take(8).map(|b| u64::from_le_bytes(core::array::from_fn(|i| b[i]))) }
+
+    fn string(&mut self) -> Option<String> {
+        let len = self.u16()? as usize;
+        core::str::from_utf8(self.take(len)?).ok().map(String::from)
+    }
+}
+
+/// Appends a 9P string: its length on 2 bytes, then its bytes.
+fn ninep_string(out: &mut Vec<u8>, text: &str) {
+    out.extend_from_slice(&(text.len() as u16).to_le_bytes());
+    out.extend_from_slice(text.as_bytes());
+}
+
+/// Serves the files of `volume` read-only over 9P2000.L on `port` until Ctrl-C.
+pub fn serve_9p(volume: &mut Fat32Volume, port: u16) -> Result<(), &'static str> {
+    let listener = sys_listen(port);
+    if listener < 0 { return Err("Cannot listen on that port"); }
+    sys_print(&format!("Serving the image read-only over 9P2000.L on port {} (Ctrl-C to stop).", port));
+    sys_print(&format!("In the guest: mount -t 9p -o trans=tcp,port={},version=9p2000.L <host> <dir>", port));
+    serve_until_interrupted(listener, None, &mut |fd| {
+        sys_print("9P client connected.");
+        ninep_session(volume, fd);
+        sys_print("9P client disconnected.");
+        sys_close(fd);
+    });
+    sys_close(listener);
+    Ok(())
+}
+
+/// Answers the messages of a client, one at a time, until it disconnects.
+fn ninep_session(volume: &mut Fat32Volume, fd: i32) {
+    let mut fids: BTreeMap<u32, NinepFid> = BTreeMap::new();
+    let mut msize = NINEP_MAX_MESSAGE;
+    loop {
+        let mut size = [0u8; 4];
+        if !sys_recv_exact(fd, &mut size) { return; }
+        let size = u32::from_le_bytes(size);
+        if !(7..=NINEP_MAX_MESSAGE).contains(&size) { return; }
+        let mut message = vec![0u8; size as usize - 4];
+        if !sys_recv_exact(fd, &mut message) { return; }
+        let (kind, tag) = (message[0], [message[1], message[2]]);
+        let mut fields = NinepFields { bytes: &message, at: 3 };
+
+        let (reply_kind, body) = match ninep_answer(volume, &mut fids, &mut msize, kind, &mut fields) {
+            Ok(body) => (kind + 1, body),
+            // Rlerror
+            Err(errno) => (7, (errno as u32).to_le_bytes().to_vec()),
+        };
+        let mut reply = ((body.len() + 7) as u32).to_le_bytes().to_vec();
+        reply.push(reply_kind);
+        reply.extend_from_slice(&tag);
+        reply.extend_from_slice(&body);
+        if !sys_send(fd, &reply) { return; }
+    }
+}
+
+/// The body of the answer to a message of type `kind`, or the errno to send back instead.
+/// Everything that would change the image is refused with EROFS.
+fn ninep_answer(volume: &mut Fat32Volume, fids: &mut BTreeMap<u32, NinepFid>, msize: &mut u32, kind: u8, fields: &mut NinepFields) -> Result<Vec<u8>, i32> {
+    let mut out = Vec::new();
+    match kind {
+        // Tversion: a new session, which drops every fid.
+        100 => {
+            let requested = fields.u32().ok_or(libc::EPROTO)?;
+            let version = fields.string().ok_or(libc::EPROTO)?;
+            *msize = requested.min(NINEP_MAX_MESSAGE);
+            fids.clear();
+            out.extend_from_slice(&msize.to_le_bytes());
+            ninep_string(&mut out, if version.starts_with("9P2000.L") { "9P2000.L" } else { "unknown" });
+        }
+        // Tauth: no authentication needed.
+        102 => return Err(libc::EOPNOTSUPP),
+        // Tattach
+        104 => {
+            let fid = fields.u32().ok_or(libc::EPROTO)?;
+            let root = NinepFid { path: "/".into(), entry: None, listing: Vec::new(), chain: Vec::new() };
+            out.extend_from_slice(&root.qid());
+            fids.insert(fid, root);
+        }
+        // Tflush: requests are answered in order, so there is never one left to cancel.
+        108 => {}
+        // Twalk
+        110 => {
+            let (fid, newfid, count) = (fields.u32().ok_or(libc::EPROTO)?, fields.u32().ok_or(libc::EPROTO)?, fields.u16().ok_or(libc::EPROTO)?);
+            let start = fids.get(&fid).ok_or(libc::EBADF)?;
+            let mut path = start.path.clone();
+            let mut entry = start.entry.clone();
+            let mut qids = Vec::new();
+            for i in 0..count {
+                let name = fields.string().ok_or(libc::EPROTO)?;
+                let next = match name.as_str() {
+                    "." => path.clone(),
+                    ".." => path.rsplit_once('/').map_or("/", |(parent, _)| if parent.is_empty() { "/" } else { parent }).into(),
+                    _ if name.contains('/') || name.is_empty() => return Err(libc::EINVAL),
+                    _ if path == "/" => format!("/{}", name),
+                    _ => format!("{}/{}", path, name),
+                };
+                let is_dir = entry.as_ref().is_none_or(|e| e.is_dir());
+                match ninep_lookup(volume, &next) {
+                    Ok(found) if is_dir => { path = next; entry = found; }
+                    // Only the first name failing is an error; otherwise the walk stops short.
+                    _ if i == 0 => return Err(if is_dir { libc::ENOENT } else { libc::ENOTDIR }),
+                    _ => break,
+                }
+                qids.push(NinepFid { path: path.clone(), entry: entry.clone(), listing: Vec::new(), chain: Vec::new() }.qid());
+            }
+            if qids.len() == count as usize { fids.insert(newfid, NinepFid { path, entry, listing: Vec::new(), chain: Vec::new() }); }
+            out.extend_from_slice(&(qids.len() as u16).to_le_bytes());
+            for qid in qids { out.extend_from_slice(&qid); }
+        }
+        // Tlopen: only for reading.
+        12 => {
+            let (fid, flags) = (fields.u32().ok_or(libc::EPROTO)?, fields.u32().ok_or(libc::EPROTO)?);
+            let write = flags as i32 & libc::O_ACCMODE != libc::O_RDONLY || flags as i32 & libc::O_TRUNC != 0;
+            if write { return Err(libc::EROFS); }
+            let open = fids.get_mut(&fid).ok_or(libc::EBADF)?;
+            if open.is_dir() {
+                let cluster = volume.directory_cluster(&open.path).map_err(|_| libc::ENOENT)?;
+                open.listing = volume.read_dir(cluster).map_err(|_| libc::EIO)?;
+                open.listing.retain(|e| !e.is_dot());
+            } else {
+                let entry = open.entry.as_ref().ok_or(libc::EBADF)?;
+                open.chain = volume.file_chain(entry).map_err(|_| libc::EIO)?;
+                sys_print(&format!("open {}", open.path));
+            }
+            out.extend_from_slice(&open.qid());
+            out.extend_from_slice(&0u32.to_le_bytes());
+        }
+        // Tgetattr
+        24 => {
+            let fid = fields.u32().ok_or(libc::EPROTO)?;
+            let file = fids.get(&fid).ok_or(libc::EBADF)?;
+            let unix = |time: &DateTime| if time.is_set() { fat_to_unix(time).max(0) } else { 0 };
+            let (mode, size, modified, accessed, created) = match &file.entry {
+                Some(e) => {
+                    let mode = if e.is_dir() { 0o040555 } else { 0o100444 };
+                    (mode, e.size as u64, unix(&e.modified), unix(&e.accessed), unix(&e.created))
+                }
+                None => (0o040555, 0, 0, 0, 0),
+            };
+            let block = volume.cluster_size() as u64;
+            out.extend_from_slice(&NINEP_GETATTR_BASIC.to_le_bytes());
+            out.extend_from_slice(&file.qid());
+            for field in [mode, 0, 0] { out.extend_from_slice(&(field as u32).to_le_bytes()); }
+            let (nlink, rdev, blocks) = (1u64, 0u64, size.div_ceil(block) * block / 512);
+            for field in [nlink, rdev, size, block, blocks] { out.extend_from_slice(&field.to_le_bytes()); }
+            for seconds in [accessed, modified, modified, created] {
+                out.extend_from_slice(&(seconds as u64).to_le_bytes());
+                out.extend_from_slice(&0u64.to_le_bytes());
+            }
+            // gen and data_version
+            out.extend_from_slice(&[0u8; 16]);
+        }
+        // Treaddir: offsets are indexes in the listing read by Tlopen.
+        40 => {
+            let (fid, offset, count) = (fields.u32().ok_or(libc::EPROTO)?, fields.u64().ok_or(libc::EPROTO)?, fields.u32().ok_or(libc::EPROTO)?);
+            let dir = fids.get(&fid).ok_or(libc::EBADF)?;
+            if !dir.is_dir() { return Err(libc::ENOTDIR); }
+            let limit = count.min(*msize - 11) as usize;
+            let mut entries = Vec::new();
+            for (i, entry) in dir.listing.iter().enumerate().skip(offset as usize) {
+                let path = format!("{}/{}", dir.path.trim_end_matches('/'), entry.name);
+                let item = NinepFid { path, entry: Some(entry.clone()), listing: Vec::new(), chain: Vec::new() };
+                let mut dirent = item.qid().to_vec();
+                dirent.extend_from_slice(&(i as u64 + 1).to_le_bytes());
+                dirent.push(if entry.is_dir() { libc::DT_DIR } else { libc::DT_REG });
+                ninep_string(&mut dirent, &entry.name);
+                if entries.len() + dirent.len() > limit { break; }
+                entries.extend_from_slice(&dirent);
+            }
+            out.extend_from_slice(&(entries.len() as u32).to_le_bytes());
+            out.extend_from_slice(&entries);
+        }
+        // Tread
+        116 => {
+            let (fid, offset, count) = (fields.u32().ok_or(libc::EPROTO)?, fields.u64().ok_or(libc::EPROTO)?, fields.u32().ok_or(libc::EPROTO)?);
+            let file = fids.get(&fid).ok_or(libc::EBADF)?;
+            if file.is_dir() { return Err(libc::EISDIR); }
+            let mut data = vec![0u8; count.min(*msize - 11) as usize];
+            let entry = file.entry.as_ref().ok_or(libc::EBADF)?;
+            let n = volume.read_chain_at(entry, &file.chain, offset, &mut data).map_err(|_| libc::EIO)?;
+            out.extend_from_slice(&(n as u32).to_le_bytes());
+            out.extend_from_slice(&data[..n]);
+        }
+        // Tclunk
+        120 => {
+            fids.remove(&fields.u32().ok_or(libc::EPROTO)?).ok_or(libc::EBADF)?;
+        }
+        // Tstatfs
+        8 => {
+            let space = volume.space();
+            let block = volume.cluster_size() as u64;
+            // MSDOS_SUPER_MAGIC
+            for field in [0x4D44u32, block as u32] { out.extend_from_slice(&field.to_le_bytes()); }
+            for field in [space.total / block, space.free / block, space.free / block, 0, 0, 0] { out.extend_from_slice(&field.to_le_bytes()); }
+            out.extend_from_slice(&255u32.to_le_bytes());
+        }
+        // Tfsync: nothing is ever written.
+        50 => {}
+        // Treadlink: FAT has no symbolic links.
+        22 => return Err(libc::EINVAL),
+        // Txattrwalk
+        30 => return Err(libc::EOPNOTSUPP),
+        // Tlcreate, Tsymlink, Tmknod, Trename, Tsetattr, Txattrcreate, Tlink, Tmkdir,
+        // Trenameat, Tunlinkat, Twrite, Tremove
+        14 | 16 | 18 | 20 | 26 | 32 | 70 | 72 | 74 | 76 | 118 | 122 => return Err(libc::EROFS),
+        _ => return Err(libc::EOPNOTSUPP),
+    }
+    Ok(out)
+}
+
+/// The entry at the absolute `path`, `None` for the root.
+fn ninep_lookup(volume: &Fat32Volume, path: &str) -> Result<Option<DirEntry>, i32> {
+    let Some((parent, name)) = path.rsplit_once('/').filter(|(_, name)| !name.is_empty()) else { return Ok(None) };
+    let parent = volume.directory_cluster(if parent.is_empty() { "/" } else { parent }).map_err(|_| libc::ENOENT)?;
+    match volume.find_entry(parent, name) {
+        Ok(Some(entry)) => Ok(Some(entry)),
+        Ok(None) => Err(libc::ENOENT),
+        Err(_) => Err(libc::EIO),
+    }
+}
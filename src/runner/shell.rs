@@ -0,0 +1,110 @@
+//! Reading command lines: splitting them into commands and words, expanding wildcards,
+//! and reporting a command that failed.
+
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, Ordering};
+use fat32::fat32::volume::Fat32Volume;
+use crate::sys::sys_print;
+
+/// Splits a command line into words at whitespace. Double quotes keep spaces in a
+/// word (`cat "My Document.txt"`) and a backslash takes the next character as is,
+/// inside quotes or out (`cat My\ Document.txt`).
+pub fn tokenize(line: &str) -> Result<Vec<String>, &'static str> {
+    let mut words = Vec::new();
+    let mut word: Option<String> = None;
+    let mut quoted = false;
+    let mut chars = line.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => word.get_or_insert_with(String::new).push(chars.next().ok_or("Trailing backslash")?),
+            '"' => {
+                quoted = !quoted;
+                word.get_or_insert_with(String::new);
+            }
+            c if c.is_whitespace() && !quoted => words.extend(word.take()),
+            c => word.get_or_insert_with(String::new).push(c),
+        }
+    }
+    if quoted { return Err("Unterminated quote"); }
+    words.extend(word);
+    Ok(words)
+}
+
+/// Splits a line into the commands separated by `;` and `&&` outside quotes and
+/// escapes, each with whether it only runs when the one before succeeded.
+pub fn split_chain(line: &str) -> Result<Vec<(&str, bool)>, &'static str> {
+    let bytes = line.as_bytes();
+    let mut commands = Vec::new();
+    let (mut start, mut after_success, mut quoted) = (0, false, false);
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'\\' => i += 1,
+            b'"' => quoted = !quoted,
+            b';' if !quoted => {
+                commands.push((line[start..i].trim(), after_success));
+                (start, after_success) = (i + 1, false);
+            }
+            b'&' if !quoted && bytes.get(i + 1) == Some(&b'&') => {
+                commands.push((line[start..i].trim(), after_success));
+                (start, after_success) = (i + 2, true);
+                i += 1;
+            }
+            _ => {}
+        }
+        i += 1;
+    }
+    commands.push((line[start..].trim(), after_success));
+    // `;` may be doubled or end the line, `&&` needs a command on both sides.
+    let dangling = commands.iter().enumerate().any(|(i, (command, after))| {
+        command.is_empty() && (*after || commands.get(i + 1).is_some_and(|next| next.1))
+    });
+    if dangling { return Err("Missing command around &&"); }
+    Ok(commands.into_iter().filter(|(command, _)| !command.is_empty()).collect())
+}
+
+/// Set by `print_error`, so that `&&` knows whether the command before failed.
+pub static FAILED: AtomicBool = AtomicBool::new(false);
+
+/// Prints why a command failed.
+pub fn print_error(message: &str) {
+    FAILED.store(true, Ordering::Relaxed);
+    sys_print(message);
+}
+
+/// Expands the wildcards of image path arguments, keeping their order.
+pub fn expand_globs(volume: &Fat32Volume, patterns: &[&str]) -> Result<Vec<String>, String> {
+    let mut paths = Vec::new();
+    for pattern in patterns {
+        let matches = volume.glob(pattern).map_err(String::from)?;
+        if matches.is_empty() { return Err(format!("No match: {}", pattern)); }
+        paths.extend(matches);
+    }
+    Ok(paths)
+}
+
+/// Parses a byte given in decimal or as `0x..`.
+pub fn parse_byte(s: &str) -> Option<u8> {
+    match s.strip_prefix("0x") {
+        Some(hex) => u8::from_str_radix(hex, 16).ok(),
+        None => s.parse().ok(),
+    }
+}
+
+/// Parses sizes like `64M`, `512K`, `2G` or a plain number of bytes.
+pub fn parse_size(s: &str) -> Option<usize> {
+    let (number, unit) = match s.char_indices().find(|(_, c)| !c.is_ascii_digit()) {
+        Some((i, _)) => s.split_at(i),
+        None => (s, ""),
+    };
+    let multiplier = match unit.to_ascii_uppercase().as_str() {
+        "" => 1,
+        "K" => 1 << 10,
+        "M" => 1 << 20,
+        "G" => 1 << 30,
+        _ => return None,
+    };
+    number.parse::<usize>().ok()?.checked_mul(multiplier)
+}
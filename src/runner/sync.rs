@@ -0,0 +1,255 @@
+//! `sync`: makes a directory of the image match one of the host, or the other way round.
+
+use alloc::format;
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::ffi::c_void;
+use core::sync::atomic::Ordering;
+use fat32::fat32::time::DateTime;
+use fat32::fat32::volume::Fat32Volume;
+use crate::host::{get_file, open_or_create_dir, put_file, read_host_file};
+use crate::mount::Mount;
+use crate::shell::print_error;
+use crate::sys::{catch_interrupt, INTERRUPTED, release_interrupt, sys_close, sys_file_size, sys_is_dir, sys_list_dir, sys_mkdir, sys_modified, sys_print, sys_rmdir, sys_unlink};
+use crate::tz::{fat_to_unix, utc_offset};
+
+/// What `sync` compares and changes.
+pub struct SyncOptions {
+    /// Copy from the image to the host instead of from the host to the image.
+    pub pull: bool,
+    /// Compare the contents of files of the same size instead of their modification times.
+    pub checksum: bool,
+    /// Delete what is only on the receiving side.
+    pub delete: bool,
+    /// Only tell what would be done.
+    pub dry_run: bool,
+    /// Push again on every change below the host directory, until Ctrl-C.
+    pub watch: bool,
+}
+
+impl SyncOptions {
+    pub const USAGE: &str = "Usage: sync [--pull|--watch] [--checksum] [--delete] [--dry-run] <host-dir> <image-dir>";
+
+    /// Reads the flags and the host and image directories of a `sync` command line.
+    pub fn parse<'w>(args: &[&'w str]) -> Option<(Self, &'w str, &'w str)> {
+        let (flags, paths): (Vec<&str>, Vec<&str>) = args.iter().partition(|a| a.starts_with("--"));
+        let options = SyncOptions {
+            pull: flags.contains(&"--pull"),
+            checksum: flags.contains(&"--checksum"),
+            delete: flags.contains(&"--delete"),
+            dry_run: flags.contains(&"--dry-run"),
+            watch: flags.contains(&"--watch"),
+        };
+        let known = flags.iter().all(|f| matches!(*f, "--pull" | "--checksum" | "--delete" | "--dry-run" | "--watch"));
+        match paths.as_slice() {
+            [host_dir, image_dir] if known && !(options.pull && options.watch) => Some((options, host_dir, image_dir)),
+            _ => None,
+        }
+    }
+}
+
+/// A file or directory of one side of a `sync`.
+struct SyncItem {
+    /// Relative to the synced directory, as spelled on that side.
+    path: String,
+    is_dir: bool,
+    size: u64,
+    /// Seconds since the Unix epoch, 0 when unknown.
+    modified: i64,
+}
+
+/// The items below a synced directory, by lowercased path, as FAT matches names without
+/// case. Parents sort before their children.
+type SyncTree = BTreeMap<String, SyncItem>;
+
+#[derive(Default)]
+pub struct SyncReport {
+    added: usize,
+    updated: usize,
+    deleted: usize,
+    unchanged: usize,
+    bytes: u64,
+}
+
+impl SyncReport {
+    pub fn summary(&self, dry_run: bool) -> String {
+        format!(
+            "{}{} added, {} updated, {} deleted, {} unchanged, {} bytes copied.",
+            if dry_run { "Dry run: " } else { "" }, self.added, self.updated, self.deleted, self.unchanged, self.bytes
+        )
+    }
+}
+
+/// The date and time of the Unix time `secs` in the time zone in use.
+fn unix_to_fat(secs: i64) -> DateTime {
+    DateTime::from_unix(secs + utc_offset(secs))
+}
+
+fn host_sync_tree(dir: &str, prefix: &str, tree: &mut SyncTree) -> Result<(), &'static str> {
+    for name in sys_list_dir(dir).ok_or("Cannot open host directory")? {
+        let host_path = format!("{}/{}", dir, name);
+        let path = if prefix.is_empty() { name } else { format!("{}/{}", prefix, name) };
+        let is_dir = sys_is_dir(&host_path);
+        let size = if is_dir { 0 } else { sys_file_size(&host_path) };
+        let modified = sys_modified(&host_path).unwrap_or(0);
+        if is_dir { host_sync_tree(&host_path, &path, tree)?; }
+        tree.insert(path.to_lowercase(), SyncItem { path, is_dir, size, modified });
+    }
+    Ok(())
+}
+
+fn image_sync_tree(volume: &Fat32Volume, dir: &str) -> Result<SyncTree, &'static str> {
+    let mut tree = SyncTree::new();
+    let prefix = dir.trim_end_matches('/');
+    for item in volume.walk(dir)? {
+        let (_, full, metadata) = item?;
+        let path = String::from(full[prefix.len()..].trim_start_matches('/'));
+        let modified = if metadata.modified.is_set() { fat_to_unix(&metadata.modified) } else { 0 };
+        let item = SyncItem { path, is_dir: metadata.is_dir(), size: metadata.size as u64, modified };
+        tree.insert(item.path.to_lowercase(), item);
+    }
+    Ok(tree)
+}
+
+/// Makes `image_dir` match `host_dir`, or the other way round with `options.pull`: files
+/// missing on the receiving side, or whose size or modification time differ, are copied
+/// with their modification time. FAT keeps times to 2 seconds, so closer ones are equal.
+/// Prints a line per change; a change that fails is reported and the others go on.
+pub fn sync_trees(volume: &mut Fat32Volume, host_dir: &str, image_dir: &str, options: &SyncOptions) -> Result<SyncReport, &'static str> {
+    if options.pull {
+        volume.directory_cluster(image_dir)?;
+    } else if !sys_is_dir(host_dir) {
+        return Err("Cannot open host directory");
+    }
+    let mut host = SyncTree::new();
+    if sys_is_dir(host_dir) { host_sync_tree(host_dir, "", &mut host)?; }
+    let image = match volume.directory_cluster(image_dir) {
+        Ok(_) => image_sync_tree(volume, image_dir)?,
+        Err(_) => SyncTree::new(),
+    };
+    if !options.dry_run {
+        if options.pull && !sys_mkdir(host_dir) { return Err("Cannot create host directory"); }
+        if !options.pull { open_or_create_dir(volume, image_dir)?; }
+    }
+
+    let host_path = |path: &str| format!("{}/{}", host_dir.trim_end_matches('/'), path);
+    let image_path = |path: &str| format!("{}/{}", image_dir.trim_end_matches('/'), path);
+    let (source, dest) = if options.pull { (&image, &host) } else { (&host, &image) };
+    let mut report = SyncReport::default();
+    for (key, item) in source {
+        let existing = dest.get(key);
+        // An existing file keeps its name as spelled on the receiving side.
+        let target = existing.map_or(&item.path, |d| &d.path);
+        let (host_name, image_name) = if options.pull { (target, &item.path) } else { (&item.path, target) };
+        if existing.is_some_and(|d| d.is_dir != item.is_dir) {
+            print_error(&format!("{}: a file on one side, a directory on the other", item.path));
+            continue;
+        }
+        let changed = match existing {
+            None => true,
+            Some(_) if item.is_dir => false,
+            Some(d) if d.size != item.size => true,
+            Some(_) if options.checksum => read_host_file(&host_path(host_name)).ok() != volume.read_file(&image_path(image_name)).ok(),
+            Some(d) => (d.modified - item.modified).abs() > 1,
+        };
+        if !changed {
+            report.unchanged += !item.is_dir as usize;
+            continue;
+        }
+        sys_print(&format!("{} {}{}", if existing.is_some() { '~' } else { '+' }, item.path, if item.is_dir { "/" } else { "" }));
+        if existing.is_some() { report.updated += 1 } else { report.added += 1 }
+        report.bytes += item.size;
+        if options.dry_run { continue; }
+        let result = match (item.is_dir, options.pull) {
+            (true, true) => if sys_mkdir(&host_path(host_name)) { Ok(()) } else { Err("Cannot create host directory") },
+            (true, false) => open_or_create_dir(volume, &image_path(image_name)).map(|_| ()),
+            (false, true) => get_file(volume, &image_path(image_name), &host_path(host_name)),
+            (false, false) => {
+                let (parent, name) = image_name.rsplit_once('/').unwrap_or(("", image_name));
+                volume.directory_cluster(&image_path(parent))
+                    .and_then(|cluster| put_file(volume, &host_path(host_name), cluster, name))
+                    .and_then(|_| volume.set_modified(&image_path(image_name), unix_to_fat(item.modified)))
+            }
+        };
+        if let Err(e) = result { print_error(&format!("{}: {}", item.path, e)); }
+    }
+
+    if options.delete {
+        // Children sort after their parent, so backwards they go first.
+        for (_, item) in dest.iter().rev().filter(|(key, _)| !source.contains_key(*key)) {
+            sys_print(&format!("- {}{}", item.path, if item.is_dir { "/" } else { "" }));
+            report.deleted += 1;
+            if options.dry_run { continue; }
+            let result = match (item.is_dir, options.pull) {
+                (true, true) => if sys_rmdir(&host_path(&item.path)) { Ok(()) } else { Err("Cannot remove host directory") },
+                (false, true) => { sys_unlink(&host_path(&item.path)); Ok(()) }
+                (_, false) => volume.remove_tree(&image_path(&item.path), false).map(|_| ()),
+            };
+            if let Err(e) = result { print_error(&format!("{}: {}", item.path, e)); }
+        }
+    }
+    Ok(report)
+}
+
+/// Host changes that start a new push of `sync --watch`.
+const WATCH_EVENTS: u32 = libc::IN_CLOSE_WRITE | libc::IN_CREATE | libc::IN_DELETE | libc::IN_MOVED_FROM | libc::IN_MOVED_TO | libc::IN_ATTRIB;
+
+/// Pushes `host_dir` into `image_dir` as `sync` does, then again on every change below
+/// `host_dir`, until Ctrl-C. Changes are gathered until the host has been quiet for a
+/// moment, and the image is saved after each batch, so it is always ready to be used.
+pub fn sync_watch(mount: &mut Mount, host_dir: &str, image_dir: &str, options: &SyncOptions) -> Result<(), &'static str> {
+    // SAFETY: inotify_init1 takes no pointer.
+    let fd = unsafe { libc::inotify_init1(libc::IN_CLOEXEC) };
+    if fd < 0 { return Err("Cannot watch host directory"); }
+    let previous = catch_interrupt();
+    let mut first = true;
+    let result = loop {
+        // Watches go in before the push, so that nothing changed during it is missed.
+        watch_host_tree(fd, host_dir);
+        match mount.volume().and_then(|mut volume| sync_trees(&mut volume, host_dir, image_dir, options)) {
+            Ok(r) if first || r.added + r.updated + r.deleted > 0 => {
+                let saved = if mount.overlay { 0 } else { mount.save() };
+                sys_print(&format!("{} {} blocks saved.", r.summary(options.dry_run), saved));
+            }
+            Ok(_) => {}
+            Err(e) => break Err(e),
+        }
+        if first { sys_print(&format!("Watching {} (Ctrl-C to stop).", host_dir)); }
+        first = false;
+        if !wait_for_changes(fd) { break Ok(()); }
+    };
+    release_interrupt(previous);
+    sys_close(fd);
+    result
+}
+
+/// Adds an inotify watch on `dir` and every directory below it. Those already watched
+/// keep their watch, and the kernel drops the watches of removed ones.
+fn watch_host_tree(fd: i32, dir: &str) {
+    let dir_c = format!("{}\0", dir);
+    // SAFETY: dir_c is a null-terminated string created just above.
+    unsafe { libc::inotify_add_watch(fd, dir_c.as_ptr() as *const i8, WATCH_EVENTS); }
+    for name in sys_list_dir(dir).unwrap_or_default() {
+        let path = format!("{}/{}", dir, name);
+        if sys_is_dir(&path) { watch_host_tree(fd, &path); }
+    }
+}
+
+/// Waits for events on the inotify descriptor `fd`, then until none came for 300 ms.
+/// False on Ctrl-C.
+fn wait_for_changes(fd: i32) -> bool {
+    let mut events = [0u8; 4096];
+    let mut timeout = -1;
+    loop {
+        let mut poll = libc::pollfd { fd, events: libc::POLLIN, revents: 0 };
+        // SAFETY: poll is a single valid pollfd.
+        let ready = unsafe { libc::poll(&mut poll, 1, timeout) };
+        if ready < 0 || INTERRUPTED.load(Ordering::Relaxed) { return false; }
+        if ready == 0 { return true; }
+        // What changed doesn't matter: the next push compares the whole tree.
+        // SAFETY: events is a valid buffer of events.len() bytes.
+        unsafe { libc::read(fd, events.as_mut_ptr() as *mut c_void, events.len()); }
+        timeout = 300;
+    }
+}
@@ -0,0 +1,317 @@
+//! Thin wrappers around the libc calls the shell makes: the terminal, host files and
+//! directories, the image file itself and Ctrl-C.
+
+use alloc::format;
+use alloc::vec;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::ffi::{c_void, CStr};
+use core::sync::atomic::{AtomicBool, Ordering};
+use fat32::fat32::time::DateTime;
+use crate::tz::fat_to_unix;
+
+pub fn sys_print(s: &str) {
+    unsafe {
+        // SAFETY: s.as_ptr() points to valid memory of s.len(). 
+        // Writing to stdout (1) is a standard operation.
+        libc::write(1, s.as_ptr() as *const c_void, s.len());
+        libc::write(1, "\n".as_ptr() as *const c_void, 1);
+    }
+}
+
+pub fn sys_print_raw(s: &str) {
+    unsafe { 
+        // SAFETY: s is a valid string slice, pointer and length are guaranteed valid.
+        libc::write(1, s.as_ptr() as *const c_void, s.len()); 
+    }
+}
+
+pub fn sys_read_line() -> String {
+    let mut buffer = Vec::new();
+    let mut c: [u8; 1] = [0];
+    loop {
+        // SAFETY: We provide a valid mutable pointer to a stack allocated buffer of size 1.
+        // Reading from stdin (0) is safe.
+        let n = unsafe { libc::read(0, c.as_mut_ptr() as *mut c_void, 1) };
+        if n <= 0 { break; }
+        if c[0] == b'\n' { break; }
+        buffer.push(c[0]);
+    }
+    String::from_utf8_lossy(&buffer).trim().into()
+}
+
+pub fn sys_open_rw(path: &str) -> i32 {
+    let path_c = format!("{}\0", path);
+    // SAFETY: path_c is a null-terminated string created just above.
+    // Passing it to open is safe as long as the underlying system supports it.
+    unsafe { libc::open(path_c.as_ptr() as *const i8, libc::O_RDWR) }
+}
+
+pub fn sys_open_read(path: &str) -> i32 {
+    let path_c = format!("{}\0", path);
+    // SAFETY: path_c is a null-terminated string created just above.
+    unsafe { libc::open(path_c.as_ptr() as *const i8, libc::O_RDONLY) }
+}
+
+pub fn sys_read_all(fd: i32) -> Vec<u8> {
+    unsafe {
+        // SAFETY: lseek is used to determine file size.
+        let size = libc::lseek(fd, 0, libc::SEEK_END);
+        libc::lseek(fd, 0, libc::SEEK_SET); 
+        if size <= 0 { return Vec::new(); }
+        
+        let mut buffer = vec![0u8; size as usize];
+        let mut done = 0;
+        while done < buffer.len() {
+            // SAFETY: We are reading into the unfilled part of a buffer allocated with sufficient capacity.
+            let n = libc::read(fd, buffer.as_mut_ptr().add(done) as *mut c_void, buffer.len() - done);
+            if n <= 0 { break; }
+            done += n as usize;
+        }
+        buffer.truncate(done);
+        buffer
+    }
+}
+
+pub fn sys_is_dir(path: &str) -> bool {
+    let path_c = format!("{}\0", path);
+    unsafe {
+        // SAFETY: path_c is null-terminated and st is a plain C struct that stat fills in.
+        let mut st: libc::stat = core::mem::zeroed();
+        libc::stat(path_c.as_ptr() as *const i8, &mut st) == 0 && (st.st_mode & libc::S_IFMT) == libc::S_IFDIR
+    }
+}
+
+/// Names of the entries of a host directory, without `.` and `..`, sorted.
+pub fn sys_list_dir(path: &str) -> Option<Vec<String>> {
+    let path_c = format!("{}\0", path);
+    let mut names = Vec::new();
+    unsafe {
+        // SAFETY: path_c is null-terminated; the DIR pointer is checked before use and closed below.
+        let dir = libc::opendir(path_c.as_ptr() as *const i8);
+        if dir.is_null() { return None; }
+        loop {
+            let ent = libc::readdir(dir);
+            if ent.is_null() { break; }
+            // SAFETY: readdir returned a valid entry whose d_name is null-terminated.
+            let name = CStr::from_ptr((*ent).d_name.as_ptr());
+            let name = String::from_utf8_lossy(name.to_bytes()).into_owned();
+            if name != "." && name != ".." { names.push(name); }
+        }
+        libc::closedir(dir);
+    }
+    names.sort();
+    Some(names)
+}
+
+pub fn sys_write_file(path: &str, data: &[u8]) -> bool {
+    let fd = sys_create(path);
+    if fd < 0 { return false; }
+    let ok = sys_write(fd, data);
+    sys_close(fd);
+    ok
+}
+
+pub fn sys_create(path: &str) -> i32 {
+    let path_c = format!("{}\0", path);
+    // SAFETY: path_c is a null-terminated string created just above.
+    unsafe { libc::open(path_c.as_ptr() as *const i8, libc::O_WRONLY | libc::O_CREAT | libc::O_TRUNC, 0o644) }
+}
+
+/// Writes all of `data` at the current position of `fd`.
+pub fn sys_write(fd: i32, data: &[u8]) -> bool {
+    let mut done = 0;
+    while done < data.len() {
+        // SAFETY: the pointer and length describe the unwritten tail of a valid slice.
+        let n = unsafe { libc::write(fd, data.as_ptr().add(done) as *const c_void, data.len() - done) };
+        if n <= 0 { return false; }
+        done += n as usize;
+    }
+    true
+}
+
+pub fn sys_file_size(path: &str) -> u64 {
+    let path_c = format!("{}\0", path);
+    unsafe {
+        // SAFETY: path_c is null-terminated and st is a plain C struct that stat fills in.
+        let mut st: libc::stat = core::mem::zeroed();
+        if libc::stat(path_c.as_ptr() as *const i8, &mut st) == 0 { st.st_size as u64 } else { 0 }
+    }
+}
+
+/// Modification time, in seconds and nanoseconds, and size of the file open as `fd`: what
+/// changes when another program writes to it.
+pub fn sys_stamp(fd: i32) -> Option<(i64, i64, i64)> {
+    unsafe {
+        // SAFETY: st is a plain C struct that fstat fills in.
+        let mut st: libc::stat = core::mem::zeroed();
+        if libc::fstat(fd, &mut st) != 0 { return None; }
+        Some((st.st_mtime as i64, st.st_mtime_nsec as i64, st.st_size as i64))
+    }
+}
+
+pub fn sys_mkdir(path: &str) -> bool {
+    let path_c = format!("{}\0", path);
+    // SAFETY: path_c is null-terminated. An already existing directory is fine for us.
+    unsafe { libc::mkdir(path_c.as_ptr() as *const i8, 0o755) == 0 || sys_is_dir(path) }
+}
+
+/// Sets the host access and modification times of `path`. Unset FAT dates are skipped.
+pub fn sys_set_times(path: &str, accessed: DateTime, modified: DateTime) {
+    if !modified.is_set() { return; }
+    let accessed = if accessed.is_set() { accessed } else { modified };
+    let times = [
+        libc::timeval { tv_sec: fat_to_unix(&accessed) as libc::time_t, tv_usec: 0 },
+        libc::timeval { tv_sec: fat_to_unix(&modified) as libc::time_t, tv_usec: 0 },
+    ];
+    let path_c = format!("{}\0", path);
+    // SAFETY: path_c is null-terminated and times holds the two timevals utimes expects.
+    unsafe { libc::utimes(path_c.as_ptr() as *const i8, times.as_ptr()); }
+}
+
+pub fn sys_close(fd: i32) {
+    // SAFETY: closing a descriptor we opened ourselves.
+    unsafe { libc::close(fd); }
+}
+
+/// Modification time of a host file, in seconds since the Unix epoch.
+pub fn sys_modified(path: &str) -> Option<i64> {
+    let path_c = format!("{}\0", path);
+    unsafe {
+        // SAFETY: path_c is null-terminated and st is a plain C struct that stat fills in.
+        let mut st: libc::stat = core::mem::zeroed();
+        (libc::stat(path_c.as_ptr() as *const i8, &mut st) == 0).then_some(st.st_mtime as i64)
+    }
+}
+
+pub fn sys_rmdir(path: &str) -> bool {
+    let path_c = format!("{}\0", path);
+    // SAFETY: path_c is a null-terminated string created just above.
+    unsafe { libc::rmdir(path_c.as_ptr() as *const i8) == 0 }
+}
+
+/// Monotonic clock in microseconds.
+pub fn sys_now_us() -> u64 {
+    // SAFETY: ts is a plain C struct that clock_gettime fills in.
+    unsafe {
+        let mut ts: libc::timespec = core::mem::zeroed();
+        libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut ts);
+        ts.tv_sec as u64 * 1_000_000 + ts.tv_nsec as u64 / 1000
+    }
+}
+
+/// Writes all of `data` at byte `offset` of `fd`.
+pub fn sys_pwrite(fd: i32, offset: usize, data: &[u8]) -> bool {
+    let mut done = 0;
+    while done < data.len() {
+        // SAFETY: the pointer and length describe the unwritten tail of a valid slice.
+        let n = unsafe { libc::pwrite(fd, data.as_ptr().add(done) as *const c_void, data.len() - done, (offset + done) as libc::off_t) };
+        if n <= 0 { return false; }
+        done += n as usize;
+    }
+    true
+}
+
+pub fn sys_fsync(fd: i32) -> bool {
+    // SAFETY: fsync only flushes a descriptor we opened ourselves.
+    unsafe { libc::fsync(fd) == 0 }
+}
+
+pub fn sys_ftruncate(fd: i32, len: usize) -> bool {
+    // SAFETY: ftruncate only acts on a descriptor we opened ourselves.
+    unsafe { libc::ftruncate(fd, len as libc::off_t) == 0 }
+}
+
+/// Takes an exclusive advisory lock on `fd` without waiting, so two shells can't save over
+/// each other's changes to one image. It goes away when the descriptor is closed.
+pub fn sys_lock(fd: i32) -> bool {
+    // SAFETY: flock only acts on a descriptor we opened ourselves.
+    unsafe { libc::flock(fd, libc::LOCK_EX | libc::LOCK_NB) == 0 }
+}
+
+/// Compressed image formats, by the magic number their files start with, and the program
+/// that decompresses them.
+const DECOMPRESSORS: &[(&[u8], &str)] = &[(&[0x1F, 0x8B], "gzip"), (&[0x28, 0xB5, 0x2F, 0xFD], "zstd")];
+
+/// Reads the image file `fd`, decompressed when it is a `.gz` or `.zst`, along with the
+/// program that decompressed it.
+pub fn read_image(fd: i32) -> Result<(Vec<u8>, Option<&'static str>), &'static str> {
+    let mut magic = [0u8; 4];
+    // SAFETY: magic is a valid buffer of 4 bytes.
+    let n = unsafe { libc::pread(fd, magic.as_mut_ptr() as *mut c_void, magic.len(), 0) };
+    let magic = &magic[..n.max(0) as usize];
+    let Some(&(_, program)) = DECOMPRESSORS.iter().find(|(m, _)| magic.starts_with(m)) else {
+        return Ok((sys_read_all(fd), None));
+    };
+    if !cfg!(feature = "compression") { return Err("Compressed image; build with the compression feature to read it"); }
+    sys_decompress(fd, program).map(|data| (data, Some(program))).ok_or("Cannot decompress image")
+}
+
+/// Runs `program -dc` with the file `fd` as input and returns what it writes, or `None`
+/// when it can't be run or fails.
+fn sys_decompress(fd: i32, program: &str) -> Option<Vec<u8>> {
+    let program_c = format!("{}\0", program);
+    let mut pipe = [0i32; 2];
+    // SAFETY: the child only calls async-signal-safe functions (dup2, close, lseek, execlp,
+    // _exit) on descriptors it inherited and null-terminated strings made before the fork.
+    // The parent reads into the unfilled part of a buffer with room for it.
+    unsafe {
+        if libc::pipe(pipe.as_mut_ptr()) != 0 { return None; }
+        let pid = libc::fork();
+        if pid == 0 {
+            libc::lseek(fd, 0, libc::SEEK_SET);
+            libc::dup2(fd, 0);
+            libc::dup2(pipe[1], 1);
+            libc::close(pipe[0]);
+            let name = program_c.as_ptr() as *const i8;
+            libc::execlp(name, name, c"-dc".as_ptr(), core::ptr::null::<i8>());
+            libc::_exit(127);
+        }
+        libc::close(pipe[1]);
+        let mut data = Vec::new();
+        let mut chunk = vec![0u8; 1 << 20];
+        loop {
+            let n = libc::read(pipe[0], chunk.as_mut_ptr() as *mut c_void, chunk.len());
+            if n <= 0 { break; }
+            data.extend_from_slice(&chunk[..n as usize]);
+        }
+        libc::close(pipe[0]);
+        let mut status = 0;
+        let exited = pid > 0 && libc::waitpid(pid, &mut status, 0) == pid && libc::WIFEXITED(status) && libc::WEXITSTATUS(status) == 0;
+        exited.then_some(data)
+    }
+}
+
+pub fn sys_unlink(path: &str) {
+    let path_c = format!("{}\0", path);
+    // SAFETY: path_c is a null-terminated string created just above.
+    unsafe { libc::unlink(path_c.as_ptr() as *const i8); }
+}
+
+/// Set by Ctrl-C while a command that runs until then (a server, `sync --watch`) catches it.
+pub static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn on_interrupt(_: libc::c_int) {
+    INTERRUPTED.store(true, Ordering::Relaxed);
+}
+
+/// Makes Ctrl-C set `INTERRUPTED` instead of ending the program, until the returned
+/// handler is put back with `release_interrupt`. The handler is installed without
+/// SA_RESTART, so a blocking call waiting at the time returns with EINTR.
+pub fn catch_interrupt() -> libc::sigaction {
+    INTERRUPTED.store(false, Ordering::Relaxed);
+    // SAFETY: the handler only stores to an atomic.
+    unsafe {
+        let mut action: libc::sigaction = core::mem::zeroed();
+        action.sa_sigaction = on_interrupt as extern "C" fn(libc::c_int) as libc::sighandler_t;
+        let mut previous: libc::sigaction = core::mem::zeroed();
+        libc::sigaction(libc::SIGINT, &action, &mut previous);
+        previous
+    }
+}
+
+pub fn release_interrupt(previous: libc::sigaction) {
+    // SAFETY: previous was filled in by sigaction in catch_interrupt.
+    unsafe { libc::sigaction(libc::SIGINT, &previous, core::ptr::null_mut()); }
+    sys_print("");
+}
@@ -0,0 +1,259 @@
+//! The terminal: the line editor with its completion, the pager, progress bars and prompts.
+
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::ffi::c_void;
+use core::sync::atomic::{AtomicBool, Ordering};
+use fat32::fat32::progress::ProgressSink;
+use crate::sys::{sys_print, sys_print_raw, sys_read_line};
+
+/// Whether long output goes through a pager, cleared by `--no-pager`.
+pub static PAGER: AtomicBool = AtomicBool::new(true);
+
+/// Rows of the terminal on stdout, `None` when stdout isn't one.
+fn terminal_rows() -> Option<usize> {
+    // SAFETY: isatty only inspects the descriptors; ws is a plain C struct ioctl fills in.
+    unsafe {
+        if libc::isatty(0) != 1 || libc::isatty(1) != 1 { return None; }
+        let mut ws: libc::winsize = core::mem::zeroed();
+        if libc::ioctl(1, libc::TIOCGWINSZ, &mut ws) != 0 || ws.ws_row == 0 { return None; }
+        Some(ws.ws_row as usize)
+    }
+}
+
+/// Prints `lines`, through `$PAGER` or a built-in pager when they don't fit on the
+/// terminal. Printed as is when stdout or stdin isn't a terminal, or with `--no-pager`.
+pub fn page(lines: &[String]) {
+    let rows = terminal_rows().filter(|_| PAGER.load(Ordering::Relaxed));
+    let Some(rows) = rows.filter(|&rows| lines.len() >= rows) else {
+        for line in lines { sys_print(line); }
+        return;
+    };
+    // SAFETY: getenv reads a null-terminated name; the command and mode given to popen
+    // are null-terminated and the stream is only used until pclose.
+    unsafe {
+        let pager = libc::getenv(c"PAGER".as_ptr());
+        if !pager.is_null() && *pager != 0 {
+            let stream = libc::popen(pager, c"w".as_ptr());
+            if !stream.is_null() {
+                for line in lines {
+                    libc::fwrite(line.as_ptr() as *const c_void, 1, line.len(), stream);
+                    libc::fwrite("\n".as_ptr() as *const c_void, 1, 1, stream);
+                }
+                libc::pclose(stream);
+                return;
+            }
+        }
+    }
+    page_builtin(lines, rows);
+}
+
+/// Shows `lines` a screen at a time: space for the next screen, enter for the next
+/// line, q to stop.
+fn page_builtin(lines: &[String], rows: usize) {
+    let saved = enter_raw_mode();
+    let mut shown = 0;
+    let mut screen = rows - 1;
+    while shown < lines.len() {
+        for line in &lines[shown..(shown + screen).min(lines.len())] { sys_print(line); }
+        shown = (shown + screen).min(lines.len());
+        if shown == lines.len() { break; }
+        sys_print_raw(&format!("--More-- ({}%)", shown * 100 / lines.len()));
+        let mut key = [0u8; 1];
+        // SAFETY: key is a valid buffer of one byte.
+        let n = unsafe { libc::read(0, key.as_mut_ptr() as *mut c_void, 1) };
+        sys_print_raw("\r\x1b[K");
+        match key[0] {
+            _ if n <= 0 => break,
+            b'q' | b'Q' => break,
+            b'\n' => screen = 1,
+            _ => screen = rows - 1,
+        }
+    }
+    restore_terminal(&saved);
+}
+
+/// Turns off line buffering and echo on the terminal, returning the settings to give
+/// back to `restore_terminal`.
+fn enter_raw_mode() -> libc::termios {
+    // SAFETY: the terminal settings are plain C structs filled in by tcgetattr.
+    unsafe {
+        let mut saved: libc::termios = core::mem::zeroed();
+        libc::tcgetattr(0, &mut saved);
+        let mut raw = saved;
+        raw.c_lflag &= !(libc::ICANON | libc::ECHO);
+        libc::tcsetattr(0, libc::TCSANOW, &raw);
+        saved
+    }
+}
+
+fn restore_terminal(saved: &libc::termios) {
+    // SAFETY: saved holds settings read by tcgetattr.
+    unsafe { libc::tcsetattr(0, libc::TCSANOW, saved); }
+}
+
+/// Shell commands, completed on the first word of a line.
+pub const COMMANDS: &[&str] = &[
+    "align", "allocate", "archive", "atime", "backup-bootsector", "bench", "carve", "cat", "cd",
+    "chain", "checksum", "codepage", "commit", "compactdir", "cp", "ddclusters", "defrag", "df",
+    "diff", "dirstat", "discard", "du", "edit-sector", "exit", "export-tar", "fat", "fatcmp",
+    "fatdump", "fatsync", "file", "find", "get", "head", "info", "ls", "manifest", "md5",
+    "mkdir", "mount", "put", "quit", "readsector", "recluster", "reload", "resize",
+    "restore-bootsector", "rm", "serial", "serve", "serve-9p", "serve-nbd", "set-serial",
+    "sha256", "slack", "snapshot", "stat", "stats", "strings", "sync", "tail", "touch", "tree",
+    "tz", "umount", "wc", "writesector", "zerofree",
+];
+
+/// Reads a line after printing `prompt`. On a terminal, Tab completes the word before the
+/// cursor with what `complete` offers for it, unquoted, and whether it is the first word
+/// of the line: the common start of the offers is filled in, and pressing Tab again
+/// lists them when there is nothing more to fill in.
+pub fn read_line_completing(prompt: &str, complete: &mut dyn FnMut(&str, bool) -> Vec<String>) -> String {
+    sys_print_raw(prompt);
+    // SAFETY: isatty only inspects the descriptor.
+    if unsafe { libc::isatty(0) } != 1 { return sys_read_line(); }
+    let saved = enter_raw_mode();
+    let mut line: Vec<u8> = Vec::new();
+    let read_byte = || {
+        let mut c = [0u8; 1];
+        // SAFETY: c is a valid buffer of one byte.
+        let n = unsafe { libc::read(0, c.as_mut_ptr() as *mut c_void, 1) };
+        (n > 0).then_some(c[0])
+    };
+    while let Some(c) = read_byte() {
+        match c {
+            b'\n' | b'\r' => break,
+            // Ctrl-D on an empty line leaves the shell, as it would in sh.
+            0x04 if line.is_empty() => {
+                line.extend_from_slice(b"exit");
+                break;
+            }
+            0x7f | 0x08 => {
+                while line.pop().is_some_and(|b| b & 0xC0 == 0x80) {}
+                redraw_line(prompt, &line);
+            }
+            // Ctrl-U clears the line.
+            0x15 => {
+                line.clear();
+                redraw_line(prompt, &line);
+            }
+            // Cursor keys and the like are not supported: skip their escape sequence.
+            0x1b if read_byte() == Some(b'[') => {
+                while read_byte().is_some_and(|b| !(0x40..=0x7e).contains(&b)) {}
+            }
+            b'\t' => complete_word(prompt, &mut line, complete),
+            c if c >= 0x20 => {
+                line.push(c);
+                // SAFETY: c is a valid buffer of one byte.
+                unsafe { libc::write(1, &c as *const u8 as *const c_void, 1); }
+            }
+            _ => {}
+        }
+    }
+    sys_print_raw("\n");
+    restore_terminal(&saved);
+    String::from_utf8_lossy(&line).trim().into()
+}
+
+fn redraw_line(prompt: &str, line: &[u8]) {
+    sys_print_raw(&format!("\r\x1b[K{}{}", prompt, String::from_utf8_lossy(line)));
+}
+
+/// Completes the last word of `line`, see `read_line_completing`.
+fn complete_word(prompt: &str, line: &mut Vec<u8>, complete: &mut dyn FnMut(&str, bool) -> Vec<String>) {
+    // The word starts after the last space that is neither quoted nor escaped.
+    let (mut start, mut quoted, mut escaped) = (0, false, false);
+    for (i, &b) in line.iter().enumerate() {
+        match b {
+            _ if escaped => escaped = false,
+            b'\\' => escaped = true,
+            b'"' => quoted = !quoted,
+            b' ' | b'\t' if !quoted => start = i + 1,
+            _ => {}
+        }
+    }
+    let text = String::from_utf8_lossy(&line[start..]).into_owned();
+    let mut word = String::new();
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' => {}
+            '\\' => word.extend(chars.next()),
+            c => word.push(c),
+        }
+    }
+    let before = String::from_utf8_lossy(&line[..start]);
+    let before = before.trim_end();
+    let first = before.is_empty() || before.ends_with(';') || before.ends_with("&&");
+    let offers = complete(&word, first);
+    let Some(common) = offers.iter().map(String::as_str).reduce(|a, b| {
+        let len = a.char_indices().zip(b.chars()).find(|((_, x), y)| x != y).map_or(a.len().min(b.len()), |((i, _), _)| i);
+        &a[..len]
+    }) else {
+        sys_print_raw("\x07");
+        return;
+    };
+    if common != word || offers.len() == 1 {
+        let mut replacement: String = common.chars().flat_map(|c| {
+            let escape = matches!(c, ' ' | '\t' | '"' | '\\').then_some('\\');
+            escape.into_iter().chain([c])
+        }).collect();
+        if offers.len() == 1 && !common.ends_with('/') { replacement.push(' '); }
+        line.truncate(start);
+        line.extend_from_slice(replacement.as_bytes());
+    } else {
+        // Nothing more to fill in: show what the word could become.
+        let names: Vec<&str> = offers.iter().map(|offer| {
+            let trimmed = offer.trim_end_matches('/');
+            &offer[trimmed.rfind('/').map_or(0, |i| i + 1)..]
+        }).collect();
+        sys_print_raw(&format!("\n{}\n", names.join("  ")));
+    }
+    redraw_line(prompt, line);
+}
+
+/// Draws a progress bar on the terminal, redrawn each time the percentage changes.
+/// Stays silent when stdout is not a terminal, so scripted sessions get clean output.
+pub struct ProgressBar {
+    enabled: bool,
+    percent: Option<u64>,
+}
+
+impl ProgressBar {
+    pub fn new() -> Self {
+        // SAFETY: isatty only inspects the descriptor.
+        ProgressBar { enabled: unsafe { libc::isatty(1) } == 1, percent: None }
+    }
+}
+
+impl ProgressSink for ProgressBar {
+    fn progress(&mut self, done: u64, total: u64, current: &str) {
+        let percent = (done * 100).checked_div(total).unwrap_or(100);
+        if !self.enabled || self.percent == Some(percent) { return; }
+        self.percent = Some(percent);
+        let filled = (percent / 5) as usize;
+        sys_print_raw(&format!("\r[{}{}] {:3}% {}/{} KiB {}\x1b[K",
+            "#".repeat(filled), " ".repeat(20 - filled), percent, done / 1024, total / 1024, current));
+    }
+
+    fn finish(&mut self) {
+        if self.enabled && self.percent.is_some() { sys_print_raw("\r\x1b[K"); }
+    }
+}
+
+/// Prints `bytes` 16 to a line: image offset, hex, then the printable characters.
+pub fn print_hexdump(offset: usize, bytes: &[u8]) {
+    for (i, line) in bytes.chunks(16).enumerate() {
+        let hex: Vec<String> = line.iter().map(|b| format!("{:02x}", b)).collect();
+        let text: String = line.iter().map(|&b| if b.is_ascii_graphic() || b == b' ' { b as char } else { '.' }).collect();
+        sys_print(&format!("{:08x}  {:<47}  {}", offset + i * 16, hex.join(" "), text));
+    }
+}
+
+/// Asks `question` and reads the answer: true for `y` or `yes`, false for anything else.
+/// Commands that destroy data ask before doing so, unless given `-f` or `--yes`.
+pub fn confirm(question: &str) -> bool {
+    sys_print_raw(&format!("{} y/n ", question));
+    matches!(sys_read_line().to_ascii_lowercase().as_str(), "y" | "yes")
+}
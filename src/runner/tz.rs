@@ -0,0 +1,84 @@
+//! The time zone timestamps are shown and written in, and how they are shown.
+
+use alloc::format;
+use alloc::string::String;
+use core::sync::atomic::{AtomicI32, Ordering};
+use fat32::fat32::dir::DirEntry;
+use fat32::fat32::time::DateTime;
+
+/// Time zone the timestamps of the images are read and written in, in minutes east of
+/// UTC, or `LOCAL_TZ` for the host's local time. FAT itself doesn't record one.
+pub static TZ_MINUTES: AtomicI32 = AtomicI32::new(LOCAL_TZ);
+
+const LOCAL_TZ: i32 = i32::MIN;
+
+/// Parses `local`, `UTC` or an offset such as `+02:00`, `-0530` or `+1`.
+pub fn parse_tz(s: &str) -> Option<i32> {
+    match s {
+        "local" => return Some(LOCAL_TZ),
+        "UTC" | "utc" | "Z" => return Some(0),
+        _ => {}
+    }
+    let sign = match s.as_bytes().first()? { b'+' => 1, b'-' => -1, _ => return None };
+    let digits: String = s[1..].chars().filter(|&c| c != ':').collect();
+    if digits.is_empty() || digits.len() > 4 || !digits.bytes().all(|b| b.is_ascii_digit()) { return None; }
+    let (hours, minutes) = if digits.len() <= 2 { (&digits[..], "0") } else { digits.split_at(digits.len() - 2) };
+    let (hours, minutes): (i32, i32) = (hours.parse().ok()?, minutes.parse().ok()?);
+    (hours <= 14 && minutes < 60).then_some(sign * (hours * 60 + minutes))
+}
+
+pub fn tz_name(tz: i32) -> String {
+    if tz == LOCAL_TZ { "local time".into() } else { format!("UTC{}", format_offset(tz as i64 * 60)) }
+}
+
+fn format_offset(seconds: i64) -> String {
+    let minutes = seconds.abs() / 60;
+    format!("{}{:02}:{:02}", if seconds < 0 { '-' } else { '+' }, minutes / 60, minutes % 60)
+}
+
+/// Offset from UTC, in seconds, of the time zone in use at the instant `unix`.
+pub fn utc_offset(unix: i64) -> i64 {
+    match TZ_MINUTES.load(Ordering::Relaxed) {
+        LOCAL_TZ => {
+            // SAFETY: tm is a plain C struct that localtime_r fills in from t.
+            unsafe {
+                let t = unix as libc::time_t;
+                let mut tm: libc::tm = core::mem::zeroed();
+                if libc::localtime_r(&t, &mut tm).is_null() { 0 } else { tm.tm_gmtoff as i64 }
+            }
+        }
+        minutes => minutes as i64 * 60,
+    }
+}
+
+/// Seconds since the Unix epoch of a timestamp read in the time zone in use.
+pub fn fat_to_unix(time: &DateTime) -> i64 {
+    let wall = time.to_unix();
+    wall - utc_offset(wall)
+}
+
+/// The current time in the time zone in use, the clock of every mounted volume.
+pub fn fat_now() -> (DateTime, u8) {
+    // SAFETY: ts is a plain C struct that clock_gettime fills in.
+    let ts = unsafe {
+        let mut ts: libc::timespec = core::mem::zeroed();
+        libc::clock_gettime(libc::CLOCK_REALTIME, &mut ts);
+        ts
+    };
+    let now = ts.tv_sec as i64;
+    DateTime::from_unix_millis((now + utc_offset(now)) * 1000 + ts.tv_nsec as i64 / 1_000_000)
+}
+
+/// `2024-03-15 13:45:30 +01:00`, or `-` for an unset date.
+pub fn format_time(time: &DateTime) -> String {
+    if !time.is_set() { return "-".into(); }
+    format!("{} {}", time, format_offset(utc_offset(fat_to_unix(time))))
+}
+
+/// A creation time with its hundredths of a second: `2024-03-15 13:45:31.25 +01:00`.
+pub fn format_created(entry: &DirEntry) -> String {
+    if !entry.created.is_set() { return "-".into(); }
+    let hundredths = entry.created_hundredths.min(199);
+    let time = DateTime { second: entry.created.second + hundredths / 100, ..entry.created };
+    format!("{}.{:02} {}", time, hundredths % 100, format_offset(utc_offset(fat_to_unix(&time))))
+}
@@ -0,0 +1,140 @@
+//! Interoperability with the reference tools: images made by `mkfs.fat` and filled by
+//! mtools must read back the same through this crate, and images this crate makes must
+//! pass `fsck.fat` and read back the same through mtools.
+//!
+//! The tools are not always installed, so the tests only run when `FAT32_INTEROP` is set:
+//!
+//!     FAT32_INTEROP=1 cargo test --test interop
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use fat32::fat32::dir::ListOptions;
+use fat32::fat32::format::{format, FormatOptions};
+use fat32::fat32::volume::Fat32Volume;
+
+const MB: usize = 1024 * 1024;
+
+/// Files put on both kinds of image: a short name, a long one with spaces and accents, an
+/// empty file and one spanning many clusters, some in nested directories.
+fn golden_files() -> Vec<(&'static str, Vec<u8>)> {
+    vec![
+        ("README.TXT", b"golden image\n".to_vec()),
+        ("DCIM/100CANON/Vacances à la plage.jpg", (0..200_000).map(|i| (i * 7) as u8).collect()),
+        ("DCIM/empty.dat", Vec::new()),
+        ("docs/Un nom de fichier très long.txt", b"long name\n".repeat(300)),
+    ]
+}
+
+fn golden_dirs() -> [&'static str; 4] {
+    ["DCIM", "DCIM/100CANON", "docs", "docs/Dossier vide"]
+}
+
+fn enabled() -> bool {
+    let set = std::env::var_os("FAT32_INTEROP").is_some();
+    if !set { eprintln!("skipped: set FAT32_INTEROP=1 to run against mkfs.fat, mtools and fsck.fat"); }
+    set
+}
+
+/// A scratch directory removed when dropped.
+struct Scratch(PathBuf);
+
+impl Scratch {
+    fn new(test: &str) -> Self {
+        let dir = std::env::temp_dir().join(format!("fat32-interop-{}-{}", std::process::id(), test));
+        fs::create_dir_all(&dir).unwrap();
+        Scratch(dir)
+    }
+}
+
+impl Drop for Scratch {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+/// Runs a tool, failing the test with its output when it fails. Returns its stdout.
+fn run(program: &str, args: &[&str]) -> Vec<u8> {
+    let output = Command::new(program)
+        .args(args)
+        // mtools refuses images whose geometry it can't reconcile with a floppy otherwise.
+        .env("MTOOLS_SKIP_CHECK", "1")
+        .output()
+        .unwrap_or_else(|e| panic!("{}: {}", program, e));
+    assert!(
+        output.status.success(),
+        "{} {:?} failed:\n{}{}",
+        program,
+        args,
+        String::from_utf8_lossy(&output.stdout),
+        String::from_utf8_lossy(&output.stderr)
+    );
+    output.stdout
+}
+
+fn mtools_path(path: &str) -> String {
+    format!("::/{}", path)
+}
+
+fn as_str(path: &Path) -> &str {
+    path.to_str().unwrap()
+}
+
+#[test]
+fn test_reads_mkfs_image() {
+    if !enabled() { return; }
+    let scratch = Scratch::new("mkfs");
+    let image = scratch.0.join("golden.img");
+    run("mkfs.fat", &["-F", "32", "-S", "512", "-s", "1", "-n", "GOLDEN", "-C", as_str(&image), "65536"]);
+    for dir in golden_dirs() {
+        run("mmd", &["-i", as_str(&image), &mtools_path(dir)]);
+    }
+    for (i, (path, content)) in golden_files().iter().enumerate() {
+        let local = scratch.0.join(format!("file{}", i));
+        fs::write(&local, content).unwrap();
+        run("mcopy", &["-i", as_str(&image), as_str(&local), &mtools_path(path)]);
+    }
+
+    let mut data = fs::read(&image).unwrap();
+    let volume = Fat32Volume::new(&mut data).unwrap();
+    for (path, content) in golden_files() {
+        assert_eq!(volume.read_file(path).unwrap(), content, "{}", path);
+    }
+    for dir in golden_dirs() {
+        assert!(volume.directory_cluster(dir).is_ok(), "{}", dir);
+    }
+    let names: Vec<String> = volume.list_entries("DCIM", &ListOptions::default()).unwrap().into_iter().map(|e| e.name).collect();
+    assert_eq!(names.len(), 2);
+    assert!(names.iter().any(|n| n == "100CANON") && names.iter().any(|n| n == "empty.dat"), "{:?}", names);
+    let walked = volume.walk("/").unwrap().count();
+    assert_eq!(walked, golden_files().len() + golden_dirs().len());
+}
+
+#[test]
+fn test_own_image_passes_fsck_and_mtools() {
+    if !enabled() { return; }
+    let scratch = Scratch::new("own");
+    let image = scratch.0.join("own.img");
+    let mut data = vec![0u8; 64 * MB];
+    format(&mut data, &FormatOptions { volume_id: 0x1234_5678, label: *b"OWN IMAGE  ", sectors_per_cluster: Some(1) }).unwrap();
+    {
+        let mut volume = Fat32Volume::new(&mut data).unwrap();
+        for dir in golden_dirs() {
+            volume.create_directory(dir).unwrap();
+        }
+        for (path, content) in golden_files() {
+            volume.create_file(path, &content, false).unwrap();
+        }
+    }
+    fs::write(&image, &data).unwrap();
+
+    // -n: report only, so any repair it would make fails the test.
+    run("fsck.fat", &["-n", "-V", as_str(&image)]);
+    for (path, content) in golden_files() {
+        assert_eq!(run("mtype", &["-i", as_str(&image), &mtools_path(path)]), content, "{}", path);
+    }
+    for dir in golden_dirs() {
+        run("mdir", &["-i", as_str(&image), &mtools_path(dir)]);
+    }
+}
@@ -0,0 +1,179 @@
+//! Drives the `runner` shell against scratch images: each test writes a script of
+//! commands to its standard input and checks what it printed and what it left on disk.
+
+use std::fs;
+use std::io::Write;
+use std::path::PathBuf;
+use std::process::{Child, Command, Stdio};
+
+/// A scratch directory, holding the session image `fat32.img`, removed when dropped.
+struct Scratch(PathBuf);
+
+impl Scratch {
+    /// Makes the directory and an empty image of `size` (`40M`...) in it.
+    fn new(test: &str, size: &str) -> Self {
+        let dir = std::env::temp_dir().join(format!("fat32-shell-{}-{}", std::process::id(), test));
+        fs::create_dir_all(&dir).unwrap();
+        let scratch = Scratch(dir);
+        scratch.image("fat32.img", size);
+        scratch
+    }
+
+    /// The runner, started in the directory, with it as `HOME` so no rc file is read.
+    fn command(&self) -> Command {
+        let mut command = Command::new(env!("CARGO_BIN_EXE_runner"));
+        command.current_dir(&self.0).env("HOME", &self.0).env("NO_COLOR", "1");
+        command
+    }
+
+    /// Runs the shell on `script`, one command per line, and returns what it printed.
+    /// Fails the test when it didn't save and exit normally.
+    fn shell(&self, script: &str) -> String {
+        let mut child = self.spawn();
+        child.stdin.take().unwrap().write_all(format!("{}\nexit\n", script).as_bytes()).unwrap();
+        Self::finish(child, script)
+    }
+
+    /// Starts the shell, for the test to write commands to as it goes.
+    fn spawn(&self) -> Child {
+        self.command()
+            .args(["--no-color", "--no-pager"])
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .unwrap()
+    }
+
+    /// Waits for a shell started by `spawn` and returns what it printed, failing the
+    /// test, which ran `script`, when it didn't save and exit normally.
+    fn finish(child: Child, script: &str) -> String {
+        let output = child.wait_with_output().unwrap();
+        let stdout = String::from_utf8_lossy(&output.stdout).into_owned();
+        assert!(
+            output.status.success() && stdout.contains("Bye."),
+            "shell failed on:\n{}\n{}{}",
+            script,
+            stdout,
+            String::from_utf8_lossy(&output.stderr)
+        );
+        stdout
+    }
+
+    fn path(&self, name: &str) -> PathBuf {
+        self.0.join(name)
+    }
+
+    /// Makes another empty image, `name`, in the directory.
+    fn image(&self, name: &str, size: &str) {
+        let output = self.command().args(["create", "--size", size, name]).output().unwrap();
+        assert!(output.status.success(), "create failed:\n{}", String::from_utf8_lossy(&output.stdout));
+    }
+}
+
+impl Drop for Scratch {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+#[test]
+fn test_resize_then_use_the_volume() {
+    let scratch = Scratch::new("resize", "40M");
+    let output = scratch.shell("touch a.txt hello\nresize --grow 64M\ncat a.txt\nresize --shrink 36M\ncat a.txt");
+    assert!(output.contains("grown from"), "{}", output);
+    assert!(output.contains("shrunk from"), "{}", output);
+    assert_eq!(output.matches("hello").count(), 2, "{}", output);
+    assert_eq!(fs::metadata(scratch.path("fat32.img")).unwrap().len(), 36 * 1024 * 1024);
+
+    let output = scratch.shell("cat a.txt");
+    assert!(output.contains("hello"), "{}", output);
+}
+
+#[test]
+fn test_discard_after_resize() {
+    let scratch = Scratch::new("discard", "40M");
+    scratch.shell("touch a.txt hello");
+    let output = scratch.shell("resize --grow 64M\ntouch b.txt world\ndiscard\ncat a.txt\nls\nresize --shrink 36M\ndiscard\ncat a.txt");
+    assert_eq!(output.matches("blocks discarded").count(), 2, "{}", output);
+    assert_eq!(output.matches("hello").count(), 2, "{}", output);
+    assert!(!output.contains("B.TXT") && !output.contains("b.txt"), "{}", output);
+    assert_eq!(fs::metadata(scratch.path("fat32.img")).unwrap().len(), 40 * 1024 * 1024);
+}
+
+#[test]
+fn test_cp_between_images() {
+    let scratch = Scratch::new("cp", "40M");
+    scratch.image("b.img", "40M");
+    let content: Vec<u8> = (0..300_000u32).map(|i| (i * 7 % 251) as u8).collect();
+    fs::write(scratch.path("big.bin"), &content).unwrap();
+
+    let output = scratch.shell("put big.bin big.bin\nmount b.img b\nmkdir b:dir\ncp big.bin b:dir\ntouch b:dir/small.txt hi\ncp b:dir/small.txt big.bin\ncp b:dir/big.bin back.bin\nget back.bin back.bin\ncat big.bin");
+    assert_eq!(output.matches("File copied.").count(), 4, "{}", output);
+    assert!(output.contains("> hi"), "{}", output);
+    assert_eq!(fs::read(scratch.path("back.bin")).unwrap(), content);
+}
+
+#[test]
+fn test_sync_both_ways() {
+    let scratch = Scratch::new("sync", "40M");
+    fs::create_dir_all(scratch.path("src/sub")).unwrap();
+    fs::write(scratch.path("src/a.txt"), "one").unwrap();
+    fs::write(scratch.path("src/sub/b.txt"), "two").unwrap();
+
+    let output = scratch.shell("sync src docs\ncat docs/sub/b.txt\nsync src docs");
+    assert!(output.contains("+ a.txt") && output.contains("+ sub/") && output.contains("+ sub/b.txt"), "{}", output);
+    assert!(output.contains("3 added, 0 updated, 0 deleted, 0 unchanged, 6 bytes copied."), "{}", output);
+    assert!(output.contains("> two"), "{}", output);
+    assert!(output.contains("0 added, 0 updated, 0 deleted, 2 unchanged, 0 bytes copied."), "{}", output);
+
+    fs::remove_file(scratch.path("src/a.txt")).unwrap();
+    let output = scratch.shell("sync --delete --dry-run src docs\ncat docs/a.txt\nsync --delete src docs\nls docs");
+    assert_eq!(output.matches("- a.txt").count(), 2, "{}", output);
+    assert!(output.contains("Dry run: 0 added, 0 updated, 1 deleted"), "{}", output);
+    assert_eq!(output.matches("one").count(), 1, "{}", output);
+
+    let output = scratch.shell("touch docs/c.txt three\nsync --pull back docs");
+    assert!(output.contains("+ c.txt"), "{}", output);
+    assert_eq!(fs::read_to_string(scratch.path("back/sub/b.txt")).unwrap(), "two");
+    assert_eq!(fs::read_to_string(scratch.path("back/c.txt")).unwrap(), "three");
+    assert!(!scratch.path("back/a.txt").exists());
+}
+
+#[cfg(feature = "serve")]
+#[test]
+fn test_serve_a_large_file() {
+    use std::io::Read;
+    use std::net::TcpStream;
+    use std::time::{Duration, Instant};
+
+    let scratch = Scratch::new("serve", "40M");
+    let content: Vec<u8> = (0..4 << 20).map(|i: u32| (i * 13 % 251) as u8).collect();
+    fs::write(scratch.path("big.bin"), &content).unwrap();
+    scratch.shell("put big.bin big.bin");
+
+    let port = 20000 + (std::process::id() % 10000) as u16;
+    let mut child = scratch.spawn();
+    let mut stdin = child.stdin.take().unwrap();
+    stdin.write_all(format!("serve --port {}\n", port).as_bytes()).unwrap();
+    let started = Instant::now();
+    let mut stream = loop {
+        match TcpStream::connect(("127.0.0.1", port)) {
+            Ok(stream) => break stream,
+            Err(_) if started.elapsed() < Duration::from_secs(10) => std::thread::sleep(Duration::from_millis(50)),
+            Err(e) => panic!("serve never listened: {}", e),
+        }
+    };
+    stream.write_all(b"GET /big.bin HTTP/1.0\r\n\r\n").unwrap();
+    let mut reply = Vec::new();
+    stream.read_to_end(&mut reply).unwrap();
+
+    Command::new("kill").args(["-INT", &child.id().to_string()]).status().unwrap();
+    stdin.write_all(b"exit\n").unwrap();
+    drop(stdin);
+    Scratch::finish(child, "serve");
+
+    let body = reply.windows(4).position(|w| w == b"\r\n\r\n").map(|at| &reply[at + 4..]).unwrap();
+    assert!(reply.starts_with(b"HTTP/1.1 200 OK"));
+    assert!(body == content, "{} bytes sent of {}", body.len(), content.len());
+}